@@ -7,9 +7,11 @@ mod wayland;
 use crate::core::client::CLIENTS;
 use crate::core::destroy_queue;
 use crate::nodes::items::camera;
+#[cfg(feature = "wayland")]
+use crate::nodes::items::panel::{ButtonLayout, DecorationTheme};
 use crate::nodes::{audio, drawable, hmd, input};
 use crate::objects::input::eye_pointer::EyePointer;
-use crate::objects::input::mouse_pointer::MousePointer;
+use crate::objects::input::mouse_pointer::{KeymapNames, MousePointer};
 use crate::objects::input::sk_controller::SkController;
 use crate::objects::input::sk_hand::SkHand;
 use crate::objects::play_space::PlaySpace;
@@ -21,6 +23,8 @@ use core::client_state::ClientState;
 use directories::ProjectDirs;
 use once_cell::sync::OnceCell;
 use stardust_xr::server;
+#[cfg(feature = "wayland")]
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
@@ -60,6 +64,123 @@ struct CliArgs {
 	/// Restore the session with the given ID (or `latest`), ignoring the startup script. Sessions are stored in directories at `~/.local/state/stardust/`.
 	#[clap(id = "SESSION_ID", long = "restore", action)]
 	restore: Option<String>,
+
+	/// XKB model for the flatscreen keyboard, falls back to $XKB_DEFAULT_MODEL
+	#[clap(long, action)]
+	xkb_model: Option<String>,
+
+	/// XKB layouts for the flatscreen keyboard (e.g. `us,ru`), falls back to $XKB_DEFAULT_LAYOUT
+	#[clap(long, action)]
+	xkb_layout: Option<String>,
+
+	/// XKB variants for the flatscreen keyboard, falls back to $XKB_DEFAULT_VARIANT
+	#[clap(long, action)]
+	xkb_variant: Option<String>,
+
+	/// XKB options for the flatscreen keyboard (e.g. `grp:alt_shift_toggle`), falls back to $XKB_DEFAULT_OPTIONS
+	#[clap(long, action)]
+	xkb_options: Option<String>,
+
+	/// Name of the Wayland socket to listen on (e.g. `wayland-stardust`), the first free `wayland-N` is used if not set
+	#[cfg(feature = "wayland")]
+	#[clap(id = "SOCKET_NAME", long = "wayland-socket", action)]
+	wayland_socket: Option<String>,
+
+	/// Accept Wayland clients on this already listening unix socket instead of binding one (e.g. from a sandboxed launcher or systemd socket activation), `--wayland-socket` is then only the name clients are given
+	#[cfg(feature = "wayland")]
+	#[clap(id = "FD", long = "wayland-socket-fd", action)]
+	wayland_socket_fd: Option<RawFd>,
+
+	/// Another Wayland output for clients to go fullscreen on, e.g. at a higher scale (`NAME=WIDTHxHEIGHT@SCALE`, can be given more than once)
+	#[cfg(feature = "wayland")]
+	#[clap(id = "OUTPUT", long = "wayland-output", action)]
+	wayland_outputs: Vec<WaylandOutput>,
+
+	/// What each Wayland client can allocate before it's disconnected (or dispatched last for `commits_per_second`), as comma separated `key=value` (`surfaces`, `buffers`, `texture_mib`, `commits_per_second` and `buffer_size`)
+	#[cfg(feature = "wayland")]
+	#[clap(id = "LIMITS", long = "wayland-client-limits", action)]
+	wayland_client_limits: Option<wayland::ClientLimits>,
+
+	/// Only move keyboard focus to Wayland surfaces when they're clicked instead of when the pointer enters them, same as $STARDUST_FOCUS_POLICY=click
+	#[cfg(feature = "wayland")]
+	#[clap(long, action)]
+	click_to_focus: bool,
+
+	/// What Wayland surfaces are composited onto, `transparent` to float over passthrough or a solid `#rrggbb` to match a VR skybox
+	#[cfg(feature = "wayland")]
+	#[clap(id = "BACKGROUND", long = "wayland-background", action)]
+	wayland_background: Option<wayland::SurfaceBackground>,
+
+	/// How many textures (1 to 3) composited Wayland surfaces cycle through, more let video update without waiting on the draw at the cost of memory, falls back to $STARDUST_TEXTURE_BUFFERS
+	#[cfg(feature = "wayland")]
+	#[clap(id = "BUFFERS", long = "wayland-texture-buffers", action)]
+	wayland_texture_buffers: Option<usize>,
+
+	/// Keep up to this many KiB of the Wayland clipboard so it outlives the client it's from, 0 turns it off (1024 by default)
+	#[cfg(feature = "wayland")]
+	#[clap(id = "KIB", long = "wayland-clipboard-cache", action)]
+	wayland_clipboard_cache: Option<usize>,
+
+	/// Cache every type on the Wayland clipboard, not just text
+	#[cfg(feature = "wayland")]
+	#[clap(long, action)]
+	wayland_clipboard_cache_all: bool,
+
+	/// Which titlebar buttons server-side decorations have on which side, like GNOME's `button-layout` (e.g. `close:minimize,maximize`)
+	#[cfg(feature = "wayland")]
+	#[clap(id = "LAYOUT", long = "decoration-buttons", action)]
+	decoration_buttons: Option<ButtonLayout>,
+
+	/// Font file for the titles of server-side decorations, StereoKit's default font if not set
+	#[cfg(feature = "wayland")]
+	#[clap(id = "FONT", long = "decoration-font", action)]
+	decoration_font: Option<String>,
+
+	/// Draw the buffer size, logical size, scale and frame rate of each Wayland toplevel over its panel, same as setting $STARDUST_DEBUG_OVERLAY
+	#[cfg(feature = "wayland")]
+	#[clap(long, action)]
+	wayland_debug_overlay: bool,
+
+	/// Measure how long Wayland clients take to react to input, logged with the Wayland metrics, same as setting $STARDUST_INPUT_LATENCY
+	#[cfg(feature = "wayland")]
+	#[clap(long, action)]
+	wayland_input_latency: bool,
+
+	/// Input devices Wayland clients' seats have, as a comma separated list of `pointer`, `keyboard` and `touch` (all of them by default), for shells that never send some of them
+	#[cfg(feature = "wayland")]
+	#[clap(id = "DEVICES", long = "wayland-seat-capabilities", action)]
+	wayland_seat_capabilities: Option<wayland::SeatCapabilities>,
+}
+
+#[cfg(feature = "wayland")]
+#[derive(Debug, Clone)]
+struct WaylandOutput {
+	name: String,
+	size: mint::Vector2<u32>,
+	scale: i32,
+}
+#[cfg(feature = "wayland")]
+impl std::str::FromStr for WaylandOutput {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || format!("{s:?} isn't NAME=WIDTHxHEIGHT@SCALE");
+		let (name, mode) = s.split_once('=').ok_or_else(invalid)?;
+		let (size, scale) = mode.split_once('@').unwrap_or((mode, "1"));
+		let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+		let (Ok(width), Ok(height), Ok(scale)) = (width.parse(), height.parse(), scale.parse())
+		else {
+			return Err(invalid());
+		};
+		if name.is_empty() || width == 0 || height == 0 || scale < 1 {
+			return Err(invalid());
+		}
+		Ok(WaylandOutput {
+			name: name.to_string(),
+			size: [width, height].into(),
+			scale,
+		})
+	}
 }
 
 static STARDUST_INSTANCE: OnceCell<String> = OnceCell::new();
@@ -165,7 +286,14 @@ fn main() {
 
 	let mut mouse_pointer = cli_args
 		.flatscreen
-		.then(MousePointer::new)
+		.then(|| {
+			MousePointer::new(KeymapNames {
+				model: cli_args.xkb_model.clone().unwrap_or_default(),
+				layout: cli_args.xkb_layout.clone().unwrap_or_default(),
+				variant: cli_args.xkb_variant.clone().unwrap_or_default(),
+				options: cli_args.xkb_options.clone(),
+			})
+		})
 		.transpose()
 		.unwrap();
 	let mut hands = (!cli_args.flatscreen)
@@ -210,7 +338,17 @@ fn main() {
 	let _tokio_handle = event_loop_info.tokio_handle.enter();
 
 	#[cfg(feature = "wayland")]
-	let mut wayland = wayland::Wayland::new().expect("Could not initialize wayland");
+	let mut wayland = match cli_args.wayland_socket_fd {
+		// it was handed to us on startup, nothing else in the process owns it
+		Some(fd) => wayland::Wayland::from_listener(
+			unsafe { OwnedFd::from_raw_fd(fd) },
+			cli_args.wayland_socket.as_deref(),
+		),
+		None => wayland::Wayland::new(cli_args.wayland_socket.as_deref()),
+	}
+	.expect("Could not initialize wayland");
+	#[cfg(feature = "wayland")]
+	configure_wayland(&cli_args, &mut wayland);
 	info!("Stardust ready!");
 
 	let mut startup_children = project_dirs
@@ -226,6 +364,10 @@ fn main() {
 		})
 		.unwrap_or_default();
 
+	// the StereoKit callbacks both need it, and it has to shut down before StereoKit does
+	#[cfg(feature = "wayland")]
+	let wayland = std::cell::RefCell::new(wayland);
+
 	let mut last_frame_delta = Duration::ZERO;
 	let mut sleep_duration = Duration::ZERO;
 	debug_span!("StereoKit").in_scope(|| {
@@ -237,7 +379,7 @@ fn main() {
 				hmd::frame(sk);
 				camera::update(sk);
 				#[cfg(feature = "wayland")]
-				wayland.frame_event(sk);
+				wayland.borrow_mut().frame_event(sk);
 				destroy_queue::clear();
 
 				if let Some(mouse_pointer) = &mut mouse_pointer {
@@ -267,13 +409,13 @@ fn main() {
 				);
 
 				#[cfg(feature = "wayland")]
-				wayland.update(sk);
+				wayland.borrow_mut().update(sk);
 				drawable::draw(sk);
 				audio::update(sk);
-				#[cfg(feature = "wayland")]
-				wayland.make_context_current();
 			},
 			|_sk| {
+				#[cfg(feature = "wayland")]
+				wayland.borrow_mut().shutdown();
 				info!("Cleanly shut down StereoKit");
 			},
 		)
@@ -294,6 +436,54 @@ fn main() {
 	info!("Cleanly shut down Stardust");
 }
 
+/// Apply the Wayland settings from the command line, before any client connects
+#[cfg(feature = "wayland")]
+fn configure_wayland(cli_args: &CliArgs, wayland: &mut wayland::Wayland) {
+	for output in &cli_args.wayland_outputs {
+		wayland.create_output(&output.name, output.size, output.scale);
+	}
+	if let Some(limits) = cli_args.wayland_client_limits {
+		wayland.set_client_limits(limits);
+	}
+	if cli_args.click_to_focus {
+		wayland.set_focus_policy(wayland::ClickToFocus);
+	}
+	if let Some(background) = cli_args.wayland_background {
+		wayland.set_background(background);
+	}
+	if let Some(buffers) = cli_args.wayland_texture_buffers {
+		wayland.set_texture_buffers(buffers);
+	}
+	if cli_args.wayland_clipboard_cache.is_some() || cli_args.wayland_clipboard_cache_all {
+		let default = wayland::ClipboardCache::default();
+		wayland.set_clipboard_cache(wayland::ClipboardCache {
+			max_bytes: cli_args
+				.wayland_clipboard_cache
+				.map_or(default.max_bytes, |kib| kib << 10),
+			text_only: !cli_args.wayland_clipboard_cache_all,
+		});
+	}
+	if cli_args.decoration_buttons.is_some() || cli_args.decoration_font.is_some() {
+		let mut theme = DecorationTheme {
+			font: cli_args.decoration_font.clone(),
+			..Default::default()
+		};
+		if let Some(button_layout) = &cli_args.decoration_buttons {
+			theme.button_layout = button_layout.clone();
+		}
+		wayland.set_decoration_theme(theme);
+	}
+	if cli_args.wayland_debug_overlay {
+		wayland.set_debug_overlay(true);
+	}
+	if cli_args.wayland_input_latency {
+		wayland.set_input_latency_tracking(true);
+	}
+	if let Some(capabilities) = cli_args.wayland_seat_capabilities {
+		wayland.set_seat_capabilities(capabilities);
+	}
+}
+
 fn adaptive_sleep(
 	sk: &impl StereoKitMultiThread,
 	last_frame_delta: &mut Duration,
@@ -8,9 +8,10 @@ use crate::nodes::spatial::Spatial;
 use crate::nodes::Aspect;
 use crate::SK_MULTITHREAD;
 use color_eyre::eyre::{eyre, Result};
+use glam::{vec2, vec3, Mat4, Vec3};
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
-use portable_atomic::{AtomicBool, Ordering};
+use portable_atomic::{AtomicBool, AtomicF32, Ordering};
 use rustc_hash::FxHashMap;
 use stardust_xr::values::ResourceID;
 
@@ -19,8 +20,8 @@ use std::path::PathBuf;
 use std::sync::{Arc, Weak};
 use stereokit::named_colors::WHITE;
 use stereokit::{
-	Bounds, Color128, Material, Model as SKModel, RenderLayer, Shader, StereoKitDraw,
-	StereoKitMultiThread, Transparency,
+	Bounds, Color128, Color32, Material, Mesh, Model as SKModel, RenderLayer, Shader,
+	StereoKitDraw, StereoKitMultiThread, Transparency, Vertex,
 };
 
 static MODEL_REGISTRY: Registry<Model> = Registry::new();
@@ -81,7 +82,17 @@ pub struct ModelPart {
 	model: Weak<Model>,
 	pending_material_parameters: Mutex<FxHashMap<String, MaterialParameter>>,
 	pending_material_replacement: Mutex<Option<Arc<Material>>>,
+	/// `Some(None)` flattens the part back out
+	pending_cylinder_radius: Mutex<Option<Option<f32>>>,
+	/// The part's own mesh from before it got bent, to flatten it again and measure it
+	flat_mesh: OnceCell<Mesh>,
+	/// Radians the part is bent around, 0 while it's flat
+	arc: AtomicF32,
 }
+/// Half the angle of the view cone parts count as in view within, generous so head turns don't
+/// catch anything off guard
+const VIEW_HALF_ANGLE: f32 = 1.4;
+
 impl ModelPart {
 	fn create_for_model(sk: &impl StereoKitMultiThread, model: &Arc<Model>, sk_model: &SKModel) {
 		HOLDOUT_MATERIAL.get_or_init(|| {
@@ -185,6 +196,9 @@ impl ModelPart {
 			model: Arc::downgrade(model),
 			pending_material_parameters: Mutex::new(FxHashMap::default()),
 			pending_material_replacement: Mutex::new(None),
+			pending_cylinder_radius: Mutex::new(None),
+			flat_mesh: OnceCell::new(),
+			arc: AtomicF32::new(0.0),
 		});
 		<ModelPart as ModelPartAspect>::add_node_members(&node);
 		node.add_aspect_raw(model_part.clone());
@@ -192,12 +206,105 @@ impl ModelPart {
 		Some(model_part)
 	}
 
+	/// If the part's model is drawn at all, the shell hides it by disabling the model
+	pub fn model_enabled(&self) -> bool {
+		self.model
+			.upgrade()
+			.is_some_and(|model| model.enabled.load(Ordering::Relaxed))
+	}
+	/// If this part could be on screen, i.e. its model is drawn and its bounds are roughly
+	/// in front of the head
+	pub fn in_view(&self, sk: &impl StereoKitMultiThread) -> bool {
+		if !self.model_enabled() {
+			return false;
+		}
+		let head = sk.input_head();
+		let world_to_head =
+			Mat4::from_rotation_translation(head.orientation.into(), head.position.into())
+				.inverse();
+		let part_to_head = world_to_head * self.space.global_transform();
+		let bounds = self.space.get_bounding_box();
+		let center = part_to_head.transform_point3(mint::Vector3::from(bounds.center).into());
+		let half_size = Vec3::from(mint::Vector3::from(bounds.dimensions)) * 0.5;
+		let radius = part_to_head.transform_vector3(half_size).length();
+		let distance = center.length();
+		if distance <= radius {
+			return true;
+		}
+		center.angle_between(Vec3::NEG_Z) <= VIEW_HALF_ANGLE + (radius / distance).asin()
+	}
+
+	/// How wide the part's bounds look from the head in radians, `None` if its model isn't drawn
+	pub fn apparent_width(&self, sk: &impl StereoKitMultiThread) -> Option<f32> {
+		if !self.model_enabled() {
+			return None;
+		}
+		let head = sk.input_head();
+		let world_to_head =
+			Mat4::from_rotation_translation(head.orientation.into(), head.position.into())
+				.inverse();
+		let part_to_head = world_to_head * self.space.global_transform();
+		let bounds = self.space.get_bounding_box();
+		let center = part_to_head.transform_point3(mint::Vector3::from(bounds.center).into());
+		let half_width = part_to_head
+			.transform_vector3(vec3(bounds.dimensions.x * 0.5, 0.0, 0.0))
+			.length();
+		Some(2.0 * (half_width / center.length().max(0.01)).atan())
+	}
+
+	/// How wide the part is in world space, along the curve while it's bent
+	pub fn width(&self) -> Option<f32> {
+		let sk = SK_MULTITHREAD.get()?;
+		let bounds = match self.flat_mesh.get() {
+			Some(flat_mesh) => sk.mesh_get_bounds(flat_mesh),
+			None => self.space.get_bounding_box(),
+		};
+		let width = self
+			.space
+			.global_transform()
+			.transform_vector3(vec3(bounds.dimensions.x, 0.0, 0.0))
+			.length();
+		(width > 0.0).then_some(width)
+	}
+
 	pub fn replace_material(&self, replacement: Arc<Material>) {
 		self.pending_material_replacement
 			.lock()
 			.replace(replacement);
 	}
 
+	/// Bend the part's mesh around a vertical cylinder `radius` meters away in front of it,
+	/// keeping its width along the curve, or flatten it again with `None`.
+	pub fn set_cylinder_radius(&self, radius: Option<f32>) {
+		self.pending_cylinder_radius.lock().replace(radius);
+	}
+	/// Radians the part is bent around, 0 while it's flat
+	pub fn arc(&self) -> f32 {
+		self.arc.load(Ordering::Relaxed)
+	}
+
+	fn update_cylinder(&self, sk: &impl StereoKitDraw, sk_model: &SKModel, radius: Option<f32>) {
+		let Some(flat_mesh) = self.flat_mesh.get().or_else(|| {
+			let mesh = sk.mesh_copy(sk.model_node_get_mesh(sk_model, self.id)?);
+			Some(self.flat_mesh.get_or_init(|| mesh))
+		}) else {
+			return;
+		};
+		let bounds = sk.mesh_get_bounds(flat_mesh);
+		let width = bounds.dimensions.x;
+		// past half a cylinder the sides would fold back behind the middle
+		let arc = radius
+			.filter(|radius| *radius > 0.0 && width > 0.0)
+			.map_or(0.0, |radius| (width / radius).min(std::f32::consts::PI));
+		if arc == 0.0 {
+			sk.model_node_set_mesh(sk_model, self.id, flat_mesh);
+		} else {
+			let mesh = cylinder_mesh(sk, &bounds, arc);
+			sk.model_node_set_mesh(sk_model, self.id, &mesh);
+		}
+		self.arc.store(arc, Ordering::Relaxed);
+	}
+
 	fn update(&self, sk: &impl StereoKitDraw) {
 		let Some(model) = self.model.upgrade() else {
 			return;
@@ -211,6 +318,9 @@ impl ModelPart {
 		let Some(client) = node.get_client() else {
 			return;
 		};
+		if let Some(radius) = self.pending_cylinder_radius.lock().take() {
+			self.update_cylinder(sk, sk_model, radius);
+		}
 		if let Some(material_replacement) = self.pending_material_replacement.lock().take() {
 			sk.model_node_set_material(sk_model, self.id, material_replacement.as_ref().as_ref());
 		}
@@ -232,6 +342,49 @@ impl ModelPart {
 		);
 	}
 }
+/// A strip of quads curving around the y axis that the flat `bounds` get wrapped onto, the middle
+/// stays where the flat mesh was and the sides come forward. UVs go from the top left to the bottom
+/// right like a surface's pixels do.
+fn cylinder_mesh(sk: &impl StereoKitMultiThread, bounds: &Bounds, arc: f32) -> Mesh {
+	let center = Vec3::from(mint::Vector3::from(bounds.center));
+	let size = Vec3::from(mint::Vector3::from(bounds.dimensions));
+	let radius = size.x / arc;
+	let front = center.z + size.z * 0.5;
+	// about one column per 2 degrees, enough that the edges between them don't show
+	let columns = (arc.to_degrees() / 2.0).ceil().max(1.0) as u32;
+
+	let mut verts = Vec::with_capacity(columns as usize * 2 + 2);
+	for column in 0..=columns {
+		let u = column as f32 / columns as f32;
+		let angle = (u - 0.5) * arc;
+		let (sin, cos) = angle.sin_cos();
+		let x = center.x + radius * sin;
+		let z = front + radius * (1.0 - cos);
+		let norm = vec3(-sin, 0.0, cos);
+		for (y, v) in [(size.y * 0.5, 0.0), (size.y * -0.5, 1.0)] {
+			verts.push(Vertex {
+				pos: vec3(x, center.y + y, z).into(),
+				norm: norm.into(),
+				uv: vec2(u, v).into(),
+				col: Color32::WHITE,
+			});
+		}
+	}
+	// StereoKit's front faces wind clockwise
+	let mut inds = Vec::with_capacity(columns as usize * 6);
+	for column in 0..columns {
+		let top_left = column * 2;
+		let (bottom_left, top_right, bottom_right) = (top_left + 1, top_left + 2, top_left + 3);
+		inds.extend([top_left, top_right, bottom_left]);
+		inds.extend([top_right, bottom_right, bottom_left]);
+	}
+
+	let mesh = sk.mesh_create();
+	sk.mesh_set_verts(&mesh, &verts, true);
+	sk.mesh_set_inds(&mesh, &inds);
+	mesh
+}
+
 impl Aspect for ModelPart {
 	const NAME: &'static str = "ModelPart";
 }
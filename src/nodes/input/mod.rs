@@ -8,6 +8,7 @@ use self::tip::Tip;
 
 use super::{
 	alias::{Alias, AliasInfo},
+	data::get_mask,
 	fields::{find_field, Field, FIELD_ALIAS_INFO},
 	spatial::{parse_transform, Spatial},
 	Aspect, Message, Node,
@@ -15,7 +16,7 @@ use super::{
 use crate::core::{client::Client, node_collections::LifeLinkedNodeMap};
 use crate::{core::registry::Registry, nodes::spatial::Transform};
 use color_eyre::eyre::Result;
-use glam::Mat4;
+use glam::{vec3, Mat4, Vec3};
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
 use portable_atomic::AtomicBool;
@@ -460,3 +461,35 @@ pub fn process_input() {
 		});
 	}
 }
+
+/// A pointer input method's ray in global space, for server-drawn UI that has no input handler
+pub struct PointerRay {
+	pub origin: Vec3,
+	pub direction: Vec3,
+	pub selecting: bool,
+}
+pub fn pointer_rays() -> Vec<PointerRay> {
+	INPUT_METHOD_REGISTRY
+		.get_valid_contents()
+		.into_iter()
+		.filter(|method| *method.enabled.lock())
+		.filter(|method| matches!(&*method.specialization.lock(), InputType::Pointer(_)))
+		.map(|method| {
+			let transform = method.spatial.global_transform();
+			let selecting = method
+				.datamap
+				.lock()
+				.as_ref()
+				.and_then(|datamap| get_mask(datamap).ok()?.index("select").ok())
+				.map(|select| select.as_f32() > 0.5)
+				.unwrap_or(false);
+			PointerRay {
+				origin: transform.transform_point3(Vec3::ZERO),
+				direction: transform
+					.transform_vector3(vec3(0.0, 0.0, -1.0))
+					.normalize(),
+				selecting,
+			}
+		})
+		.collect()
+}
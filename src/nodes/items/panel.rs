@@ -2,40 +2,68 @@ use crate::{
 	core::{
 		client::{get_env, state, Client, INTERNAL_CLIENT},
 		registry::Registry,
+		scenegraph::MethodResponseSender,
 	},
 	nodes::{
+		data::get_mask,
 		drawable::model::ModelPart,
 		items::{Item, ItemType, TypeInfo},
 		spatial::Spatial,
 		Message, Node,
 	},
 };
-use color_eyre::eyre::{eyre, Result};
+use color_eyre::eyre::{bail, eyre, Report, Result};
 use glam::Mat4;
 use lazy_static::lazy_static;
 use mint::Vector2;
 use nanoid::nanoid;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use rustc_hash::FxHashMap;
 use serde::{
 	de::{Deserializer, Error, SeqAccess, Visitor},
 	ser::Serializer,
 	Deserialize, Serialize,
 };
-use stardust_xr::schemas::flex::{deserialize, serialize};
-use std::sync::{Arc, Weak};
-use tracing::{debug, info};
+use stardust_xr::{
+	schemas::flex::{deserialize, serialize},
+	values::Datamap,
+};
+use std::{
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, AtomicU32, Ordering},
+		Arc, Weak,
+	},
+};
+use tracing::{debug, info, warn};
 
 lazy_static! {
 	pub static ref ITEM_TYPE_INFO_PANEL: TypeInfo = TypeInfo {
 		type_name: "panel",
 		aliased_local_signals: vec![
 			"apply_surface_material",
+			"export_surface",
+			"stop_surface_export",
 			"close_toplevel",
+			"kill_client",
+			"raise_toplevel",
+			"lower_toplevel",
+			"stack_above",
 			"auto_size_toplevel",
 			"set_toplevel_size",
 			"set_toplevel_focused_visuals",
+			"set_alpha",
+			"set_toplevel_minimized",
+			"set_geometry",
+			"set_decoration_theme",
+			"grab_exclusive_input",
+			"release_exclusive_input",
+			"set_surface_color_space",
+			"set_surface_filtering",
 			"pointer_motion",
 			"pointer_button",
+			"pointer_input",
 			"pointer_scroll",
 			"keyboard_keymap",
 			"keyboard_key",
@@ -43,20 +71,41 @@ lazy_static! {
 			"touch_move",
 			"touch_up",
 			"reset_touches",
+			"pen_motion",
+			"pen_button",
+			"pen_leave",
+			"text_input_update",
 		],
-		aliased_local_methods: vec![],
+		aliased_local_methods: vec!["snapshot_surface", "keyboard_state", "client_info"],
 		aliased_remote_signals: vec![
+			"toplevel_mapped",
+			"toplevel_unmapped",
+			"toplevel_destroyed",
 			"toplevel_parent_changed",
 			"toplevel_title_changed",
 			"toplevel_app_id_changed",
 			"toplevel_fullscreen_active",
 			"toplevel_move_request",
 			"toplevel_resize_request",
+			"toplevel_activation_request",
+			"toplevel_attention_changed",
+			"toplevel_minimize_request",
 			"toplevel_size_changed",
+			"toplevel_geometry_changed",
+			"toplevel_size_hints_changed",
+			"toplevel_states_changed",
+			"toplevel_content_type_changed",
 			"set_cursor",
+			"set_cursor_shape",
 			"new_child",
 			"reposition_child",
 			"drop_child",
+			"text_input_state",
+			"keyboard_leds_changed",
+			"keyboard_layout_changed",
+			"surface_export_resized",
+			"stack_position_changed",
+			"exclusive_input_changed",
 		],
 		ui: Default::default(),
 		items: Registry::new(),
@@ -65,7 +114,7 @@ lazy_static! {
 }
 
 /// An ID for a surface inside this panel item
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 #[allow(dead_code)]
 pub enum SurfaceID {
 	Cursor,
@@ -129,7 +178,7 @@ impl serde::Serialize for SurfaceID {
 }
 
 /// The origin and size of the surface's "solid" part.
-#[derive(Debug, Serialize, Clone, Copy)]
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
 pub struct Geometry {
 	pub origin: Vector2<i32>,
 	pub size: Vector2<u32>,
@@ -151,6 +200,277 @@ pub struct ToplevelInfo {
 	pub max_size: Option<Vector2<u32>>,
 	/// Surface geometry
 	pub logical_rectangle: Geometry,
+	/// The states the toplevel was last configured with
+	pub states: ToplevelStates,
+	/// If the toplevel has a buffer, unmapped toplevels keep their panel item until destroyed
+	pub mapped: bool,
+	pub content_type: ContentType,
+}
+/// What a surface shows according to its client (`wp_content_type_v1`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentType {
+	#[default]
+	None,
+	Photo,
+	Video,
+	Game,
+}
+/// The states a toplevel can be configured with.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct ToplevelStates {
+	pub maximized: bool,
+	pub fullscreen: bool,
+	pub activated: bool,
+	/// The toplevel is being interactively resized
+	pub resizing: bool,
+	/// Hidden with its last frame kept for thumbnails, and not getting frame callbacks so the
+	/// client can pause until it's restored
+	pub minimized: bool,
+}
+
+/// How the colors in a surface's buffers are encoded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorSpace {
+	/// What clients render for a normal desktop
+	#[default]
+	Srgb,
+	/// Linear light values, e.g. from clients rendering straight out of a linear pipeline
+	Linear,
+}
+
+/// How a surface gets sampled when it's drawn at a different size than its pixels.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SurfaceFiltering {
+	/// Mipmapped and anisotropic, so text doesn't shimmer on distant or angled panels.
+	/// Every commit copies the whole surface tree to regenerate the mipmaps, so it's opt-in.
+	Trilinear,
+	/// Smooth without mipmaps, sharper than trilinear for photos shown close to their size.
+	/// The client's buffer gets sampled directly, only its damage is uploaded.
+	#[default]
+	Linear,
+	/// Sharp pixels, e.g. for pixel art
+	Nearest,
+	/// Picked from the content type the client set: games are nearest, as they're most often
+	/// pixel art scaled up, and everything else trilinear
+	Auto,
+}
+impl SurfaceFiltering {
+	/// What `Auto` is for a surface with `content_type`, the rest stay what they are
+	pub fn for_content(self, content_type: ContentType) -> Self {
+		match (self, content_type) {
+			(SurfaceFiltering::Auto, ContentType::Game) => SurfaceFiltering::Nearest,
+			(SurfaceFiltering::Auto, _) => SurfaceFiltering::Trilinear,
+			(filtering, _) => filtering,
+		}
+	}
+}
+
+/// What the toplevel's model parts get shaped into, they keep their width along the surface.
+/// Input positions are still where the flat panel got hit, straight through it, and get moved
+/// onto the curve before the client sees them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PanelGeometry {
+	/// The model part's own mesh
+	#[default]
+	Flat,
+	/// Curved around a vertical axis `radius` meters in front of the panel, so the sides of wide
+	/// panels face the user too. Anything bent further than half a cylinder is limited to that.
+	Cylindrical { radius: f32 },
+}
+impl PanelGeometry {
+	pub fn cylinder_radius(self) -> Option<f32> {
+		match self {
+			PanelGeometry::Flat => None,
+			PanelGeometry::Cylindrical { radius } => Some(radius),
+		}
+	}
+}
+
+/// A titlebar button of server-side decorations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecorationButton {
+	Close,
+	Maximize,
+	Minimize,
+}
+/// Which titlebar buttons go on which side, each in order from left to right
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ButtonLayout {
+	pub left: Vec<DecorationButton>,
+	pub right: Vec<DecorationButton>,
+}
+impl ButtonLayout {
+	pub fn buttons(&self) -> impl Iterator<Item = DecorationButton> + '_ {
+		self.left.iter().chain(&self.right).copied()
+	}
+}
+impl Default for ButtonLayout {
+	fn default() -> Self {
+		ButtonLayout {
+			left: Vec::new(),
+			right: vec![
+				DecorationButton::Minimize,
+				DecorationButton::Maximize,
+				DecorationButton::Close,
+			],
+		}
+	}
+}
+/// GNOME's `button-layout`, buttons left and right of a `:` separated by `,` like
+/// "appmenu:minimize,close". Without a `:` they're all on the left, like in GNOME. Buttons that
+/// exist there but not here (`appmenu`, `menu`, `icon` and `spacer`) are skipped, so a layout can
+/// be copied over from a desktop.
+impl FromStr for ButtonLayout {
+	type Err = Report;
+	fn from_str(layout: &str) -> Result<Self> {
+		let (left, right) = layout.split_once(':').unwrap_or((layout, ""));
+		if right.contains(':') {
+			bail!("Button layout {layout:?} has more than one ':'");
+		}
+		let mut seen = Vec::new();
+		let mut parse_side = |buttons: &str| -> Result<Vec<DecorationButton>> {
+			let mut side = Vec::new();
+			for name in buttons.split(',').map(str::trim) {
+				let button = match name {
+					"close" => DecorationButton::Close,
+					"maximize" => DecorationButton::Maximize,
+					"minimize" => DecorationButton::Minimize,
+					"" | "appmenu" | "menu" | "icon" | "spacer" => continue,
+					name => bail!("Button layout {layout:?} has an unknown button {name:?}"),
+				};
+				if seen.contains(&button) {
+					bail!("Button layout {layout:?} has {name:?} more than once");
+				}
+				seen.push(button);
+				side.push(button);
+			}
+			Ok(side)
+		};
+		Ok(ButtonLayout {
+			left: parse_side(left)?,
+			right: parse_side(right)?,
+		})
+	}
+}
+impl TryFrom<String> for ButtonLayout {
+	type Error = Report;
+	fn try_from(layout: String) -> Result<Self> {
+		layout.parse()
+	}
+}
+
+/// How the server draws the titlebars of toplevels it decorates, anything left out of the
+/// theme the shell sends is the default
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DecorationTheme {
+	/// Linear RGBA, like the rest of StereoKit's colors
+	pub titlebar_color: [f32; 4],
+	pub text_color: [f32; 4],
+	/// Behind the button a pointer is over
+	pub hovered_color: [f32; 4],
+	pub close_hovered_color: [f32; 4],
+	/// Of the titlebar's top corners in surface pixels, at most the titlebar's height
+	pub corner_radius: f32,
+	/// A font file for the title and buttons, StereoKit's default font if `None`
+	pub font: Option<String>,
+	pub button_layout: ButtonLayout,
+}
+impl Default for DecorationTheme {
+	fn default() -> Self {
+		DecorationTheme {
+			titlebar_color: [0.1, 0.1, 0.1, 1.0],
+			text_color: [1.0; 4],
+			hovered_color: [0.25, 0.25, 0.25, 1.0],
+			close_hovered_color: [0.75, 0.1, 0.1, 1.0],
+			corner_radius: 0.0,
+			font: None,
+			button_layout: ButtonLayout::default(),
+		}
+	}
+}
+
+pub const BTN_LEFT: u32 = 0x110;
+pub const BTN_RIGHT: u32 = 0x111;
+pub const BTN_MIDDLE: u32 = 0x112;
+pub const BTN_SIDE: u32 = 0x113;
+pub const BTN_EXTRA: u32 = 0x114;
+/// Analog inputs like triggers and pinches press their button past this
+const POINTER_INPUT_THRESHOLD: f32 = 0.5;
+
+/// Which fields of an input method's datamap press which pointer buttons with `pointer_input`.
+/// Several fields can press the same button, it stays pressed until none of them are.
+/// Set `STARDUST_POINTER_BUTTONS` to override it, e.g. `select=left,grab=right,back=side`.
+pub static POINTER_BUTTON_MAP: Lazy<Mutex<Vec<(String, u32)>>> = Lazy::new(|| {
+	let map = std::env::var("STARDUST_POINTER_BUTTONS")
+		.ok()
+		.map(|map| parse_pointer_button_map(&map))
+		.unwrap_or_else(|| {
+			vec![
+				("select".to_string(), BTN_LEFT),
+				("pinch_strength".to_string(), BTN_LEFT),
+				("context".to_string(), BTN_RIGHT),
+				("middle".to_string(), BTN_MIDDLE),
+				("back".to_string(), BTN_SIDE),
+				("forward".to_string(), BTN_EXTRA),
+			]
+		});
+	Mutex::new(map)
+});
+fn parse_pointer_button_map(map: &str) -> Vec<(String, u32)> {
+	map.split(',')
+		.filter(|entry| !entry.trim().is_empty())
+		.filter_map(|entry| {
+			let mapping = entry.split_once('=').and_then(|(field, button)| {
+				let button = match button.trim() {
+					"left" => BTN_LEFT,
+					"right" => BTN_RIGHT,
+					"middle" => BTN_MIDDLE,
+					"side" | "back" => BTN_SIDE,
+					"extra" | "forward" => BTN_EXTRA,
+					code => code.parse().ok()?,
+				};
+				Some((field.trim().to_string(), button))
+			});
+			if mapping.is_none() {
+				warn!(entry, "Invalid pointer button mapping");
+			}
+			mapping
+		})
+		.collect()
+}
+/// The buttons the datamap has pressed according to `map`, sorted
+fn mapped_pointer_buttons(map: &[(String, u32)], datamap: &Datamap) -> Result<Vec<u32>> {
+	let input = get_mask(datamap)?;
+	let mut pressed: Vec<u32> = map
+		.iter()
+		.filter(|(field, _)| {
+			input
+				.index(field.as_str())
+				.map_or(false, |value| value.as_f32() > POINTER_INPUT_THRESHOLD)
+		})
+		.map(|(_, button)| *button)
+		.collect();
+	pressed.sort_unstable();
+	pressed.dedup();
+	Ok(pressed)
+}
+/// The button events going from `held` to `pressed`, releases first
+fn pointer_button_changes(held: &[u32], pressed: &[u32]) -> Vec<(u32, bool)> {
+	let released = held
+		.iter()
+		.filter(|b| !pressed.contains(b))
+		.map(|b| (*b, false));
+	let newly_pressed = pressed
+		.iter()
+		.filter(|b| !held.contains(b))
+		.map(|b| (*b, true));
+	released.chain(newly_pressed).collect()
 }
 
 /// Data on positioning a child
@@ -165,6 +485,8 @@ pub struct ChildInfo {
 pub struct PanelItemInitData {
 	/// The cursor, if applicable.
 	pub cursor: Option<Geometry>,
+	/// The name of the cursor the shell should draw instead of a cursor surface, if any.
+	pub cursor_shape: Option<String>,
 	/// Size of the toplevel surface in pixels.
 	pub toplevel: ToplevelInfo,
 	/// Vector of childs that already exist
@@ -173,17 +495,160 @@ pub struct PanelItemInitData {
 	pub pointer_grab: Option<SurfaceID>,
 	/// The surface, if any, that has exclusive input to the keyboard.
 	pub keyboard_grab: Option<SurfaceID>,
+	/// Where the panel item is in the stack, 0 at the bottom. Filled in by the panel item.
+	pub stack_position: u32,
+}
+
+/// What the focused text input wants, so a keyboard component can adapt.
+/// The values are the same as `zwp_text_input_v3`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TextInputInfo {
+	/// Text around the cursor, if the client gave any
+	pub surrounding_text: Option<String>,
+	/// Byte offset of the cursor in the surrounding text
+	pub cursor: i32,
+	/// Byte offset of the selection anchor in the surrounding text
+	pub anchor: i32,
+	/// Bitfield of `content_hint`
+	pub content_hint: u32,
+	/// The `content_purpose`, e.g. 2 for digits or 8 for passwords
+	pub content_purpose: u32,
+	/// Where the text cursor is on the surface
+	pub cursor_rectangle: Option<Geometry>,
+}
+/// A still of a surface as it's composited, for thumbnails.
+#[derive(Debug, Clone, Serialize)]
+pub struct SurfaceSnapshot {
+	pub size: Vector2<u32>,
+	/// `size.x * size.y` RGBA8 pixels, rows from top to bottom
+	pub pixels: Vec<u8>,
+}
+/// The lock indicators of the seat's keyboard, so a keyboard component can light its own.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct KeyboardLeds {
+	pub caps_lock: bool,
+	pub num_lock: bool,
+	pub scroll_lock: bool,
+}
+/// How keys sent to a surface are read right now, so something typing text into it (like voice
+/// typing) can pick the keycodes for it. The keymap is the one registered as `keymap_id`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyboardState {
+	pub keymap_id: String,
+	/// The xkb modifier masks, like in `wl_keyboard.modifiers`
+	pub mods_depressed: u32,
+	pub mods_latched: u32,
+	pub mods_locked: u32,
+	/// The effective xkb layout, e.g. switched with `grp:alt_shift_toggle`
+	pub layout: u32,
+}
+/// Who a client is and what it has open, for something like a task manager
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientInfo {
+	/// From the client's socket, `None` if it couldn't tell
+	pub pid: Option<i32>,
+	pub uid: Option<u32>,
+	pub gid: Option<u32>,
+	pub surfaces: usize,
+	/// Surfaces with a buffer imported into a texture
+	pub buffers: usize,
+	pub texture_bytes: u64,
+	/// UIDs of the panel items of all its toplevels
+	pub panel_items: Vec<String>,
+}
+/// Text from an input method (on-screen keyboard, voice, etc.) to apply atomically.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TextInputUpdate {
+	/// Text being composed, replaces any previous preedit
+	pub preedit_string: Option<String>,
+	pub preedit_cursor_begin: i32,
+	pub preedit_cursor_end: i32,
+	/// Text to insert at the cursor
+	pub commit_string: Option<String>,
+	/// Bytes to delete before the cursor
+	pub delete_before: u32,
+	/// Bytes to delete after the cursor
+	pub delete_after: u32,
+}
+
+/// Which end of a stylus is being used.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PenKind {
+	#[default]
+	Pen,
+	Eraser,
+}
+/// A stylus, or a spatial input acting as one.
+/// Apps keep settings per tool, so the same tool should always have the same serial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub struct PenTool {
+	#[serde(default)]
+	pub kind: PenKind,
+	/// Unique to this tool, e.g. the serial number of a real stylus
+	pub serial: u64,
+	/// The model of the tool if it's a real stylus, 0 otherwise
+	#[serde(default)]
+	pub hardware_id: u64,
+}
+/// Where a pen is over a surface and how it's held.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct PenState {
+	/// Position on the surface in pixels
+	pub position: Vector2<f32>,
+	/// From 0 to 1, the tip is touching the surface while this is above 0
+	pub pressure: f32,
+	/// Degrees the pen is tilted towards the surface's positive x and y axes
+	pub tilt: Vector2<f32>,
 }
 
 pub trait Backend: Send + Sync + 'static {
 	fn start_data(&self) -> Result<PanelItemInitData>;
 
 	fn apply_surface_material(&self, surface: SurfaceID, model_part: &Arc<ModelPart>);
+	/// Override how a surface's content is interpreted, sRGB unless told otherwise
+	fn set_surface_color_space(&self, surface: SurfaceID, color_space: ColorSpace);
+	fn set_surface_filtering(&self, surface: SurfaceID, filtering: SurfaceFiltering);
+	/// Keep a live copy of the surface on `model_part`, at most `max_size` pixels.
+	/// `on_resize` gets called with the copy's size whenever it's (re)allocated.
+	fn export_surface(
+		&self,
+		surface: SurfaceID,
+		model_part: &Arc<ModelPart>,
+		max_size: Option<Vector2<u32>>,
+		on_resize: Box<dyn Fn(Vector2<u32>) + Send + Sync>,
+	);
+	fn stop_surface_export(&self, surface: SurfaceID, model_part: &Arc<ModelPart>);
+	/// Read the surface back once on the next frame, scaled down to fit in `max_size`.
+	/// `None` if it doesn't have a buffer.
+	fn snapshot_surface(
+		&self,
+		surface: SurfaceID,
+		max_size: u32,
+		on_snapshot: Box<dyn FnOnce(Option<SurfaceSnapshot>) + Send>,
+	);
 
 	fn close_toplevel(&self);
+	/// Disconnect the toplevel's client outright, for when it doesn't respond to `close_toplevel`
+	fn kill_client(&self);
+	/// `None` if the client is gone or can't be told apart from others, like X11 clients
+	fn client_info(&self) -> Option<ClientInfo>;
 	fn auto_size_toplevel(&self);
 	fn set_toplevel_size(&self, size: Vector2<u32>);
 	fn set_toplevel_focused_visuals(&self, focused: bool);
+	/// Where the panel item is in the stack, 0 at the bottom. Surfaces higher up get drawn over
+	/// the ones below where they overlap.
+	fn set_stack_position(&self, position: u32);
+	/// Fade all of the panel item's surfaces (not the cursor) independent of their own alpha
+	fn set_alpha(&self, alpha: f32);
+	/// Hide the panel item's surfaces and stop their frame callbacks, keeping the client and
+	/// the textures. It's deactivated and stays so until it's restored with `false`.
+	fn set_toplevel_minimized(&self, minimized: bool);
+	/// Shape of the model parts the toplevel is shown on, popups stay flat
+	fn set_geometry(&self, geometry: PanelGeometry);
+	/// Theme just this panel item's decorations, `None` goes back to the global one
+	fn set_decoration_theme(&self, theme: Option<DecorationTheme>);
 
 	fn pointer_motion(&self, surface: &SurfaceID, position: Vector2<f32>);
 	fn pointer_button(&self, surface: &SurfaceID, button: u32, pressed: bool);
@@ -193,13 +658,24 @@ pub trait Backend: Send + Sync + 'static {
 		scroll_distance: Option<Vector2<f32>>,
 		scroll_steps: Option<Vector2<f32>>,
 	);
+	/// Keep the pointer on the toplevel while the panel item has exclusive input, entering it
+	/// if it's elsewhere, as the buttons and scrolling from every other panel item end up there
+	fn set_pointer_grab(&self, grabbed: bool);
 
 	fn keyboard_keys(&self, surface: &SurfaceID, keymap_id: &str, keys: Vec<i32>);
+	/// `None` until the surface got keys, as that's what sets its keymap
+	fn keyboard_state(&self, surface: &SurfaceID) -> Option<KeyboardState>;
+
+	fn text_input_update(&self, surface: &SurfaceID, update: TextInputUpdate);
 
 	fn touch_down(&self, surface: &SurfaceID, id: u32, position: Vector2<f32>);
 	fn touch_move(&self, id: u32, position: Vector2<f32>);
 	fn touch_up(&self, id: u32);
 	fn reset_touches(&self);
+
+	fn pen_motion(&self, surface: &SurfaceID, tool: PenTool, state: PenState);
+	fn pen_button(&self, tool: PenTool, button: u32, pressed: bool);
+	fn pen_leave(&self, tool: PenTool);
 }
 
 pub fn panel_item_from_node(node: &Node) -> Option<Arc<dyn PanelItemTrait>> {
@@ -212,12 +688,177 @@ pub fn panel_item_from_node(node: &Node) -> Option<Arc<dyn PanelItemTrait>> {
 pub trait PanelItemTrait: Backend + Send + Sync + 'static {
 	fn uid(&self) -> &str;
 	fn serialize_start_data(&self, id: &str) -> Result<Message>;
+	/// Tell the shell the panel item got or lost exclusive input
+	fn exclusive_input_changed(&self, exclusive: bool);
+
+	/// Put the panel item above every other one
+	fn raise_toplevel(&self) {
+		move_in_stack(self.uid(), StackPlace::Top);
+	}
+	/// Put the panel item below every other one
+	fn lower_toplevel(&self) {
+		move_in_stack(self.uid(), StackPlace::Bottom);
+	}
+	/// Put the panel item right above the one with `uid`, or on top if there isn't one
+	fn stack_above(&self, uid: String) {
+		move_in_stack(self.uid(), StackPlace::Above(&uid));
+	}
+
+	/// Play mode for something like a fullscreen game: keys, pointer buttons and scrolling
+	/// for any panel item go to this one's toplevel instead, and the rest don't get focus.
+	/// Positional input (motion, touches and pens) for them is dropped, it means nothing here.
+	/// It lasts until `release_exclusive_input`, the user pressing Super+Escape or the toplevel
+	/// going away, and taking it from another panel item releases it there.
+	fn grab_exclusive_input(&self) {
+		let Some(panel_item) = STACK
+			.lock()
+			.iter()
+			.find(|item| item.upgrade().is_some_and(|item| item.uid() == self.uid()))
+			.cloned()
+		else {
+			return;
+		};
+		let previous = EXCLUSIVE_INPUT.lock().replace(ExclusiveInput {
+			uid: self.uid().to_string(),
+			panel_item,
+			super_held: false,
+		});
+		info!(uid = self.uid(), "Panel item grabbed exclusive input");
+		match previous {
+			Some(previous) if previous.uid == self.uid() => return,
+			Some(previous) => {
+				if let Some(previous) = previous.panel_item.upgrade() {
+					previous.exclusive_input_changed(false);
+				}
+			}
+			None => (),
+		}
+		self.exclusive_input_changed(true);
+	}
+	fn release_exclusive_input(&self) {
+		release_exclusive_input(self.uid());
+	}
+}
+
+/// evdev key codes for the chord that releases exclusive input
+const KEY_ESC: i32 = 1;
+const KEY_LEFTMETA: i32 = 125;
+const KEY_RIGHTMETA: i32 = 126;
+/// See `PanelItemTrait::grab_exclusive_input`
+struct ExclusiveInput {
+	uid: String,
+	panel_item: Weak<dyn PanelItemTrait>,
+	/// The game gets every other key, even Escape on its own, so it takes a chord to get out
+	super_held: bool,
+}
+static EXCLUSIVE_INPUT: Mutex<Option<ExclusiveInput>> = Mutex::new(None);
+/// The panel item with exclusive input if it's not the one with `uid`, so input for that one
+/// goes there instead. If it's gone nothing has it anymore.
+fn exclusive_input_elsewhere(uid: &str) -> Option<Arc<dyn PanelItemTrait>> {
+	let exclusive = EXCLUSIVE_INPUT.lock();
+	let exclusive = exclusive
+		.as_ref()
+		.filter(|exclusive| exclusive.uid != uid)?;
+	exclusive.panel_item.upgrade()
+}
+/// Keep track of Super for the panel item with exclusive input, taking Escape out of `keys` if
+/// it's pressed along with it. Returns if it was.
+fn exclusive_input_escape(uid: &str, keys: &mut Vec<i32>) -> bool {
+	let mut exclusive = EXCLUSIVE_INPUT.lock();
+	let Some(exclusive) = exclusive.as_mut().filter(|exclusive| exclusive.uid == uid) else {
+		return false;
+	};
+	let mut escaped = false;
+	keys.retain(|key| {
+		match key.abs() {
+			KEY_LEFTMETA | KEY_RIGHTMETA => exclusive.super_held = *key > 0,
+			KEY_ESC if *key > 0 && exclusive.super_held => {
+				escaped = true;
+				return false;
+			}
+			_ => (),
+		}
+		true
+	});
+	escaped
+}
+/// Give input back to every panel item, if the one with `uid` has it
+fn release_exclusive_input(uid: &str) {
+	let released = {
+		let mut exclusive = EXCLUSIVE_INPUT.lock();
+		if exclusive
+			.as_ref()
+			.map_or(true, |exclusive| exclusive.uid != uid)
+		{
+			return;
+		}
+		exclusive.take()
+	};
+	info!(uid, "Panel item released exclusive input");
+	if let Some(panel_item) = released.and_then(|released| released.panel_item.upgrade()) {
+		panel_item.exclusive_input_changed(false);
+	}
+}
+
+/// Every panel item, bottom to top. Blended surfaces are drawn in this order and the shell
+/// gets each one's position, so overlapping panels look and take input the same way.
+static STACK: Lazy<Mutex<Vec<Weak<dyn PanelItemTrait>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+/// Where in the stack `move_in_stack` puts a panel item
+#[derive(Debug, Clone, Copy)]
+enum StackPlace<'a> {
+	Top,
+	Bottom,
+	/// Right above the one with this uid, or on top if there isn't one
+	Above(&'a str),
+}
+fn move_in_stack(uid: &str, place: StackPlace) {
+	let mut stack = STACK.lock();
+	let has_uid = |item: &Weak<dyn PanelItemTrait>, uid: &str| {
+		item.upgrade().is_some_and(|item| item.uid() == uid)
+	};
+	if move_item(&mut stack, uid, place, has_uid) {
+		restack(&mut stack);
+	}
+}
+/// Move the item with `uid` to `place`, returns if it was there to move
+fn move_item<T>(
+	stack: &mut Vec<T>,
+	uid: &str,
+	place: StackPlace,
+	has_uid: impl Fn(&T, &str) -> bool,
+) -> bool {
+	let Some(index) = stack.iter().position(|item| has_uid(item, uid)) else {
+		return false;
+	};
+	let item = stack.remove(index);
+	let position = match place {
+		StackPlace::Top => stack.len(),
+		StackPlace::Bottom => 0,
+		StackPlace::Above(below) => stack
+			.iter()
+			.position(|other| has_uid(other, below))
+			.map_or(stack.len(), |position| position + 1),
+	};
+	stack.insert(position, item);
+	true
+}
+/// Tell every panel item where it is now, after dropping the ones that are gone
+fn restack(stack: &mut Vec<Weak<dyn PanelItemTrait>>) {
+	stack.retain(|item| item.strong_count() > 0);
+	for (position, item) in stack.iter().filter_map(Weak::upgrade).enumerate() {
+		item.set_stack_position(position as u32);
+	}
 }
 
 pub struct PanelItem<B: Backend + ?Sized> {
 	pub uid: String,
 	node: Weak<Node>,
 	pub backend: Box<B>,
+	/// Buttons pressed through `pointer_input` on each surface
+	input_buttons: Mutex<FxHashMap<SurfaceID, Vec<u32>>>,
+	/// The toplevel wanted focus but wasn't allowed to take it
+	attention: AtomicBool,
+	stack_position: AtomicU32,
 }
 impl<B: Backend + ?Sized> PanelItem<B> {
 	pub fn create(backend: Box<B>, pid: Option<i32>) -> (Arc<Node>, Arc<PanelItem<B>>) {
@@ -243,9 +884,17 @@ impl<B: Backend + ?Sized> PanelItem<B> {
 			uid: uid.clone(),
 			node: Arc::downgrade(&node),
 			backend,
+			input_buttons: Mutex::new(FxHashMap::default()),
+			attention: AtomicBool::new(false),
+			stack_position: AtomicU32::new(0),
 		});
 
 		let generic_panel_item: Arc<dyn PanelItemTrait> = panel_item.clone();
+		// new windows open on top
+		let mut stack = STACK.lock();
+		stack.push(Arc::downgrade(&generic_panel_item));
+		restack(&mut stack);
+		drop(stack);
 		Item::add_to(
 			&node,
 			uid,
@@ -254,33 +903,87 @@ impl<B: Backend + ?Sized> PanelItem<B> {
 		);
 
 		node.add_local_signal("apply_surface_material", Self::apply_surface_material_flex);
+		node.add_local_signal("export_surface", Self::export_surface_flex);
+		node.add_local_signal("stop_surface_export", Self::stop_surface_export_flex);
+		node.add_local_method("snapshot_surface", Self::snapshot_surface_flex);
+		node.add_local_method("keyboard_state", Self::keyboard_state_flex);
+		node.add_local_method("client_info", Self::client_info_flex);
 		node.add_local_signal("close_toplevel", Self::close_toplevel_flex);
+		node.add_local_signal("kill_client", Self::kill_client_flex);
+		node.add_local_signal("raise_toplevel", Self::raise_toplevel_flex);
+		node.add_local_signal("lower_toplevel", Self::lower_toplevel_flex);
+		node.add_local_signal("stack_above", Self::stack_above_flex);
 		node.add_local_signal("auto_size_toplevel", Self::auto_size_toplevel_flex);
 		node.add_local_signal("set_toplevel_size", Self::set_toplevel_size_flex);
+		node.add_local_signal(
+			"set_toplevel_focused_visuals",
+			Self::set_toplevel_focused_visuals_flex,
+		);
+		node.add_local_signal("set_alpha", Self::set_alpha_flex);
+		node.add_local_signal("set_toplevel_minimized", Self::set_toplevel_minimized_flex);
+		node.add_local_signal("set_geometry", Self::set_geometry_flex);
+		node.add_local_signal("set_decoration_theme", Self::set_decoration_theme_flex);
+		node.add_local_signal("grab_exclusive_input", Self::grab_exclusive_input_flex);
+		node.add_local_signal(
+			"release_exclusive_input",
+			Self::release_exclusive_input_flex,
+		);
+		node.add_local_signal(
+			"set_surface_color_space",
+			Self::set_surface_color_space_flex,
+		);
+		node.add_local_signal("set_surface_filtering", Self::set_surface_filtering_flex);
 
 		node.add_local_signal("pointer_motion", Self::pointer_motion_flex);
 		node.add_local_signal("pointer_button", Self::pointer_button_flex);
+		node.add_local_signal("pointer_input", Self::pointer_input_flex);
 		node.add_local_signal("pointer_scroll", Self::pointer_scroll_flex);
 
 		node.add_local_signal("keyboard_key", Self::keyboard_keys_flex);
 
+		node.add_local_signal("text_input_update", Self::text_input_update_flex);
+
 		node.add_local_signal("touch_down", Self::touch_down_flex);
 		node.add_local_signal("touch_move", Self::touch_move_flex);
 		node.add_local_signal("touch_up", Self::touch_up_flex);
 		node.add_local_signal("reset_touches", Self::reset_touches_flex);
 
+		node.add_local_signal("pen_motion", Self::pen_motion_flex);
+		node.add_local_signal("pen_button", Self::pen_button_flex);
+		node.add_local_signal("pen_leave", Self::pen_leave_flex);
+
 		(node, panel_item)
 	}
+	/// The toplevel is gone for good, unlike when it's unmapped
 	pub fn drop_toplevel(&self) {
+		release_exclusive_input(&self.uid);
 		let Some(node) = self.node.upgrade() else {
 			return;
 		};
+		let _ = node.send_remote_signal("toplevel_destroyed", Vec::<u8>::new());
 		node.destroy();
 	}
 }
 
 // Remote signals
+// The item UI gets `create_item` when a toplevel first maps, then on the panel item it's
+// `toplevel_mapped`, any number of `toplevel_unmapped` and `toplevel_mapped` after that
+// as it's hidden and shown, and `toplevel_destroyed` right before the item is destroyed.
 impl<B: Backend + ?Sized> PanelItem<B> {
+	/// The toplevel has a buffer, every time it does again after being unmapped too
+	pub fn toplevel_mapped(&self) {
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal("toplevel_mapped", Vec::<u8>::new());
+	}
+	/// The toplevel is hidden but can be mapped again, `geometry` is where it last was
+	pub fn toplevel_unmapped(&self, geometry: Geometry) {
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal("toplevel_unmapped", serialize(geometry).unwrap());
+	}
 	pub fn toplevel_parent_changed(&self, parent: &str) {
 		let Some(node) = self.node.upgrade() else {
 			return;
@@ -305,12 +1008,14 @@ impl<B: Backend + ?Sized> PanelItem<B> {
 		};
 		let _ = node.send_remote_signal("toplevel_fullscreen_active", serialize(active).unwrap());
 	}
+	/// xdg toplevels get moved by the backend already when this is sent, X11 windows by the shell
 	pub fn toplevel_move_request(&self) {
 		let Some(node) = self.node.upgrade() else {
 			return;
 		};
 		let _ = node.send_remote_signal("toplevel_move_request", Vec::<u8>::new());
 	}
+	/// Like `toplevel_move_request`, the backend resizes xdg toplevels itself
 	pub fn toplevel_resize_request(&self, up: bool, down: bool, left: bool, right: bool) {
 		let Some(node) = self.node.upgrade() else {
 			return;
@@ -320,12 +1025,78 @@ impl<B: Backend + ?Sized> PanelItem<B> {
 			serialize((up, down, left, right)).unwrap(),
 		);
 	}
+	pub fn toplevel_activation_request(&self) {
+		if exclusive_input_elsewhere(&self.uid).is_some() {
+			self.set_toplevel_attention(true);
+			return;
+		}
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal("toplevel_activation_request", Vec::<u8>::new());
+	}
+	/// The toplevel wants the user to look at it, as it wasn't allowed to take focus itself.
+	/// Cleared once the shell focuses it.
+	pub fn set_toplevel_attention(&self, attention: bool) {
+		if self.attention.swap(attention, Ordering::Relaxed) == attention {
+			return;
+		}
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ =
+			node.send_remote_signal("toplevel_attention_changed", serialize(attention).unwrap());
+	}
+	/// The client or its decoration's button minimized the toplevel, the shell can restore it
+	/// with `set_toplevel_minimized`
+	pub fn toplevel_minimize_request(&self) {
+		self.set_toplevel_minimized(true);
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal("toplevel_minimize_request", Vec::<u8>::new());
+	}
 	pub fn toplevel_size_changed(&self, size: Vector2<u32>) {
 		let Some(node) = self.node.upgrade() else {
 			return;
 		};
 		let _ = node.send_remote_signal("toplevel_size_changed", serialize(size).unwrap());
 	}
+	/// The part of the surface that's the window, without the client's shadows or margins
+	pub fn toplevel_geometry_changed(&self, geometry: Geometry) {
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal("toplevel_geometry_changed", serialize(geometry).unwrap());
+	}
+	pub fn toplevel_size_hints_changed(
+		&self,
+		min_size: Option<Vector2<u32>>,
+		max_size: Option<Vector2<u32>>,
+	) {
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal(
+			"toplevel_size_hints_changed",
+			serialize((min_size, max_size)).unwrap(),
+		);
+	}
+	pub fn toplevel_states_changed(&self, states: ToplevelStates) {
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal("toplevel_states_changed", serialize(states).unwrap());
+	}
+	pub fn toplevel_content_type_changed(&self, content_type: ContentType) {
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal(
+			"toplevel_content_type_changed",
+			serialize(content_type).unwrap(),
+		);
+	}
 
 	pub fn set_cursor(&self, geometry: Option<Geometry>) {
 		let Some(node) = self.node.upgrade() else {
@@ -334,6 +1105,13 @@ impl<B: Backend + ?Sized> PanelItem<B> {
 		let _ = node.send_remote_signal("set_cursor", serialize(geometry).unwrap());
 	}
 
+	pub fn set_cursor_shape(&self, shape: Option<&str>) {
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal("set_cursor_shape", serialize(shape).unwrap());
+	}
+
 	pub fn new_child(&self, uid: &str, info: ChildInfo) {
 		let Some(node) = self.node.upgrade() else {
 			return;
@@ -352,6 +1130,30 @@ impl<B: Backend + ?Sized> PanelItem<B> {
 		};
 		let _ = node.send_remote_signal("drop_child", serialize(uid).unwrap());
 	}
+
+	pub fn text_input_state(&self, surface: &SurfaceID, info: Option<TextInputInfo>) {
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal("text_input_state", serialize((surface, info)).unwrap());
+	}
+	pub fn keyboard_leds_changed(&self, leds: KeyboardLeds) {
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal("keyboard_leds_changed", serialize(leds).unwrap());
+	}
+	/// The keymap or layout keys to a surface are read with changed, modifiers changing on their
+	/// own don't count
+	pub fn keyboard_layout_changed(&self, surface: &SurfaceID, state: KeyboardState) {
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal(
+			"keyboard_layout_changed",
+			serialize((surface, state)).unwrap(),
+		);
+	}
 }
 // Local signals
 macro_rules! flex_no_args {
@@ -409,10 +1211,171 @@ impl<B: Backend + ?Sized> PanelItem<B> {
 
 		Ok(())
 	}
+	fn export_surface_flex(
+		node: Arc<Node>,
+		calling_client: Arc<Client>,
+		message: Message,
+	) -> Result<()> {
+		let Some(panel_item) = panel_item_from_node(&node) else {
+			return Ok(());
+		};
+
+		#[derive(Debug, Deserialize)]
+		struct SurfaceExportInfo<'a> {
+			surface: SurfaceID,
+			model_node_path: &'a str,
+			max_size: Option<Vector2<u32>>,
+		}
+
+		let info: SurfaceExportInfo = deserialize(message.as_ref())?;
+
+		let model_node = calling_client
+			.scenegraph
+			.get_node(info.model_node_path)
+			.ok_or_else(|| eyre!("Model node not found"))?;
+		let model_part = model_node.get_aspect::<ModelPart>()?;
+		debug!(?info, "Export surface");
+
+		let weak_node = Arc::downgrade(&node);
+		let surface = info.surface.clone();
+		let model_node_path = info.model_node_path.to_string();
+		let on_resize = move |size: Vector2<u32>| {
+			let Some(node) = weak_node.upgrade() else {
+				return;
+			};
+			let _ = node.send_remote_signal(
+				"surface_export_resized",
+				serialize((&surface, &model_node_path, size)).unwrap(),
+			);
+		};
+		panel_item.export_surface(
+			info.surface,
+			&model_part,
+			info.max_size,
+			Box::new(on_resize),
+		);
+
+		Ok(())
+	}
+	fn stop_surface_export_flex(
+		node: Arc<Node>,
+		calling_client: Arc<Client>,
+		message: Message,
+	) -> Result<()> {
+		let Some(panel_item) = panel_item_from_node(&node) else {
+			return Ok(());
+		};
+		let (surface, model_node_path): (SurfaceID, &str) = deserialize(message.as_ref())?;
+		let model_node = calling_client
+			.scenegraph
+			.get_node(model_node_path)
+			.ok_or_else(|| eyre!("Model node not found"))?;
+		let model_part = model_node.get_aspect::<ModelPart>()?;
+		debug!(?surface, "Stop surface export");
+
+		panel_item.stop_surface_export(surface, &model_part);
+
+		Ok(())
+	}
+	fn snapshot_surface_flex(
+		node: Arc<Node>,
+		_calling_client: Arc<Client>,
+		message: Message,
+		response: MethodResponseSender,
+	) {
+		let request = deserialize::<(SurfaceID, u32)>(message.as_ref());
+		response.wrap_async(async move {
+			let (surface, max_size) = request?;
+			let panel_item =
+				panel_item_from_node(&node).ok_or_else(|| eyre!("Not a panel item"))?;
+			debug!(?surface, max_size, "Snapshot surface");
+			let (tx, rx) = tokio::sync::oneshot::channel();
+			panel_item.snapshot_surface(
+				surface,
+				max_size,
+				Box::new(move |snapshot| {
+					let _ = tx.send(snapshot);
+				}),
+			);
+			// the surface went away before the next frame
+			Ok((rx.await.ok().flatten(), Vec::new()))
+		});
+	}
+	fn keyboard_state_flex(
+		node: Arc<Node>,
+		_calling_client: Arc<Client>,
+		message: Message,
+		response: MethodResponseSender,
+	) {
+		response.wrap_sync(move || {
+			let surface = deserialize::<SurfaceID>(message.as_ref())?;
+			let panel_item =
+				panel_item_from_node(&node).ok_or_else(|| eyre!("Not a panel item"))?;
+			Ok(serialize(panel_item.keyboard_state(&surface))?.into())
+		});
+	}
+	fn client_info_flex(
+		node: Arc<Node>,
+		_calling_client: Arc<Client>,
+		_message: Message,
+		response: MethodResponseSender,
+	) {
+		response.wrap_sync(move || {
+			let panel_item =
+				panel_item_from_node(&node).ok_or_else(|| eyre!("Not a panel item"))?;
+			Ok(serialize(panel_item.client_info())?.into())
+		});
+	}
 
 	flex_no_args!(close_toplevel_flex, close_toplevel);
+	flex_no_args!(kill_client_flex, kill_client);
+	flex_no_args!(raise_toplevel_flex, raise_toplevel);
+	flex_no_args!(lower_toplevel_flex, lower_toplevel);
+	flex_deserialize!(stack_above_flex, stack_above);
 	flex_no_args!(auto_size_toplevel_flex, auto_size_toplevel);
 	flex_deserialize!(set_toplevel_size_flex, set_toplevel_size);
+	flex_deserialize!(
+		set_toplevel_focused_visuals_flex,
+		set_toplevel_focused_visuals
+	);
+	flex_deserialize!(set_alpha_flex, set_alpha);
+	flex_deserialize!(set_toplevel_minimized_flex, set_toplevel_minimized);
+	flex_deserialize!(set_geometry_flex, set_geometry);
+	flex_deserialize!(set_decoration_theme_flex, set_decoration_theme);
+	flex_no_args!(grab_exclusive_input_flex, grab_exclusive_input);
+	flex_no_args!(release_exclusive_input_flex, release_exclusive_input);
+	fn set_surface_color_space_flex(
+		node: Arc<Node>,
+		_calling_client: Arc<Client>,
+		message: Message,
+	) -> Result<()> {
+		let Some(panel_item) = panel_item_from_node(&node) else {
+			return Ok(());
+		};
+
+		let (surface, color_space): (SurfaceID, ColorSpace) = deserialize(message.as_ref())?;
+		debug!(?surface, ?color_space, "Set surface color space");
+
+		panel_item.set_surface_color_space(surface, color_space);
+
+		Ok(())
+	}
+	fn set_surface_filtering_flex(
+		node: Arc<Node>,
+		_calling_client: Arc<Client>,
+		message: Message,
+	) -> Result<()> {
+		let Some(panel_item) = panel_item_from_node(&node) else {
+			return Ok(());
+		};
+
+		let (surface, filtering): (SurfaceID, SurfaceFiltering) = deserialize(message.as_ref())?;
+		debug!(?surface, ?filtering, "Set surface filtering");
+
+		panel_item.set_surface_filtering(surface, filtering);
+
+		Ok(())
+	}
 
 	fn pointer_motion_flex(
 		node: Arc<Node>,
@@ -445,6 +1408,31 @@ impl<B: Backend + ?Sized> PanelItem<B> {
 		panel_item.pointer_button(&surface_id, button, state != 0);
 		Ok(())
 	}
+	/// Press and release buttons from an input method's datamap, mapped by `POINTER_BUTTON_MAP`.
+	fn pointer_input_flex(
+		node: Arc<Node>,
+		_calling_client: Arc<Client>,
+		message: Message,
+	) -> Result<()> {
+		let Some(panel_item) = panel_item_from_node(&node) else {
+			return Ok(());
+		};
+
+		let (surface_id, datamap): (SurfaceID, Datamap) = deserialize(message.as_ref())?;
+		let pressed = mapped_pointer_buttons(&POINTER_BUTTON_MAP.lock(), &datamap)?;
+
+		let mut input_buttons = panel_item.input_buttons.lock();
+		let held = input_buttons.entry(surface_id.clone()).or_default();
+		if *held == pressed {
+			return Ok(());
+		}
+		debug!(?surface_id, ?pressed, "Pointer input");
+		for (button, pressed) in pointer_button_changes(held, &pressed) {
+			panel_item.pointer_button(&surface_id, button, pressed);
+		}
+		*held = pressed;
+		Ok(())
+	}
 	fn pointer_scroll_flex(
 		node: Arc<Node>,
 		_calling_client: Arc<Client>,
@@ -484,6 +1472,21 @@ impl<B: Backend + ?Sized> PanelItem<B> {
 
 		Ok(())
 	}
+	fn text_input_update_flex(
+		node: Arc<Node>,
+		_calling_client: Arc<Client>,
+		message: Message,
+	) -> Result<()> {
+		let Some(panel_item) = panel_item_from_node(&node) else {
+			return Ok(());
+		};
+		let (surface_id, update): (SurfaceID, TextInputUpdate) = deserialize(message.as_ref())?;
+		debug!(?surface_id, ?update, "Text input update");
+
+		panel_item.text_input_update(&surface_id, update);
+
+		Ok(())
+	}
 	pub fn grab_keyboard(&self, sid: Option<SurfaceID>) {
 		let Some(node) = self.node.upgrade() else {
 			return;
@@ -528,6 +1531,41 @@ impl<B: Backend + ?Sized> PanelItem<B> {
 	}
 	flex_deserialize!(touch_up_flex, touch_up);
 	flex_no_args!(reset_touches_flex, reset_touches);
+
+	fn pen_motion_flex(
+		node: Arc<Node>,
+		_calling_client: Arc<Client>,
+		message: Message,
+	) -> Result<()> {
+		let Some(panel_item) = panel_item_from_node(&node) else {
+			return Ok(());
+		};
+
+		let (surface_id, tool, state): (SurfaceID, PenTool, PenState) =
+			deserialize(message.as_ref())?;
+		debug!(?surface_id, ?tool, ?state, "Pen motion");
+
+		panel_item.pen_motion(&surface_id, tool, state);
+
+		Ok(())
+	}
+	fn pen_button_flex(
+		node: Arc<Node>,
+		_calling_client: Arc<Client>,
+		message: Message,
+	) -> Result<()> {
+		let Some(panel_item) = panel_item_from_node(&node) else {
+			return Ok(());
+		};
+
+		let (tool, button, pressed): (PenTool, u32, bool) = deserialize(message.as_ref())?;
+		debug!(?tool, button, pressed, "Pen button");
+
+		panel_item.pen_button(tool, button, pressed);
+
+		Ok(())
+	}
+	flex_deserialize!(pen_leave_flex, pen_leave);
 }
 impl<B: Backend + ?Sized> PanelItemTrait for PanelItem<B> {
 	fn uid(&self) -> &str {
@@ -537,19 +1575,63 @@ impl<B: Backend + ?Sized> PanelItemTrait for PanelItem<B> {
 	fn serialize_start_data(&self, id: &str) -> Result<Message> {
 		Ok(serialize((id, self.start_data()?))?.into())
 	}
+	fn exclusive_input_changed(&self, exclusive: bool) {
+		self.backend.set_pointer_grab(exclusive);
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal("exclusive_input_changed", serialize(exclusive).unwrap());
+	}
 }
 impl<B: Backend + ?Sized> Backend for PanelItem<B> {
 	fn start_data(&self) -> Result<PanelItemInitData> {
-		self.backend.start_data()
+		Ok(PanelItemInitData {
+			stack_position: self.stack_position.load(Ordering::Relaxed),
+			..self.backend.start_data()?
+		})
 	}
 
 	fn apply_surface_material(&self, surface: SurfaceID, model_part: &Arc<ModelPart>) {
 		self.backend.apply_surface_material(surface, model_part)
 	}
+	fn set_surface_color_space(&self, surface: SurfaceID, color_space: ColorSpace) {
+		self.backend.set_surface_color_space(surface, color_space)
+	}
+	fn set_surface_filtering(&self, surface: SurfaceID, filtering: SurfaceFiltering) {
+		self.backend.set_surface_filtering(surface, filtering)
+	}
+	fn export_surface(
+		&self,
+		surface: SurfaceID,
+		model_part: &Arc<ModelPart>,
+		max_size: Option<Vector2<u32>>,
+		on_resize: Box<dyn Fn(Vector2<u32>) + Send + Sync>,
+	) {
+		self.backend
+			.export_surface(surface, model_part, max_size, on_resize)
+	}
+	fn stop_surface_export(&self, surface: SurfaceID, model_part: &Arc<ModelPart>) {
+		self.backend.stop_surface_export(surface, model_part)
+	}
+	fn snapshot_surface(
+		&self,
+		surface: SurfaceID,
+		max_size: u32,
+		on_snapshot: Box<dyn FnOnce(Option<SurfaceSnapshot>) + Send>,
+	) {
+		self.backend
+			.snapshot_surface(surface, max_size, on_snapshot)
+	}
 
 	fn close_toplevel(&self) {
 		self.backend.close_toplevel()
 	}
+	fn kill_client(&self) {
+		self.backend.kill_client()
+	}
+	fn client_info(&self) -> Option<ClientInfo> {
+		self.backend.client_info()
+	}
 	fn auto_size_toplevel(&self) {
 		self.backend.auto_size_toplevel()
 	}
@@ -557,13 +1639,52 @@ impl<B: Backend + ?Sized> Backend for PanelItem<B> {
 		self.backend.set_toplevel_size(size)
 	}
 	fn set_toplevel_focused_visuals(&self, focused: bool) {
+		if focused && exclusive_input_elsewhere(&self.uid).is_some() {
+			return;
+		}
+		if focused {
+			self.set_toplevel_attention(false);
+			self.raise_toplevel();
+		}
 		self.backend.set_toplevel_focused_visuals(focused)
 	}
+	fn set_stack_position(&self, position: u32) {
+		if self.stack_position.swap(position, Ordering::Relaxed) == position {
+			return;
+		}
+		self.backend.set_stack_position(position);
+		let Some(node) = self.node.upgrade() else {
+			return;
+		};
+		let _ = node.send_remote_signal("stack_position_changed", serialize(position).unwrap());
+	}
+	fn set_alpha(&self, alpha: f32) {
+		self.backend.set_alpha(alpha)
+	}
+	fn set_toplevel_minimized(&self, minimized: bool) {
+		// nothing of it's left to take the input
+		if minimized {
+			release_exclusive_input(&self.uid);
+		}
+		self.backend.set_toplevel_minimized(minimized)
+	}
+	fn set_geometry(&self, geometry: PanelGeometry) {
+		self.backend.set_geometry(geometry)
+	}
+	fn set_decoration_theme(&self, theme: Option<DecorationTheme>) {
+		self.backend.set_decoration_theme(theme)
+	}
 
 	fn pointer_motion(&self, surface: &SurfaceID, position: Vector2<f32>) {
+		if exclusive_input_elsewhere(&self.uid).is_some() {
+			return;
+		}
 		self.backend.pointer_motion(surface, position)
 	}
 	fn pointer_button(&self, surface: &SurfaceID, button: u32, pressed: bool) {
+		if let Some(exclusive) = exclusive_input_elsewhere(&self.uid) {
+			return exclusive.pointer_button(&SurfaceID::Toplevel, button, pressed);
+		}
 		self.backend.pointer_button(surface, button, pressed)
 	}
 	fn pointer_scroll(
@@ -572,18 +1693,51 @@ impl<B: Backend + ?Sized> Backend for PanelItem<B> {
 		scroll_distance: Option<Vector2<f32>>,
 		scroll_steps: Option<Vector2<f32>>,
 	) {
+		if let Some(exclusive) = exclusive_input_elsewhere(&self.uid) {
+			return exclusive.pointer_scroll(&SurfaceID::Toplevel, scroll_distance, scroll_steps);
+		}
 		self.backend
 			.pointer_scroll(surface, scroll_distance, scroll_steps)
 	}
+	fn set_pointer_grab(&self, grabbed: bool) {
+		self.backend.set_pointer_grab(grabbed)
+	}
 
-	fn keyboard_keys(&self, surface: &SurfaceID, keymap_id: &str, keys: Vec<i32>) {
+	fn keyboard_keys(&self, surface: &SurfaceID, keymap_id: &str, mut keys: Vec<i32>) {
+		if let Some(exclusive) = exclusive_input_elsewhere(&self.uid) {
+			return exclusive.keyboard_keys(&SurfaceID::Toplevel, keymap_id, keys);
+		}
+		if exclusive_input_escape(&self.uid, &mut keys) {
+			self.release_exclusive_input();
+		}
 		self.backend.keyboard_keys(surface, keymap_id, keys)
 	}
+	fn keyboard_state(&self, surface: &SurfaceID) -> Option<KeyboardState> {
+		// keys go wherever exclusive input is, so they're read how that surface reads them
+		if let Some(exclusive) = exclusive_input_elsewhere(&self.uid) {
+			return exclusive.keyboard_state(&SurfaceID::Toplevel);
+		}
+		self.backend.keyboard_state(surface)
+	}
 
+	fn text_input_update(&self, surface: &SurfaceID, update: TextInputUpdate) {
+		if exclusive_input_elsewhere(&self.uid).is_some() {
+			return;
+		}
+		self.backend.text_input_update(surface, update)
+	}
+
+	// Touches and pens only get lifted once exclusive input is taken, so none are stuck down
 	fn touch_down(&self, surface: &SurfaceID, id: u32, position: Vector2<f32>) {
+		if exclusive_input_elsewhere(&self.uid).is_some() {
+			return;
+		}
 		self.backend.touch_down(surface, id, position)
 	}
 	fn touch_move(&self, id: u32, position: Vector2<f32>) {
+		if exclusive_input_elsewhere(&self.uid).is_some() {
+			return;
+		}
 		self.backend.touch_move(id, position)
 	}
 	fn touch_up(&self, id: u32) {
@@ -592,10 +1746,237 @@ impl<B: Backend + ?Sized> Backend for PanelItem<B> {
 	fn reset_touches(&self) {
 		self.backend.reset_touches()
 	}
+
+	fn pen_motion(&self, surface: &SurfaceID, tool: PenTool, state: PenState) {
+		if exclusive_input_elsewhere(&self.uid).is_some() {
+			return;
+		}
+		self.backend.pen_motion(surface, tool, state)
+	}
+	fn pen_button(&self, tool: PenTool, button: u32, pressed: bool) {
+		if exclusive_input_elsewhere(&self.uid).is_some() {
+			return;
+		}
+		self.backend.pen_button(tool, button, pressed)
+	}
+	fn pen_leave(&self, tool: PenTool) {
+		self.backend.pen_leave(tool)
+	}
 }
 impl<B: Backend + ?Sized> Drop for PanelItem<B> {
 	fn drop(&mut self) {
 		// Dropped panel item, basically just a debug breakpoint place
 		info!("Dropped panel item {}", self.uid);
+		// without telling anyone, there's no node left to tell
+		if let Some(mut exclusive) = EXCLUSIVE_INPUT.try_lock() {
+			if exclusive
+				.as_ref()
+				.is_some_and(|exclusive| exclusive.uid == self.uid)
+			{
+				exclusive.take();
+			}
+		}
+		// the items above it move down, unless this was dropped while restacking and it happens
+		// once that's done anyway
+		if let Some(mut stack) = STACK.try_lock() {
+			restack(&mut stack);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn restacked<'a>(stack: &[&'a str], uid: &str, place: StackPlace) -> Option<Vec<&'a str>> {
+		let mut stack = stack.to_vec();
+		let moved = move_item(&mut stack, uid, place, |item, uid| *item == uid);
+		moved.then_some(stack)
+	}
+
+	#[test]
+	fn raised_panels_go_above_overlapping_ones() {
+		// two overlapping panels, the newer one opened on top
+		let stack = ["terminal", "browser"];
+		assert_eq!(
+			restacked(&stack, "terminal", StackPlace::Top),
+			Some(vec!["browser", "terminal"])
+		);
+		assert_eq!(
+			restacked(&stack, "browser", StackPlace::Bottom),
+			Some(vec!["browser", "terminal"])
+		);
+		let stack = ["terminal", "browser", "editor"];
+		assert_eq!(
+			restacked(&stack, "editor", StackPlace::Above("terminal")),
+			Some(vec!["terminal", "editor", "browser"])
+		);
+		// above one that's gone is on top
+		assert_eq!(
+			restacked(&stack, "terminal", StackPlace::Above("closed")),
+			Some(vec!["browser", "editor", "terminal"])
+		);
+		assert_eq!(restacked(&stack, "closed", StackPlace::Top), None);
+	}
+
+	#[test]
+	fn button_layouts_parse_like_gnome() {
+		let layout = "appmenu:minimize,close".parse::<ButtonLayout>().unwrap();
+		assert!(layout.left.is_empty());
+		assert_eq!(
+			layout.right,
+			[DecorationButton::Minimize, DecorationButton::Close]
+		);
+		let layout = " close , maximize:".parse::<ButtonLayout>().unwrap();
+		assert_eq!(
+			layout.left,
+			[DecorationButton::Close, DecorationButton::Maximize]
+		);
+		assert!(layout.right.is_empty());
+		assert_eq!("".parse::<ButtonLayout>().unwrap().buttons().count(), 0);
+	}
+
+	#[test]
+	fn button_layouts_without_a_colon_are_all_left() {
+		let layout = "close,minimize".parse::<ButtonLayout>().unwrap();
+		assert_eq!(
+			layout.left,
+			[DecorationButton::Close, DecorationButton::Minimize]
+		);
+		assert!(layout.right.is_empty());
+	}
+
+	#[test]
+	fn invalid_button_layouts_are_errors() {
+		// two colons
+		assert!("close:minimize:maximize".parse::<ButtonLayout>().is_err());
+		// a button twice, even on different sides
+		assert!("close:minimize,close".parse::<ButtonLayout>().is_err());
+		// a button that doesn't exist anywhere
+		assert!("close,shade".parse::<ButtonLayout>().is_err());
+	}
+
+	#[test]
+	fn pointer_button_maps_parse() {
+		assert_eq!(
+			parse_pointer_button_map("select=left, grab = right,back=back,trigger=274,,"),
+			[
+				("select".to_string(), BTN_LEFT),
+				("grab".to_string(), BTN_RIGHT),
+				("back".to_string(), BTN_SIDE),
+				("trigger".to_string(), BTN_MIDDLE),
+			]
+		);
+		// broken entries are skipped, the rest still apply
+		assert_eq!(
+			parse_pointer_button_map("select,grab=wheel,context=right"),
+			[("context".to_string(), BTN_RIGHT)]
+		);
+	}
+
+	#[test]
+	fn inputs_press_their_linux_buttons() {
+		#[derive(Default, Serialize)]
+		struct Input {
+			select: f32,
+			pinch_strength: f32,
+			context: f32,
+			middle: f32,
+			back: f32,
+			forward: f32,
+		}
+		let pressed = |input: Input| {
+			let datamap = Datamap::from_typed(input).unwrap();
+			mapped_pointer_buttons(&POINTER_BUTTON_MAP.lock(), &datamap).unwrap()
+		};
+		let none = Input::default;
+		assert!(pressed(none()).is_empty());
+		let inputs = [
+			(
+				Input {
+					select: 1.0,
+					..none()
+				},
+				BTN_LEFT,
+			),
+			(
+				Input {
+					context: 1.0,
+					..none()
+				},
+				BTN_RIGHT,
+			),
+			(
+				Input {
+					middle: 1.0,
+					..none()
+				},
+				BTN_MIDDLE,
+			),
+			(
+				Input {
+					back: 1.0,
+					..none()
+				},
+				BTN_SIDE,
+			),
+			(
+				Input {
+					forward: 1.0,
+					..none()
+				},
+				BTN_EXTRA,
+			),
+		];
+		for (input, button) in inputs {
+			assert_eq!(pressed(input), [button]);
+		}
+		// analog ones only press past halfway, and two inputs on one button press it once
+		let half = Input {
+			pinch_strength: 0.5,
+			..none()
+		};
+		assert!(pressed(half).is_empty());
+		let both = Input {
+			select: 1.0,
+			pinch_strength: 0.9,
+			..none()
+		};
+		assert_eq!(pressed(both), [BTN_LEFT]);
+	}
+
+	#[test]
+	fn holding_a_button_while_pressing_another() {
+		assert_eq!(pointer_button_changes(&[], &[BTN_LEFT]), [(BTN_LEFT, true)]);
+		assert_eq!(
+			pointer_button_changes(&[BTN_LEFT], &[BTN_LEFT, BTN_RIGHT]),
+			[(BTN_RIGHT, true)]
+		);
+		assert_eq!(
+			pointer_button_changes(&[BTN_LEFT, BTN_RIGHT], &[BTN_RIGHT, BTN_SIDE]),
+			[(BTN_LEFT, false), (BTN_SIDE, true)]
+		);
+		assert!(pointer_button_changes(&[BTN_RIGHT], &[BTN_RIGHT]).is_empty());
+	}
+
+	#[test]
+	fn text_input_updates_only_need_what_changed() {
+		// what a keyboard component typing "42" into a digits field sends
+		#[derive(Serialize)]
+		struct Commit {
+			commit_string: &'static str,
+		}
+		let message = serialize((
+			SurfaceID::Toplevel,
+			Commit {
+				commit_string: "42",
+			},
+		))
+		.unwrap();
+		let (surface, update): (SurfaceID, TextInputUpdate) = deserialize(&message).unwrap();
+		assert_eq!(surface, SurfaceID::Toplevel);
+		assert_eq!(update.commit_string.as_deref(), Some("42"));
+		assert_eq!(update.preedit_string, None);
+		assert_eq!((update.delete_before, update.delete_after), (0, 0));
 	}
 }
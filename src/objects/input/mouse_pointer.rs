@@ -10,7 +10,7 @@ use crate::{
 		Node,
 	},
 };
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
 use glam::{vec2, vec3, Mat4, Vec2, Vec3};
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
@@ -48,6 +48,15 @@ impl Default for KeyboardEvent {
 	}
 }
 
+/// RMLVO names for the flatscreen keyboard's keymap, empty ones use xkbcommon's defaults
+#[derive(Debug, Clone, Default)]
+pub struct KeymapNames {
+	pub model: String,
+	pub layout: String,
+	pub variant: String,
+	pub options: Option<String>,
+}
+
 pub struct MousePointer {
 	node: Arc<Node>,
 	spatial: Arc<Spatial>,
@@ -57,18 +66,26 @@ pub struct MousePointer {
 	keyboard_sender: Arc<PulseSender>,
 }
 impl MousePointer {
-	pub fn new() -> Result<Self> {
+	pub fn new(keymap_names: KeymapNames) -> Result<Self> {
 		let node = Node::create_parent_name(&INTERNAL_CLIENT, "", &nanoid!(), false)
 			.add_to_scenegraph()?;
 		let spatial = Spatial::add_to(&node, None, Mat4::IDENTITY, false);
 		let pointer =
 			InputMethod::add_to(&node, InputType::Pointer(Pointer::default()), None).unwrap();
 
+		let keymap = Keymap::new_from_names(
+			&Context::new(0),
+			"evdev",
+			&keymap_names.model,
+			&keymap_names.layout,
+			&keymap_names.variant,
+			keymap_names.options.clone(),
+			0,
+		)
+		.ok_or_else(|| eyre!("Could not create keymap from {keymap_names:?}"))?;
 		KEYMAPS.lock().insert(
 			"flatscreen".to_string(),
-			Keymap::new_from_names(&Context::new(0), "evdev", "", "", "", None, 0)
-				.unwrap()
-				.get_as_string(FORMAT_TEXT_V1),
+			keymap.get_as_string(FORMAT_TEXT_V1),
 		);
 
 		let keyboard_sender = PulseSender::add_to(
@@ -0,0 +1,70 @@
+use mint::Vector2;
+use parking_lot::Mutex;
+use smithay::{
+	reexports::wayland_server::protocol::wl_surface::WlSurface,
+	wayland::compositor::{
+		self, SubsurfaceCachedState, SurfaceAttributes, SurfaceData, TraversalAction,
+	},
+};
+use tracing::debug;
+
+/// Smithay's subsurface role
+const SUBSURFACE_ROLE: &str = "subsurface";
+
+/// How far the `x`, `y` of `wl_surface.attach` (before version 5) and `wl_surface.offset`
+/// (since) have moved the surface's contents in total
+#[derive(Default)]
+struct BufferOffset(Mutex<Vector2<i32>>);
+
+/// Take the offsets that just got applied out of the state of every surface in the tree, so
+/// they move things along with the buffer they came with. Children are in there as committing a
+/// surface also applies the state its synchronized subsurfaces cached.
+pub fn apply_buffer_offsets(surface: &WlSurface) {
+	compositor::with_surface_tree_upward(
+		surface,
+		(),
+		|_, _, _| TraversalAction::DoChildren(()),
+		|surface, data, _| apply_buffer_offset(surface, data),
+		|_, _, _| true,
+	);
+}
+fn apply_buffer_offset(surface: &WlSurface, data: &SurfaceData) {
+	let Some(delta) = data
+		.cached_state
+		.current::<SurfaceAttributes>()
+		.buffer_delta
+		.take()
+	else {
+		return;
+	};
+	debug!(?surface, ?delta, "Surface contents moved");
+	data.data_map
+		.insert_if_missing_threadsafe(BufferOffset::default);
+	if let Some(offset) = data.data_map.get::<BufferOffset>() {
+		let mut offset = offset.0.lock();
+		offset.x += delta.x;
+		offset.y += delta.y;
+	}
+	// Everything else that gets drawn where its offset says is drawn by us (like drag icons) or
+	// the shell (like cursors) and looks it up, but subsurfaces are composited by smithay from
+	// their position. A later `set_position` puts them where it says again.
+	if compositor::get_role(surface) == Some(SUBSURFACE_ROLE) {
+		data.cached_state
+			.pending::<SubsurfaceCachedState>()
+			.location += delta;
+		data.cached_state
+			.current::<SubsurfaceCachedState>()
+			.location += delta;
+	}
+}
+
+/// How far the surface's contents have been moved since it was made, in surface pixels.
+/// Roles that place the surface relative to something (like a cursor's hotspot) go by how much
+/// this changed since the role was given.
+pub fn buffer_offset(surface: &WlSurface) -> Vector2<i32> {
+	compositor::with_states(surface, |data| {
+		data.data_map
+			.get::<BufferOffset>()
+			.map_or([0, 0].into(), |offset| *offset.0.lock())
+	})
+}
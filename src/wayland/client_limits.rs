@@ -0,0 +1,312 @@
+use parking_lot::{const_mutex, Mutex};
+use portable_atomic::{AtomicU32, Ordering};
+use smithay::{
+	backend::renderer::buffer_dimensions,
+	reexports::wayland_server::{
+		backend::protocol::ProtocolError,
+		protocol::{
+			wl_display,
+			wl_surface::{self, WlSurface},
+		},
+		Client, DisplayHandle, Resource,
+	},
+	utils::{Buffer, Size},
+	wayland::compositor::{self, BufferAssignment, SurfaceAttributes},
+};
+use std::{
+	str::FromStr,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// How much a single client may allocate before it gets disconnected,
+/// so one misbehaving client can't run the whole session out of GPU memory.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientLimits {
+	pub surfaces: usize,
+	/// Surfaces that have a buffer imported into a texture
+	pub buffers: usize,
+	pub texture_bytes: u64,
+	/// Far more than any client redrawing every surface each frame needs, clients going past
+	/// this are spinning and get dispatched after everyone else for the rest of the second
+	pub commits_per_second: u32,
+	/// Largest width or height of a buffer, lowered to what the GPU supports if that's less
+	pub buffer_size: u32,
+}
+impl ClientLimits {
+	pub const DEFAULT: Self = ClientLimits {
+		surfaces: 4096,
+		buffers: 1024,
+		texture_bytes: 4 << 30,
+		commits_per_second: 20_000,
+		buffer_size: 8192,
+	};
+}
+impl Default for ClientLimits {
+	fn default() -> Self {
+		Self::DEFAULT
+	}
+}
+/// Comma separated `key=value` pairs like `surfaces=256,texture_mib=1024`, the keys being the
+/// field names except `texture_mib` for `texture_bytes`. Anything not given stays the default.
+impl FromStr for ClientLimits {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut limits = ClientLimits::DEFAULT;
+		for pair in s.split(',').filter(|pair| !pair.is_empty()) {
+			let (key, value) = pair
+				.split_once('=')
+				.ok_or_else(|| format!("{pair:?} isn't key=value"))?;
+			let invalid = |_| format!("{value:?} isn't a valid {key}");
+			match key {
+				"surfaces" => limits.surfaces = value.parse().map_err(invalid)?,
+				"buffers" => limits.buffers = value.parse().map_err(invalid)?,
+				"texture_mib" => {
+					limits.texture_bytes = value.parse::<u64>().map_err(invalid)? << 20
+				}
+				"commits_per_second" => {
+					limits.commits_per_second = value.parse().map_err(invalid)?
+				}
+				"buffer_size" => limits.buffer_size = value.parse().map_err(invalid)?,
+				_ => return Err(format!("{key:?} isn't a client limit")),
+			}
+		}
+		Ok(limits)
+	}
+}
+pub static CLIENT_LIMITS: Mutex<ClientLimits> = const_mutex(ClientLimits::DEFAULT);
+/// `GL_MAX_TEXTURE_SIZE` of the renderer, set once it's created
+pub static MAX_TEXTURE_SIZE: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// The largest width or height a client's buffer can have
+pub fn max_buffer_size() -> u32 {
+	CLIENT_LIMITS
+		.lock()
+		.buffer_size
+		.min(MAX_TEXTURE_SIZE.load(Ordering::Relaxed))
+}
+
+/// Post `invalid_size` if the buffer just committed to the surface is over `max_buffer_size`,
+/// as it would otherwise just fail to import and leave the surface black. Returns if it was.
+pub fn enforce_buffer_size(surface: &WlSurface) -> bool {
+	let size = compositor::with_states(surface, |data| {
+		match &data.cached_state.current::<SurfaceAttributes>().buffer {
+			Some(BufferAssignment::NewBuffer(buffer)) => buffer_dimensions(buffer),
+			_ => None,
+		}
+	});
+	let max = max_buffer_size();
+	let Some(message) = size.and_then(|size| oversized(size, max)) else {
+		return false;
+	};
+	warn!(?surface, ?size, max, "Client committed an oversized buffer");
+	surface.post_error(wl_surface::Error::InvalidSize, message);
+	true
+}
+/// The error for a buffer of `size` if it's over `max` in either dimension
+fn oversized(size: Size<i32, Buffer>, max: u32) -> Option<String> {
+	if size.w as u32 <= max && size.h as u32 <= max {
+		return None;
+	}
+	Some(format!(
+		"Buffer is {}x{}, the maximum is {max}x{max}",
+		size.w, size.h
+	))
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceCounts {
+	pub surfaces: usize,
+	pub buffers: usize,
+	pub texture_bytes: u64,
+}
+impl ResourceCounts {
+	fn within(&self, limits: &ClientLimits) -> bool {
+		self.surfaces <= limits.surfaces
+			&& self.buffers <= limits.buffers
+			&& self.texture_bytes <= limits.texture_bytes
+	}
+}
+
+/// What a client currently has allocated, resources remove themselves from it when dropped
+#[derive(Debug, Default)]
+pub struct ResourceUsage(Mutex<ResourceCounts>);
+impl ResourceUsage {
+	pub fn counts(&self) -> ResourceCounts {
+		*self.0.lock()
+	}
+	pub fn within_limits(&self) -> bool {
+		self.counts().within(&CLIENT_LIMITS.lock())
+	}
+
+	pub fn add_surface(self: &Arc<Self>) -> SurfaceResource {
+		self.0.lock().surfaces += 1;
+		SurfaceResource(self.clone())
+	}
+	pub fn add_buffer(self: &Arc<Self>, texture_bytes: u64) -> BufferResource {
+		let mut counts = self.0.lock();
+		counts.buffers += 1;
+		counts.texture_bytes += texture_bytes;
+		BufferResource {
+			usage: self.clone(),
+			texture_bytes,
+		}
+	}
+
+	/// Disconnect the client with a `no_memory` error if it's over the limits, returns if it was.
+	pub fn enforce(&self, dh: &DisplayHandle, client: &Client) -> bool {
+		if self.within_limits() {
+			return false;
+		}
+		let counts = self.counts();
+		warn!(?counts, limits = ?*CLIENT_LIMITS.lock(), "Client exceeded its resource limits");
+		disconnect(dh, client, format!("Resource limits exceeded: {counts:?}"));
+		true
+	}
+}
+
+/// Commits in the current second, so a client spinning on commits can't monopolize dispatching
+/// (see `DisplayWrapper::dispatch_clients`)
+#[derive(Debug)]
+pub struct CommitRate(Mutex<(Instant, u32)>);
+impl Default for CommitRate {
+	fn default() -> Self {
+		CommitRate(Mutex::new((Instant::now(), 0)))
+	}
+}
+impl CommitRate {
+	/// Count a commit, warning once about the client when it goes over the limit
+	pub fn count(&self) {
+		self.count_at(Instant::now(), CLIENT_LIMITS.lock().commits_per_second);
+	}
+	fn count_at(&self, now: Instant, limit: u32) {
+		let mut rate = self.0.lock();
+		if now.saturating_duration_since(rate.0) >= Duration::from_secs(1) {
+			*rate = (now, 0);
+		}
+		rate.1 += 1;
+		if rate.1 == limit + 1 {
+			warn!(limit, "Client is flooding commits, throttling it");
+		}
+	}
+	/// If the client went over the limit this second
+	pub fn throttled(&self) -> bool {
+		self.throttled_at(Instant::now(), CLIENT_LIMITS.lock().commits_per_second)
+	}
+	fn throttled_at(&self, now: Instant, limit: u32) -> bool {
+		let rate = self.0.lock();
+		now.saturating_duration_since(rate.0) < Duration::from_secs(1) && rate.1 > limit
+	}
+}
+
+fn disconnect(dh: &DisplayHandle, client: &Client, message: String) {
+	client.kill(
+		dh,
+		ProtocolError {
+			code: wl_display::Error::NoMemory as u32,
+			object_id: 1,
+			object_interface: "wl_display".to_string(),
+			message,
+		},
+	);
+}
+
+/// Stored on every surface of the client
+pub struct SurfaceResource(Arc<ResourceUsage>);
+impl Drop for SurfaceResource {
+	fn drop(&mut self) {
+		self.0 .0.lock().surfaces -= 1;
+	}
+}
+
+/// Stored with the texture of a mapped surface
+pub struct BufferResource {
+	usage: Arc<ResourceUsage>,
+	texture_bytes: u64,
+}
+impl Drop for BufferResource {
+	fn drop(&mut self) {
+		let mut counts = self.usage.0.lock();
+		counts.buffers -= 1;
+		counts.texture_bytes -= self.texture_bytes;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn commit_rate_throttles_for_the_rest_of_the_second() {
+		let start = Instant::now();
+		let rate = CommitRate(Mutex::new((start, 0)));
+		let at = |millis| start + Duration::from_millis(millis);
+		for _ in 0..3 {
+			rate.count_at(at(100), 3);
+		}
+		assert!(!rate.throttled_at(at(100), 3));
+		rate.count_at(at(200), 3);
+		assert!(rate.throttled_at(at(200), 3));
+		assert!(rate.throttled_at(at(999), 3));
+		// A new second starts over without the client having to commit again
+		assert!(!rate.throttled_at(at(1000), 3));
+		rate.count_at(at(1000), 3);
+		assert!(!rate.throttled_at(at(1000), 3));
+	}
+
+	#[test]
+	fn only_the_client_over_the_limits_is_over_them() {
+		let limits = ClientLimits {
+			buffers: 2,
+			texture_bytes: 1000,
+			..ClientLimits::DEFAULT
+		};
+		let leaking = Arc::new(ResourceUsage::default());
+		let other = Arc::new(ResourceUsage::default());
+		let _other_buffer = other.add_buffer(500);
+		let buffers = (0..2).map(|_| leaking.add_buffer(400)).collect::<Vec<_>>();
+		assert!(leaking.counts().within(&limits));
+		let over = leaking.add_buffer(400);
+		assert!(!leaking.counts().within(&limits));
+		assert!(other.counts().within(&limits));
+
+		// freeing resources takes them off the client's count again
+		drop(over);
+		drop(buffers);
+		let _surface = leaking.add_surface();
+		assert_eq!(
+			leaking.counts(),
+			ResourceCounts {
+				surfaces: 1,
+				buffers: 0,
+				texture_bytes: 0
+			}
+		);
+	}
+
+	#[test]
+	fn oversized_buffers_are_errors() {
+		assert_eq!(oversized(Size::from((8192, 8192)), 8192), None);
+		assert_eq!(
+			oversized(Size::from((8193, 10)), 8192).as_deref(),
+			Some("Buffer is 8193x10, the maximum is 8192x8192")
+		);
+		assert!(oversized(Size::from((10, 16384)), 8192).is_some());
+	}
+
+	#[test]
+	fn limits_parse_from_key_value_pairs() {
+		let limits = "surfaces=256,texture_mib=1024"
+			.parse::<ClientLimits>()
+			.unwrap();
+		assert_eq!(limits.surfaces, 256);
+		assert_eq!(limits.texture_bytes, 1 << 30);
+		assert_eq!(limits.buffers, ClientLimits::DEFAULT.buffers);
+		assert!("".parse::<ClientLimits>().is_ok());
+		assert!("surfaces".parse::<ClientLimits>().is_err());
+		assert!("surfaces=lots".parse::<ClientLimits>().is_err());
+		assert!("windows=3".parse::<ClientLimits>().is_err());
+	}
+}
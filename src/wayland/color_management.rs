@@ -0,0 +1,643 @@
+// Re-export only the actual code, and then only use this re-export
+// The `generated` module below is just some boilerplate to properly isolate stuff
+// and avoid exposing internal details.
+pub use generated::{
+	wp_color_management_output_v1, wp_color_management_surface_feedback_v1,
+	wp_color_management_surface_v1, wp_color_manager_v1, wp_image_description_creator_icc_v1,
+	wp_image_description_creator_params_v1, wp_image_description_info_v1, wp_image_description_v1,
+};
+
+#[allow(non_upper_case_globals, non_camel_case_types)]
+mod generated {
+	use smithay::reexports::wayland_server::{self, protocol::*};
+
+	pub mod __interfaces {
+		use smithay::reexports::wayland_server::protocol::__interfaces::*;
+		wayland_scanner::generate_interfaces!("src/wayland/color-management-v1.xml");
+	}
+	use self::__interfaces::*;
+
+	wayland_scanner::generate_server_code!("src/wayland/color-management-v1.xml");
+}
+
+use super::state::WaylandState;
+use parking_lot::Mutex;
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+use smithay::{
+	reexports::wayland_server::{
+		backend::ClientId, protocol::wl_surface::WlSurface, Client, DataInit, Dispatch,
+		DisplayHandle, GlobalDispatch, New, Resource, WEnum, Weak as WlWeak,
+	},
+	wayland::compositor::{self, Cacheable},
+};
+use tracing::debug;
+use wp_color_management_output_v1::WpColorManagementOutputV1;
+use wp_color_management_surface_feedback_v1::WpColorManagementSurfaceFeedbackV1;
+use wp_color_management_surface_v1::WpColorManagementSurfaceV1;
+use wp_color_manager_v1::{Primaries as NamedPrimaries, RenderIntent, WpColorManagerV1};
+use wp_image_description_creator_icc_v1::WpImageDescriptionCreatorIccV1;
+use wp_image_description_creator_params_v1::WpImageDescriptionCreatorParamsV1;
+use wp_image_description_info_v1::WpImageDescriptionInfoV1;
+use wp_image_description_v1::WpImageDescriptionV1;
+
+/// Reference white of PQ and HLG content that doesn't say otherwise, from BT.2408
+pub const HDR_REFERENCE_WHITE: u32 = 203;
+/// Reference white of everything else, from the sRGB spec
+pub const SDR_REFERENCE_WHITE: u32 = 80;
+/// Identity of the sRGB description we prefer, the ones clients create start after it
+const SRGB_IDENTITY: u32 = 1;
+static NEXT_IDENTITY: AtomicU32 = AtomicU32::new(SRGB_IDENTITY + 1);
+
+/// The transfer functions we can decode. Every surface ends up encoded as sRGB for StereoKit:
+/// `Srgb`, `Gamma22` and `Bt1886` are close enough to be drawn as is, `ExtLinear` is encoded
+/// like `ColorSpace::Linear`, and `St2084Pq` and `Hlg` are scaled so reference white is 1.0.
+/// Anything brighter than reference white or outside 0..1 gets clamped, there's no HDR output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransferFunction {
+	Srgb,
+	Gamma22,
+	Bt1886,
+	ExtLinear,
+	St2084Pq,
+	Hlg,
+}
+impl TransferFunction {
+	const ALL: [Self; 6] = [
+		Self::Srgb,
+		Self::Gamma22,
+		Self::Bt1886,
+		Self::ExtLinear,
+		Self::St2084Pq,
+		Self::Hlg,
+	];
+
+	fn from_named(named: wp_color_manager_v1::TransferFunction) -> Option<Self> {
+		Self::ALL.into_iter().find(|tf| tf.named() == named)
+	}
+	fn named(self) -> wp_color_manager_v1::TransferFunction {
+		use wp_color_manager_v1::TransferFunction as Named;
+		match self {
+			Self::Srgb => Named::Srgb,
+			Self::Gamma22 => Named::Gamma22,
+			Self::Bt1886 => Named::Bt1886,
+			Self::ExtLinear => Named::ExtLinear,
+			Self::St2084Pq => Named::St2084Pq,
+			Self::Hlg => Named::Hlg,
+		}
+	}
+}
+
+/// The primaries clients can describe their surfaces with. Only the transfer function gets
+/// decoded for now, wide gamut content is shown with its primaries taken as sRGB's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Primaries {
+	Srgb,
+	Bt2020,
+	DisplayP3,
+}
+impl Primaries {
+	const ALL: [Self; 3] = [Self::Srgb, Self::Bt2020, Self::DisplayP3];
+
+	fn from_named(named: NamedPrimaries) -> Option<Self> {
+		Self::ALL
+			.into_iter()
+			.find(|primaries| primaries.named() == named)
+	}
+	fn named(self) -> NamedPrimaries {
+		match self {
+			Self::Srgb => NamedPrimaries::Srgb,
+			Self::Bt2020 => NamedPrimaries::Bt2020,
+			Self::DisplayP3 => NamedPrimaries::DisplayP3,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DynamicRange {
+	Sdr,
+	/// Brighter than reference white, which gets clamped until there's HDR output
+	Hdr,
+}
+
+/// Luminances in cd/m², except `min` which is in 0.0001 cd/m²
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Luminances {
+	pub min: u32,
+	pub max: u32,
+	pub reference: u32,
+}
+
+/// How a client says the colors of its surface are encoded, with `wp_color_management_v1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageDescription {
+	pub transfer_function: TransferFunction,
+	pub primaries: Primaries,
+	/// `None` uses the defaults of the transfer function
+	pub luminances: Option<Luminances>,
+}
+impl ImageDescription {
+	pub const SRGB: Self = ImageDescription {
+		transfer_function: TransferFunction::Srgb,
+		primaries: Primaries::Srgb,
+		luminances: None,
+	};
+
+	pub fn luminances(&self) -> Luminances {
+		self.luminances.unwrap_or(match self.transfer_function {
+			TransferFunction::St2084Pq => Luminances {
+				min: 50,
+				max: 10000,
+				reference: HDR_REFERENCE_WHITE,
+			},
+			TransferFunction::Hlg => Luminances {
+				min: 50,
+				max: 1000,
+				reference: HDR_REFERENCE_WHITE,
+			},
+			_ => Luminances {
+				min: 2000,
+				max: SDR_REFERENCE_WHITE,
+				reference: SDR_REFERENCE_WHITE,
+			},
+		})
+	}
+	pub fn dynamic_range(&self) -> DynamicRange {
+		let luminances = self.luminances();
+		let hdr_transfer = matches!(
+			self.transfer_function,
+			TransferFunction::St2084Pq | TransferFunction::Hlg
+		);
+		if hdr_transfer || luminances.max > luminances.reference {
+			DynamicRange::Hdr
+		} else {
+			DynamicRange::Sdr
+		}
+	}
+}
+
+/// The image description of a surface, double buffered like the rest of its state
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ColorManagementCachedState {
+	pub description: Option<ImageDescription>,
+}
+impl Cacheable for ColorManagementCachedState {
+	fn commit(&mut self, _dh: &DisplayHandle) -> Self {
+		*self
+	}
+	fn merge_into(self, into: &mut Self, _dh: &DisplayHandle) {
+		*into = self;
+	}
+}
+
+/// The image description of the surface, if its client set one
+pub fn surface_image_description(surface: &WlSurface) -> Option<ImageDescription> {
+	compositor::with_states(surface, |data| {
+		data.cached_state
+			.current::<ColorManagementCachedState>()
+			.description
+	})
+}
+
+/// Only one `wp_color_management_surface_v1` can exist per surface at once
+#[derive(Default)]
+struct ColorManagedSurface(AtomicBool);
+
+pub struct ImageDescriptionData {
+	/// `None` if it `failed`
+	description: Option<ImageDescription>,
+	/// Only descriptions we made can be queried, not the ones the client made itself
+	queryable: bool,
+}
+
+#[derive(Default)]
+pub struct ParamsCreatorData(Mutex<Params>);
+#[derive(Default)]
+struct Params {
+	transfer_function: Option<TransferFunction>,
+	primaries: Option<Primaries>,
+	luminances: Option<Luminances>,
+	mastering_luminance: bool,
+	max_cll: bool,
+	max_fall: bool,
+}
+
+fn init_description(
+	data_init: &mut DataInit<'_, WaylandState>,
+	resource: New<WpImageDescriptionV1>,
+	description: ImageDescription,
+	queryable: bool,
+) {
+	let identity = if description == ImageDescription::SRGB {
+		SRGB_IDENTITY
+	} else {
+		NEXT_IDENTITY.fetch_add(1, Ordering::Relaxed)
+	};
+	let image_description = data_init.init(
+		resource,
+		ImageDescriptionData {
+			description: Some(description),
+			queryable,
+		},
+	);
+	image_description.ready(identity);
+}
+
+impl GlobalDispatch<WpColorManagerV1, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<WpColorManagerV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		let manager = data_init.init(resource, ());
+		manager.supported_intent(RenderIntent::Perceptual);
+		manager.supported_feature(wp_color_manager_v1::Feature::Parametric);
+		manager.supported_feature(wp_color_manager_v1::Feature::SetLuminances);
+		for transfer_function in TransferFunction::ALL {
+			manager.supported_tf_named(transfer_function.named());
+		}
+		for primaries in Primaries::ALL {
+			manager.supported_primaries_named(primaries.named());
+		}
+		manager.done();
+	}
+}
+
+impl Dispatch<WpColorManagerV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		manager: &WpColorManagerV1,
+		request: wp_color_manager_v1::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			wp_color_manager_v1::Request::GetOutput { id, .. } => {
+				data_init.init(id, ());
+			}
+			wp_color_manager_v1::Request::GetSurface { id, surface } => {
+				let exists = compositor::with_states(&surface, |data| {
+					data.data_map
+						.insert_if_missing_threadsafe(ColorManagedSurface::default);
+					let marker = data.data_map.get::<ColorManagedSurface>().unwrap();
+					marker.0.swap(true, Ordering::Relaxed)
+				});
+				data_init.init(id, surface.downgrade());
+				if exists {
+					manager.post_error(
+						wp_color_manager_v1::Error::SurfaceExists,
+						"The surface already has a color management surface",
+					);
+				}
+			}
+			wp_color_manager_v1::Request::GetSurfaceFeedback { id, surface } => {
+				data_init.init(id, surface.downgrade());
+			}
+			wp_color_manager_v1::Request::CreateIccCreator { obj } => {
+				data_init.init(obj, ());
+				manager.post_error(
+					wp_color_manager_v1::Error::UnsupportedFeature,
+					"ICC profiles are unsupported",
+				);
+			}
+			wp_color_manager_v1::Request::CreateParametricCreator { obj } => {
+				data_init.init(obj, ParamsCreatorData::default());
+			}
+			wp_color_manager_v1::Request::CreateWindowsScrgb { image_description } => {
+				data_init.init(
+					image_description,
+					ImageDescriptionData {
+						description: None,
+						queryable: false,
+					},
+				);
+				manager.post_error(
+					wp_color_manager_v1::Error::UnsupportedFeature,
+					"Windows scRGB is unsupported",
+				);
+			}
+			wp_color_manager_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<WpColorManagementOutputV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_output: &WpColorManagementOutputV1,
+		request: wp_color_management_output_v1::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			// StereoKit takes sRGB, whatever the headset's display actually is
+			wp_color_management_output_v1::Request::GetImageDescription { image_description } => {
+				init_description(data_init, image_description, ImageDescription::SRGB, true);
+			}
+			wp_color_management_output_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<WpColorManagementSurfaceV1, WlWeak<WlSurface>, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		resource: &WpColorManagementSurfaceV1,
+		request: wp_color_management_surface_v1::Request,
+		surface: &WlWeak<WlSurface>,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		let Ok(surface) = surface.upgrade() else {
+			if !matches!(request, wp_color_management_surface_v1::Request::Destroy) {
+				resource.post_error(
+					wp_color_management_surface_v1::Error::Inert,
+					"The surface was destroyed",
+				);
+			}
+			return;
+		};
+		let description = match request {
+			wp_color_management_surface_v1::Request::SetImageDescription {
+				image_description,
+				render_intent,
+			} => {
+				if render_intent != WEnum::Value(RenderIntent::Perceptual) {
+					resource.post_error(
+						wp_color_management_surface_v1::Error::RenderIntent,
+						"Only the perceptual render intent is supported",
+					);
+					return;
+				}
+				let Some(description) = image_description
+					.data::<ImageDescriptionData>()
+					.and_then(|data| data.description)
+				else {
+					resource.post_error(
+						wp_color_management_surface_v1::Error::ImageDescription,
+						"The image description failed",
+					);
+					return;
+				};
+				debug!(?surface, ?description, "Set surface image description");
+				Some(description)
+			}
+			wp_color_management_surface_v1::Request::UnsetImageDescription => None,
+			wp_color_management_surface_v1::Request::Destroy => return,
+			_ => unreachable!(),
+		};
+		compositor::with_states(&surface, |data| {
+			data.cached_state
+				.pending::<ColorManagementCachedState>()
+				.description = description;
+		});
+	}
+
+	/// Destroying it unsets the description on the next commit, and lets another one be made
+	fn destroyed(
+		_state: &mut WaylandState,
+		_client: ClientId,
+		_resource: &WpColorManagementSurfaceV1,
+		surface: &WlWeak<WlSurface>,
+	) {
+		let Ok(surface) = surface.upgrade() else {
+			return;
+		};
+		compositor::with_states(&surface, |data| {
+			data.cached_state
+				.pending::<ColorManagementCachedState>()
+				.description = None;
+			if let Some(marker) = data.data_map.get::<ColorManagedSurface>() {
+				marker.0.store(false, Ordering::Relaxed);
+			}
+		});
+	}
+}
+
+impl Dispatch<WpColorManagementSurfaceFeedbackV1, WlWeak<WlSurface>, WaylandState>
+	for WaylandState
+{
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		resource: &WpColorManagementSurfaceFeedbackV1,
+		request: wp_color_management_surface_feedback_v1::Request,
+		surface: &WlWeak<WlSurface>,
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		let image_description = match request {
+			wp_color_management_surface_feedback_v1::Request::GetPreferred {
+				image_description,
+			}
+			| wp_color_management_surface_feedback_v1::Request::GetPreferredParametric {
+				image_description,
+			} => image_description,
+			wp_color_management_surface_feedback_v1::Request::Destroy => return,
+			_ => unreachable!(),
+		};
+		// the description still has to be made so the client doesn't get out of sync with us
+		init_description(data_init, image_description, ImageDescription::SRGB, true);
+		if surface.upgrade().is_err() {
+			resource.post_error(
+				wp_color_management_surface_feedback_v1::Error::Inert,
+				"The surface was destroyed",
+			);
+		}
+	}
+}
+
+impl Dispatch<WpImageDescriptionCreatorIccV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_creator: &WpImageDescriptionCreatorIccV1,
+		request: wp_image_description_creator_icc_v1::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		// the client already got an error for making this
+		if let wp_image_description_creator_icc_v1::Request::Create { image_description } = request
+		{
+			data_init.init(
+				image_description,
+				ImageDescriptionData {
+					description: None,
+					queryable: false,
+				},
+			);
+		}
+	}
+}
+
+impl Dispatch<WpImageDescriptionCreatorParamsV1, ParamsCreatorData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		creator: &WpImageDescriptionCreatorParamsV1,
+		request: wp_image_description_creator_params_v1::Request,
+		data: &ParamsCreatorData,
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		use wp_image_description_creator_params_v1::{Error, Request};
+
+		let mut params = data.0.lock();
+		let already_set = |creator: &WpImageDescriptionCreatorParamsV1, set: bool| {
+			if set {
+				creator.post_error(Error::AlreadySet, "The property was already set");
+			}
+			set
+		};
+		match request {
+			Request::Create { image_description } => {
+				let (Some(transfer_function), Some(primaries)) =
+					(params.transfer_function, params.primaries)
+				else {
+					data_init.init(
+						image_description,
+						ImageDescriptionData {
+							description: None,
+							queryable: false,
+						},
+					);
+					creator.post_error(
+						Error::IncompleteSet,
+						"The transfer function and primaries have to be set",
+					);
+					return;
+				};
+				let description = ImageDescription {
+					transfer_function,
+					primaries,
+					luminances: params.luminances,
+				};
+				init_description(data_init, image_description, description, false);
+			}
+			Request::SetTfNamed { tf } => {
+				if already_set(creator, params.transfer_function.is_some()) {
+					return;
+				}
+				let transfer_function =
+					tf.into_result().ok().and_then(TransferFunction::from_named);
+				if transfer_function.is_none() {
+					creator.post_error(Error::InvalidTf, "Unsupported transfer function");
+				}
+				params.transfer_function = transfer_function;
+			}
+			Request::SetPrimariesNamed { primaries: named } => {
+				if already_set(creator, params.primaries.is_some()) {
+					return;
+				}
+				let primaries = named.into_result().ok().and_then(Primaries::from_named);
+				if primaries.is_none() {
+					creator.post_error(Error::InvalidPrimariesNamed, "Unsupported primaries");
+				}
+				params.primaries = primaries;
+			}
+			Request::SetLuminances {
+				min_lum,
+				max_lum,
+				reference_lum,
+			} => {
+				if already_set(creator, params.luminances.is_some()) {
+					return;
+				}
+				let min = min_lum as f64 / 10000.0;
+				if min >= max_lum as f64 || min >= reference_lum as f64 {
+					creator.post_error(
+						Error::InvalidLuminance,
+						"The minimum luminance has to be below the maximum and reference",
+					);
+					return;
+				}
+				params.luminances = Some(Luminances {
+					min: min_lum,
+					max: max_lum,
+					reference: reference_lum,
+				});
+			}
+			// mastering metadata is only a hint for tone mapping, which is just clamping for now
+			Request::SetMasteringLuminance { .. } => {
+				already_set(creator, params.mastering_luminance);
+				params.mastering_luminance = true;
+			}
+			Request::SetMaxCll { .. } => {
+				already_set(creator, params.max_cll);
+				params.max_cll = true;
+			}
+			Request::SetMaxFall { .. } => {
+				already_set(creator, params.max_fall);
+				params.max_fall = true;
+			}
+			Request::SetTfPower { .. }
+			| Request::SetPrimaries { .. }
+			| Request::SetMasteringDisplayPrimaries { .. } => {
+				creator.post_error(Error::UnsupportedFeature, "The feature isn't advertised");
+			}
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<WpImageDescriptionV1, ImageDescriptionData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		resource: &WpImageDescriptionV1,
+		request: wp_image_description_v1::Request,
+		data: &ImageDescriptionData,
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			wp_image_description_v1::Request::GetInformation { information } => {
+				let info = data_init.init(information, ());
+				let Some(description) = data.description else {
+					resource.post_error(
+						wp_image_description_v1::Error::NotReady,
+						"The image description failed",
+					);
+					return;
+				};
+				if !data.queryable {
+					resource.post_error(
+						wp_image_description_v1::Error::NoInformation,
+						"Only image descriptions from the compositor can be queried",
+					);
+					return;
+				}
+				let luminances = description.luminances();
+				info.primaries_named(description.primaries.named());
+				info.tf_named(description.transfer_function.named());
+				info.luminances(luminances.min, luminances.max, luminances.reference);
+				info.done();
+			}
+			wp_image_description_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<WpImageDescriptionInfoV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_info: &WpImageDescriptionInfoV1,
+		_request: wp_image_description_info_v1::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+	}
+}
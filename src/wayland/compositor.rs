@@ -1,24 +1,118 @@
 use crate::wayland::surface::CoreSurface;
 
-use super::state::{ClientState, WaylandState};
+use super::{
+	buffer_offset::apply_buffer_offsets,
+	client_limits::enforce_buffer_size,
+	state::{ClientState, WaylandState},
+	validation::reject_invalid_commit,
+};
+use parking_lot::{const_mutex, Mutex, MutexGuard};
 use portable_atomic::{AtomicU32, Ordering};
 #[cfg(feature = "xwayland")]
 use smithay::xwayland::XWaylandClientData;
 use smithay::{
 	delegate_compositor,
-	reexports::wayland_server::{protocol::wl_surface::WlSurface, Client},
-	wayland::compositor::{self, CompositorClientState, CompositorHandler, CompositorState},
+	reexports::wayland_server::{
+		backend::ObjectId, protocol::wl_surface::WlSurface, Client, Resource,
+	},
+	wayland::compositor::{
+		self, CompositorClientState, CompositorHandler, CompositorState, TraversalAction,
+	},
 };
-use std::sync::Arc;
+use std::{cell::RefCell, sync::Arc};
 use tracing::debug;
 
+/// Held while smithay applies a commit, from the commit hooks around it. A commit applies the
+/// state its synchronized subsurfaces cached one surface after another, so the render thread
+/// holds this while taking a tree's buffers to never get a parent's new buffer with a
+/// subsurface's old one (like a video player's controls over the wrong frame).
+pub static APPLYING_COMMITS: Mutex<()> = const_mutex(());
+/// The surface whose commit is being applied, applying it can run its subsurfaces' hooks too
+type Applying = Option<(ObjectId, MutexGuard<'static, ()>)>;
+thread_local! {
+	static APPLYING: RefCell<Applying> = const { RefCell::new(None) };
+}
+fn start_applying(surface: &WlSurface) {
+	APPLYING.with_borrow_mut(|applying| {
+		if applying.is_none() {
+			*applying = Some((surface.id(), APPLYING_COMMITS.lock()));
+		}
+	});
+}
+fn stop_applying(surface: &WlSurface) {
+	APPLYING.with_borrow_mut(|applying| {
+		if applying.as_ref().is_some_and(|(id, _)| *id == surface.id()) {
+			applying.take();
+		}
+	});
+}
+
+/// The surface and every subsurface under it, committing it also applies what its
+/// synchronized subsurfaces cached
+pub(super) fn surface_tree(surface: &WlSurface) -> Vec<WlSurface> {
+	let mut surfaces = Vec::new();
+	compositor::with_surface_tree_upward(
+		surface,
+		(),
+		|_, _, _| TraversalAction::DoChildren(()),
+		|surface, _, _| surfaces.push(surface.clone()),
+		|_, _, _| true,
+	);
+	surfaces
+}
+
 impl CompositorHandler for WaylandState {
 	fn compositor_state(&mut self) -> &mut CompositorState {
 		&mut self.compositor_state
 	}
 
+	fn new_surface(&mut self, surface: &WlSurface) {
+		compositor::add_pre_commit_hook::<Self, _>(surface, |_, _, surface| {
+			start_applying(surface)
+		});
+		compositor::add_post_commit_hook::<Self, _>(surface, |_, _, surface| {
+			stop_applying(surface)
+		});
+		let Some(client) = surface.client() else {
+			return;
+		};
+		let Some(client_state) = client.get_data::<ClientState>() else {
+			return;
+		};
+		let resource = client_state.resources.add_surface();
+		compositor::with_states(surface, |data| {
+			data.data_map.insert_if_missing_threadsafe(|| resource);
+		});
+		client_state
+			.resources
+			.enforce(&self.display_handle, &client);
+	}
+
 	fn commit(&mut self, surface: &WlSurface) {
 		debug!(?surface, "Surface commit");
+		// every commit counts, even the ones that only get cached
+		if let Some(client_state) = surface
+			.client()
+			.as_ref()
+			.and_then(|client| client.get_data::<ClientState>())
+		{
+			client_state.commit_rate.count();
+		}
+		// a synchronized subsurface's commit only cached its state, it gets applied and checked
+		// along with the rest of the tree once its parent commits
+		if compositor::is_sync_subsurface(surface) {
+			return;
+		}
+		if surface_tree(surface)
+			.iter()
+			.any(|surface| enforce_buffer_size(surface) || reject_invalid_commit(surface))
+		{
+			return;
+		}
+		apply_buffer_offsets(surface);
+		if let Some(client_state) = client_state {
+			client_state.seat.commit_pointer_constraint(surface);
+		}
 		let mut count = 0;
 		let core_surface = compositor::with_states(surface, |data| {
 			let count_new = data
@@ -33,6 +127,13 @@ impl CompositorHandler for WaylandState {
 			data.data_map.get::<Arc<CoreSurface>>().cloned()
 		});
 		if let Some(core_surface) = core_surface {
+			// only surfaces that get uploaded count as reacting to input, not their subsurfaces
+			if let Some(generated) = surface
+				.client()
+				.and_then(|client| client.get_data::<ClientState>()?.seat.input_committed())
+			{
+				core_surface.input_committed(generated);
+			}
 			core_surface.commit(count);
 		}
 	}
@@ -0,0 +1,142 @@
+use super::state::WaylandState;
+use crate::nodes::items::panel::ContentType;
+use smithay::{
+	reexports::{
+		wayland_protocols::wp::content_type::v1::server::{
+			wp_content_type_manager_v1::{self, WpContentTypeManagerV1},
+			wp_content_type_v1::{self, Type, WpContentTypeV1},
+		},
+		wayland_server::{
+			backend::ClientId, protocol::wl_surface::WlSurface, Client, DataInit, Dispatch,
+			DisplayHandle, GlobalDispatch, New, Resource, WEnum, Weak as WlWeak,
+		},
+	},
+	wayland::compositor::{self, Cacheable},
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ContentTypeCachedState {
+	pub content_type: ContentType,
+}
+impl Cacheable for ContentTypeCachedState {
+	fn commit(&mut self, _dh: &DisplayHandle) -> Self {
+		*self
+	}
+	fn merge_into(self, into: &mut Self, _dh: &DisplayHandle) {
+		*into = self;
+	}
+}
+
+/// What the surface shows, `ContentType::None` unless its client said otherwise
+pub fn surface_content_type(surface: &WlSurface) -> ContentType {
+	compositor::with_states(surface, |data| {
+		data.cached_state
+			.current::<ContentTypeCachedState>()
+			.content_type
+	})
+}
+
+/// Only one `wp_content_type_v1` can exist per surface at once
+#[derive(Default)]
+struct ContentTypedSurface(AtomicBool);
+
+fn set_pending(surface: &WlSurface, content_type: ContentType) {
+	compositor::with_states(surface, |data| {
+		data.cached_state
+			.pending::<ContentTypeCachedState>()
+			.content_type = content_type;
+	});
+}
+
+impl GlobalDispatch<WpContentTypeManagerV1, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<WpContentTypeManagerV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<WpContentTypeManagerV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		manager: &WpContentTypeManagerV1,
+		request: wp_content_type_manager_v1::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			wp_content_type_manager_v1::Request::GetSurfaceContentType { id, surface } => {
+				let exists = compositor::with_states(&surface, |data| {
+					data.data_map
+						.insert_if_missing_threadsafe(ContentTypedSurface::default);
+					let marker = data.data_map.get::<ContentTypedSurface>().unwrap();
+					marker.0.swap(true, Ordering::Relaxed)
+				});
+				data_init.init(id, surface.downgrade());
+				if exists {
+					manager.post_error(
+						wp_content_type_manager_v1::Error::AlreadyConstructed,
+						"The surface already has a content type object",
+					);
+				}
+			}
+			wp_content_type_manager_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<WpContentTypeV1, WlWeak<WlSurface>, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &WpContentTypeV1,
+		request: wp_content_type_v1::Request,
+		surface: &WlWeak<WlSurface>,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			wp_content_type_v1::Request::SetContentType { content_type } => {
+				let Ok(surface) = surface.upgrade() else {
+					return;
+				};
+				let content_type = match content_type {
+					WEnum::Value(Type::Photo) => ContentType::Photo,
+					WEnum::Value(Type::Video) => ContentType::Video,
+					WEnum::Value(Type::Game) => ContentType::Game,
+					_ => ContentType::None,
+				};
+				set_pending(&surface, content_type);
+			}
+			wp_content_type_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+
+	/// Destroying it resets the content type on the next commit, and lets another one be made
+	fn destroyed(
+		_state: &mut WaylandState,
+		_client: ClientId,
+		_resource: &WpContentTypeV1,
+		surface: &WlWeak<WlSurface>,
+	) {
+		let Ok(surface) = surface.upgrade() else {
+			return;
+		};
+		set_pending(&surface, ContentType::None);
+		compositor::with_states(&surface, |data| {
+			if let Some(marker) = data.data_map.get::<ContentTypedSurface>() {
+				marker.0.store(false, Ordering::Relaxed);
+			}
+		});
+	}
+}
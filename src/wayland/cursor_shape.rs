@@ -0,0 +1,86 @@
+use super::{seat::CursorInfo, seat::SeatData, state::WaylandState};
+use smithay::reexports::{
+	wayland_protocols::wp::cursor_shape::v1::server::{
+		wp_cursor_shape_device_v1::{self, WpCursorShapeDeviceV1},
+		wp_cursor_shape_manager_v1::{self, WpCursorShapeManagerV1},
+	},
+	wayland_server::{
+		Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource, WEnum,
+	},
+};
+use std::sync::Arc;
+
+impl GlobalDispatch<WpCursorShapeManagerV1, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<WpCursorShapeManagerV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<WpCursorShapeManagerV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &WpCursorShapeManagerV1,
+		request: wp_cursor_shape_manager_v1::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			wp_cursor_shape_manager_v1::Request::GetPointer {
+				cursor_shape_device,
+				pointer,
+			} => {
+				let seat = pointer.data::<Arc<SeatData>>().cloned();
+				data_init.init(cursor_shape_device, seat);
+			}
+			// tablets aren't supported so their cursors have nowhere to go
+			wp_cursor_shape_manager_v1::Request::GetTabletToolV2 {
+				cursor_shape_device,
+				..
+			} => {
+				data_init.init(cursor_shape_device, None);
+			}
+			wp_cursor_shape_manager_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<WpCursorShapeDeviceV1, Option<Arc<SeatData>>, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		resource: &WpCursorShapeDeviceV1,
+		request: wp_cursor_shape_device_v1::Request,
+		seat: &Option<Arc<SeatData>>,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			wp_cursor_shape_device_v1::Request::SetShape { serial, shape } => {
+				let WEnum::Value(shape) = shape else {
+					resource.post_error(
+						wp_cursor_shape_device_v1::Error::InvalidShape,
+						"Unknown cursor shape",
+					);
+					return;
+				};
+				let Some(seat) = seat else {
+					return;
+				};
+				// shapes set with a serial from before the pointer last entered are ignored
+				seat.set_focused_cursor(serial, Some(CursorInfo::Shape(shape)));
+			}
+			wp_cursor_shape_device_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
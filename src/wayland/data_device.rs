@@ -1,22 +1,674 @@
-use smithay::reexports::wayland_server::{
-	protocol::{
-		wl_data_device::{
-			Request::{Release, SetSelection, StartDrag},
-			WlDataDevice,
-		},
-		wl_data_device_manager::{
-			Request::{CreateDataSource, GetDataDevice},
-			WlDataDeviceManager,
-		},
-		wl_data_source::{
-			Request::{Destroy, Offer, SetActions},
-			WlDataSource,
+use super::{
+	buffer_offset::buffer_offset,
+	seat::{event_time, PointerEvent, SeatData},
+	serial::{valid_press, valid_selection},
+	state::{ClientState, WaylandState},
+	surface::{give_role, CoreSurface, DND_ICON_ROLE, TOP_MATERIAL_OFFSET},
+	transfer::{is_text, offered_mime_types, read_whole, receive, send_cached},
+	utils,
+	xdg_shell::{panel_transform, XdgSurfaceData},
+	SERIAL_COUNTER,
+};
+use crate::{
+	core::task,
+	nodes::{spatial::Spatial, Node},
+};
+use glam::{vec3, Mat4};
+use mint::Vector2;
+use once_cell::sync::OnceCell;
+use parking_lot::{const_mutex, Mutex};
+use portable_atomic::{AtomicBool, Ordering};
+use rustc_hash::FxHashMap;
+use smithay::{
+	reexports::wayland_server::{
+		backend::ClientId,
+		protocol::{
+			wl_data_device::{
+				self,
+				Request::{Release, SetSelection, StartDrag},
+				WlDataDevice,
+			},
+			wl_data_device_manager::{
+				DndAction,
+				Request::{CreateDataSource, GetDataDevice},
+				WlDataDeviceManager,
+			},
+			wl_data_offer::{self, WlDataOffer},
+			wl_data_source::{
+				self,
+				Request::{Destroy, Offer, SetActions},
+				WlDataSource,
+			},
+			wl_surface::WlSurface,
 		},
+		Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource, WEnum,
+		Weak as WlWeak,
 	},
-	Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+	wayland::compositor,
+};
+use std::{
+	os::fd::OwnedFd,
+	sync::{Arc, Weak},
 };
+use stereokit::{named_colors::WHITE, Color32, Mesh, RenderLayer, StereoKitDraw, Vertex};
+use tokio::sync::watch;
+use tracing::debug;
+
+static DRAG: Mutex<Option<Drag>> = Mutex::new(None);
+/// Meters in front of the panel the drag icon is drawn at, so it doesn't fight the panel's depth
+const DRAG_ICON_DEPTH: f32 = 0.005;
+/// From (0, 0) to (1, -1) with UVs going from the top left to the bottom right
+static DRAG_ICON_QUAD: OnceCell<Mesh> = OnceCell::new();
+
+/// What of a selection gets read into memory as soon as it's set, so it can still be pasted
+/// after its source exits (like a short lived command that copied something)
+#[derive(Debug, Clone, Copy)]
+pub struct ClipboardCache {
+	/// Selections with more than this in the cached types don't get kept, 0 turns caching off
+	pub max_bytes: usize,
+	/// Only cache text, images and such are big and a lot of work for a source to write out
+	pub text_only: bool,
+}
+impl ClipboardCache {
+	pub const DEFAULT: Self = ClipboardCache {
+		max_bytes: 1 << 20,
+		text_only: true,
+	};
+}
+impl Default for ClipboardCache {
+	fn default() -> Self {
+		Self::DEFAULT
+	}
+}
+pub static CLIPBOARD_CACHE: Mutex<ClipboardCache> = const_mutex(ClipboardCache::DEFAULT);
+
+/// The clipboard, shared by every client since they all sit at the same seat
+#[derive(Default)]
+pub struct DataDeviceState {
+	devices: Vec<WlDataDevice>,
+	selection: Option<Arc<Selection>>,
+}
+impl DataDeviceState {
+	fn set_selection(&mut self, dh: &DisplayHandle, source: Option<WlDataSource>) {
+		if let Some(old_selection) = self.selection.take() {
+			if Some(&old_selection.source) != source.as_ref() && old_selection.source.is_alive() {
+				old_selection.source.cancelled();
+				flush(&old_selection.source);
+			}
+		}
+		self.selection = source.map(Selection::new);
+		self.offer_to_all(dh);
+	}
+	/// The source exited, what of it got cached is the selection now
+	fn source_destroyed(&mut self, dh: &DisplayHandle, source: &WlDataSource) {
+		let Some(selection) = &self.selection else {
+			return;
+		};
+		if &selection.source != source {
+			return;
+		}
+		if selection.is_gone() {
+			self.set_selection(dh, None);
+		} else {
+			debug!("Selection source exited, keeping its cached data");
+			self.offer_to_all(dh);
+		}
+	}
+	fn offer_to_all(&mut self, dh: &DisplayHandle) {
+		self.devices.retain(|device| device.is_alive());
+		for device in &self.devices {
+			self.offer_selection(dh, device);
+		}
+	}
+	fn offer_selection(&self, dh: &DisplayHandle, device: &WlDataDevice) {
+		let Some(selection) = &self.selection else {
+			device.selection(None);
+			return;
+		};
+		let Some(client) = device.client() else {
+			return;
+		};
+		let Ok(offer) = client.create_resource::<WlDataOffer, _, WaylandState>(
+			dh,
+			device.version(),
+			SelectionOfferData {
+				selection: selection.clone(),
+			},
+		) else {
+			return;
+		};
+		device.data_offer(&offer);
+		for mime_type in offered_mime_types(&selection.mime_types()) {
+			offer.offer(mime_type);
+		}
+		device.selection(Some(&offer));
+	}
+}
+
+struct Selection {
+	/// Dead once its client exits, pastes come from `cache` after that
+	source: WlDataSource,
+	/// What the source offered when it became the selection
+	source_types: Vec<String>,
+	/// The ones being read into `cache`
+	cached_types: Vec<String>,
+	cache: Mutex<FxHashMap<String, Arc<[u8]>>>,
+	/// How many of `cached_types` are still being read
+	reads: watch::Sender<usize>,
+	too_big: AtomicBool,
+}
+impl Selection {
+	fn new(source: WlDataSource) -> Arc<Self> {
+		let source_types = source
+			.data::<DataSourceData>()
+			.map(|data| data.mime_types.lock().clone())
+			.unwrap_or_default();
+		let settings = *CLIPBOARD_CACHE.lock();
+		let cached_types = source_types
+			.iter()
+			.filter(|_| settings.max_bytes > 0)
+			.filter(|mime_type| !settings.text_only || is_text(mime_type))
+			.cloned()
+			.collect::<Vec<_>>();
+		let selection = Arc::new(Selection {
+			source,
+			source_types,
+			reads: watch::channel(cached_types.len()).0,
+			cached_types,
+			cache: Mutex::new(FxHashMap::default()),
+			too_big: AtomicBool::new(false),
+		});
+		selection.fill_cache(settings.max_bytes);
+		selection
+	}
+	fn mime_types(&self) -> Vec<String> {
+		if self.source.is_alive() {
+			self.source_types.clone()
+		} else if *self.reads.borrow() > 0 {
+			self.cached_types.clone()
+		} else {
+			self.cache.lock().keys().cloned().collect()
+		}
+	}
+	/// Nothing can be pasted from it anymore
+	fn is_gone(&self) -> bool {
+		!self.source.is_alive() && *self.reads.borrow() == 0 && self.cache.lock().is_empty()
+	}
+	/// Read `cached_types` out of the source, the whole selection gets dropped from the cache if
+	/// together they go over `max_bytes`
+	fn fill_cache(self: &Arc<Self>, max_bytes: usize) {
+		for mime_type in &self.cached_types {
+			let selection = Arc::downgrade(self);
+			let send = |mime_type, fd| self.source.send(mime_type, fd);
+			read_whole(mime_type.clone(), max_bytes, send, {
+				let mime_type = mime_type.clone();
+				move |data| Selection::store(selection, mime_type, data, max_bytes)
+			});
+		}
+		if !self.cached_types.is_empty() {
+			flush(&self.source);
+		}
+	}
+	fn store(
+		selection: Weak<Selection>,
+		mime_type: String,
+		data: Option<Vec<u8>>,
+		max_bytes: usize,
+	) {
+		let Some(selection) = selection.upgrade() else {
+			return;
+		};
+		let mut cache = selection.cache.lock();
+		match data {
+			_ if selection.too_big.load(Ordering::Relaxed) => (),
+			Some(data)
+				if cache.values().map(|d| d.len()).sum::<usize>() + data.len() > max_bytes =>
+			{
+				debug!(mime_type, "Selection too big to cache");
+				selection.too_big.store(true, Ordering::Relaxed);
+				cache.clear();
+			}
+			Some(data) => {
+				cache.insert(mime_type, data.into());
+			}
+			None => (),
+		}
+		drop(cache);
+		selection.reads.send_modify(|reads| *reads -= 1);
+	}
+	/// Paste `mime_type` into `fd` from the source, or once it's gone from the cache as soon as
+	/// it's been read
+	fn receive(self: &Arc<Self>, mime_type: String, fd: OwnedFd) {
+		if self.source.is_alive() {
+			receive(&self.source_types, mime_type, fd, |mime_type, fd| {
+				self.source.send(mime_type, fd);
+				flush(&self.source);
+			});
+			return;
+		}
+		let selection = self.clone();
+		let mut reads = self.reads.subscribe();
+		let _ = task::new(|| "cached selection paste", async move {
+			while *reads.borrow_and_update() > 0 {
+				if reads.changed().await.is_err() {
+					return;
+				}
+			}
+			send_cached(&selection.cache.lock(), mime_type, fd);
+		});
+	}
+}
+
+/// An offer of the selection rather than of a drag
+pub struct SelectionOfferData {
+	selection: Arc<Selection>,
+}
+
+pub struct DataSourceData {
+	mime_types: Mutex<Vec<String>>,
+	dnd_actions: Mutex<DndAction>,
+}
+impl DataSourceData {
+	fn new(version: u32) -> Self {
+		let dnd_actions = legacy_actions(version, wl_data_source::REQ_SET_ACTIONS_SINCE);
+		DataSourceData {
+			mime_types: Mutex::new(Vec::new()),
+			dnd_actions: Mutex::new(dnd_actions),
+		}
+	}
+}
+
+/// Sources and offers from before actions existed can't set any, they only know copying
+fn legacy_actions(version: u32, set_actions_since: u32) -> DndAction {
+	if version < set_actions_since {
+		DndAction::Copy
+	} else {
+		DndAction::empty()
+	}
+}
+
+pub struct DataOfferData {
+	source: WlDataSource,
+	state: Mutex<OfferState>,
+}
+struct OfferState {
+	accepted: Option<String>,
+	dest_actions: DndAction,
+	preferred_action: DndAction,
+	chosen_action: DndAction,
+	dropped: bool,
+}
+impl OfferState {
+	/// Renegotiate against what the source allows, `None` if the action didn't change
+	fn negotiate(&mut self, source_actions: DndAction) -> Option<DndAction> {
+		let action = choose_action(source_actions, self.dest_actions, self.preferred_action);
+		if action == self.chosen_action {
+			return None;
+		}
+		self.chosen_action = action;
+		Some(action)
+	}
+	/// A drop only goes through if the target took a type and there's an action to do with it
+	fn drop_accepted(&self) -> bool {
+		self.accepted.is_some() && !self.chosen_action.is_empty()
+	}
+	fn finish_error(&self) -> Option<(wl_data_offer::Error, &'static str)> {
+		if !self.dropped || self.accepted.is_none() {
+			return Some((
+				wl_data_offer::Error::InvalidFinish,
+				"Finish requested before a successful drop",
+			));
+		}
+		if self.chosen_action == DndAction::Ask {
+			return Some((
+				wl_data_offer::Error::InvalidAction,
+				"Finish requested while the action is still ask",
+			));
+		}
+		None
+	}
+}
+
+/// Same as weston: the destination's preferred action if both sides allow it,
+/// otherwise copy, move then ask
+fn choose_action(
+	source_actions: DndAction,
+	dest_actions: DndAction,
+	preferred_action: DndAction,
+) -> DndAction {
+	let available = source_actions & dest_actions;
+	if available.intersects(preferred_action) {
+		return available & preferred_action;
+	}
+	[DndAction::Copy, DndAction::Move, DndAction::Ask]
+		.into_iter()
+		.find(|action| available.contains(*action))
+		.unwrap_or(DndAction::empty())
+}
+
+fn update_action(offer: &WlDataOffer) {
+	let Some(offer_data) = offer.data::<DataOfferData>() else {
+		return;
+	};
+	let Some(source_data) = offer_data.source.data::<DataSourceData>() else {
+		return;
+	};
+	let Some(action) = offer_data
+		.state
+		.lock()
+		.negotiate(*source_data.dnd_actions.lock())
+	else {
+		return;
+	};
+	if offer.version() >= wl_data_offer::EVT_ACTION_SINCE {
+		offer.action(action);
+	}
+	if offer_data.source.version() >= wl_data_source::EVT_ACTION_SINCE {
+		offer_data.source.action(action);
+	}
+}
+
+fn seat_for(surface: &WlSurface) -> Option<Arc<SeatData>> {
+	let client = surface.client()?;
+	let client_state = client.get_data::<ClientState>()?;
+	Some(client_state.seat.clone())
+}
+fn flush(resource: &impl Resource) {
+	let Some(client) = resource.client() else {
+		return;
+	};
+	if let Some(client_state) = client.get_data::<ClientState>() {
+		client_state.flush();
+	}
+}
+
+struct Drag {
+	dh: DisplayHandle,
+	source: Option<WlDataSource>,
+	origin: WlSurface,
+	icon: Option<WlSurface>,
+	/// The last toplevel the pointer was over and where on it, the icon stays there while the
+	/// pointer's over anything it can't be drawn on
+	icon_position: Option<(WlWeak<WlSurface>, Vector2<f32>)>,
+	target: Option<DragTarget>,
+}
+struct DragTarget {
+	surface: WlSurface,
+	device: WlDataDevice,
+	offer: Option<WlDataOffer>,
+}
+impl Drag {
+	fn create_offer(&self, device: &WlDataDevice) -> Option<WlDataOffer> {
+		let source = self.source.as_ref()?;
+		let source_data = source.data::<DataSourceData>()?;
+		let client = device.client()?;
+		let dest_actions = legacy_actions(device.version(), wl_data_offer::REQ_SET_ACTIONS_SINCE);
+		let offer = client
+			.create_resource::<WlDataOffer, _, WaylandState>(
+				&self.dh,
+				device.version(),
+				DataOfferData {
+					source: source.clone(),
+					state: Mutex::new(OfferState {
+						accepted: None,
+						dest_actions,
+						preferred_action: dest_actions,
+						chosen_action: DndAction::empty(),
+						dropped: false,
+					}),
+				},
+			)
+			.ok()?;
+		device.data_offer(&offer);
+		for mime_type in offered_mime_types(&source_data.mime_types.lock()) {
+			offer.offer(mime_type);
+		}
+		if offer.version() >= wl_data_offer::EVT_SOURCE_ACTIONS_SINCE {
+			offer.source_actions(*source_data.dnd_actions.lock());
+		}
+		// an old destination never sets actions, so nothing else would negotiate its copy
+		update_action(&offer);
+		Some(offer)
+	}
+	fn enter(&mut self, surface: &WlSurface, position: Vector2<f32>) {
+		// Drags without a source never leave the client that started them
+		if self.source.is_none()
+			&& surface.client().map(|c| c.id()) != self.origin.client().map(|c| c.id())
+		{
+			return;
+		}
+		let Some(seat) = seat_for(surface) else {
+			return;
+		};
+		let Some(device) = seat.data_device() else {
+			return;
+		};
+		let offer = self.create_offer(&device);
+		device.enter(
+			SERIAL_COUNTER.inc(),
+			surface,
+			position.x as f64,
+			position.y as f64,
+			offer.as_ref(),
+		);
+		self.target = Some(DragTarget {
+			surface: surface.clone(),
+			device,
+			offer,
+		});
+	}
+	fn leave(&mut self) {
+		let Some(target) = self.target.take() else {
+			return;
+		};
+		target.device.leave();
+		flush(&target.device);
+	}
+	/// Move the drag over to another surface. Unlike on a drop, what the last target accepted
+	/// and the action it negotiated don't count anymore, so the source hears it's over nothing
+	/// until the new target says otherwise.
+	fn switch_target(&mut self, surface: &WlSurface, position: Vector2<f32>) {
+		let last_offer = self.target.as_ref().and_then(|target| target.offer.clone());
+		self.leave();
+		if let (Some(source), Some(offer_data)) = (
+			self.source.as_ref().filter(|source| source.is_alive()),
+			last_offer
+				.as_ref()
+				.and_then(|offer| offer.data::<DataOfferData>()),
+		) {
+			let state = offer_data.state.lock();
+			if state.accepted.is_some() {
+				source.target(None);
+			}
+			if !state.chosen_action.is_empty()
+				&& source.version() >= wl_data_source::EVT_ACTION_SINCE
+			{
+				source.action(DndAction::empty());
+			}
+		}
+		self.enter(surface, position);
+		if let Some(source) = &self.source {
+			flush(source);
+		}
+	}
+	/// The source changed what it allows mid drag, the offer it's over has to renegotiate
+	fn source_actions_changed(&self, actions: DndAction) {
+		let Some(offer) = self
+			.target
+			.as_ref()
+			.and_then(|target| target.offer.as_ref())
+		else {
+			return;
+		};
+		if offer.version() >= wl_data_offer::EVT_SOURCE_ACTIONS_SINCE {
+			offer.source_actions(actions);
+		}
+		update_action(offer);
+		flush(offer);
+	}
+	fn drop_on_target(mut self) {
+		let offer_data = self
+			.target
+			.as_ref()
+			.and_then(|target| target.offer.as_ref())
+			.and_then(|offer| offer.data::<DataOfferData>());
+		let accepted =
+			offer_data.map_or(false, |offer_data| offer_data.state.lock().drop_accepted());
+		let Some(source) = self.source.clone() else {
+			// Client-local drags handle the data themselves
+			if let Some(target) = &self.target {
+				target.device.drop();
+			}
+			self.leave();
+			return;
+		};
+		if !accepted {
+			self.leave();
+			source.cancelled();
+			flush(&source);
+			return;
+		}
+		if let Some(offer_data) = offer_data {
+			offer_data.state.lock().dropped = true;
+		}
+		if let Some(target) = &self.target {
+			target.device.drop();
+		}
+		self.leave();
+		if source.version() >= wl_data_source::EVT_DND_DROP_PERFORMED_SINCE {
+			source.dnd_drop_performed();
+		}
+		flush(&source);
+	}
+	fn cancel(mut self) {
+		self.leave();
+		if let Some(source) = &self.source {
+			source.cancelled();
+			flush(source);
+		}
+	}
+}
 
-use super::state::WaylandState;
+/// While something is being dragged the pointer belongs to the drag.
+/// Returns true if the event got used up.
+pub fn drag_pointer_event(surface: &WlSurface, event: &PointerEvent) -> bool {
+	let mut drag_lock = DRAG.lock();
+	let Some(drag) = drag_lock.as_mut() else {
+		return false;
+	};
+	match *event {
+		PointerEvent::Motion(position) => {
+			if utils::get_data::<Node>(surface).is_some() {
+				drag.icon_position = Some((surface.downgrade(), position));
+			}
+			if drag.target.as_ref().map(|target| &target.surface) == Some(surface) {
+				if let Some(target) = &drag.target {
+					target
+						.device
+						.motion(event_time(), position.x as f64, position.y as f64);
+				}
+			} else {
+				drag.switch_target(surface, position);
+			}
+			flush(surface);
+		}
+		_ if drag_ends(event) => {
+			// the icon isn't drawn anymore from here on
+			if let Some(drag) = drag_lock.take() {
+				drag.drop_on_target();
+			}
+		}
+		_ => (),
+	}
+	true
+}
+/// Letting go of the button drops what's being dragged, no matter which button it is
+fn drag_ends(event: &PointerEvent) -> bool {
+	matches!(event, PointerEvent::Button { state: 0, .. })
+}
+
+/// Where the drag icon's quad goes on the panel it's over, `panel` being from its surface pixels.
+/// Attaching the icon with an offset moves it away from the pointer, and the quad goes down from
+/// its top left like the surface's pixels.
+fn drag_icon_transform(
+	panel: Mat4,
+	position: Vector2<f32>,
+	offset: Vector2<i32>,
+	size: Vector2<u32>,
+) -> Mat4 {
+	panel
+		* Mat4::from_translation(vec3(
+			position.x + offset.x as f32,
+			position.y + offset.y as f32,
+			DRAG_ICON_DEPTH,
+		)) * Mat4::from_scale(vec3(size.x as f32, -(size.y as f32), 1.0))
+}
+
+/// Draw the icon of the current drag at the pointer on the panel it's over, it's a surface of
+/// its own that the shell doesn't know about and doesn't take input
+pub fn draw_drag_icon(sk: &impl StereoKitDraw) {
+	let drag = DRAG.lock();
+	let Some(drag) = drag.as_ref() else {
+		return;
+	};
+	let (Some(icon), Some((surface, position))) = (&drag.icon, &drag.icon_position) else {
+		return;
+	};
+	let Ok(surface) = surface.upgrade() else {
+		return;
+	};
+	let Some(spatial) =
+		utils::get_data::<Node>(&surface).and_then(|node| node.get_aspect::<Spatial>().ok())
+	else {
+		return;
+	};
+	let Some(window) = utils::get_data::<XdgSurfaceData>(&surface)
+		.and_then(|xdg_surface_data| xdg_surface_data.window_geometry())
+	else {
+		return;
+	};
+	let Some(core_surface) = CoreSurface::from_wl_surface(icon) else {
+		return;
+	};
+	let (Some(size), Some(material)) = (core_surface.size(), core_surface.material()) else {
+		return;
+	};
+	let transform = drag_icon_transform(
+		panel_transform(&surface, &spatial, window),
+		*position,
+		buffer_offset(icon),
+		size,
+	);
+	let quad = DRAG_ICON_QUAD.get_or_init(|| {
+		let vertex = |x: f32, y: f32| Vertex {
+			pos: vec3(x, -y, 0.0).into(),
+			norm: vec3(0.0, 0.0, 1.0).into(),
+			uv: [x, y].into(),
+			col: Color32::WHITE,
+		};
+		let mesh = sk.mesh_create();
+		// StereoKit's front faces wind clockwise
+		sk.mesh_set_verts(
+			&mesh,
+			&[
+				vertex(0.0, 0.0),
+				vertex(0.0, 1.0),
+				vertex(1.0, 0.0),
+				vertex(1.0, 1.0),
+			],
+			true,
+		);
+		sk.mesh_set_inds(&mesh, &[0, 2, 1, 2, 3, 1]);
+		mesh
+	});
+	sk.mesh_draw(
+		quad,
+		material.as_ref().as_ref(),
+		transform,
+		WHITE,
+		RenderLayer::LAYER0,
+	);
+}
 
 impl GlobalDispatch<WlDataDeviceManager, (), WaylandState> for WaylandState {
 	fn bind(
@@ -33,68 +685,388 @@ impl GlobalDispatch<WlDataDeviceManager, (), WaylandState> for WaylandState {
 
 impl Dispatch<WlDataDeviceManager, (), WaylandState> for WaylandState {
 	fn request(
-		_state: &mut WaylandState,
+		state: &mut WaylandState,
 		_client: &Client,
-		_resource: &WlDataDeviceManager,
+		resource: &WlDataDeviceManager,
 		request: <WlDataDeviceManager as Resource>::Request,
 		_data: &(),
-		_dhandle: &DisplayHandle,
+		dhandle: &DisplayHandle,
 		data_init: &mut DataInit<'_, WaylandState>,
 	) {
 		match request {
 			CreateDataSource { id } => {
-				data_init.init(id, ());
+				data_init.init(id, DataSourceData::new(resource.version()));
 			}
-			GetDataDevice { id, seat: _ } => {
-				data_init.init(id, ());
+			GetDataDevice { id, seat } => {
+				let data_device = data_init.init(id, ());
+				if let Some(seat_data) = seat.data::<Arc<SeatData>>() {
+					seat_data.set_data_device(data_device.clone());
+				}
+				let data_device_state = &mut state.data_device_state;
+				if data_device_state.selection.is_some() {
+					data_device_state.offer_selection(dhandle, &data_device);
+				}
+				data_device_state.devices.push(data_device);
 			}
 			_ => unreachable!(),
 		}
 	}
 }
 
-impl Dispatch<WlDataSource, (), WaylandState> for WaylandState {
+impl Dispatch<WlDataSource, DataSourceData, WaylandState> for WaylandState {
 	fn request(
 		_state: &mut WaylandState,
 		_client: &Client,
-		_resource: &WlDataSource,
+		resource: &WlDataSource,
 		request: <WlDataSource as Resource>::Request,
-		_data: &(),
+		data: &DataSourceData,
 		_dhandle: &DisplayHandle,
 		_data_init: &mut DataInit<'_, WaylandState>,
 	) {
 		match request {
-			Offer { mime_type: _ } => {}
+			Offer { mime_type } => {
+				data.mime_types.lock().push(mime_type);
+			}
 			Destroy => {}
-			SetActions { dnd_actions: _ } => {}
+			SetActions { dnd_actions } => {
+				let WEnum::Value(dnd_actions) = dnd_actions else {
+					resource.post_error(
+						wl_data_source::Error::InvalidActionMask,
+						"Unknown drag and drop actions",
+					);
+					return;
+				};
+				*data.dnd_actions.lock() = dnd_actions;
+				let drag = DRAG.lock();
+				if let Some(drag) = drag
+					.as_ref()
+					.filter(|drag| drag.source.as_ref() == Some(resource))
+				{
+					drag.source_actions_changed(dnd_actions);
+				}
+			}
 			_ => unreachable!(),
 		}
 	}
+
+	fn destroyed(
+		state: &mut WaylandState,
+		_client: ClientId,
+		resource: &WlDataSource,
+		_data: &DataSourceData,
+	) {
+		let dh = state.display_handle.clone();
+		state.data_device_state.source_destroyed(&dh, resource);
+
+		let mut drag_lock = DRAG.lock();
+		if drag_lock.as_ref().and_then(|drag| drag.source.as_ref()) == Some(resource) {
+			if let Some(mut drag) = drag_lock.take() {
+				drag.leave();
+			}
+		}
+	}
 }
 
 impl Dispatch<WlDataDevice, (), WaylandState> for WaylandState {
 	fn request(
-		_state: &mut WaylandState,
-		_client: &Client,
-		_resource: &WlDataDevice,
+		state: &mut WaylandState,
+		client: &Client,
+		resource: &WlDataDevice,
 		request: <WlDataDevice as Resource>::Request,
 		_data: &(),
-		_dhandle: &DisplayHandle,
+		dhandle: &DisplayHandle,
 		_data_init: &mut DataInit<'_, WaylandState>,
 	) {
 		match request {
 			StartDrag {
-				source: _,
-				origin: _,
-				icon: _,
-				serial: _,
-			} => {}
-			SetSelection {
-				source: _,
-				serial: _,
-			} => {}
+				source,
+				origin,
+				icon,
+				serial,
+			} => {
+				let Some(seat_data) = client.get_data::<ClientState>().map(|s| s.seat.clone())
+				else {
+					return;
+				};
+				if !valid_press(&seat_data, serial, origin.as_ref()) {
+					if let Some(source) = source {
+						source.cancelled();
+					}
+					return;
+				}
+				if let Some(icon) = icon.as_ref() {
+					if !give_role(icon, DND_ICON_ROLE) {
+						resource.post_error(
+							wl_data_device::Error::Role,
+							"The drag icon already has another role",
+						);
+						return;
+					}
+					CoreSurface::add_to(dhandle.clone(), icon, || (), || (), |_| ());
+					compositor::with_states(icon, |data| {
+						if let Some(core_surface) = data.data_map.get::<Arc<CoreSurface>>() {
+							core_surface.set_material_offset(TOP_MATERIAL_OFFSET);
+							core_surface.keep_transparent();
+						}
+					})
+				}
+				let old_drag = DRAG.lock().replace(Drag {
+					dh: dhandle.clone(),
+					source,
+					origin,
+					icon,
+					icon_position: None,
+					target: None,
+				});
+				if let Some(old_drag) = old_drag {
+					old_drag.cancel();
+				}
+			}
+			SetSelection { source, serial } => {
+				let Some(seat_data) = client.get_data::<ClientState>().map(|s| s.seat.clone())
+				else {
+					return;
+				};
+				if !valid_selection(&seat_data, serial) {
+					if let Some(source) = source {
+						source.cancelled();
+					}
+					return;
+				}
+				state.data_device_state.set_selection(dhandle, source);
+			}
 			Release => {}
 			_ => unreachable!(),
 		}
 	}
 }
+
+impl Dispatch<WlDataOffer, DataOfferData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		resource: &WlDataOffer,
+		request: <WlDataOffer as Resource>::Request,
+		data: &DataOfferData,
+		_dhandle: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			wl_data_offer::Request::Accept {
+				serial: _,
+				mime_type,
+			} => {
+				data.state.lock().accepted = mime_type.clone();
+				if data.source.is_alive() {
+					data.source.target(mime_type);
+				}
+			}
+			wl_data_offer::Request::Receive { mime_type, fd } => {
+				let Some(source_data) = data.source.data::<DataSourceData>() else {
+					return;
+				};
+				if data.source.is_alive() {
+					let source_types = source_data.mime_types.lock().clone();
+					receive(&source_types, mime_type, fd, |mime_type, fd| {
+						data.source.send(mime_type, fd);
+						flush(&data.source);
+					});
+				}
+			}
+			wl_data_offer::Request::SetActions {
+				dnd_actions,
+				preferred_action,
+			} => {
+				let (WEnum::Value(dnd_actions), WEnum::Value(preferred_action)) =
+					(dnd_actions, preferred_action)
+				else {
+					resource.post_error(
+						wl_data_offer::Error::InvalidActionMask,
+						"Unknown drag and drop actions",
+					);
+					return;
+				};
+				if preferred_action.bits().count_ones() > 1 {
+					resource.post_error(
+						wl_data_offer::Error::InvalidAction,
+						"Preferred action must be a single action",
+					);
+					return;
+				}
+				{
+					let mut state = data.state.lock();
+					state.dest_actions = dnd_actions;
+					state.preferred_action = preferred_action;
+				}
+				update_action(resource);
+			}
+			wl_data_offer::Request::Finish => {
+				if let Some((error, message)) = data.state.lock().finish_error() {
+					resource.post_error(error, message);
+					return;
+				}
+				if data.source.version() >= wl_data_source::EVT_DND_FINISHED_SINCE {
+					data.source.dnd_finished();
+					flush(&data.source);
+				}
+			}
+			wl_data_offer::Request::Destroy => {}
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<WlDataOffer, SelectionOfferData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		resource: &WlDataOffer,
+		request: <WlDataOffer as Resource>::Request,
+		data: &SelectionOfferData,
+		_dhandle: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			wl_data_offer::Request::Accept { .. } => {}
+			wl_data_offer::Request::Receive { mime_type, fd } => {
+				data.selection.receive(mime_type, fd);
+			}
+			wl_data_offer::Request::SetActions { .. } => {}
+			wl_data_offer::Request::Finish => {
+				resource.post_error(
+					wl_data_offer::Error::InvalidFinish,
+					"Finish requested on a selection offer",
+				);
+			}
+			wl_data_offer::Request::Destroy => {}
+			_ => unreachable!(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn preferred_action_wins_when_both_sides_allow_it() {
+		let both = DndAction::Copy | DndAction::Move;
+		assert_eq!(
+			choose_action(both, both | DndAction::Ask, DndAction::Move),
+			DndAction::Move
+		);
+		assert_eq!(
+			choose_action(both | DndAction::Ask, both | DndAction::Ask, DndAction::Ask),
+			DndAction::Ask
+		);
+	}
+
+	#[test]
+	fn falls_back_to_copy_then_move_then_ask() {
+		let all = DndAction::Copy | DndAction::Move | DndAction::Ask;
+		// The preferred action isn't one the source allows
+		assert_eq!(
+			choose_action(DndAction::Copy | DndAction::Move, all, DndAction::Ask),
+			DndAction::Copy
+		);
+		assert_eq!(
+			choose_action(DndAction::Move | DndAction::Ask, all, DndAction::Copy),
+			DndAction::Move
+		);
+		assert_eq!(
+			choose_action(DndAction::Ask, all, DndAction::Copy),
+			DndAction::Ask
+		);
+		// No preference at all
+		assert_eq!(choose_action(all, all, DndAction::empty()), DndAction::Copy);
+	}
+
+	#[test]
+	fn nothing_in_common_means_no_action() {
+		assert_eq!(
+			choose_action(DndAction::Copy, DndAction::Move, DndAction::Move),
+			DndAction::empty()
+		);
+		assert_eq!(
+			choose_action(DndAction::empty(), DndAction::Copy, DndAction::Copy),
+			DndAction::empty()
+		);
+	}
+
+	fn offer(dest_actions: DndAction, preferred_action: DndAction) -> OfferState {
+		OfferState {
+			accepted: None,
+			dest_actions,
+			preferred_action,
+			chosen_action: DndAction::empty(),
+			dropped: false,
+		}
+	}
+
+	#[test]
+	fn negotiate_only_reports_changes() {
+		let mut state = offer(DndAction::Copy | DndAction::Move, DndAction::Move);
+		let both = DndAction::Copy | DndAction::Move;
+		assert_eq!(state.negotiate(both), Some(DndAction::Move));
+		assert_eq!(state.negotiate(both), None);
+		// The source stops allowing move mid drag
+		assert_eq!(state.negotiate(DndAction::Copy), Some(DndAction::Copy));
+		assert_eq!(state.negotiate(DndAction::Ask), Some(DndAction::empty()));
+		assert_eq!(state.chosen_action, DndAction::empty());
+	}
+
+	#[test]
+	fn drop_needs_a_type_and_an_action() {
+		let mut state = offer(DndAction::Copy, DndAction::Copy);
+		state.negotiate(DndAction::Copy);
+		assert!(!state.drop_accepted());
+		state.accepted = Some("text/plain".to_string());
+		assert!(state.drop_accepted());
+		state.negotiate(DndAction::Move);
+		assert!(!state.drop_accepted());
+	}
+
+	#[test]
+	fn finish_only_after_a_decided_drop() {
+		let all = DndAction::Copy | DndAction::Move | DndAction::Ask;
+		let mut state = offer(all, DndAction::Ask);
+		state.negotiate(all);
+		let error = |state: &OfferState| state.finish_error().map(|(error, _)| error);
+		assert_eq!(error(&state), Some(wl_data_offer::Error::InvalidFinish));
+		state.accepted = Some("text/plain".to_string());
+		assert_eq!(error(&state), Some(wl_data_offer::Error::InvalidFinish));
+		state.dropped = true;
+		// Ask has to be resolved with another set_actions before finishing
+		assert_eq!(error(&state), Some(wl_data_offer::Error::InvalidAction));
+		state.preferred_action = DndAction::Move;
+		state.negotiate(all);
+		assert_eq!(error(&state), None);
+	}
+
+	#[test]
+	fn drag_icons_follow_the_pointer() {
+		// an icon attached 4px up and left, i.e. its hotspot is at (4, 4)
+		let transform = drag_icon_transform(
+			Mat4::IDENTITY,
+			[100.0, 50.0].into(),
+			[-4, -4].into(),
+			[32, 16].into(),
+		);
+		let corner = |x, y| transform.transform_point3(vec3(x, -y, 0.0));
+		assert_eq!(corner(0.0, 0.0), vec3(96.0, 46.0, DRAG_ICON_DEPTH));
+		assert_eq!(corner(1.0, 1.0), vec3(128.0, 62.0, DRAG_ICON_DEPTH));
+	}
+
+	#[test]
+	fn releasing_a_button_drops() {
+		assert!(drag_ends(&PointerEvent::Button {
+			button: 0x111,
+			state: 0
+		}));
+		assert!(!drag_ends(&PointerEvent::Button {
+			button: 0x110,
+			state: 1
+		}));
+		assert!(!drag_ends(&PointerEvent::Motion([1.0, 2.0].into())));
+	}
+}
@@ -0,0 +1,108 @@
+use super::{
+	decoration::title_style,
+	surface::CORE_SURFACES,
+	utils,
+	xdg_shell::{panel_transform, pixels_per_meter, XdgBackend, XdgSurfaceData},
+};
+use crate::nodes::{items::panel::PanelItem, spatial::Spatial, Node};
+use glam::{vec3, Mat4, Vec3};
+use once_cell::sync::Lazy;
+use smithay::{backend::renderer::utils::RendererSurfaceStateUserData, wayland::compositor};
+use std::sync::{
+	atomic::{AtomicBool, Ordering},
+	Weak,
+};
+use stereokit::{Color128, StereoKitDraw, TextAlign};
+
+/// Surface pixels between the overlay and the window's top left corner
+const MARGIN: f32 = 8.0;
+/// Height of a line of the overlay in surface pixels, so it scales along with the panel
+const LINE_HEIGHT: f32 = 16.0;
+const TEXT_COLOR: [f32; 4] = [1.0, 0.9, 0.2, 1.0];
+
+/// Drawing each panel's sizes, scale and frame rate over it, off unless
+/// `STARDUST_DEBUG_OVERLAY` is set or with `--wayland-debug-overlay`.
+pub static DEBUG_OVERLAY: Lazy<AtomicBool> =
+	Lazy::new(|| AtomicBool::new(std::env::var_os("STARDUST_DEBUG_OVERLAY").is_some()));
+
+pub fn draw_all(sk: &impl StereoKitDraw) {
+	if !DEBUG_OVERLAY.load(Ordering::Relaxed) {
+		return;
+	}
+	let Some(style) = title_style(sk, &None) else {
+		return;
+	};
+	for core_surface in CORE_SURFACES.get_valid_contents() {
+		if core_surface.minimized() {
+			continue;
+		}
+		let Some(wl_surface) = core_surface.wl_surface() else {
+			continue;
+		};
+		// only toplevels have a panel of their own, popups and subsurfaces are in its texture
+		if utils::get_data::<Weak<PanelItem<XdgBackend>>>(&wl_surface)
+			.as_deref()
+			.and_then(Weak::upgrade)
+			.is_none()
+		{
+			continue;
+		}
+		let Some(node) = utils::get_data::<Node>(&wl_surface) else {
+			continue;
+		};
+		if !node.enabled.load(Ordering::Relaxed) {
+			continue;
+		}
+		let Ok(spatial) = node.get_aspect::<Spatial>() else {
+			continue;
+		};
+		let Some(window) = utils::get_data::<XdgSurfaceData>(&wl_surface)
+			.and_then(|xdg_surface_data| xdg_surface_data.window_geometry())
+		else {
+			continue;
+		};
+		let Some((buffer_size, buffer_scale)) = compositor::with_states(&wl_surface, |data| {
+			let surface_state = data
+				.data_map
+				.get::<RendererSurfaceStateUserData>()?
+				.borrow();
+			Some((surface_state.buffer_size()?, surface_state.buffer_scale()))
+		}) else {
+			continue;
+		};
+		let Some(size) = core_surface.size() else {
+			continue;
+		};
+		let text = format!(
+			"buffer {}x{}\nlogical {}x{}\nscale {buffer_scale} (preferred {:.2})\n{:.0} fps",
+			buffer_size.w,
+			buffer_size.h,
+			size.x,
+			size.y,
+			core_surface.fractional_scale(),
+			core_surface.commit_rate(),
+		);
+
+		// In meters from the window's top left, y up, just in front of the panel
+		let corner = vec3(
+			window.origin.x as f32 + MARGIN,
+			window.origin.y as f32 + MARGIN,
+			0.0,
+		);
+		let pixels_per_meter = pixels_per_meter(&wl_surface, &spatial);
+		let transform = panel_transform(&wl_surface, &spatial, window)
+			* Mat4::from_translation(corner)
+			* Mat4::from_scale(vec3(pixels_per_meter, -pixels_per_meter, 1.0))
+			* Mat4::from_translation(vec3(0.0, 0.0, 0.001))
+			* Mat4::from_scale(Vec3::splat(LINE_HEIGHT / pixels_per_meter));
+		sk.text_add_at(
+			&text,
+			transform,
+			style,
+			TextAlign::TopLeft,
+			TextAlign::TopLeft,
+			vec3(0.0, 0.0, 0.0),
+			Color128::from(TEXT_COLOR),
+		);
+	}
+}
@@ -1,4 +1,27 @@
-use super::state::WaylandState;
+use super::{
+	state::WaylandState,
+	utils,
+	xdg_shell::{
+		panel_transform, pixels_per_meter, ray_surface_position, ToplevelData, XdgBackend,
+		XdgSurfaceData,
+	},
+};
+use crate::{
+	core::registry::Registry,
+	nodes::{
+		input::pointer_rays,
+		items::panel::{
+			Backend, ButtonLayout, DecorationButton, DecorationTheme, Geometry, PanelItem,
+		},
+		spatial::Spatial,
+		Node,
+	},
+};
+use glam::{vec3, Mat4, Vec3};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use portable_atomic::Ordering;
+use rustc_hash::FxHashMap;
 use smithay::{
 	delegate_kde_decoration,
 	reexports::{
@@ -19,6 +42,291 @@ use smithay::{
 	},
 	wayland::shell::{self, kde::decoration::KdeDecorationHandler},
 };
+use std::sync::{Arc, Weak as ArcWeak};
+use stereokit::{
+	named_colors::WHITE, Color128, LinePoint as SkLinePoint, StereoKitDraw, TextAlign, TextStyle,
+};
+use tracing::warn;
+
+static DECORATIONS: Registry<ToplevelDecoration> = Registry::new();
+/// What toplevels without a theme of their own get decorated with, see
+/// `Wayland::set_decoration_theme` and `XdgBackend::decoration_theme`
+pub static DECORATION_THEME: Lazy<Mutex<Arc<DecorationTheme>>> = Lazy::new(Default::default);
+/// Keyed by the theme's font, `None` if it couldn't be loaded so it isn't tried every frame
+static TITLE_STYLES: Lazy<Mutex<FxHashMap<Option<String>, Option<TextStyle>>>> =
+	Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+/// Height of the titlebar in surface pixels, so it scales along with the panel
+const TITLEBAR_HEIGHT: f32 = 32.0;
+/// How many strips the top of the titlebar gets split into to round its corners
+const CORNER_STRIPS: u32 = 8;
+
+fn glyph(button: DecorationButton) -> &'static str {
+	match button {
+		DecorationButton::Close => "×",
+		DecorationButton::Maximize => "□",
+		DecorationButton::Minimize => "–",
+	}
+}
+/// Where the left edge of each button goes, in surface pixels from the left of the window
+fn button_slots(layout: &ButtonLayout, width: f32) -> Vec<(DecorationButton, f32)> {
+	let left = layout
+		.left
+		.iter()
+		.enumerate()
+		.map(|(i, button)| (*button, i as f32 * TITLEBAR_HEIGHT));
+	let right_start = width - (layout.right.len() as f32 * TITLEBAR_HEIGHT);
+	let right = layout
+		.right
+		.iter()
+		.enumerate()
+		.map(|(i, button)| (*button, right_start + (i as f32 * TITLEBAR_HEIGHT)));
+	left.chain(right).collect()
+}
+pub fn title_style(sk: &impl StereoKitDraw, font: &Option<String>) -> Option<TextStyle> {
+	*TITLE_STYLES.lock().entry(font.clone()).or_insert_with(|| {
+		let loaded = match font {
+			Some(path) => sk.font_create(path),
+			None => sk.font_find("default/font"),
+		};
+		let loaded = loaded
+			.map_err(|err| warn!(?err, ?font, "Could not load the decoration font"))
+			.ok()?;
+		Some(unsafe { sk.text_make_style(loaded, 1.0, WHITE) })
+	})
+}
+
+/// Server-side decoration state of a toplevel, drawn as a titlebar above its panel
+pub struct ToplevelDecoration {
+	toplevel: Weak<WlSurface>,
+	mode: Mutex<Mode>,
+	pressed: Mutex<Option<DecorationButton>>,
+}
+impl ToplevelDecoration {
+	fn get_or_add(wl_surface: &WlSurface) -> Arc<ToplevelDecoration> {
+		if let Some(decoration) = utils::get_data::<ToplevelDecoration>(wl_surface) {
+			return decoration;
+		}
+		let decoration = DECORATIONS.add(ToplevelDecoration {
+			toplevel: wl_surface.downgrade(),
+			mode: Mutex::new(Mode::ServerSide),
+			pressed: Mutex::new(None),
+		});
+		utils::insert_data_raw(wl_surface, decoration.clone());
+		decoration
+	}
+	fn from_toplevel(toplevel: &Weak<XdgToplevel>) -> Option<Arc<ToplevelDecoration>> {
+		let toplevel = toplevel.upgrade().ok()?;
+		let wl_surface = toplevel.data::<Weak<WlSurface>>()?.upgrade().ok()?;
+		Some(Self::get_or_add(&wl_surface))
+	}
+	fn set_mode(&self, mode: Mode) {
+		*self.mode.lock() = mode;
+	}
+
+	/// Find the button a pointer is over, along with whether it's selecting.
+	/// The titlebar is the `TITLEBAR_HEIGHT` pixels above the window geometry.
+	fn pointed_button(
+		&self,
+		panel_transform: Mat4,
+		window: Geometry,
+		slots: &[(DecorationButton, f32)],
+	) -> Option<(DecorationButton, bool)> {
+		let top = window.origin.y as f32;
+		let left = window.origin.x as f32;
+		let right = left + window.size.x as f32;
+		pointer_rays().into_iter().find_map(|ray| {
+			let point = ray_surface_position(panel_transform, ray.origin, ray.direction)?;
+			let in_titlebar = (top - TITLEBAR_HEIGHT..=top).contains(&point.y)
+				&& (left..=right).contains(&point.x);
+			if !in_titlebar {
+				return None;
+			}
+			let x = point.x - left;
+			let (button, _) = slots
+				.iter()
+				.find(|(_, slot)| (*slot..*slot + TITLEBAR_HEIGHT).contains(&x))?;
+			Some((*button, ray.selecting))
+		})
+	}
+	/// Buttons activate on release over the same one they were pressed on
+	fn update_pressed(
+		&self,
+		pointed: Option<(DecorationButton, bool)>,
+	) -> Option<DecorationButton> {
+		let mut pressed = self.pressed.lock();
+		match pointed {
+			Some((button, true)) => {
+				pressed.get_or_insert(button);
+				None
+			}
+			Some((button, false)) => pressed.take().filter(|p| *p == button),
+			None => {
+				pressed.take();
+				None
+			}
+		}
+	}
+	fn activate(&self, panel_item: &PanelItem<XdgBackend>, button: DecorationButton) {
+		match button {
+			DecorationButton::Close => panel_item.backend.close_toplevel(),
+			DecorationButton::Maximize => panel_item
+				.backend
+				.update_toplevel_states(|states| states.maximized = !states.maximized),
+			DecorationButton::Minimize => panel_item.toplevel_minimize_request(),
+		}
+	}
+
+	fn draw(&self, sk: &impl StereoKitDraw) {
+		if *self.mode.lock() != Mode::ServerSide {
+			return;
+		}
+		let Ok(wl_surface) = self.toplevel.upgrade() else {
+			return;
+		};
+		let Some(panel_item) = utils::get_data::<ArcWeak<PanelItem<XdgBackend>>>(&wl_surface)
+			.as_deref()
+			.and_then(ArcWeak::upgrade)
+		else {
+			return;
+		};
+		if panel_item.backend.toplevel_states().minimized {
+			return;
+		}
+		let Some(node) = utils::get_data::<Node>(&wl_surface) else {
+			return;
+		};
+		if !node.enabled.load(Ordering::Relaxed) {
+			return;
+		}
+		let Ok(spatial) = node.get_aspect::<Spatial>() else {
+			return;
+		};
+		let Some(window) = utils::get_data::<XdgSurfaceData>(&wl_surface)
+			.and_then(|xdg_surface_data| xdg_surface_data.window_geometry())
+		else {
+			return;
+		};
+		let title = utils::get_data::<ToplevelData>(&wl_surface)
+			.and_then(|toplevel_data| toplevel_data.title())
+			.unwrap_or_default();
+		let theme = panel_item.backend.decoration_theme();
+		let slots = button_slots(&theme.button_layout, window.size.x as f32);
+
+		// Everything is relative to the panel so the titlebar keeps the panel's apparent size and
+		// follows it however it's rotated. Drawing is in meters from the titlebar's center.
+		let panel_transform = panel_transform(&wl_surface, &spatial, window);
+		let pixels_per_meter = pixels_per_meter(&wl_surface, &spatial);
+		let width = window.size.x as f32 / pixels_per_meter;
+		let bar_height = TITLEBAR_HEIGHT / pixels_per_meter;
+		let titlebar_center = vec3(
+			window.origin.x as f32 + window.size.x as f32 * 0.5,
+			window.origin.y as f32 - TITLEBAR_HEIGHT * 0.5,
+			0.0,
+		);
+		let titlebar_transform = panel_transform
+			* Mat4::from_translation(titlebar_center)
+			* Mat4::from_scale(vec3(pixels_per_meter, -pixels_per_meter, 1.0));
+
+		let pointed = self.pointed_button(panel_transform, window, &slots);
+		if let Some(button) = self.update_pressed(pointed) {
+			self.activate(&panel_item, button);
+		}
+
+		let strip = |left: f32, right: f32, bottom: f32, top: f32, color: [f32; 4]| {
+			if right <= left {
+				return;
+			}
+			let y = (bottom + top) * 0.5;
+			let thickness = titlebar_transform
+				.transform_vector3(vec3(0.0, top - bottom, 0.0))
+				.length();
+			let point = |x: f32| SkLinePoint {
+				pt: titlebar_transform.transform_point3(vec3(x, y, 0.0)).into(),
+				thickness,
+				color: stereokit::sys::color128::from(color).into(),
+			};
+			sk.line_add_listv(&[point(left), point(right)]);
+		};
+		// A line is as thick as the whole bar, so the top corners are rounded by splitting the
+		// top `radius` into strips that get shorter along the curve
+		let radius = (theme.corner_radius.max(0.0) / pixels_per_meter)
+			.min(bar_height)
+			.min(width * 0.5);
+		let bar = |left: f32, right: f32, color: [f32; 4]| {
+			let top = bar_height * 0.5;
+			let curve_bottom = top - radius;
+			strip(left, right, -bar_height * 0.5, curve_bottom, color);
+			if radius <= 0.0 {
+				return;
+			}
+			for i in 0..CORNER_STRIPS {
+				let strip_bottom = curve_bottom + (radius * i as f32 / CORNER_STRIPS as f32);
+				let strip_top = curve_bottom + (radius * (i + 1) as f32 / CORNER_STRIPS as f32);
+				let height = ((strip_bottom + strip_top) * 0.5) - curve_bottom;
+				let inset = radius - (radius * radius - height * height).max(0.0).sqrt();
+				strip(
+					left.max((-width * 0.5) + inset),
+					right.min((width * 0.5) - inset),
+					strip_bottom,
+					strip_top,
+					color,
+				);
+			}
+		};
+		bar(-width * 0.5, width * 0.5, theme.titlebar_color);
+
+		let Some(style) = title_style(sk, &theme.font) else {
+			return;
+		};
+		let text_transform = |x: f32| {
+			titlebar_transform
+				* Mat4::from_translation(vec3(x, 0.0, 0.0005))
+				* Mat4::from_scale(Vec3::splat(bar_height * 0.6))
+		};
+		let text_color = Color128::from(theme.text_color);
+		for (button, slot) in &slots {
+			let left = (slot / pixels_per_meter) - (width * 0.5);
+			if pointed.map(|(pointed, _)| pointed) == Some(*button) {
+				let color = match button {
+					DecorationButton::Close => theme.close_hovered_color,
+					_ => theme.hovered_color,
+				};
+				bar(left, left + bar_height, color);
+			}
+			sk.text_add_at(
+				glyph(*button),
+				text_transform(left + (bar_height * 0.5)),
+				style,
+				TextAlign::Center,
+				TextAlign::Center,
+				vec3(0.0, 0.0, 0.0),
+				text_color,
+			);
+		}
+		let title_left = theme.button_layout.left.len() as f32 * bar_height;
+		sk.text_add_at(
+			&title,
+			text_transform((-width * 0.5) + title_left + (bar_height * 0.3)),
+			style,
+			TextAlign::CenterLeft,
+			TextAlign::CenterLeft,
+			vec3(0.0, 0.0, 0.0),
+			text_color,
+		);
+	}
+}
+impl Drop for ToplevelDecoration {
+	fn drop(&mut self) {
+		DECORATIONS.remove(self);
+	}
+}
+
+pub fn draw_all(sk: &impl StereoKitDraw) {
+	for decoration in DECORATIONS.get_valid_contents() {
+		decoration.draw(sk);
+	}
+}
 
 impl GlobalDispatch<ZxdgDecorationManagerV1, (), WaylandState> for WaylandState {
 	fn bind(
@@ -46,7 +354,9 @@ impl Dispatch<ZxdgDecorationManagerV1, (), WaylandState> for WaylandState {
 		match request {
 			zxdg_decoration_manager_v1::Request::Destroy => (),
 			zxdg_decoration_manager_v1::Request::GetToplevelDecoration { id, toplevel } => {
-				data_init.init(id, toplevel.downgrade());
+				let toplevel = toplevel.downgrade();
+				ToplevelDecoration::from_toplevel(&toplevel);
+				data_init.init(id, toplevel);
 			}
 			_ => unreachable!(),
 		}
@@ -58,18 +368,34 @@ impl Dispatch<ZxdgToplevelDecorationV1, Weak<XdgToplevel>, WaylandState> for Way
 		_client: &Client,
 		resource: &ZxdgToplevelDecorationV1,
 		request: zxdg_toplevel_decoration_v1::Request,
-		_data: &Weak<XdgToplevel>,
+		data: &Weak<XdgToplevel>,
 		_dhandle: &DisplayHandle,
 		_data_init: &mut DataInit<'_, WaylandState>,
 	) {
+		let decoration = ToplevelDecoration::from_toplevel(data);
 		match request {
-			zxdg_toplevel_decoration_v1::Request::SetMode { mode: _ } => {
-				resource.configure(Mode::ServerSide);
+			zxdg_toplevel_decoration_v1::Request::SetMode { mode } => {
+				let mode = match mode {
+					WEnum::Value(Mode::ClientSide) => Mode::ClientSide,
+					_ => Mode::ServerSide,
+				};
+				if let Some(decoration) = decoration {
+					decoration.set_mode(mode);
+				}
+				resource.configure(mode);
 			}
 			zxdg_toplevel_decoration_v1::Request::UnsetMode => {
+				if let Some(decoration) = decoration {
+					decoration.set_mode(Mode::ServerSide);
+				}
 				resource.configure(Mode::ServerSide);
 			}
-			zxdg_toplevel_decoration_v1::Request::Destroy => (),
+			zxdg_toplevel_decoration_v1::Request::Destroy => {
+				// without a decoration object the client draws its own
+				if let Some(decoration) = decoration {
+					decoration.set_mode(Mode::ClientSide);
+				}
+			}
 			_ => unreachable!(),
 		}
 	}
@@ -80,17 +406,22 @@ impl KdeDecorationHandler for WaylandState {
 		&self.kde_decoration_state
 	}
 
-	fn new_decoration(&mut self, _surface: &WlSurface, decoration: &OrgKdeKwinServerDecoration) {
+	fn new_decoration(&mut self, surface: &WlSurface, decoration: &OrgKdeKwinServerDecoration) {
+		ToplevelDecoration::get_or_add(surface).set_mode(Mode::ServerSide);
 		decoration.mode(KdeMode::Server);
 	}
 
 	fn request_mode(
 		&mut self,
-		_surface: &WlSurface,
+		surface: &WlSurface,
 		decoration: &OrgKdeKwinServerDecoration,
 		mode: WEnum<KdeMode>,
 	) {
 		let Ok(mode) = mode.into_result() else {return};
+		ToplevelDecoration::get_or_add(surface).set_mode(match mode {
+			KdeMode::Server => Mode::ServerSide,
+			_ => Mode::ClientSide,
+		});
 		decoration.mode(mode);
 	}
 }
@@ -0,0 +1,90 @@
+use once_cell::sync::Lazy;
+use parking_lot::{const_mutex, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// How recent input has to be for a new toplevel to count as the one the user just opened
+const LAUNCH_FOCUS_WINDOW: Duration = Duration::from_secs(3);
+
+/// Something wanting focus without the user giving it to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FocusRequest {
+	/// A toplevel just got mapped
+	NewToplevel,
+	/// `xdg_activation_v1` with a valid token, so the requesting client just had input
+	Activation,
+}
+/// What the pointer did on a surface that could give it keyboard focus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerFocusEvent {
+	Enter,
+	Press,
+}
+
+/// Decides which surfaces get keyboard focus, so windows can't keep taking it from each other.
+/// Requests that aren't allowed leave the toplevel unfocused and ask for the user's attention
+/// on its panel item instead.
+pub trait FocusPolicy: Send + Sync {
+	/// Whether this moves keyboard focus to the surface under the pointer
+	fn pointer_focus(&self, event: PointerFocusEvent) -> bool;
+	/// `since_input` is how long ago the user last pressed a button or key
+	fn allow_request(&self, request: FocusRequest, since_input: Duration) -> bool;
+}
+
+fn allow_request_default(request: FocusRequest, since_input: Duration) -> bool {
+	match request {
+		FocusRequest::NewToplevel => since_input < LAUNCH_FOCUS_WINDOW,
+		FocusRequest::Activation => true,
+	}
+}
+
+/// Keyboard focus goes wherever the pointer goes
+pub struct FocusFollowsPointer;
+impl FocusPolicy for FocusFollowsPointer {
+	fn pointer_focus(&self, event: PointerFocusEvent) -> bool {
+		event == PointerFocusEvent::Enter
+	}
+	fn allow_request(&self, request: FocusRequest, since_input: Duration) -> bool {
+		allow_request_default(request, since_input)
+	}
+}
+/// Keyboard focus only moves when a surface gets clicked
+pub struct ClickToFocus;
+impl FocusPolicy for ClickToFocus {
+	fn pointer_focus(&self, event: PointerFocusEvent) -> bool {
+		event == PointerFocusEvent::Press
+	}
+	fn allow_request(&self, request: FocusRequest, since_input: Duration) -> bool {
+		allow_request_default(request, since_input)
+	}
+}
+
+/// Set `STARDUST_FOCUS_POLICY` to `click` for click to focus, otherwise focus follows the pointer
+static FOCUS_POLICY: Lazy<RwLock<Box<dyn FocusPolicy>>> = Lazy::new(|| {
+	let policy: Box<dyn FocusPolicy> = match std::env::var("STARDUST_FOCUS_POLICY").as_deref() {
+		Ok("click") => Box::new(ClickToFocus),
+		_ => Box::new(FocusFollowsPointer),
+	};
+	RwLock::new(policy)
+});
+static LAST_INPUT: Mutex<Option<Instant>> = const_mutex(None);
+
+pub fn set_focus_policy(policy: Box<dyn FocusPolicy>) {
+	*FOCUS_POLICY.write() = policy;
+}
+pub fn pointer_focus(event: PointerFocusEvent) -> bool {
+	FOCUS_POLICY.read().pointer_focus(event)
+}
+pub fn allow_request(request: FocusRequest) -> bool {
+	let since_input = LAST_INPUT
+		.lock()
+		.map_or(Duration::MAX, |last_input| last_input.elapsed());
+	let allowed = FOCUS_POLICY.read().allow_request(request, since_input);
+	debug!(?request, ?since_input, allowed, "Focus request");
+	allowed
+}
+
+/// The user pressed something, call for every button, key and touch
+pub fn input_happened() {
+	LAST_INPUT.lock().replace(Instant::now());
+}
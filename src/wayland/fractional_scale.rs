@@ -0,0 +1,134 @@
+use super::{state::WaylandState, surface::CoreSurface};
+use smithay::{
+	delegate_fractional_scale,
+	reexports::wayland_server::protocol::wl_surface::WlSurface,
+	utils::Transform,
+	wayland::{
+		compositor::{self, TraversalAction},
+		fractional_scale::{with_fractional_scale, FractionalScaleHandler},
+	},
+};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// The scale surfaces get before anything knows how big their panel appears, matches the default output
+pub const DEFAULT_FRACTIONAL_SCALE: f64 = 2.0;
+/// Display pixels across a radian of view, about what current headsets have in the middle
+const DISPLAY_PIXELS_PER_RADIAN: f64 = 1200.0;
+/// Scales are rounded to this, and the apparent scale has to be off by at least this much from
+/// the current one before it changes, so a panel sitting at a boundary doesn't flip back and forth
+const SCALE_STEP: f64 = 0.25;
+const MIN_SCALE: f64 = 1.0;
+const MAX_SCALE: f64 = 4.0;
+/// How long the apparent scale has to stay at a new value before clients are told, so zooming a
+/// panel smoothly only makes its client reallocate once it stops
+const SCALE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The scale a surface `width` pixels wide should render at to match the display
+/// when it looks `angle` radians wide, or `None` if it isn't far enough from `current` to change
+pub fn apparent_scale(angle: f32, width: u32, current: f64) -> Option<f64> {
+	let scale = angle as f64 * DISPLAY_PIXELS_PER_RADIAN / width.max(1) as f64;
+	let scale = scale.clamp(MIN_SCALE, MAX_SCALE);
+	if (scale - current).abs() < SCALE_STEP {
+		return None;
+	}
+	Some((scale / SCALE_STEP).round() * SCALE_STEP)
+}
+/// Holds a new apparent scale back until it's stayed the same for `SCALE_DEBOUNCE`
+#[derive(Debug, Default)]
+pub struct ScaleDebounce(Option<(f64, Instant)>);
+impl ScaleDebounce {
+	/// Takes what `apparent_scale` said this frame, returns the scale to send once it held
+	pub fn update(&mut self, apparent: Option<f64>, now: Instant) -> Option<f64> {
+		let Some(scale) = apparent else {
+			self.0 = None;
+			return None;
+		};
+		match self.0 {
+			Some((pending, since)) if pending == scale => {
+				if now.saturating_duration_since(since) < SCALE_DEBOUNCE {
+					return None;
+				}
+				self.0 = None;
+				Some(scale)
+			}
+			_ => {
+				self.0 = Some((scale, now));
+				None
+			}
+		}
+	}
+}
+
+/// Send the preferred scale to every `wp_fractional_scale_v1` in the surface's tree, subsurfaces
+/// get drawn at the same scale as their parent. This is sent in 120ths of a scale on the wire,
+/// so 1.5 becomes 180. Clients on `wl_surface` version 6 also get it rounded up as their
+/// preferred buffer scale, so ones without fractional scaling follow along too.
+pub fn send_preferred_scale(surface: &WlSurface, scale: f64) {
+	compositor::with_surface_tree_downward(
+		surface,
+		(),
+		|_, _, _| TraversalAction::DoChildren(()),
+		|surface, states, _| {
+			with_fractional_scale(states, |fractional_scale| {
+				fractional_scale.set_preferred_scale(scale);
+			});
+			compositor::send_surface_state(surface, states, scale.ceil() as i32, Transform::Normal);
+		},
+		|_, _, _| true,
+	);
+}
+
+impl FractionalScaleHandler for WaylandState {
+	fn new_fractional_scale(&mut self, surface: WlSurface) {
+		// subsurfaces go with the surface they're on
+		let mut root = surface.clone();
+		while let Some(parent) = compositor::get_parent(&root) {
+			root = parent;
+		}
+		let scale = CoreSurface::from_wl_surface(&root)
+			.map(|core_surface| core_surface.fractional_scale())
+			.unwrap_or(DEFAULT_FRACTIONAL_SCALE);
+		debug!(?surface, scale, "New fractional scale");
+		send_preferred_scale(&surface, scale);
+	}
+}
+delegate_fractional_scale!(WaylandState);
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A second or so of frames on a 90Hz headset, with a 500 pixel wide surface that looks
+	/// `angle(t)` radians wide, returning the scales that got sent
+	fn scales_sent(current: &mut f64, seconds: f64, angle: impl Fn(f64) -> f32) -> Vec<f64> {
+		let mut debounce = ScaleDebounce::default();
+		let start = Instant::now();
+		let mut sent = Vec::new();
+		for frame in 0..(seconds * 90.0) as u32 {
+			let t = frame as f64 / 90.0;
+			let apparent = apparent_scale(angle(t), 500, *current);
+			if let Some(scale) = debounce.update(apparent, start + Duration::from_secs_f64(t)) {
+				*current = scale;
+				sent.push(scale);
+			}
+		}
+		sent
+	}
+
+	#[test]
+	fn crossing_a_scale_threshold_sends_one_update() {
+		let mut current = 2.0;
+		assert_eq!(scales_sent(&mut current, 2.0, |_| 1.25), [3.0]);
+		// close enough to the current scale isn't worth reallocating for
+		assert!(scales_sent(&mut current, 2.0, |_| 1.3).is_empty());
+	}
+
+	#[test]
+	fn smooth_zooms_only_send_where_they_stop() {
+		let mut current = 2.0;
+		// from scale 2 up to 4 in half a second, then held there
+		let zoom = |t: f64| ((2.0 + 4.0 * t.min(0.5)) / 2.4) as f32;
+		assert_eq!(scales_sent(&mut current, 2.0, zoom), [4.0]);
+	}
+}
@@ -0,0 +1,84 @@
+//! The renderer uses StereoKit's EGL context, and a context can only be current on one thread
+//! at a time. So all GL, including smithay's, happens on the thread StereoKit draws on (the one
+//! `Wayland` got created on), always inside a `CurrentContext`. Nothing else needs the context:
+//! the Wayland event loop and async tasks only queue work for it, and textures dropped
+//! elsewhere are either deleted through `destroy_queue` or queued by smithay for the renderer.
+
+use color_eyre::eyre::{ensure, Result};
+use once_cell::sync::OnceCell;
+use smithay::backend::{egl::ffi::egl, renderer::gles::GlesRenderer};
+use std::{
+	ops::{Deref, DerefMut},
+	thread::{self, ThreadId},
+};
+use tracing::warn;
+
+/// The thread the first renderer was made on, GL can't be touched anywhere else
+static GL_THREAD: OnceCell<ThreadId> = OnceCell::new();
+
+/// Puts back whatever was current on the thread when it was made once it's dropped, so
+/// StereoKit finds its context (and draw surface) the way it left it instead of ours
+pub struct RestoreContext {
+	display: egl::types::EGLDisplay,
+	draw: egl::types::EGLSurface,
+	read: egl::types::EGLSurface,
+	context: egl::types::EGLContext,
+}
+impl RestoreContext {
+	pub fn save() -> Result<Self> {
+		let gl_thread = *GL_THREAD.get_or_init(|| thread::current().id());
+		ensure!(
+			gl_thread == thread::current().id(),
+			"GL can only be used on the thread StereoKit draws on"
+		);
+		Ok(unsafe {
+			RestoreContext {
+				display: egl::GetCurrentDisplay(),
+				draw: egl::GetCurrentSurface(egl::DRAW),
+				read: egl::GetCurrentSurface(egl::READ),
+				context: egl::GetCurrentContext(),
+			}
+		})
+	}
+}
+impl Drop for RestoreContext {
+	fn drop(&mut self) {
+		let restored = unsafe {
+			if self.context.is_null() {
+				egl::ReleaseThread()
+			} else {
+				egl::MakeCurrent(self.display, self.draw, self.read, self.context)
+			}
+		};
+		if restored != egl::TRUE {
+			warn!("Could not restore the previous EGL context");
+		}
+	}
+}
+
+/// The renderer with its context current, only use the renderer through one of these
+pub struct CurrentContext<'a> {
+	renderer: &'a mut GlesRenderer,
+	_restore: RestoreContext,
+}
+impl<'a> CurrentContext<'a> {
+	pub fn new(renderer: &'a mut GlesRenderer) -> Result<Self> {
+		let restore = RestoreContext::save()?;
+		unsafe { renderer.egl_context().make_current()? };
+		Ok(CurrentContext {
+			renderer,
+			_restore: restore,
+		})
+	}
+}
+impl Deref for CurrentContext<'_> {
+	type Target = GlesRenderer;
+	fn deref(&self) -> &Self::Target {
+		self.renderer
+	}
+}
+impl DerefMut for CurrentContext<'_> {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		self.renderer
+	}
+}
@@ -0,0 +1,100 @@
+use super::{state::WaylandState, surface::CoreSurface};
+use crate::core::registry::Registry;
+use smithay::reexports::{
+	wayland_protocols::wp::idle_inhibit::zv1::server::{
+		zwp_idle_inhibit_manager_v1::{self, ZwpIdleInhibitManagerV1},
+		zwp_idle_inhibitor_v1::{self, ZwpIdleInhibitorV1},
+	},
+	wayland_server::{
+		protocol::wl_surface::WlSurface, Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch,
+		New, Resource, Weak as WlWeak,
+	},
+};
+use std::sync::Arc;
+use tracing::debug;
+
+static IDLE_INHIBITORS: Registry<IdleInhibitor> = Registry::new();
+
+/// Whether any client currently wants to keep the session from idling
+pub fn idle_inhibited() -> bool {
+	IDLE_INHIBITORS
+		.get_valid_contents()
+		.iter()
+		.any(|inhibitor| inhibitor.active())
+}
+
+pub struct IdleInhibitor {
+	surface: WlWeak<WlSurface>,
+}
+impl IdleInhibitor {
+	/// Inhibitors only count while their surface is alive and mapped,
+	/// once the surface is destroyed the inhibitor does nothing anymore.
+	fn active(&self) -> bool {
+		let Ok(surface) = self.surface.upgrade() else {
+			return false;
+		};
+		CoreSurface::from_wl_surface(&surface)
+			.and_then(|core_surface| core_surface.size())
+			.is_some()
+	}
+}
+impl Drop for IdleInhibitor {
+	fn drop(&mut self) {
+		IDLE_INHIBITORS.remove(self);
+	}
+}
+
+impl GlobalDispatch<ZwpIdleInhibitManagerV1, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZwpIdleInhibitManagerV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ZwpIdleInhibitManagerV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwpIdleInhibitManagerV1,
+		request: zwp_idle_inhibit_manager_v1::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_idle_inhibit_manager_v1::Request::CreateInhibitor { id, surface } => {
+				let inhibitor = IDLE_INHIBITORS.add(IdleInhibitor {
+					surface: surface.downgrade(),
+				});
+				let inhibitor = data_init.init(id, inhibitor);
+				debug!(?inhibitor, ?surface, "Create idle inhibitor");
+			}
+			zwp_idle_inhibit_manager_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ZwpIdleInhibitorV1, Arc<IdleInhibitor>, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		resource: &ZwpIdleInhibitorV1,
+		request: zwp_idle_inhibitor_v1::Request,
+		_data: &Arc<IdleInhibitor>,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			// the registry entry goes away along with the resource's data
+			zwp_idle_inhibitor_v1::Request::Destroy => debug!(?resource, "Destroy idle inhibitor"),
+			_ => unreachable!(),
+		}
+	}
+}
@@ -0,0 +1,136 @@
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use portable_atomic::{AtomicBool, AtomicU64, Ordering};
+use std::{
+	collections::VecDeque,
+	time::{Duration, Instant},
+};
+use tracing::trace;
+
+/// A surface taking longer than this to process (mostly importing its buffers) is a slow upload
+pub const SLOW_UPLOAD: Duration = Duration::from_millis(2);
+/// How often the metrics are logged at debug level
+pub const LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// How many of the latest samples of each stage the latency percentiles are from
+const LATENCY_SAMPLES: usize = 1000;
+
+/// Counted in `CoreSurface::process` as compositing happens on the surfaces themselves
+static FRAMES_COMPOSITED: AtomicU64 = AtomicU64::new(0);
+
+pub fn frame_composited() {
+	FRAMES_COMPOSITED.fetch_add(1, Ordering::Relaxed);
+}
+pub fn frames_composited() -> u64 {
+	FRAMES_COMPOSITED.load(Ordering::Relaxed)
+}
+
+/// Measuring input latency, off unless `STARDUST_INPUT_LATENCY` is set or with
+/// `--wayland-input-latency`.
+pub static TRACK_INPUT_LATENCY: Lazy<AtomicBool> =
+	Lazy::new(|| AtomicBool::new(std::env::var_os("STARDUST_INPUT_LATENCY").is_some()));
+/// Indexed by `LatencyStage`
+static LATENCIES: Mutex<[VecDeque<Duration>; 4]> = Mutex::new([
+	VecDeque::new(),
+	VecDeque::new(),
+	VecDeque::new(),
+	VecDeque::new(),
+]);
+
+/// Where the time from spatial input to the client's reaction being on its texture goes
+#[derive(Debug, Clone, Copy)]
+pub enum LatencyStage {
+	/// From the shell's input to the event going out, mostly motion waiting for the next frame
+	Dispatch,
+	/// From the event going out to the client's next commit
+	Client,
+	/// From that commit to its buffers being uploaded
+	Render,
+	/// All of them together
+	Total,
+}
+pub fn record_latency(stage: LatencyStage, latency: Duration) {
+	trace!(?stage, ?latency, "Input latency");
+	let mut latencies = LATENCIES.lock();
+	let samples = &mut latencies[stage as usize];
+	if samples.len() >= LATENCY_SAMPLES {
+		samples.pop_front();
+	}
+	samples.push_back(latency);
+}
+pub fn latency(stage: LatencyStage) -> Option<Latency> {
+	let mut samples = LATENCIES.lock()[stage as usize]
+		.iter()
+		.copied()
+		.collect::<Vec<_>>();
+	if samples.is_empty() {
+		return None;
+	}
+	samples.sort_unstable();
+	let percentile = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+	Some(Latency {
+		p50: percentile(0.5),
+		p99: percentile(0.99),
+	})
+}
+
+/// Input to a client it hasn't reacted to yet, one per seat. Only the oldest input of each
+/// stage is timed, so a burst of events is one sample and tracking costs next to nothing.
+#[derive(Default)]
+pub struct PendingInput {
+	/// When the oldest input that hasn't gone out to the client yet came in
+	generated: Option<Instant>,
+	/// When the oldest input the client hasn't committed since came in, and when it went out
+	sent: Option<(Instant, Instant)>,
+}
+impl PendingInput {
+	pub fn generated(&mut self) {
+		if TRACK_INPUT_LATENCY.load(Ordering::Relaxed) {
+			self.generated.get_or_insert_with(Instant::now);
+		}
+	}
+	pub fn sent(&mut self) {
+		let Some(generated) = self.generated.take() else {
+			return;
+		};
+		let now = Instant::now();
+		record_latency(LatencyStage::Dispatch, now - generated);
+		self.sent.get_or_insert((generated, now));
+	}
+	/// The client committed, returns when the input it's reacting to came in
+	pub fn committed(&mut self) -> Option<Instant> {
+		let (generated, sent) = self.sent.take()?;
+		record_latency(LatencyStage::Client, sent.elapsed());
+		Some(generated)
+	}
+}
+
+/// How the compositor is doing, as numbers to graph next to the tracing spans.
+/// Counts are since startup, the rest is what it is right now.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WaylandMetrics {
+	pub clients: usize,
+	pub surfaces: usize,
+	/// Every mapped surface has one
+	pub textures: usize,
+	pub texture_bytes: u64,
+	pub frames: u64,
+	/// Surface trees that got flattened into our own texture
+	pub frames_composited: u64,
+	/// How long processing all the surfaces took in the last update
+	pub upload_time: Duration,
+	/// Surfaces that took longer than `SLOW_UPLOAD` to process
+	pub slow_uploads: u64,
+	/// Each `LatencyStage` of input, `None` if it's not tracked or nothing was measured yet
+	pub input_dispatch_latency: Option<Latency>,
+	pub input_client_latency: Option<Latency>,
+	pub input_render_latency: Option<Latency>,
+	pub input_latency: Option<Latency>,
+	/// A client is keeping the session from going idle, e.g. while playing a video
+	pub idle_inhibited: bool,
+}
+/// Of the latest samples
+#[derive(Debug, Clone, Copy)]
+pub struct Latency {
+	pub p50: Duration,
+	pub p99: Duration,
+}
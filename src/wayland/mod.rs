@@ -1,12 +1,34 @@
+mod buffer_offset;
+mod client_limits;
+mod color_management;
 mod compositor;
+mod content_type;
+mod cursor_shape;
 mod data_device;
+mod debug_overlay;
 mod decoration;
+mod drm;
+mod focus_policy;
+mod fractional_scale;
+mod gl_context;
+mod idle_inhibit;
+mod metrics;
+mod pointer_constraints;
+mod primary_selection;
+mod protocol_trace;
+mod relative_pointer;
 mod seat;
+mod serial;
+mod socket;
 mod state;
 mod surface;
-// mod xdg_activation;
-mod drm;
+mod tablet;
+mod text_input;
+mod transfer;
 mod utils;
+mod validation;
+mod xdg_activation;
+mod xdg_foreign;
 mod xdg_shell;
 #[cfg(feature = "xwayland_rootful")]
 pub mod xwayland_rootful;
@@ -19,47 +41,199 @@ pub mod xwayland_rootless;
 #[cfg(feature = "xwayland_rootless")]
 use self::xwayland_rootless::XWaylandState;
 
-use self::{state::WaylandState, surface::CORE_SURFACES};
-use crate::wayland::seat::SeatData;
-use crate::{core::task, wayland::state::ClientState};
+use self::{
+	client_limits::{CLIENT_LIMITS, MAX_TEXTURE_SIZE},
+	data_device::CLIPBOARD_CACHE,
+	debug_overlay::DEBUG_OVERLAY,
+	decoration::DECORATION_THEME,
+	focus_policy::FocusPolicy,
+	gl_context::{CurrentContext, RestoreContext},
+	metrics::{LatencyStage, LOG_INTERVAL, SLOW_UPLOAD, TRACK_INPUT_LATENCY},
+	protocol_trace::TracedClients,
+	socket::WaylandSocket,
+	state::WaylandState,
+	surface::{CoreSurface, FrameTiming, CORE_SURFACES, MAX_TEXTURE_BUFFERS, TEXTURE_BUFFERS},
+	utils::get_data,
+	xdg_shell::XdgBackend,
+};
+use crate::wayland::seat::{SeatData, SEAT_CAPABILITIES};
+use crate::{
+	core::{destroy_queue, task},
+	nodes::items::panel::{ClientInfo, DecorationTheme, PanelItem},
+	wayland::state::{ClientState, CLIENTS},
+};
+pub use client_limits::ClientLimits;
 use color_eyre::eyre::{ensure, Result};
+pub use data_device::ClipboardCache;
+pub use focus_policy::ClickToFocus;
 use global_counter::primitive::exact::CounterU32;
-use once_cell::sync::OnceCell;
+pub use metrics::{Latency, WaylandMetrics};
+use mint::Vector2;
+use once_cell::sync::{Lazy, OnceCell};
 use parking_lot::Mutex;
+use portable_atomic::Ordering;
+pub use seat::SeatCapabilities;
 use sk::StereoKitDraw;
 use smithay::backend::allocator::dmabuf::Dmabuf;
-use smithay::backend::egl::EGLContext;
-use smithay::backend::renderer::gles::GlesRenderer;
-use smithay::backend::renderer::ImportDma;
+use smithay::backend::egl::{ffi::egl::GetProcAddress, EGLContext};
+use smithay::backend::renderer::gles::{ffi, GlesRenderer};
+use smithay::backend::renderer::{ImportDma, Renderer};
 use smithay::output::Output;
-use smithay::reexports::wayland_server::backend::ClientId;
-use smithay::reexports::wayland_server::DisplayHandle;
-use smithay::reexports::wayland_server::{Display, ListeningSocket};
+use smithay::reexports::wayland_server::backend::{ClientId, DisconnectReason, GlobalId};
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::reexports::wayland_server::Display;
+use smithay::reexports::wayland_server::{Client, DisplayHandle, Resource};
 use smithay::wayland::dmabuf;
-use std::ffi::OsStr;
-use std::os::fd::OwnedFd;
+use state::ClientCredentials;
+use std::ffi::c_void;
+use std::os::fd::{BorrowedFd, OwnedFd};
 use std::os::unix::prelude::AsRawFd;
 use std::{
-	ffi::c_void,
-	os::unix::{net::UnixListener, prelude::FromRawFd},
-	sync::Arc,
+	os::unix::net::UnixListener,
+	sync::{Arc, Weak},
+	time::{Duration, Instant},
 };
 use stereokit as sk;
+pub use surface::SurfaceBackground;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::{
 	io::unix::AsyncFd, net::UnixListener as AsyncUnixListener, sync::mpsc, task::JoinHandle,
 };
-use tracing::{debug_span, info, instrument};
+use tracing::{debug, debug_span, error, info, instrument, warn, Span};
 
 pub static X_DISPLAY: OnceCell<u32> = OnceCell::new();
 pub static WAYLAND_DISPLAY: OnceCell<String> = OnceCell::new();
 pub static SERIAL_COUNTER: CounterU32 = CounterU32::new(0);
+/// Globals to remove from the display, unbounded so a storm of disconnects never blocks
+static GLOBAL_DESTROY_QUEUE: OnceCell<mpsc::UnboundedSender<GlobalId>> = OnceCell::new();
+/// Clients to disconnect, killed by the event loop so they go away even if they're hung
+static CLIENT_KILL_QUEUE: OnceCell<mpsc::UnboundedSender<ClientId>> = OnceCell::new();
+/// How long buffer imports may take per frame, ones not expected to fit in what's left of it
+/// wait for a later one (see `CoreSurface::fits_upload_budget`).
+/// The renderer shares StereoKit's EGL context, so imports can't just move to another thread.
+const UPLOAD_BUDGET: Duration = Duration::from_millis(4);
+/// How long a dispatch pass keeps giving clients their turn, the rest go first in the next one
+const DISPATCH_BUDGET: Duration = Duration::from_millis(2);
+/// A frame this many times slower than the estimated refresh rate missed a vsync or more, the
+/// display didn't slow down
+const MISSED_FRAME_RATIO: f64 = 1.5;
+/// Unless that many in a row are, then the headset switched rate (e.g. to half for reprojection)
+const REFRESH_CHANGE_FRAMES: u32 = 30;
 
 struct EGLRawHandles {
 	display: *const c_void,
 	config: *const c_void,
 	context: *const c_void,
 }
+/// `GL_NO_ERROR`, `glGetGraphicsResetStatus` returns anything else once the context was lost
+const GL_NO_ERROR: u32 = 0;
+/// `glGetGraphicsResetStatusEXT`, EGL's proc addresses don't depend on the context.
+/// Without robustness there's no reset status, a lost context only shows up as EGL errors.
+static GET_GRAPHICS_RESET_STATUS: Lazy<Option<extern "system" fn() -> u32>> =
+	Lazy::new(|| unsafe {
+		let get_status = GetProcAddress(b"glGetGraphicsResetStatusEXT\0".as_ptr() as *const _);
+		if get_status.is_null() {
+			return None;
+		}
+		Some(std::mem::transmute::<_, extern "system" fn() -> u32>(
+			get_status,
+		))
+	});
+fn graphics_reset_status(renderer: &mut GlesRenderer) -> Result<u32> {
+	let Some(get_status) = *GET_GRAPHICS_RESET_STATUS else {
+		return Ok(GL_NO_ERROR);
+	};
+	Ok(renderer.with_context(|_| get_status())?)
+}
+/// Remove `global` from the display once the event loop gets to it, as it can't be done while
+/// dispatching (e.g. from a client disconnecting)
+pub fn destroy_global(global: GlobalId) {
+	if let Some(queue) = GLOBAL_DESTROY_QUEUE.get() {
+		let _ = queue.send(global);
+	}
+}
+/// Remove `first` and everything queued after it in one pass, so a client with many globals
+/// doesn't take a loop iteration for each. Returns how many were removed.
+fn remove_globals(
+	dh: &DisplayHandle,
+	first: GlobalId,
+	queue: &mut UnboundedReceiver<GlobalId>,
+) -> usize {
+	let mut globals = vec![first];
+	while let Ok(global) = queue.try_recv() {
+		globals.push(global);
+	}
+	let count = globals.len();
+	for global in globals {
+		dh.remove_global::<WaylandState>(global);
+	}
+	count
+}
+
+/// Disconnect `client` without asking, for hung clients that won't close when told to.
+/// All of its resources get destroyed like it closed the connection itself, so its surfaces,
+/// panel items, textures and globals all go with it.
+pub fn kill_client(client: ClientId) {
+	if let Some(queue) = CLIENT_KILL_QUEUE.get() {
+		let _ = queue.send(client);
+	}
+}
+/// Who `client` is and what it has open, see `Backend::client_info`
+pub fn client_info(client: &Client) -> Option<ClientInfo> {
+	let client_state = client.get_data::<ClientState>()?;
+	let mut panel_items = Vec::new();
+	for core_surface in CORE_SURFACES.get_valid_contents() {
+		let Some(wl_surface) = core_surface.wl_surface() else {
+			continue;
+		};
+		if wl_surface.client().map(|other| other.id()) != Some(client.id()) {
+			continue;
+		}
+		let Some(panel_item) = get_data::<Weak<PanelItem<XdgBackend>>>(&wl_surface)
+			.as_deref()
+			.and_then(Weak::upgrade)
+		else {
+			continue;
+		};
+		panel_items.push(panel_item.uid.clone());
+	}
+	let credentials = client_state.credentials;
+	let resources = client_state.resources.counts();
+	Some(ClientInfo {
+		pid: credentials.and_then(|credentials| credentials.pid),
+		uid: credentials.map(|credentials| credentials.uid),
+		gid: credentials.map(|credentials| credentials.gid),
+		surfaces: resources.surfaces,
+		buffers: resources.buffers,
+		texture_bytes: resources.texture_bytes,
+		panel_items,
+	})
+}
+
+fn create_renderer(egl_raw_handles: EGLRawHandles) -> Result<GlesRenderer> {
+	let _restore = RestoreContext::save()?;
+	let mut renderer = unsafe {
+		GlesRenderer::new(EGLContext::from_raw(
+			egl_raw_handles.display,
+			egl_raw_handles.config,
+			egl_raw_handles.context,
+		)?)?
+	};
+	let max_texture_size = renderer.with_context(|gl| unsafe {
+		let mut size = 0;
+		gl.GetIntegerv(ffi::MAX_TEXTURE_SIZE, &mut size);
+		size
+	})?;
+	if max_texture_size > 0 {
+		MAX_TEXTURE_SIZE.store(max_texture_size as u32, Ordering::Relaxed);
+	}
+	debug!(
+		max_texture_size,
+		max_buffer_size = client_limits::max_buffer_size(),
+		"Created renderer"
+	);
+	Ok(renderer)
+}
 fn get_sk_egl() -> Result<EGLRawHandles> {
 	ensure!(
 		unsafe { sk::sys::backend_graphics_get() }
@@ -76,13 +250,127 @@ fn get_sk_egl() -> Result<EGLRawHandles> {
 	})
 }
 
-pub struct DisplayWrapper(Mutex<Display<WaylandState>>, DisplayHandle);
+/// Start after the client that came last when `turns` clients had their turn, keeping the order
+/// otherwise but with throttled clients after everyone else
+fn dispatch_order<C>(clients: &mut [C], turns: usize, throttled: impl Fn(&C) -> bool) {
+	if clients.is_empty() {
+		return;
+	}
+	clients.rotate_left(turns % clients.len());
+	clients.sort_by_key(|client| throttled(client));
+}
+
+/// StereoKit doesn't tell us the display's refresh rate, so it's estimated from frame times.
+/// Small changes aren't advertised as every one makes clients reconfigure.
+#[derive(Debug, Clone, Copy)]
+struct RefreshEstimator {
+	/// Smoothed frames per second
+	frame_rate: f64,
+	/// What the outputs advertise as their refresh rate
+	advertised_frame_rate: f64,
+	/// Frames in a row too slow to count towards `frame_rate`
+	slow_frames: u32,
+}
+impl Default for RefreshEstimator {
+	fn default() -> Self {
+		RefreshEstimator {
+			frame_rate: 60.0,
+			advertised_frame_rate: 60.0,
+			slow_frames: 0,
+		}
+	}
+}
+impl RefreshEstimator {
+	/// Returns the new rate to advertise if it changed enough to be worth it
+	fn update(&mut self, frame_time: f64) -> Option<f64> {
+		let frame_rate = 1.0 / frame_time;
+		if !frame_rate.is_finite() {
+			return None;
+		}
+		if frame_rate * MISSED_FRAME_RATIO < self.frame_rate {
+			self.slow_frames += 1;
+			if self.slow_frames < REFRESH_CHANGE_FRAMES {
+				return None;
+			}
+			// easing all the way down would take a while, clients should pace to it right away
+			self.frame_rate = frame_rate;
+		}
+		self.slow_frames = 0;
+		self.frame_rate += (frame_rate - self.frame_rate) * 0.05;
+		// rounded first, the smoothed rate approaching from below would stop 2 short otherwise
+		if (self.frame_rate.round() - self.advertised_frame_rate).abs() < 2.0 {
+			return None;
+		}
+		self.advertised_frame_rate = self.frame_rate.round();
+		Some(self.advertised_frame_rate)
+	}
+	/// The refresh interval for presentation feedback, what the outputs advertise so clients get
+	/// the same rate from both
+	fn interval(&self) -> Duration {
+		Duration::from_secs_f64(1.0 / self.advertised_frame_rate)
+	}
+}
+
+/// The last field is how many clients had their turn dispatching, to know who's next
+pub struct DisplayWrapper(
+	Mutex<Display<WaylandState>>,
+	DisplayHandle,
+	TracedClients,
+	Mutex<usize>,
+);
 impl DisplayWrapper {
 	pub fn handle(&self) -> DisplayHandle {
 		self.1.clone()
 	}
-	pub fn dispatch_clients(&self, state: &mut WaylandState) -> Result<usize, std::io::Error> {
-		self.0.lock().dispatch_clients(state)
+	/// Dispatch clients one at a time, taking turns so one with a lot of requests can't keep
+	/// everyone else waiting: it starts after whoever came last in the previous pass, clients
+	/// flooding commits (see `CommitRate`) go after everyone else and no one else's turn starts
+	/// after `DISPATCH_BUDGET`. Returns if every client had its turn.
+	pub fn dispatch_clients(&self, state: &mut WaylandState) -> bool {
+		let mut display = self.0.lock();
+		let mut clients = self.1.backend_handle().all_clients().collect::<Vec<_>>();
+		self.2.retain(&clients);
+		if clients.is_empty() {
+			return true;
+		}
+		let mut turns = self.3.lock();
+		dispatch_order(&mut clients, *turns, |client| {
+			Client::from_id(&self.1, client.clone())
+				.ok()
+				.and_then(|client| {
+					let client_state = client.get_data::<ClientState>()?;
+					Some(client_state.commit_rate.throttled())
+				})
+				.unwrap_or(false)
+		});
+
+		let start = Instant::now();
+		for (i, client) in clients.iter().enumerate() {
+			if i > 0 && start.elapsed() >= DISPATCH_BUDGET {
+				*turns += i;
+				return false;
+			}
+			let span = self.2.span(client).unwrap_or_else(Span::none);
+			// a client that broke its connection is killed by the backend
+			let _ = span.in_scope(|| {
+				display
+					.backend()
+					.dispatch_single_client(state, client.clone())
+			});
+		}
+		*turns += clients.len();
+		true
+	}
+	/// Dispatch everyone's requests however long that takes, also what destroys the resources
+	/// of killed clients if they haven't had their turn since
+	pub fn dispatch_all_clients(&self, state: &mut WaylandState) -> Result<usize, std::io::Error> {
+		let mut display = self.0.lock();
+		self.2.dispatch(&mut display, state);
+		display.dispatch_clients(state)
+	}
+	/// See `protocol_trace::TracedClients`
+	pub fn trace_client(&self, client: ClientId, pid: Option<i32>) {
+		self.2.add(client, pid);
 	}
 	pub fn flush_clients(&self, client: Option<ClientId>) {
 		if let Some(mut lock) = self.0.try_lock() {
@@ -92,14 +380,40 @@ impl DisplayWrapper {
 	pub fn poll_fd(&self) -> Result<OwnedFd, std::io::Error> {
 		self.0.lock().backend().poll_fd().try_clone_to_owned()
 	}
+	/// Unlike `flush_clients` this waits for the display, so it can't be used while dispatching.
+	pub fn flush_all_clients(&self) {
+		let _ = self.0.lock().backend().flush(None);
+	}
+	pub fn disconnect_clients(&self) {
+		let handle = self.1.backend_handle();
+		let clients = handle.all_clients().collect::<Vec<_>>();
+		for client in clients {
+			handle.kill_client(client, DisconnectReason::ConnectionClosed);
+		}
+	}
 }
 
 pub struct Wayland {
 	display: Arc<DisplayWrapper>,
 	pub socket_name: Option<String>,
+	socket: Option<WaylandSocket>,
 	join_handle: JoinHandle<Result<()>>,
-	renderer: GlesRenderer,
+	state: Arc<Mutex<WaylandState>>,
+	/// `None` once it's shut down
+	renderer: Option<GlesRenderer>,
+	/// The renderer's context got reset, it's recreated once StereoKit has a new one
+	renderer_reset: bool,
+	/// Recreating the renderer after a GPU reset didn't help, surfaces aren't updated anymore
+	renderer_lost: bool,
 	output: Output,
+	frame_sequence: u64,
+	refresh: RefreshEstimator,
+	/// Where surface processing starts next frame, so the same surfaces don't always go over budget
+	upload_offset: usize,
+	background: SurfaceBackground,
+	/// The counters, `metrics` fills in the rest when asked
+	metrics: WaylandMetrics,
+	last_metrics_log: Instant,
 	dmabuf_rx: UnboundedReceiver<(Dmabuf, Option<dmabuf::ImportNotifier>)>,
 	#[cfg(feature = "xwayland_rootful")]
 	pub x_lock: X11Lock,
@@ -107,32 +421,48 @@ pub struct Wayland {
 	pub xwayland_state: XWaylandState,
 }
 impl Wayland {
-	pub fn new() -> Result<Self> {
-		let egl_raw_handles = get_sk_egl()?;
-		let renderer = unsafe {
-			GlesRenderer::new(EGLContext::from_raw(
-				egl_raw_handles.display,
-				egl_raw_handles.config,
-				egl_raw_handles.context,
-			)?)?
-		};
+	/// Listen on `socket_name` in `$XDG_RUNTIME_DIR`, or the first free `wayland-N` if `None`.
+	/// A name that's already taken is an error so clients never end up on the wrong server.
+	pub fn new(socket_name: Option<&str>) -> Result<Self> {
+		let socket = WaylandSocket::bind(socket_name)?;
+		let socket_name = socket.name();
+		Wayland::with_socket(socket, socket_name)
+	}
+	/// Accept clients on a unix socket that's already bound and listening, like one from systemd
+	/// socket activation, instead of binding one. `socket_name` is what clients should use as
+	/// `WAYLAND_DISPLAY`, found from the socket's path if `None`. It's an error if `listener`
+	/// isn't a listening unix stream socket.
+	pub fn from_listener(listener: OwnedFd, socket_name: Option<&str>) -> Result<Self> {
+		let socket = WaylandSocket::inherit(listener)?;
+		let socket_name = socket_name
+			.map(ToString::to_string)
+			.or_else(|| socket.name());
+		Wayland::with_socket(socket, socket_name)
+	}
+	fn with_socket(socket: WaylandSocket, socket_name: Option<String>) -> Result<Self> {
+		let renderer = create_renderer(get_sk_egl()?)?;
 
 		let display: Display<WaylandState> = Display::new()?;
 		let display_handle = display.handle();
 
 		let (dmabuf_tx, dmabuf_rx) = mpsc::unbounded_channel();
-		let display = Arc::new(DisplayWrapper(Mutex::new(display), display_handle.clone()));
+		let display = Arc::new(DisplayWrapper(
+			Mutex::new(display),
+			display_handle.clone(),
+			TracedClients::default(),
+			Mutex::new(0),
+		));
 
 		#[cfg(feature = "xwayland_rootless")]
 		let xwayland_state = XWaylandState::create(&display_handle)?;
-		let wayland_state = WaylandState::new(display_handle, &renderer, dmabuf_tx);
+		let wayland_state = WaylandState::new(
+			display_handle,
+			&renderer,
+			dmabuf_tx,
+			seat::CLOCK.id() as u32,
+		);
 		let output = wayland_state.lock().output.clone();
 
-		let socket = ListeningSocket::bind_auto("wayland", 0..33)?;
-		let socket_name = socket
-			.socket_name()
-			.and_then(OsStr::to_str)
-			.map(ToString::to_string);
 		if let Some(socket_name) = &socket_name {
 			let _ = WAYLAND_DISPLAY.set(socket_name.clone());
 		}
@@ -140,13 +470,23 @@ impl Wayland {
 		let x_display = start_xwayland(socket.as_raw_fd())?;
 		info!(socket_name, "Wayland active");
 
-		let join_handle = Wayland::start_loop(display.clone(), socket, wayland_state)?;
+		let join_handle = Wayland::start_loop(display.clone(), &socket, wayland_state.clone())?;
 		Ok(Wayland {
 			display,
 			socket_name,
+			socket: Some(socket),
 			join_handle,
-			renderer,
+			state: wayland_state,
+			renderer: Some(renderer),
+			renderer_reset: false,
+			renderer_lost: false,
 			output,
+			frame_sequence: 0,
+			refresh: RefreshEstimator::default(),
+			upload_offset: 0,
+			background: SurfaceBackground::default(),
+			metrics: WaylandMetrics::default(),
+			last_metrics_log: Instant::now(),
 			dmabuf_rx,
 			#[cfg(feature = "xwayland_rootful")]
 			x_lock: x_display,
@@ -157,11 +497,13 @@ impl Wayland {
 
 	fn start_loop(
 		display: Arc<DisplayWrapper>,
-		socket: ListeningSocket,
+		socket: &WaylandSocket,
 		state: Arc<Mutex<WaylandState>>,
 	) -> Result<JoinHandle<Result<()>>> {
-		let listen_async =
-			AsyncUnixListener::from_std(unsafe { UnixListener::from_raw_fd(socket.as_raw_fd()) })?;
+		// The socket itself stays with `Wayland` so it can be closed on shutdown
+		let listen_fd =
+			unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) }.try_clone_to_owned()?;
+		let listen_async = AsyncUnixListener::from_std(UnixListener::from(listen_fd))?;
 
 		let dispatch_poll_fd = display.poll_fd()?;
 		let dispatch_poll_listener = AsyncFd::new(dispatch_poll_fd)?;
@@ -169,29 +511,64 @@ impl Wayland {
 		let dh1 = display.handle();
 		let mut dh2 = dh1.clone();
 
+		let (global_destroy_tx, mut global_destroy_rx) = mpsc::unbounded_channel();
+		let _ = GLOBAL_DESTROY_QUEUE.set(global_destroy_tx);
+		let (client_kill_tx, mut client_kill_rx) = mpsc::unbounded_channel();
+		let _ = CLIENT_KILL_QUEUE.set(client_kill_tx);
+
 		Ok(task::new(|| "wayland loop", async move {
-			let _socket = socket; // Keep the socket alive
 			loop {
 				tokio::select! {
 					acc = listen_async.accept() => { // New client connected
 						let (stream, _) = acc?;
+						let credentials = stream.peer_cred().ok().map(|cred| ClientCredentials {
+							pid: cred.pid(),
+							uid: cred.uid(),
+							gid: cred.gid(),
+						});
 						let client_state = Arc::new(ClientState {
 							id: OnceCell::new(),
+							credentials,
 							compositor_state: Default::default(),
 							display: Arc::downgrade(&display),
-							seat: SeatData::new(&dh1)
+							seat: SeatData::new(&dh1),
+							resources: Default::default(),
+							commit_rate: Default::default(),
 						});
+						CLIENTS.add_raw(&client_state);
+						let pid = credentials.and_then(|credentials| credentials.pid);
 						let client = dh2.insert_client(stream.into_std()?, client_state.clone())?;
 						let _ = client_state.seat.client.set(client.id());
+						if protocol_trace::should_trace(pid) {
+							display.trace_client(client.id(), pid);
+						}
+					}
+					Some(global) = global_destroy_rx.recv() => { // Globals to remove
+						let count = remove_globals(&dh2, global, &mut global_destroy_rx);
+						debug!(count, "Removed globals");
+					}
+					Some(client) = client_kill_rx.recv() => { // Clients to disconnect
+						info!(?client, "Killing Wayland client");
+						dh2.backend_handle()
+							.kill_client(client, DisconnectReason::ConnectionClosed);
+						// its resources are only destroyed while dispatching, which a hung
+						// client would never wake the loop up for
+						display.dispatch_all_clients(&mut state.lock())?;
+						display.flush_clients(None);
 					}
 					e = dispatch_poll_listener.readable() => { // Dispatch
 						let mut guard = e?;
-						debug_span!("Dispatch wayland event").in_scope(|| -> Result<(), color_eyre::Report> {
-							display.dispatch_clients(&mut state.lock())?;
+						let dispatched_all = debug_span!("Dispatch wayland event").in_scope(|| {
+							let dispatched_all = display.dispatch_clients(&mut state.lock());
 							display.flush_clients(None);
-							Ok(())
-						})?;
-						guard.clear_ready();
+							dispatched_all
+						});
+						// the clients left over need the next pass even if they don't send more
+						if dispatched_all {
+							guard.clear_ready();
+						}
+						// A busy client makes the poll fd ready again right away, let the rest run
+						tokio::task::yield_now().await;
 					}
 				}
 			}
@@ -200,34 +577,595 @@ impl Wayland {
 
 	#[instrument(level = "debug", name = "Wayland frame", skip(self, sk))]
 	pub fn update(&mut self, sk: &impl StereoKitDraw) {
+		if !self.check_renderer() {
+			return;
+		}
+		let Some(renderer) = self.renderer.as_mut() else {
+			return;
+		};
+		let mut renderer = match CurrentContext::new(renderer) {
+			Ok(renderer) => renderer,
+			Err(err) => {
+				error!(?err, "Could not make the Wayland renderer current");
+				return;
+			}
+		};
 		while let Ok((dmabuf, notifier)) = self.dmabuf_rx.try_recv() {
-			if self.renderer.import_dmabuf(&dmabuf, None).is_err() {
-				if let Some(notifier) = notifier {
-					notifier.failed();
+			match renderer.import_dmabuf(&dmabuf, None) {
+				Ok(_) => {
+					if let Some(notifier) = notifier {
+						let _ = notifier.successful::<WaylandState>();
+					}
+				}
+				Err(err) => {
+					warn!(?err, "Could not import dmabuf");
+					if let Some(notifier) = notifier {
+						notifier.failed();
+					}
 				}
 			}
 		}
-		for core_surface in CORE_SURFACES.get_valid_contents() {
-			core_surface.process(sk, &mut self.renderer);
+		// A client spamming huge buffers only delays its own (and maybe a few other) surfaces,
+		// instead of dropping headset frames
+		let upload_start = Instant::now();
+		let mut core_surfaces = CORE_SURFACES.get_valid_contents();
+		if !core_surfaces.is_empty() {
+			self.upload_offset %= core_surfaces.len();
+			core_surfaces.rotate_left(self.upload_offset);
+		}
+		let mut first_deferred = None;
+		let mut deferred = 0;
+		for (i, core_surface) in core_surfaces.iter().enumerate() {
+			let left = UPLOAD_BUDGET.saturating_sub(upload_start.elapsed());
+			if !core_surface.fits_upload_budget(left) {
+				first_deferred.get_or_insert(i);
+				deferred += 1;
+				core_surface.defer_upload();
+				continue;
+			}
+			let process_start = Instant::now();
+			core_surface.process(sk, &mut renderer, self.background);
+			let process_time = process_start.elapsed();
+			core_surface.processed(process_time);
+			if process_time > SLOW_UPLOAD {
+				self.metrics.slow_uploads += 1;
+			}
 		}
+		if let Some(first_deferred) = first_deferred {
+			debug!(deferred, "Surface upload budget exceeded");
+			self.upload_offset += first_deferred;
+		}
+		self.metrics.upload_time = upload_start.elapsed();
+		for core_surface in &core_surfaces {
+			core_surface.take_snapshots(&mut renderer);
+		}
+		drop(renderer);
+		for core_surface in &core_surfaces {
+			core_surface.update_exports(sk);
+		}
+		decoration::draw_all(sk);
+		debug_overlay::draw_all(sk);
+		data_device::draw_drag_icon(sk);
 
 		self.display.flush_clients(None);
 	}
 
-	pub fn frame_event(&self, sk: &impl StereoKitDraw) {
+	/// After a GPU reset every texture is gone along with the context, which stays unusable even
+	/// once its reset status goes back to `GL_NO_ERROR`. So as soon as StereoKit has a different
+	/// context, make one attempt at starting over with a renderer for it and importing all the
+	/// surfaces again. Returns if the renderer is usable.
+	fn check_renderer(&mut self) -> bool {
+		if self.renderer_lost {
+			return false;
+		}
+		let Some(renderer) = self.renderer.as_mut() else {
+			return false;
+		};
+		if !self.renderer_reset {
+			let status = match CurrentContext::new(renderer)
+				.and_then(|mut renderer| graphics_reset_status(&mut renderer))
+			{
+				Ok(GL_NO_ERROR) => return true,
+				Ok(status) => format!("{status:#x}"),
+				Err(err) => format!("{err:?}"),
+			};
+			warn!(status, "GPU reset, waiting for StereoKit's new context");
+			self.renderer_reset = true;
+		}
+
+		let egl_raw_handles = match get_sk_egl() {
+			Ok(egl_raw_handles) => egl_raw_handles,
+			Err(err) => {
+				error!(
+					?err,
+					"Could not get StereoKit's context, surfaces won't update anymore"
+				);
+				self.renderer_lost = true;
+				return false;
+			}
+		};
+		if egl_raw_handles.context == renderer.egl_context().get_context_handle() as *const c_void {
+			return false;
+		}
+		let renderer = match create_renderer(egl_raw_handles) {
+			Ok(renderer) => renderer,
+			Err(err) => {
+				error!(
+					?err,
+					"Could not recover from GPU reset, surfaces won't update anymore"
+				);
+				self.renderer_lost = true;
+				return false;
+			}
+		};
+		// the old textures died with the context, new ones are imported from the buffers
+		self.renderer = Some(renderer);
+		self.renderer_reset = false;
 		for core_surface in CORE_SURFACES.get_valid_contents() {
-			core_surface.frame(sk, self.output.clone());
+			core_surface.reset_texture();
 		}
+		info!("Recovered from GPU reset");
+		true
 	}
 
-	pub fn make_context_current(&self) {
-		unsafe {
-			let _ = self.renderer.egl_context().make_current();
+	pub fn frame_event(&mut self, sk: &impl StereoKitDraw) {
+		self.frame_sequence += 1;
+		if let Some(frame_rate) = self.refresh.update(sk.time_elapsed_unscaled()) {
+			self.state.lock().set_refresh((frame_rate * 1000.0) as i32);
+		}
+		let timing = FrameTiming {
+			time: seat::CLOCK.now(),
+			refresh: self.refresh.interval(),
+			sequence: self.frame_sequence,
+		};
+		// before the frame callbacks so clients have the pointer where it is when they draw
+		for client_state in CLIENTS.get_valid_contents() {
+			client_state.seat.send_pointer_motion();
+		}
+		let mut visible_outputs = Vec::new();
+		for core_surface in CORE_SURFACES.get_valid_contents() {
+			if core_surface.frame(sk, &self.output, &timing) {
+				visible_outputs.extend(core_surface.output());
+			}
+		}
+		self.state.lock().update_output_activity(&visible_outputs);
+		self.metrics.frames += 1;
+		if self.last_metrics_log.elapsed() >= LOG_INTERVAL {
+			self.last_metrics_log = Instant::now();
+			let metrics = self.metrics();
+			debug!(?metrics, "Wayland metrics");
+		}
+	}
+
+	/// Another output clients can go fullscreen on, see `WaylandState::create_output`
+	pub fn create_output(&self, name: &str, size: Vector2<u32>, scale: i32) {
+		self.state.lock().create_output(name, size, scale);
+	}
+	pub fn destroy_output(&self, name: &str) {
+		self.state.lock().destroy_output(name)
+	}
+	/// Assign a surface to a given output, or the default output if `None`
+	pub fn set_surface_output(&self, surface: &WlSurface, output: Option<&str>) {
+		let Some(core_surface) = CoreSurface::from_wl_surface(surface) else {
+			return;
+		};
+		let state = self.state.lock();
+		let output = output
+			.and_then(|name| state.get_output(name))
+			.unwrap_or_else(|| state.output.clone());
+		core_surface.set_output(Some(output));
+	}
+
+	/// Whether a client is keeping the session from going idle, e.g. while playing a video.
+	/// Cheap enough to ask every frame, unlike building all of `metrics`.
+	pub fn idle_inhibited(&self) -> bool {
+		idle_inhibit::idle_inhibited()
+	}
+
+	/// Replace the policy deciding when surfaces get keyboard focus, e.g. for click to focus
+	pub fn set_focus_policy(&self, policy: impl FocusPolicy + 'static) {
+		focus_policy::set_focus_policy(Box::new(policy));
+	}
+
+	/// Transparent for AR so panels float over passthrough, or a solid color for VR.
+	/// Every surface gets composited again on the next frame.
+	pub fn set_background(&mut self, background: SurfaceBackground) {
+		self.background = background;
+	}
+
+	/// Clients going over these get disconnected, or dispatched last for going over the commits
+	pub fn set_client_limits(&self, limits: ClientLimits) {
+		*CLIENT_LIMITS.lock() = limits;
+		debug!(
+			?limits,
+			max_buffer_size = client_limits::max_buffer_size(),
+			"Client limits changed"
+		);
+	}
+	/// Which input devices clients' seats have, e.g. without touch for a shell that never sends
+	/// any. Every client's told right away, and input for what was taken away isn't sent.
+	pub fn set_seat_capabilities(&self, capabilities: SeatCapabilities) {
+		*SEAT_CAPABILITIES.lock() = capabilities;
+		for client_state in CLIENTS.get_valid_contents() {
+			client_state.seat.update_capabilities();
+		}
+		self.display.flush_clients(None);
+	}
+	/// What of the clipboard the server keeps a copy of so it outlives the client it came from.
+	/// Selections set after this use it.
+	pub fn set_clipboard_cache(&self, cache: ClipboardCache) {
+		*CLIPBOARD_CACHE.lock() = cache;
+	}
+	/// How the titlebars of server-side decorated toplevels look, unless the shell themed a
+	/// panel item with `set_decoration_theme`. They're all redrawn with it on the next frame.
+	pub fn set_decoration_theme(&self, theme: DecorationTheme) {
+		*DECORATION_THEME.lock() = Arc::new(theme);
+	}
+	/// Draw the buffer size, logical size, scale and frame rate of each toplevel over its panel,
+	/// for layout and scale bugs. Starts on if `STARDUST_DEBUG_OVERLAY` is set.
+	pub fn set_debug_overlay(&self, enabled: bool) {
+		DEBUG_OVERLAY.store(enabled, Ordering::Relaxed);
+	}
+	/// Time input from when the shell sends it until the client's reaction is uploaded, for
+	/// `metrics` and at trace level. Starts on if `STARDUST_INPUT_LATENCY` is set.
+	pub fn set_input_latency_tracking(&self, enabled: bool) {
+		TRACK_INPUT_LATENCY.store(enabled, Ordering::Relaxed);
+	}
+	/// How many textures composited surfaces cycle through, from 1 to 3. More let high frame
+	/// rate clients like video update without waiting on the draw, at the cost of memory.
+	/// Surfaces pick it up on their next commit.
+	pub fn set_texture_buffers(&self, buffers: usize) {
+		TEXTURE_BUFFERS.store(buffers.clamp(1, MAX_TEXTURE_BUFFERS), Ordering::Relaxed);
+	}
+	/// The largest buffer width or height clients can use, bigger ones are a protocol error.
+	/// This is `ClientLimits::buffer_size` unless the GPU supports less, so a shell can tell
+	/// clients what to stay under.
+	pub fn max_buffer_size(&self) -> u32 {
+		client_limits::max_buffer_size()
+	}
+	/// Every connected client with who it is and what it has open, for something like a task
+	/// manager. Unlike `Backend::client_info` this includes clients without any toplevel, like
+	/// clipboard managers or ones that never mapped, which can be killed with `kill_client`.
+	/// Xwayland's clients are all behind its one connection.
+	pub fn clients(&self) -> Vec<(ClientId, ClientInfo)> {
+		let display_handle = self.display.handle();
+		let backend_handle = display_handle.backend_handle();
+		backend_handle
+			.all_clients()
+			.filter_map(|id| {
+				let client = Client::from_id(&display_handle, id.clone()).ok()?;
+				Some((id, client_info(&client)?))
+			})
+			.collect()
+	}
+	pub fn metrics(&self) -> WaylandMetrics {
+		let core_surfaces = CORE_SURFACES.get_valid_contents();
+		let texture_bytes = core_surfaces
+			.iter()
+			.filter_map(|core_surface| core_surface.texture_bytes())
+			.collect::<Vec<_>>();
+		WaylandMetrics {
+			clients: CLIENTS.get_valid_contents().len(),
+			surfaces: core_surfaces.len(),
+			textures: texture_bytes.len(),
+			texture_bytes: texture_bytes.iter().sum(),
+			frames_composited: metrics::frames_composited(),
+			input_dispatch_latency: metrics::latency(LatencyStage::Dispatch),
+			input_client_latency: metrics::latency(LatencyStage::Client),
+			input_render_latency: metrics::latency(LatencyStage::Render),
+			input_latency: metrics::latency(LatencyStage::Total),
+			idle_inhibited: idle_inhibit::idle_inhibited(),
+			..self.metrics
 		}
 	}
 }
+impl Wayland {
+	/// Tear everything down in order while StereoKit is still running, as GL objects freed
+	/// after its context is gone crash on exit: stop the loop, disconnect every client so
+	/// their surfaces go away, free the textures that are left with the context current and
+	/// only then drop the renderer. Dropping `Wayland` does this if it didn't happen yet.
+	pub fn shutdown(&mut self) {
+		let Some(renderer) = self.renderer.take() else {
+			return;
+		};
+		info!("Shutting down Wayland");
+		teardown(&mut Shutdown {
+			wayland: self,
+			renderer: Some(renderer),
+		});
+		info!("Wayland shut down");
+	}
+}
+
+/// The steps of [`Wayland::shutdown`], each relying on the ones before it having run
+trait Teardown {
+	fn stop_loop(&mut self);
+	fn disconnect_clients(&mut self);
+	fn free_textures(&mut self);
+	fn drop_renderer(&mut self);
+}
+fn teardown(steps: &mut impl Teardown) {
+	steps.stop_loop();
+	steps.disconnect_clients();
+	steps.free_textures();
+	steps.drop_renderer();
+}
+
+struct Shutdown<'a> {
+	wayland: &'a mut Wayland,
+	renderer: Option<GlesRenderer>,
+}
+impl Teardown for Shutdown<'_> {
+	fn stop_loop(&mut self) {
+		self.wayland.join_handle.abort();
+		// Removes the socket file so no new clients can connect
+		self.wayland.socket.take();
+	}
+	fn disconnect_clients(&mut self) {
+		let Wayland { display, state, .. } = &mut *self.wayland;
+		// Handle the last requests (e.g. a clipboard manager's final selection) and send every
+		// event before the connections go away, so no transfer is left half finished
+		if let Err(err) = display.dispatch_all_clients(&mut state.lock()) {
+			warn!(?err, "Could not dispatch clients on shutdown");
+		}
+		display.flush_all_clients();
+		display.disconnect_clients();
+		// clients' resources are only destroyed while dispatching
+		if let Err(err) = display.dispatch_all_clients(&mut state.lock()) {
+			warn!(?err, "Could not destroy client resources on shutdown");
+		}
+	}
+	fn free_textures(&mut self) {
+		let Some(renderer) = self.renderer.as_mut() else {
+			return;
+		};
+		match CurrentContext::new(renderer) {
+			Ok(mut renderer) => {
+				// surfaces that are still around, e.g. held by Xwayland
+				for core_surface in CORE_SURFACES.get_valid_contents() {
+					core_surface.free_textures();
+				}
+				destroy_queue::clear();
+				if let Err(err) = renderer.cleanup_texture_cache() {
+					warn!(?err, "Could not free textures on shutdown");
+				}
+			}
+			Err(err) => error!(
+				?err,
+				"Could not make the Wayland renderer current on shutdown"
+			),
+		}
+	}
+	fn drop_renderer(&mut self) {
+		// it makes its context current to delete its programs
+		let restore = RestoreContext::save();
+		self.renderer.take();
+		drop(restore);
+	}
+}
 impl Drop for Wayland {
 	fn drop(&mut self) {
-		self.join_handle.abort();
+		self.shutdown();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use smithay::backend::{
+		allocator::{dmabuf::DmabufFlags, Format, Fourcc, Modifier},
+		egl::{EGLDevice, EGLDisplay},
+	};
+	use smithay::reexports::wayland_protocols::wp::pointer_constraints::zv1::server::{
+		zwp_pointer_constraints_v1::ZwpPointerConstraintsV1,
+	};
+	use std::{cell::RefCell, os::fd::FromRawFd, rc::Rc};
+
+	/// A linear `width`x`height` ARGB8888 dmabuf made from a memfd, so no GPU allocator's needed
+	fn udmabuf(width: u32, height: u32) -> Dmabuf {
+		/// `_IOW('u', 0x42, struct udmabuf_create)`
+		const UDMABUF_CREATE: libc::c_ulong = 0x4018_7542;
+		#[repr(C)]
+		struct UdmabufCreate {
+			memfd: u32,
+			flags: u32,
+			offset: u64,
+			size: u64,
+		}
+		let size = (width * height * 4) as u64;
+		unsafe {
+			let memfd = libc::memfd_create(
+				b"dmabuf\0".as_ptr() as *const _,
+				libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+			);
+			assert!(memfd >= 0);
+			let memfd = OwnedFd::from_raw_fd(memfd);
+			assert_eq!(libc::ftruncate(memfd.as_raw_fd(), size as _), 0);
+			assert_eq!(
+				libc::fcntl(memfd.as_raw_fd(), libc::F_ADD_SEALS, libc::F_SEAL_SHRINK),
+				0
+			);
+			let device = std::fs::File::open("/dev/udmabuf").unwrap();
+			let create = UdmabufCreate {
+				memfd: memfd.as_raw_fd() as u32,
+				flags: 1, // UDMABUF_FLAGS_CLOEXEC
+				offset: 0,
+				size,
+			};
+			let fd = libc::ioctl(device.as_raw_fd(), UDMABUF_CREATE, &create);
+			assert!(fd >= 0);
+			let mut builder = Dmabuf::builder(
+				(width as i32, height as i32),
+				Fourcc::Argb8888,
+				DmabufFlags::empty(),
+			);
+			builder.add_plane(OwnedFd::from_raw_fd(fd), 0, 0, width * 4, Modifier::Linear);
+			builder.build().unwrap()
+		}
+	}
+
+	#[test]
+	#[ignore = "needs a GPU render node and /dev/udmabuf"]
+	fn linear_dmabufs_import_into_textures() {
+		let device = EGLDevice::enumerate()
+			.unwrap()
+			.find(|device| matches!(device.try_get_render_node(), Ok(Some(_))))
+			.expect("No GPU with a render node");
+		let display = EGLDisplay::new(device).unwrap();
+		let mut renderer =
+			unsafe { GlesRenderer::new(EGLContext::new(&display).unwrap()) }.unwrap();
+		// what clients are told they can use
+		let linear = Format {
+			code: Fourcc::Argb8888,
+			modifier: Modifier::Linear,
+		};
+		assert!(renderer
+			.egl_context()
+			.dmabuf_texture_formats()
+			.contains(&linear));
+
+		let texture = renderer.import_dmabuf(&udmabuf(64, 64), None).unwrap();
+		assert_ne!(texture.tex_id(), 0);
+	}
+
+	#[test]
+	fn queued_globals_are_all_removed_at_once() {
+		let display = Display::<WaylandState>::new().unwrap();
+		let dh = display.handle();
+		let (queue, mut queued) = mpsc::unbounded_channel();
+		// like a disconnect storm, queued faster than the loop gets to them
+		let globals = (0..100)
+			.map(|_| dh.create_global::<WaylandState, ZwpPointerConstraintsV1, _>(1, ()))
+			.collect::<Vec<_>>();
+		for global in &globals[1..] {
+			queue.send(global.clone()).unwrap();
+		}
+		assert_eq!(remove_globals(&dh, globals[0].clone(), &mut queued), 100);
+		assert!(queued.try_recv().is_err());
+		for global in globals {
+			assert!(dh.backend_handle().global_info(global).is_err());
+		}
+	}
+
+	/// Logs its name when dropped, standing in for the renderer
+	struct Dropped(Rc<RefCell<Vec<&'static str>>>);
+	impl Drop for Dropped {
+		fn drop(&mut self) {
+			self.0.borrow_mut().push("renderer dropped");
+		}
+	}
+	struct Steps {
+		log: Rc<RefCell<Vec<&'static str>>>,
+		renderer: Option<Dropped>,
+	}
+	impl Teardown for Steps {
+		fn stop_loop(&mut self) {
+			self.log.borrow_mut().push("loop stopped");
+		}
+		fn disconnect_clients(&mut self) {
+			self.log.borrow_mut().push("clients disconnected");
+		}
+		fn free_textures(&mut self) {
+			assert!(self.renderer.is_some(), "textures freed without a renderer");
+			self.log.borrow_mut().push("textures freed");
+		}
+		fn drop_renderer(&mut self) {
+			self.renderer.take();
+		}
+	}
+
+	#[test]
+	fn textures_are_freed_before_the_renderer_goes() {
+		let log = Rc::new(RefCell::new(Vec::new()));
+		let mut steps = Steps {
+			log: log.clone(),
+			renderer: Some(Dropped(log.clone())),
+		};
+		teardown(&mut steps);
+		assert_eq!(
+			*log.borrow(),
+			[
+				"loop stopped",
+				"clients disconnected",
+				"textures freed",
+				"renderer dropped",
+			]
+		);
+	}
+
+	#[test]
+	fn dispatch_takes_turns() {
+		let order = |turns| {
+			let mut clients = [0, 1, 2, 3];
+			dispatch_order(&mut clients, turns, |_| false);
+			clients
+		};
+		assert_eq!(order(0), [0, 1, 2, 3]);
+		// Client 1 was the last to have its turn before the budget ran out
+		assert_eq!(order(2), [2, 3, 0, 1]);
+		assert_eq!(order(6), [2, 3, 0, 1]);
+	}
+
+	#[test]
+	fn throttled_clients_dispatch_last() {
+		let mut clients = [0, 1, 2, 3, 4];
+		dispatch_order(&mut clients, 1, |client| *client == 1 || *client == 3);
+		assert_eq!(clients, [2, 4, 0, 1, 3]);
+
+		let mut empty: [u32; 0] = [];
+		dispatch_order(&mut empty, 3, |_| true);
+	}
+
+	/// Run `frames` frames at `hz`, returning the last rate that got advertised
+	fn run(refresh: &mut RefreshEstimator, hz: f64, frames: u32) -> Option<f64> {
+		(0..frames).fold(None, |advertised, _| {
+			refresh.update(1.0 / hz).or(advertised)
+		})
+	}
+
+	#[test]
+	fn refresh_converges_on_the_display_rate() {
+		let mut refresh = RefreshEstimator::default();
+		assert_eq!(run(&mut refresh, 90.0, 200), Some(90.0));
+		assert_eq!(refresh.advertised_frame_rate, 90.0);
+		// Jitter around the rate isn't worth making clients reconfigure
+		assert_eq!(run(&mut refresh, 89.0, 200), None);
+		assert_eq!(refresh.update(0.0), None);
+	}
+
+	#[test]
+	fn presentation_refresh_matches_the_display() {
+		let mut refresh = RefreshEstimator::default();
+		assert_eq!(refresh.interval(), Duration::from_secs_f64(1.0 / 60.0));
+		let advertised = run(&mut refresh, 90.0, 200).unwrap();
+		assert_eq!(
+			refresh.interval(),
+			Duration::from_secs_f64(1.0 / advertised)
+		);
+		assert!((refresh.interval().as_secs_f64() - 1.0 / 90.0).abs() < 1e-5);
+	}
+
+	#[test]
+	fn refresh_ignores_hitches() {
+		let mut refresh = RefreshEstimator::default();
+		run(&mut refresh, 90.0, 200);
+		for _ in 0..5 {
+			// A few missed vsyncs in a row, then back to normal
+			assert_eq!(run(&mut refresh, 30.0, REFRESH_CHANGE_FRAMES - 1), None);
+			assert_eq!(run(&mut refresh, 90.0, 1), None);
+		}
+		assert_eq!(refresh.advertised_frame_rate, 90.0);
+		assert!((refresh.frame_rate - 90.0).abs() < 1.0);
+	}
+
+	#[test]
+	fn refresh_follows_a_switch_to_half_rate() {
+		let mut refresh = RefreshEstimator::default();
+		run(&mut refresh, 90.0, 200);
+		assert_eq!(run(&mut refresh, 45.0, REFRESH_CHANGE_FRAMES - 1), None);
+		// Advertised right away instead of easing down over a few seconds
+		assert_eq!(refresh.update(1.0 / 45.0), Some(45.0));
+		assert_eq!(run(&mut refresh, 45.0, 200), None);
+		assert_eq!(refresh.advertised_frame_rate, 45.0);
 	}
 }
@@ -0,0 +1,186 @@
+use super::{
+	seat::{PointerConstraint, PointerConstraintKind, SeatData},
+	state::WaylandState,
+};
+use smithay::{
+	reexports::{
+		wayland_protocols::wp::pointer_constraints::zv1::server::{
+			zwp_confined_pointer_v1::{self, ZwpConfinedPointerV1},
+			zwp_locked_pointer_v1::{self, ZwpLockedPointerV1},
+			zwp_pointer_constraints_v1::{self, Lifetime, ZwpPointerConstraintsV1},
+		},
+		wayland_server::{
+			backend::ClientId, protocol::wl_surface::WlSurface, Client, DataInit, Dispatch,
+			DisplayHandle, GlobalDispatch, New, Resource, WEnum, Weak as WlWeak,
+		},
+	},
+	wayland::compositor,
+};
+use std::sync::Arc;
+
+pub struct PointerConstraintData {
+	seat: Arc<SeatData>,
+	surface: WlWeak<WlSurface>,
+}
+impl PointerConstraintData {
+	fn set_region(&self, region: Option<compositor::RegionAttributes>) {
+		let Ok(surface) = self.surface.upgrade() else {
+			return;
+		};
+		self.seat.set_pointer_constraint_region(&surface, region);
+	}
+	fn remove(&self) {
+		self.seat.remove_pointer_constraint(&self.surface.id());
+	}
+}
+
+impl GlobalDispatch<ZwpPointerConstraintsV1, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZwpPointerConstraintsV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ZwpPointerConstraintsV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		resource: &ZwpPointerConstraintsV1,
+		request: zwp_pointer_constraints_v1::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		let (surface, pointer, region, lifetime, kind) = match request {
+			zwp_pointer_constraints_v1::Request::LockPointer {
+				id,
+				surface,
+				pointer,
+				region,
+				lifetime,
+			} => {
+				let Some(seat) = pointer.data::<Arc<SeatData>>().cloned() else {
+					return;
+				};
+				let locked = data_init.init(
+					id,
+					PointerConstraintData {
+						seat,
+						surface: surface.downgrade(),
+					},
+				);
+				(
+					surface,
+					pointer,
+					region,
+					lifetime,
+					PointerConstraintKind::Locked(locked),
+				)
+			}
+			zwp_pointer_constraints_v1::Request::ConfinePointer {
+				id,
+				surface,
+				pointer,
+				region,
+				lifetime,
+			} => {
+				let Some(seat) = pointer.data::<Arc<SeatData>>().cloned() else {
+					return;
+				};
+				let confined = data_init.init(
+					id,
+					PointerConstraintData {
+						seat,
+						surface: surface.downgrade(),
+					},
+				);
+				(
+					surface,
+					pointer,
+					region,
+					lifetime,
+					PointerConstraintKind::Confined(confined),
+				)
+			}
+			zwp_pointer_constraints_v1::Request::Destroy => return,
+			_ => unreachable!(),
+		};
+		let Some(seat) = pointer.data::<Arc<SeatData>>() else {
+			return;
+		};
+		let region = region.map(|region| compositor::get_region_attributes(&region));
+		let oneshot = matches!(lifetime, WEnum::Value(Lifetime::Oneshot));
+		if !seat.add_pointer_constraint(&surface, PointerConstraint::new(kind, region, oneshot)) {
+			resource.post_error(
+				zwp_pointer_constraints_v1::Error::AlreadyConstrained,
+				"Surface already has a pointer constraint on this seat",
+			);
+		}
+	}
+}
+
+impl Dispatch<ZwpLockedPointerV1, PointerConstraintData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwpLockedPointerV1,
+		request: zwp_locked_pointer_v1::Request,
+		data: &PointerConstraintData,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			// We never warp the pointer, the shell decides where it is
+			zwp_locked_pointer_v1::Request::SetCursorPositionHint { .. } => (),
+			zwp_locked_pointer_v1::Request::SetRegion { region } => {
+				data.set_region(region.map(|region| compositor::get_region_attributes(&region)));
+			}
+			zwp_locked_pointer_v1::Request::Destroy => data.remove(),
+			_ => unreachable!(),
+		}
+	}
+
+	fn destroyed(
+		_state: &mut WaylandState,
+		_client: ClientId,
+		_resource: &ZwpLockedPointerV1,
+		data: &PointerConstraintData,
+	) {
+		data.remove();
+	}
+}
+
+impl Dispatch<ZwpConfinedPointerV1, PointerConstraintData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwpConfinedPointerV1,
+		request: zwp_confined_pointer_v1::Request,
+		data: &PointerConstraintData,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_confined_pointer_v1::Request::SetRegion { region } => {
+				data.set_region(region.map(|region| compositor::get_region_attributes(&region)));
+			}
+			zwp_confined_pointer_v1::Request::Destroy => data.remove(),
+			_ => unreachable!(),
+		}
+	}
+
+	fn destroyed(
+		_state: &mut WaylandState,
+		_client: ClientId,
+		_resource: &ZwpConfinedPointerV1,
+		data: &PointerConstraintData,
+	) {
+		data.remove();
+	}
+}
@@ -0,0 +1,217 @@
+use super::{
+	serial::valid_selection,
+	state::{ClientState, WaylandState},
+	transfer::{offered_mime_types, receive},
+};
+use parking_lot::Mutex;
+use smithay::reexports::{
+	wayland_protocols::wp::primary_selection::zv1::server::{
+		zwp_primary_selection_device_manager_v1::{self, ZwpPrimarySelectionDeviceManagerV1},
+		zwp_primary_selection_device_v1::{self, ZwpPrimarySelectionDeviceV1},
+		zwp_primary_selection_offer_v1::{self, ZwpPrimarySelectionOfferV1},
+		zwp_primary_selection_source_v1::{self, ZwpPrimarySelectionSourceV1},
+	},
+	wayland_server::{
+		backend::ClientId, Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+	},
+};
+
+/// The middle-click selection, shared by every client since they all sit at the same seat
+#[derive(Default)]
+pub struct PrimarySelectionState {
+	devices: Vec<ZwpPrimarySelectionDeviceV1>,
+	selection: Option<ZwpPrimarySelectionSourceV1>,
+}
+impl PrimarySelectionState {
+	fn set_selection(&mut self, dh: &DisplayHandle, source: Option<ZwpPrimarySelectionSourceV1>) {
+		if let Some(old_source) = self.selection.take() {
+			if Some(&old_source) != source.as_ref() && old_source.is_alive() {
+				old_source.cancelled();
+			}
+		}
+		self.selection = source;
+		self.devices.retain(|device| device.is_alive());
+		for device in &self.devices {
+			self.offer_selection(dh, device);
+		}
+	}
+	fn offer_selection(&self, dh: &DisplayHandle, device: &ZwpPrimarySelectionDeviceV1) {
+		let Some(source) = &self.selection else {
+			device.selection(None);
+			return;
+		};
+		let Some(client) = device.client() else {
+			return;
+		};
+		let Ok(offer) = client.create_resource::<ZwpPrimarySelectionOfferV1, _, WaylandState>(
+			dh,
+			device.version(),
+			source.clone(),
+		) else {
+			return;
+		};
+		device.data_offer(&offer);
+		for mime_type in offered_mime_types(&mime_types(source)) {
+			offer.offer(mime_type);
+		}
+		device.selection(Some(&offer));
+	}
+}
+
+fn flush(source: &ZwpPrimarySelectionSourceV1) {
+	let Some(client) = source.client() else {
+		return;
+	};
+	if let Some(client_state) = client.get_data::<ClientState>() {
+		client_state.flush();
+	}
+}
+fn mime_types(source: &ZwpPrimarySelectionSourceV1) -> Vec<String> {
+	source
+		.data::<Mutex<Vec<String>>>()
+		.map(|mime_types| mime_types.lock().clone())
+		.unwrap_or_default()
+}
+
+impl GlobalDispatch<ZwpPrimarySelectionDeviceManagerV1, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZwpPrimarySelectionDeviceManagerV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ZwpPrimarySelectionDeviceManagerV1, (), WaylandState> for WaylandState {
+	fn request(
+		state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwpPrimarySelectionDeviceManagerV1,
+		request: zwp_primary_selection_device_manager_v1::Request,
+		_data: &(),
+		dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_primary_selection_device_manager_v1::Request::CreateSource { id } => {
+				data_init.init(id, Mutex::new(Vec::<String>::new()));
+			}
+			zwp_primary_selection_device_manager_v1::Request::GetDevice { id, seat: _ } => {
+				let device = data_init.init(id, ());
+				let primary_selection = &mut state.primary_selection_state;
+				if primary_selection.selection.is_some() {
+					primary_selection.offer_selection(dh, &device);
+				}
+				primary_selection.devices.push(device);
+			}
+			zwp_primary_selection_device_manager_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ZwpPrimarySelectionSourceV1, Mutex<Vec<String>>, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwpPrimarySelectionSourceV1,
+		request: zwp_primary_selection_source_v1::Request,
+		mime_types: &Mutex<Vec<String>>,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_primary_selection_source_v1::Request::Offer { mime_type } => {
+				mime_types.lock().push(mime_type);
+			}
+			zwp_primary_selection_source_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+
+	fn destroyed(
+		state: &mut WaylandState,
+		_client: ClientId,
+		resource: &ZwpPrimarySelectionSourceV1,
+		_data: &Mutex<Vec<String>>,
+	) {
+		let primary_selection = &mut state.primary_selection_state;
+		if primary_selection.selection.as_ref() == Some(resource) {
+			let dh = state.display_handle.clone();
+			state.primary_selection_state.set_selection(&dh, None);
+		}
+	}
+}
+
+impl Dispatch<ZwpPrimarySelectionDeviceV1, (), WaylandState> for WaylandState {
+	fn request(
+		state: &mut WaylandState,
+		client: &Client,
+		_resource: &ZwpPrimarySelectionDeviceV1,
+		request: zwp_primary_selection_device_v1::Request,
+		_data: &(),
+		dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_primary_selection_device_v1::Request::SetSelection { source, serial } => {
+				let Some(client_state) = client.get_data::<ClientState>() else {
+					return;
+				};
+				if !valid_selection(&client_state.seat, serial) {
+					if let Some(source) = source {
+						source.cancelled();
+					}
+					return;
+				}
+				state.primary_selection_state.set_selection(dh, source);
+			}
+			zwp_primary_selection_device_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+
+	fn destroyed(
+		state: &mut WaylandState,
+		_client: ClientId,
+		resource: &ZwpPrimarySelectionDeviceV1,
+		_data: &(),
+	) {
+		state
+			.primary_selection_state
+			.devices
+			.retain(|device| device != resource);
+	}
+}
+
+impl Dispatch<ZwpPrimarySelectionOfferV1, ZwpPrimarySelectionSourceV1, WaylandState>
+	for WaylandState
+{
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwpPrimarySelectionOfferV1,
+		request: zwp_primary_selection_offer_v1::Request,
+		source: &ZwpPrimarySelectionSourceV1,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_primary_selection_offer_v1::Request::Receive { mime_type, fd } => {
+				// The source may have been replaced since this was offered
+				if source.is_alive() {
+					receive(&mime_types(source), mime_type, fd, |mime_type, fd| {
+						source.send(mime_type, fd);
+						flush(source);
+					});
+				}
+			}
+			zwp_primary_selection_offer_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
@@ -0,0 +1,71 @@
+use super::state::WaylandState;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use smithay::reexports::wayland_server::{backend::ClientId, Display};
+use tracing::{debug, debug_span, info, Span};
+
+static TRACE_FILTER: Lazy<Option<Vec<String>>> = Lazy::new(|| {
+	let filter = std::env::var("STARDUST_WAYLAND_TRACE").ok()?;
+	Some(
+		filter
+			.split(',')
+			.map(|entry| entry.trim().to_string())
+			.filter(|entry| !entry.is_empty())
+			.collect(),
+	)
+});
+
+/// Opt-in tracing of clients, to debug misbehaving ones.
+/// Set `STARDUST_WAYLAND_TRACE` to `all` or a comma separated list of PIDs or executable names.
+pub fn should_trace(pid: Option<i32>) -> bool {
+	let Some(filter) = TRACE_FILTER.as_ref() else {
+		return false;
+	};
+	let executable = pid.and_then(executable_name);
+	filter.iter().any(|entry| {
+		entry == "all"
+			|| pid.is_some_and(|pid| *entry == pid.to_string())
+			|| executable.as_ref() == Some(entry)
+	})
+}
+fn executable_name(pid: i32) -> Option<String> {
+	let comm = std::fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+	Some(comm.trim().to_string())
+}
+
+/// Traced clients get their requests dispatched on their own in a span, so everything logged
+/// while handling them (by us and smithay) can be told apart from other clients' and filtered
+/// on like any other span.
+#[derive(Default)]
+pub struct TracedClients(Mutex<Vec<(ClientId, Span)>>);
+impl TracedClients {
+	pub fn add(&self, client: ClientId, pid: Option<i32>) {
+		let span = debug_span!("Wayland client trace", pid, ?client);
+		span.in_scope(|| info!("Tracing client"));
+		self.0.lock().push((client, span));
+	}
+	/// The span to dispatch `client` in if it's traced
+	pub fn span(&self, client: &ClientId) -> Option<Span> {
+		let clients = self.0.lock();
+		let (_, span) = clients.iter().find(|(id, _)| id == client)?;
+		Some(span.clone())
+	}
+	/// Forget clients that aren't in `clients` anymore
+	pub fn retain(&self, clients: &[ClientId]) {
+		self.0.lock().retain(|(client, _)| clients.contains(client));
+	}
+	/// Dispatch the requests of every traced client, forgetting the ones that are gone
+	pub fn dispatch(&self, display: &mut Display<WaylandState>, state: &mut WaylandState) {
+		self.0.lock().retain(|(client, span)| {
+			span.in_scope(|| {
+				let dispatched = display
+					.backend()
+					.dispatch_single_client(state, client.clone());
+				if let Err(err) = &dispatched {
+					debug!(?err, "Client trace stopped");
+				}
+				dispatched.is_ok()
+			})
+		});
+	}
+}
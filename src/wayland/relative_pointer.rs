@@ -0,0 +1,74 @@
+use super::{seat::SeatData, state::WaylandState};
+use smithay::reexports::{
+	wayland_protocols::wp::relative_pointer::zv1::server::{
+		zwp_relative_pointer_manager_v1::{self, ZwpRelativePointerManagerV1},
+		zwp_relative_pointer_v1::{self, ZwpRelativePointerV1},
+	},
+	wayland_server::{
+		backend::ClientId, Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+	},
+};
+use std::sync::Arc;
+
+impl GlobalDispatch<ZwpRelativePointerManagerV1, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZwpRelativePointerManagerV1>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ZwpRelativePointerManagerV1, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwpRelativePointerManagerV1,
+		request: zwp_relative_pointer_manager_v1::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_relative_pointer_manager_v1::Request::GetRelativePointer { id, pointer } => {
+				let Some(seat) = pointer.data::<Arc<SeatData>>().cloned() else {
+					return;
+				};
+				let relative_pointer = data_init.init(id, seat.clone());
+				seat.add_relative_pointer(relative_pointer);
+			}
+			zwp_relative_pointer_manager_v1::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ZwpRelativePointerV1, Arc<SeatData>, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		resource: &ZwpRelativePointerV1,
+		request: zwp_relative_pointer_v1::Request,
+		seat: &Arc<SeatData>,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_relative_pointer_v1::Request::Destroy => seat.remove_relative_pointer(resource),
+			_ => unreachable!(),
+		}
+	}
+
+	fn destroyed(
+		_state: &mut WaylandState,
+		_client: ClientId,
+		resource: &ZwpRelativePointerV1,
+		seat: &Arc<SeatData>,
+	) {
+		seat.remove_relative_pointer(resource);
+	}
+}
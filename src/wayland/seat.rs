@@ -1,40 +1,63 @@
 use super::{
+	buffer_offset::buffer_offset,
+	data_device::drag_pointer_event,
+	focus_policy::{self, PointerFocusEvent},
+	metrics::PendingInput,
+	serial::{SerialEvent, SerialLog},
 	state::{ClientState, WaylandState},
-	surface::CoreSurface,
+	surface::{give_role, CoreSurface, CURSOR_ROLE, TOP_MATERIAL_OFFSET},
+	tablet::Tablets,
+	text_input::TextInputs,
 	SERIAL_COUNTER,
 };
 use crate::{
 	core::task,
-	nodes::items::panel::{Backend, Geometry, PanelItem},
+	nodes::items::panel::{Backend, Geometry, KeyboardLeds, KeyboardState, PanelItem},
 };
 use color_eyre::eyre::{bail, eyre, Result};
 use mint::Vector2;
-use nanoid::nanoid;
-use once_cell::sync::OnceCell;
-use parking_lot::Mutex;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::{const_mutex, Mutex};
 use rand::{seq::IteratorRandom, thread_rng};
 use rustc_hash::{FxHashMap, FxHashSet};
 use smithay::{
 	input::keyboard::{KeymapFile, ModifiersState},
-	reexports::wayland_server::{
-		backend::{ClientId, GlobalId, ObjectId},
-		protocol::{
-			wl_keyboard::{self, KeyState, WlKeyboard},
-			wl_pointer::{self, Axis, ButtonState, WlPointer},
-			wl_seat::{self, Capability, WlSeat, EVT_NAME_SINCE},
-			wl_surface::WlSurface,
-			wl_touch::{self, WlTouch},
+	reexports::{
+		wayland_protocols::wp::{
+			cursor_shape::v1::server::wp_cursor_shape_device_v1::Shape,
+			pointer_constraints::zv1::server::{
+				zwp_confined_pointer_v1::ZwpConfinedPointerV1,
+				zwp_locked_pointer_v1::ZwpLockedPointerV1,
+			},
+			relative_pointer::zv1::server::zwp_relative_pointer_v1::ZwpRelativePointerV1,
+		},
+		wayland_server::{
+			backend::{ClientId, GlobalId, ObjectId},
+			protocol::{
+				wl_data_device::WlDataDevice,
+				wl_keyboard::{self, KeyState, WlKeyboard},
+				wl_pointer::{self, Axis, AxisSource, ButtonState, WlPointer},
+				wl_seat::{self, Capability, WlSeat, EVT_NAME_SINCE},
+				wl_surface::WlSurface,
+				wl_touch::{self, WlTouch},
+			},
+			Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+			Weak as WlWeak,
 		},
-		Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource, Weak as WlWeak,
 	},
-	wayland::compositor,
+	utils::{Clock, Monotonic},
+	wayland::compositor::{self, RegionAttributes},
 };
 use std::{
 	collections::VecDeque,
-	sync::Arc,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicI32, Ordering},
+		Arc,
+	},
 	time::{Duration, Instant},
 };
-use tokio::sync::watch;
+use tokio::{sync::watch, task::JoinHandle};
 use tracing::{debug, warn};
 use xkbcommon::xkb::{self, ffi::XKB_KEYMAP_FORMAT_TEXT_V1, Keycode, Keymap};
 
@@ -48,28 +71,270 @@ pub fn handle_cursor<B: Backend>(
 			let Some(panel_item) = panel_item_weak.upgrade() else {continue};
 			let cursor_info = cursor.borrow();
 			panel_item.set_cursor(cursor_info.as_ref().and_then(CursorInfo::cursor_data));
+			panel_item.set_cursor_shape(cursor_info.as_ref().and_then(CursorInfo::shape_name));
 		}
 	});
 }
 
+/// What clients' seats have, see `Wayland::set_seat_capabilities`. Clients only bind the
+/// devices advertised, so this has to match the input the shell actually sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeatCapabilities {
+	pub pointer: bool,
+	pub keyboard: bool,
+	pub touch: bool,
+}
+impl SeatCapabilities {
+	pub const DEFAULT: Self = SeatCapabilities {
+		pointer: true,
+		keyboard: true,
+		touch: true,
+	};
+	fn wl_capabilities(self) -> Capability {
+		let mut capabilities = Capability::empty();
+		if self.pointer {
+			capabilities |= Capability::Pointer;
+		}
+		if self.keyboard {
+			capabilities |= Capability::Keyboard;
+		}
+		if self.touch {
+			capabilities |= Capability::Touch;
+		}
+		capabilities
+	}
+}
+impl Default for SeatCapabilities {
+	fn default() -> Self {
+		Self::DEFAULT
+	}
+}
+/// Everything a client's seat has been advertised, devices can only be gotten for these. Ones
+/// that were taken away since can still be gotten, they just don't get any events.
+struct AdvertisedCapabilities(Capability);
+impl AdvertisedCapabilities {
+	fn advertise(&mut self, capabilities: SeatCapabilities) -> Capability {
+		let capabilities = capabilities.wl_capabilities();
+		self.0 |= capabilities;
+		capabilities
+	}
+	fn had(&self, capability: Capability) -> bool {
+		self.0.contains(capability)
+	}
+}
+/// The devices separated by commas, like `pointer,keyboard`. Empty for none at all.
+impl FromStr for SeatCapabilities {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut capabilities = SeatCapabilities {
+			pointer: false,
+			keyboard: false,
+			touch: false,
+		};
+		for device in s.split(',').map(str::trim).filter(|device| !device.is_empty()) {
+			match device {
+				"pointer" => capabilities.pointer = true,
+				"keyboard" => capabilities.keyboard = true,
+				"touch" => capabilities.touch = true,
+				_ => return Err(format!("{device:?} isn't pointer, keyboard or touch")),
+			}
+		}
+		Ok(capabilities)
+	}
+}
+pub static SEAT_CAPABILITIES: Mutex<SeatCapabilities> = const_mutex(SeatCapabilities::DEFAULT);
+/// Each client only ever sees its own one seat
+const SEAT_NAME: &str = "seat0";
+
+/// Key repeat rate (keys per second) and delay (ms).
+/// Repeats are generated by us rather than the client.
+pub struct KeyRepeat {
+	pub rate: i32,
+	pub delay: i32,
+}
+lazy_static::lazy_static! {
+	pub static ref KEY_REPEAT: KeyRepeat = KeyRepeat {
+		rate: env_or("STARDUST_KEY_REPEAT_RATE", 25),
+		delay: env_or("STARDUST_KEY_REPEAT_DELAY", 600),
+	};
+}
+fn env_or(var: &str, default: i32) -> i32 {
+	std::env::var(var)
+		.ok()
+		.and_then(|value| value.parse().ok())
+		.unwrap_or(default)
+}
+fn send_repeat_info(keyboard: &WlKeyboard) {
+	if keyboard.version() >= wl_keyboard::EVT_REPEAT_INFO_SINCE {
+		// A rate of 0 tells the client not to repeat on its own,
+		// otherwise every repeat would be doubled
+		keyboard.repeat_info(0, KEY_REPEAT.delay);
+	}
+}
+
+impl KeyboardLeds {
+	/// Caps and num lock come from the locked modifiers, not every keymap has LEDs for them
+	fn of(state: &xkb::State) -> Self {
+		KeyboardLeds {
+			caps_lock: state.mod_name_is_active(xkb::MOD_NAME_CAPS, xkb::STATE_MODS_LOCKED),
+			num_lock: state.mod_name_is_active(xkb::MOD_NAME_NUM, xkb::STATE_MODS_LOCKED),
+			scroll_lock: state.led_name_is_active(xkb::LED_NAME_SCROLL),
+		}
+	}
+}
+/// Each surface has its own xkb state, but locks are the seat's so CapsLock stays on when
+/// typing moves to another surface or keymap
+#[derive(Debug, Default)]
+struct KeyboardLocks {
+	leds: KeyboardLeds,
+	/// What the shell was last told
+	reported: KeyboardLeds,
+	/// The keymap ID and layout the shell was last told keys are read with
+	reported_layout: Option<(String, u32)>,
+}
+impl KeyboardLocks {
+	/// The lock indicators if they changed since they were last reported
+	fn leds_change(&mut self) -> Option<KeyboardLeds> {
+		if self.leds == self.reported {
+			return None;
+		}
+		self.reported = self.leds;
+		Some(self.leds)
+	}
+}
+
 pub struct KeyboardInfo {
+	keymap_id: String,
 	keymap_string: String,
 	keymap: KeymapFile,
 	state: xkb::State,
 	mods: ModifiersState,
 	keys: FxHashSet<u32>,
+	repeat: Option<(u32, JoinHandle<()>)>,
+	/// The seat's
+	locks: Arc<Mutex<KeyboardLocks>>,
 }
 impl KeyboardInfo {
-	pub fn new(keymap_string: String, keymap: &Keymap) -> Self {
+	fn new(
+		keymap_id: String,
+		keymap_string: String,
+		keymap: &Keymap,
+		locks: Arc<Mutex<KeyboardLocks>>,
+	) -> Self {
 		KeyboardInfo {
+			keymap_id,
 			keymap_string,
 			state: xkb::State::new(keymap),
 			keymap: KeymapFile::new(keymap),
 			mods: ModifiersState::default(),
 			keys: FxHashSet::default(),
+			repeat: None,
+			locks,
 		}
 	}
-	pub fn process(&mut self, key: u32, pressed: bool, keyboard: &WlKeyboard) -> Result<usize> {
+	/// Lock caps and num lock in this state like they are on the seat
+	fn sync_locks(&mut self) {
+		let leds = self.locks.lock().leds;
+		let keymap = self.state.get_keymap();
+		let current = self.state.serialize_mods(xkb::STATE_MODS_LOCKED);
+		let mut locked = current;
+		for (name, on) in [
+			(xkb::MOD_NAME_CAPS, leds.caps_lock),
+			(xkb::MOD_NAME_NUM, leds.num_lock),
+		] {
+			let index = keymap.mod_get_index(name);
+			if index == xkb::MOD_INVALID {
+				continue;
+			}
+			if on {
+				locked |= 1 << index;
+			} else {
+				locked &= !(1 << index);
+			}
+		}
+		if locked == current {
+			return;
+		}
+		self.state.update_mask(
+			self.state.serialize_mods(xkb::STATE_MODS_DEPRESSED),
+			self.state.serialize_mods(xkb::STATE_MODS_LATCHED),
+			locked,
+			self.state.serialize_layout(xkb::STATE_LAYOUT_DEPRESSED),
+			self.state.serialize_layout(xkb::STATE_LAYOUT_LATCHED),
+			self.state.serialize_layout(xkb::STATE_LAYOUT_LOCKED),
+		);
+		self.mods.update_with(&self.state);
+	}
+	fn keyboard_state(&self) -> KeyboardState {
+		KeyboardState {
+			keymap_id: self.keymap_id.clone(),
+			mods_depressed: self.mods.serialized.depressed,
+			mods_latched: self.mods.serialized.latched,
+			mods_locked: self.mods.serialized.locked,
+			layout: self.state.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE),
+		}
+	}
+	fn send_modifiers(&self, keyboard: &WlKeyboard) {
+		keyboard.modifiers(
+			SERIAL_COUNTER.inc(),
+			self.mods.serialized.depressed,
+			self.mods.serialized.latched,
+			self.mods.serialized.locked,
+			// So layout switches (e.g. grp:alt_shift_toggle) reach the client
+			self.state.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE),
+		);
+	}
+	fn start_repeat(&mut self, key: u32, keyboard: &WlKeyboard) {
+		// Like libinput, only the last pressed key repeats
+		self.stop_repeat();
+		if KEY_REPEAT.rate <= 0 || !self.state.get_keymap().key_repeats(Keycode::new(key + 8)) {
+			return;
+		}
+		let keyboard = keyboard.clone();
+		let Ok(repeat_task) = task::new(|| "key repeat", async move {
+			tokio::time::sleep(Duration::from_millis(KEY_REPEAT.delay.max(0) as u64)).await;
+			let mut interval =
+				tokio::time::interval(Duration::from_secs_f64(1.0 / KEY_REPEAT.rate as f64));
+			loop {
+				interval.tick().await;
+				if !keyboard.is_alive() {
+					break;
+				}
+				keyboard.key(SERIAL_COUNTER.inc(), event_time(), key, KeyState::Pressed);
+				if let Some(client) = keyboard.client() {
+					if let Some(client_state) = client.get_data::<ClientState>() {
+						client_state.flush();
+					}
+				}
+			}
+		}) else {return};
+		self.repeat = Some((key, repeat_task));
+	}
+	fn stop_repeat(&mut self) {
+		if let Some((_, repeat_task)) = self.repeat.take() {
+			repeat_task.abort();
+		}
+	}
+	/// The held keys as the `wl_array` of keycodes `wl_keyboard.enter` wants
+	fn pressed_keys(&self) -> Vec<u8> {
+		self.keys.iter().flat_map(|key| key.to_ne_bytes()).collect()
+	}
+	/// After `wl_keyboard.leave` the client assumes nothing is held, so forget the keys too
+	fn release_all(&mut self) {
+		self.stop_repeat();
+		for key in self.keys.drain() {
+			self.state.update_key(Keycode::new(key + 8), xkb::KeyDirection::Up);
+		}
+		self.mods.update_with(&self.state);
+	}
+	/// Track a key going down or up in the held keys and the xkb state. Returns `None` if the
+	/// client shouldn't get it, otherwise if the modifiers changed.
+	fn update_key(&mut self, key: u32, pressed: bool) -> Option<bool> {
+		// releases of keys that were let go of on leave, the client already thinks they're up
+		if !pressed && !self.keys.contains(&key) {
+			return None;
+		}
 		let xkb_key_state = if pressed {
 			xkb::KeyDirection::Down
 		} else {
@@ -78,38 +343,50 @@ impl KeyboardInfo {
 		let state_components = self.state.update_key(Keycode::new(key + 8), xkb_key_state);
 		if state_components != 0 {
 			self.mods.update_with(&self.state);
-			keyboard.modifiers(
-				0,
-				self.mods.serialized.depressed,
-				self.mods.serialized.latched,
-				self.mods.serialized.locked,
-				0,
-			);
-		}
-		// if pressed {
-		// 	println!("Key {key} is being pressed with {state_components} modifiers");
-		// } else {
-		// 	println!("Key {key} is being released with {state_components} modifiers");
-		// }
+			self.locks.lock().leds = KeyboardLeds::of(&self.state);
+		}
+		if pressed {
+			self.keys.insert(key);
+		} else {
+			self.keys.remove(&key);
+		}
+		Some(state_components != 0)
+	}
+	/// `serial` mints the serial of the key event, if one gets sent
+	pub fn process(
+		&mut self,
+		key: u32,
+		pressed: bool,
+		time: u32,
+		keyboard: &WlKeyboard,
+		serial: impl FnOnce(bool) -> u32,
+	) -> Result<usize> {
+		let Some(mods_changed) = self.update_key(key, pressed) else {
+			return Ok(self.keys.len());
+		};
+		if mods_changed {
+			self.send_modifiers(keyboard);
+		}
 
 		let wl_key_state = if pressed {
 			KeyState::Pressed
 		} else {
 			KeyState::Released
 		};
-		keyboard.key(SERIAL_COUNTER.inc(), 0, key, wl_key_state);
-		match wl_key_state {
-			KeyState::Pressed => {
-				self.keys.insert(key);
-			}
-			KeyState::Released => {
-				self.keys.remove(&key);
-			}
-			_ => unimplemented!(),
+		keyboard.key(serial(pressed), time, key, wl_key_state);
+		if pressed {
+			self.start_repeat(key, keyboard);
+		} else if self.repeat.as_ref().map(|(k, _)| *k) == Some(key) {
+			self.stop_repeat();
 		}
 		Ok(self.keys.len())
 	}
 }
+impl Drop for KeyboardInfo {
+	fn drop(&mut self) {
+		self.stop_repeat();
+	}
+}
 unsafe impl Send for KeyboardInfo {}
 
 #[derive(Debug, Clone, Copy)]
@@ -130,26 +407,121 @@ pub enum KeyboardEvent {
 	Key { key: u32, state: bool },
 }
 
+#[derive(Debug, Clone)]
+pub enum PointerConstraintKind {
+	Locked(ZwpLockedPointerV1),
+	Confined(ZwpConfinedPointerV1),
+}
+#[derive(Debug)]
+pub struct PointerConstraint {
+	pub kind: PointerConstraintKind,
+	pub region: Option<RegionAttributes>,
+	/// Applied on the next commit of the surface
+	pub pending_region: Option<Option<RegionAttributes>>,
+	pub oneshot: bool,
+	active: bool,
+	/// A oneshot constraint that was already deactivated, it can't come back
+	defunct: bool,
+}
+impl PointerConstraint {
+	pub fn new(
+		kind: PointerConstraintKind,
+		region: Option<RegionAttributes>,
+		oneshot: bool,
+	) -> Self {
+		PointerConstraint {
+			kind,
+			region,
+			pending_region: None,
+			oneshot,
+			active: false,
+			defunct: false,
+		}
+	}
+	fn contains(&self, position: Vector2<f32>) -> bool {
+		self.region
+			.as_ref()
+			.map_or(true, |region| region.contains((position.x as i32, position.y as i32)))
+	}
+	fn activate(&mut self, position: Vector2<f32>) {
+		if self.active || self.defunct || !self.contains(position) {
+			return;
+		}
+		self.active = true;
+		match &self.kind {
+			PointerConstraintKind::Locked(locked) => locked.locked(),
+			PointerConstraintKind::Confined(confined) => confined.confined(),
+		}
+	}
+	fn deactivate(&mut self) {
+		if !self.active {
+			return;
+		}
+		self.active = false;
+		self.defunct = self.oneshot;
+		match &self.kind {
+			PointerConstraintKind::Locked(locked) => locked.unlocked(),
+			PointerConstraintKind::Confined(confined) => confined.unconfined(),
+		}
+	}
+	/// Whether absolute motion to this position should reach the client
+	fn allows_motion(&self, position: Vector2<f32>) -> bool {
+		match (&self.kind, self.active) {
+			(_, false) => true,
+			(PointerConstraintKind::Locked(_), true) => false,
+			(PointerConstraintKind::Confined(_), true) => self.contains(position),
+		}
+	}
+}
+
 const POINTER_EVENT_TIMEOUT: Duration = Duration::from_millis(50);
+/// How far one scroll step goes in surface pixels, like a mouse wheel click in most compositors
+const SCROLL_STEP_DISTANCE: f32 = 10.0;
 struct SurfaceInfo {
 	wl_surface: WlWeak<WlSurface>,
 	cursor_sender: watch::Sender<Option<CursorInfo>>,
-	pointer_queue: VecDeque<PointerEvent>,
+	/// Stamped with the `event_time_us` they came in at, not when they get sent, so a client still
+	/// sees how far apart they were (e.g. for double clicks) when several arrive in one batch.
+	/// Motion in a row only keeps the latest, see `SeatData::pointer_event`.
+	pointer_queue: VecDeque<(u64, PointerEvent)>,
 	pointer_latest_event: Instant,
-	keyboard_queue: VecDeque<KeyboardEvent>,
+	pointer_position: Option<Vector2<f32>>,
+	pointer_enter_serial: Option<u32>,
+	/// Buttons held down on this surface, it keeps the pointer while any are
+	pointer_buttons: Vec<u32>,
+	/// A button got pressed since the seat last looked, for click to focus
+	pointer_pressed: bool,
+	/// Exclusive input keeps the pointer here, past the input region and the timeout
+	pointer_grabbed: bool,
+	keyboard_queue: VecDeque<(u32, KeyboardEvent)>,
 	keyboard_info: Option<KeyboardInfo>,
+	/// The seat's, shared so the surface can mint serials for it
+	serials: Arc<SerialLog>,
 }
 impl SurfaceInfo {
-	fn new(wl_surface: &WlSurface, cursor_sender: watch::Sender<Option<CursorInfo>>) -> Self {
+	fn new(
+		wl_surface: &WlSurface,
+		cursor_sender: watch::Sender<Option<CursorInfo>>,
+		serials: Arc<SerialLog>,
+	) -> Self {
 		SurfaceInfo {
 			wl_surface: wl_surface.downgrade(),
 			cursor_sender,
 			pointer_queue: VecDeque::new(),
 			pointer_latest_event: Instant::now(),
+			pointer_position: None,
+			pointer_enter_serial: None,
+			pointer_buttons: Vec::new(),
+			pointer_pressed: false,
+			pointer_grabbed: false,
 			keyboard_queue: VecDeque::new(),
 			keyboard_info: None,
+			serials,
 		}
 	}
+	fn serial(&self, event: SerialEvent) -> u32 {
+		self.serials.next(event, &self.wl_surface.id())
+	}
 	fn flush(&self) {
 		if let Some(client) = self.wl_surface.upgrade().ok().and_then(|s| s.client()) {
 			if let Some(client_data) = client.get_data::<ClientState>() {
@@ -157,7 +529,13 @@ impl SurfaceInfo {
 			}
 		}
 	}
-	fn handle_pointer_events(&mut self, pointer: &WlPointer, mut locked: bool) -> bool {
+	fn handle_pointer_events(
+		&mut self,
+		pointer: &WlPointer,
+		mut locked: bool,
+		mut constraint: Option<&mut PointerConstraint>,
+		relative_pointers: &[ZwpRelativePointerV1],
+	) -> bool {
 		let Ok(focus) = self.wl_surface.upgrade() else { return false; };
 		let Some(core_surface) = CoreSurface::from_wl_surface(&focus) else { return false; };
 		let Some(focus_size) = core_surface.size() else { return false; };
@@ -165,20 +543,59 @@ impl SurfaceInfo {
 		if !self.pointer_queue.is_empty() {
 			self.pointer_latest_event = Instant::now();
 		}
-		while let Some(event) = self.pointer_queue.pop_front() {
+		// Once the pointer is outside the input region only motion back into it matters
+		let mut outside_input = false;
+		while let Some((utime, event)) = self.pointer_queue.pop_front() {
+			let time = event_time_ms(utime);
 			match (locked, event) {
+				// Past the input region the pointer falls through to whatever the shell has behind
+				// the surface, except while a button is held as the surface has an implicit grab
+				(_, PointerEvent::Motion(pos))
+					if !core_surface.accepts_input(pos)
+						&& self.pointer_buttons.is_empty()
+						&& !self.pointer_grabbed =>
+				{
+					if locked {
+						self.pointer_leave(pointer, &focus, constraint.as_deref_mut());
+						locked = false;
+					}
+					outside_input = true;
+				}
+				(false, _) if outside_input => (),
 				(false, PointerEvent::Motion(pos)) => {
+					let serial = self.serial(SerialEvent::PointerEnter);
+					self.pointer_enter_serial = Some(serial);
 					pointer.enter(
-						SERIAL_COUNTER.inc(),
+						serial,
 						&focus,
 						(pos.x as f64).clamp(0.0, focus_size.x as f64),
 						(pos.y as f64).clamp(0.0, focus_size.y as f64),
 					);
 					locked = true;
+					self.pointer_position = Some(pos);
+					if let Some(constraint) = constraint.as_deref_mut() {
+						constraint.activate(pos);
+					}
 				}
 				(true, PointerEvent::Motion(pos)) => {
+					// The shell gives us where the pointer ray hits the panel plane,
+					// so the relative motion is how far that point moved in surface pixels
+					if let Some(old_pos) = self.pointer_position {
+						let (utime_hi, utime_lo, dx, dy) = relative_motion(utime, old_pos, pos);
+						for relative_pointer in relative_pointers {
+							// No acceleration gets applied, so both are the same
+							relative_pointer.relative_motion(utime_hi, utime_lo, dx, dy, dx, dy);
+						}
+					}
+					self.pointer_position = Some(pos);
+					if let Some(constraint) = constraint.as_deref_mut() {
+						constraint.activate(pos);
+						if !constraint.allows_motion(pos) {
+							continue;
+						}
+					}
 					pointer.motion(
-						0,
+						time,
 						(pos.x as f64).clamp(0.0, focus_size.x as f64),
 						(pos.y as f64).clamp(0.0, focus_size.y as f64),
 					);
@@ -187,16 +604,25 @@ impl SurfaceInfo {
 					}
 				}
 				(true, PointerEvent::Button { button, state }) => {
-					pointer.button(
-						0,
-						0,
-						button,
-						match state {
-							0 => ButtonState::Released,
-							1 => ButtonState::Pressed,
-							_ => continue,
-						},
-					);
+					let state = match state {
+						0 => ButtonState::Released,
+						1 => ButtonState::Pressed,
+						_ => continue,
+					};
+					// every button gets exactly one press and one release, however many
+					// inputs are mapped to it
+					let held = self.pointer_buttons.contains(&button);
+					if held == (state == ButtonState::Pressed) {
+						continue;
+					}
+					self.pointer_buttons.retain(|b| *b != button);
+					if state == ButtonState::Pressed {
+						self.pointer_buttons.push(button);
+						self.pointer_pressed = true;
+					}
+					let pressed = state == ButtonState::Pressed;
+					let serial = self.serial(SerialEvent::PointerButton { button, pressed });
+					pointer.button(serial, time, button, state);
 					if pointer.version() >= wl_pointer::EVT_FRAME_SINCE {
 						pointer.frame();
 					}
@@ -208,23 +634,15 @@ impl SurfaceInfo {
 						axis_discrete,
 					},
 				) => {
-					if let Some(axis_continuous) = axis_continuous {
-						pointer.axis(0, Axis::HorizontalScroll, axis_continuous.x as f64);
-						pointer.axis(0, Axis::VerticalScroll, -axis_continuous.y as f64);
-					}
-					if pointer.version() >= wl_pointer::EVT_AXIS_DISCRETE_SINCE {
-						if let Some(axis_discrete) = axis_discrete {
-							pointer.axis_discrete(Axis::HorizontalScroll, axis_discrete.x as i32);
-							pointer.axis_discrete(Axis::VerticalScroll, -axis_discrete.y as i32);
+					for event in scroll_events(pointer.version(), axis_continuous, axis_discrete) {
+						match event {
+							ScrollEvent::Source(source) => pointer.axis_source(source),
+							ScrollEvent::Axis(axis, distance) => pointer.axis(time, axis, distance),
+							ScrollEvent::Value120(axis, value) => pointer.axis_value120(axis, value),
+							ScrollEvent::Discrete(axis, steps) => pointer.axis_discrete(axis, steps),
+							ScrollEvent::Stop(axis) => pointer.axis_stop(time, axis),
 						}
 					}
-					if pointer.version() >= wl_pointer::EVT_AXIS_STOP_SINCE
-						&& axis_discrete.is_none()
-						&& axis_continuous.is_none()
-					{
-						pointer.axis_stop(0, Axis::HorizontalScroll);
-						pointer.axis_stop(0, Axis::VerticalScroll);
-					}
 					if pointer.version() >= wl_pointer::EVT_FRAME_SINCE {
 						pointer.frame();
 					}
@@ -234,37 +652,87 @@ impl SurfaceInfo {
 				}
 			}
 		}
-		if self.pointer_latest_event.elapsed() > POINTER_EVENT_TIMEOUT {
-			pointer.leave(SERIAL_COUNTER.inc(), &focus);
+		if self.pointer_latest_event.elapsed() > POINTER_EVENT_TIMEOUT && !self.pointer_grabbed {
+			if locked {
+				self.pointer_leave(pointer, &focus, constraint);
+			}
 			locked = false;
 		}
 		self.flush();
 
 		locked
 	}
-	fn handle_keyboard_events(&mut self, keyboard: &WlKeyboard, mut locked: bool) -> bool {
+	fn pointer_leave(
+		&mut self,
+		pointer: &WlPointer,
+		focus: &WlSurface,
+		constraint: Option<&mut PointerConstraint>,
+	) {
+		if let Some(constraint) = constraint {
+			constraint.deactivate();
+		}
+		pointer.leave(self.serial(SerialEvent::PointerLeave), focus);
+		self.pointer_position = None;
+		self.pointer_enter_serial = None;
+		self.pointer_buttons.clear();
+		self.pointer_pressed = false;
+		// the client's cursor only applies while it has the pointer, so go back to the default
+		self.cursor_sender.send_if_modified(|cursor| cursor.take().is_some());
+	}
+	fn keys_held(&self) -> bool {
+		self.keyboard_info.as_ref().is_some_and(|info| !info.keys.is_empty())
+	}
+	/// Returns false if the surface can't take keyboard focus (yet)
+	fn keyboard_enter(&mut self, keyboard: &WlKeyboard) -> bool {
 		let Ok(focus) = self.wl_surface.upgrade() else { return false; };
-		let Some(info) = self.keyboard_info.as_mut() else { return true; };
+		let Some(info) = self.keyboard_info.as_mut() else { return false; };
+
+		if info.keymap.send(keyboard).is_err() {
+			return false;
+		}
+		info.sync_locks();
+		send_repeat_info(keyboard);
+		keyboard.enter(self.serial(SerialEvent::KeyboardEnter), &focus, info.pressed_keys());
+		info.send_modifiers(keyboard);
+		true
+	}
+	fn keyboard_leave(&mut self, keyboard: &WlKeyboard) {
+		if let Some(info) = self.keyboard_info.as_mut() {
+			info.release_all();
+		}
+		if let Ok(focus) = self.wl_surface.upgrade() {
+			keyboard.leave(self.serial(SerialEvent::KeyboardLeave), &focus);
+		}
+		self.flush();
+	}
+	fn handle_keyboard_events(&mut self, keyboard: &WlKeyboard, mut locked: bool) -> bool {
+		if self.wl_surface.upgrade().is_err() {
+			return false;
+		}
+		if self.keyboard_info.is_none() {
+			return true;
+		}
 
 		if !locked {
-			keyboard.enter(0, &focus, vec![]);
-			if keyboard.version() >= wl_keyboard::EVT_REPEAT_INFO_SINCE {
-				keyboard.repeat_info(0, 0);
-			}
-			locked = info.keymap.send(keyboard).is_ok();
+			locked = self.keyboard_enter(keyboard);
 		}
-		while let Some(event) = self.keyboard_queue.pop_front() {
+		while let Some((time, event)) = self.keyboard_queue.pop_front() {
 			debug!(locked, ?event, "Process keyboard event");
+			let Some(info) = self.keyboard_info.as_mut() else {break};
 			match (locked, event) {
 				(true, KeyboardEvent::Keymap) => {
 					let _ = info.keymap.send(keyboard);
+					info.sync_locks();
+					info.send_modifiers(keyboard);
 				}
 				(true, KeyboardEvent::Key { key, state }) => {
-					if let Ok(key_count) = info.process(key, state, keyboard) {
-						if key_count == 0 {
-							keyboard.leave(SERIAL_COUNTER.inc(), &focus);
-							return false;
-						}
+					let serial = |pressed| {
+						let event = SerialEvent::Key { key, pressed };
+						self.serials.next(event, &self.wl_surface.id())
+					};
+					if let Ok(0) = info.process(key, state, time, keyboard, serial) {
+						self.keyboard_leave(keyboard);
+						return false;
 					}
 				}
 				(locked, event) => {
@@ -277,6 +745,29 @@ impl SurfaceInfo {
 	}
 }
 
+/// Send `wl_keyboard.leave` to the old focus (releasing everything it held)
+/// and `enter` to the new one. Focus ends up null if the new surface can't take it.
+fn switch_keyboard_focus(
+	surfaces: &mut FxHashMap<ObjectId, SurfaceInfo>,
+	keyboard: &WlKeyboard,
+	focus: &mut ObjectId,
+	new_focus: ObjectId,
+) {
+	if *focus == new_focus {
+		return;
+	}
+	if let Some(old) = surfaces.get_mut(focus) {
+		old.keyboard_leave(keyboard);
+	}
+	*focus = ObjectId::null();
+	if let Some(new) = surfaces.get_mut(&new_focus) {
+		if new.keyboard_enter(keyboard) {
+			*focus = new_focus;
+			new.flush();
+		}
+	}
+}
+
 pub struct SeatData {
 	pub client: OnceCell<ClientId>,
 	global_id: OnceCell<GlobalId>,
@@ -284,10 +775,24 @@ pub struct SeatData {
 	pointer: OnceCell<(WlPointer, Mutex<ObjectId>)>,
 	keyboard: OnceCell<(WlKeyboard, Mutex<ObjectId>)>,
 	touch: OnceCell<WlTouch>,
-	touches: Mutex<FxHashMap<ObjectId, u32>>,
+	/// Keyed by the panel item that owns them and the ID the shell gave them
+	touches: Mutex<FxHashMap<(ObjectId, u32), TouchPoint>>,
+	next_touch_id: AtomicI32,
+	pointer_constraints: Mutex<FxHashMap<ObjectId, PointerConstraint>>,
+	relative_pointers: Mutex<Vec<ZwpRelativePointerV1>>,
+	data_device: Mutex<Option<WlDataDevice>>,
+	keyboard_locks: Arc<Mutex<KeyboardLocks>>,
+	pub text_inputs: TextInputs,
+	pub tablets: Tablets,
+	pub serials: Arc<SerialLog>,
+	input_latency: Mutex<PendingInput>,
+	/// Every `wl_seat` the client bound, to tell them when the capabilities change
+	seats: Mutex<Vec<WlSeat>>,
+	advertised: Mutex<AdvertisedCapabilities>,
 }
 impl SeatData {
 	pub fn new(dh: &DisplayHandle) -> Arc<Self> {
+		let serials = Arc::<SerialLog>::default();
 		let seat_data = Arc::new(SeatData {
 			client: OnceCell::new(),
 			global_id: OnceCell::new(),
@@ -296,16 +801,62 @@ impl SeatData {
 			keyboard: OnceCell::new(),
 			touch: OnceCell::new(),
 			touches: Mutex::new(FxHashMap::default()),
+			next_touch_id: AtomicI32::new(0),
+			pointer_constraints: Mutex::new(FxHashMap::default()),
+			relative_pointers: Mutex::new(Vec::new()),
+			data_device: Mutex::new(None),
+			keyboard_locks: Arc::default(),
+			text_inputs: TextInputs::default(),
+			tablets: Tablets::new(dh, serials.clone()),
+			serials,
+			input_latency: Mutex::new(PendingInput::default()),
+			seats: Mutex::new(Vec::new()),
+			advertised: Mutex::new(AdvertisedCapabilities(Capability::empty())),
 		});
 
 		let _ = seat_data
 			.global_id
-			.set(dh.create_global::<WaylandState, _, _>(7, seat_data.clone()));
+			.set(dh.create_global::<WaylandState, _, _>(8, seat_data.clone()));
 
 		seat_data
 	}
 
-	pub fn set_keymap(&self, keymap_str: String, surfaces: Vec<WlSurface>) -> Result<()> {
+	/// Tell the client's `wl_seat`s what `SEAT_CAPABILITIES` are now
+	pub fn update_capabilities(&self) {
+		for seat in self.seats.lock().iter() {
+			self.advertise_capabilities(seat);
+		}
+	}
+	fn advertise_capabilities(&self, seat: &WlSeat) {
+		let capabilities = self.advertised.lock().advertise(*SEAT_CAPABILITIES.lock());
+		seat.capabilities(capabilities);
+	}
+	/// Getting a device for a capability the seat never had is a protocol error
+	fn reject_missing_capability(&self, seat: &WlSeat, capability: Capability) -> bool {
+		if self.advertised.lock().had(capability) {
+			return false;
+		}
+		warn!(?capability, "Client got an input device its seat never had");
+		seat.post_error(
+			wl_seat::Error::MissingCapability,
+			format!("The seat never had the {capability:?} capability"),
+		);
+		true
+	}
+
+	/// Queue the seat's global to be removed, it also holds on to the seat data
+	pub fn destroy_global(&self) {
+		if let Some(global_id) = self.global_id.get() {
+			super::destroy_global(global_id.clone());
+		}
+	}
+
+	pub fn set_keymap(
+		&self,
+		keymap_id: &str,
+		keymap_str: String,
+		surfaces: Vec<WlSurface>,
+	) -> Result<()> {
 		let context = xkb::Context::new(0);
 		let keymap =
 			Keymap::new_from_string(&context, keymap_str.clone(), XKB_KEYMAP_FORMAT_TEXT_V1, 0)
@@ -319,47 +870,177 @@ impl SeatData {
 					continue;
 				}
 			}
-			surface_info
-				.keyboard_info
-				.replace(KeyboardInfo::new(keymap_str.clone(), &keymap));
+			surface_info.keyboard_info.replace(KeyboardInfo::new(
+				keymap_id.to_string(),
+				keymap_str.clone(),
+				&keymap,
+				self.keyboard_locks.clone(),
+			));
 
 			if *focus.lock() == surface.id() {
-				surface_info.keyboard_queue.push_back(KeyboardEvent::Keymap);
+				surface_info.keyboard_queue.push_back((event_time(), KeyboardEvent::Keymap));
 			}
 		}
 		Ok(())
 	}
 
+	/// The lock indicators if they changed since the last time this was asked
+	pub fn keyboard_leds_change(&self) -> Option<KeyboardLeds> {
+		self.keyboard_locks.lock().leds_change()
+	}
+
+	/// The keymap and modifier state keys to the surface are read with, `None` before it got any
+	pub fn keyboard_state(&self, surface: &WlSurface) -> Option<KeyboardState> {
+		let surfaces = self.surfaces.lock();
+		let keyboard_info = surfaces.get(&surface.id())?.keyboard_info.as_ref()?;
+		Some(keyboard_info.keyboard_state())
+	}
+	/// The surface's keyboard state if its keymap or layout changed since the last time this
+	/// was asked, so whatever's typing into it can pick keycodes for the new one
+	pub fn keyboard_layout_change(&self, surface: &WlSurface) -> Option<KeyboardState> {
+		let state = self.keyboard_state(surface)?;
+		let layout = (state.keymap_id.clone(), state.layout);
+		let mut locks = self.keyboard_locks.lock();
+		if locks.reported_layout.as_ref() == Some(&layout) {
+			return None;
+		}
+		locks.reported_layout = Some(layout);
+		Some(state)
+	}
+
 	pub fn pointer_event(&self, surface: &WlSurface, event: PointerEvent) {
+		if !SEAT_CAPABILITIES.lock().pointer {
+			return;
+		}
+		self.input_latency.lock().generated();
+		if let PointerEvent::Button { state: 1, .. } = event {
+			focus_policy::input_happened();
+		}
+		let event = match event {
+			PointerEvent::Motion(position) => PointerEvent::Motion(unbent(surface, position)),
+			event => event,
+		};
+		if drag_pointer_event(surface, &event) {
+			return;
+		}
 		let mut surfaces = self.surfaces.lock();
 		let Some(surface_info) = surfaces.get_mut(&surface.id()) else {return};
-		surface_info.pointer_queue.push_back(event);
+		// Spatial pointers move every time the shell updates, way more often than a client
+		// draws, so motion waits for `send_pointer_motion` and only the latest position is worth
+		// sending. Anything else goes out right away along with the motion before it.
+		if let PointerEvent::Motion(_) = event {
+			if let Some((time, queued @ PointerEvent::Motion(_))) =
+				surface_info.pointer_queue.back_mut()
+			{
+				*time = event_time_us();
+				*queued = event;
+			} else {
+				surface_info.pointer_queue.push_back((event_time_us(), event));
+			}
+			return;
+		}
+		surface_info.pointer_queue.push_back((event_time_us(), event));
 		drop(surfaces);
 		self.handle_pointer_events();
+		self.input_latency.lock().sent();
+	}
+	/// Keep the pointer on `surface` for exclusive input, taking it from wherever it is now. Events
+	/// for other surfaces wait until it's let go of.
+	pub fn grab_pointer(&self, surface: &WlSurface, grabbed: bool) {
+		{
+			let mut surfaces = self.surfaces.lock();
+			let Some((pointer, pointer_focus)) = self.pointer.get() else {return};
+			let mut pointer_focus = pointer_focus.lock();
+			let mut constraints = self.pointer_constraints.lock();
+			let focused = *pointer_focus == surface.id();
+			if grabbed && !focused {
+				if let Some(surface_info) = surfaces.get_mut(&*pointer_focus) {
+					if let Ok(focus) = surface_info.wl_surface.upgrade() {
+						let constraint = constraints.get_mut(&*pointer_focus);
+						surface_info.pointer_leave(pointer, &focus, constraint);
+					}
+				}
+				*pointer_focus = ObjectId::null();
+			}
+			let Some(surface_info) = surfaces.get_mut(&surface.id()) else {return};
+			surface_info.pointer_grabbed = grabbed;
+			// it's entered with motion, in the middle unless some is already waiting
+			let queued_motion =
+				matches!(surface_info.pointer_queue.front(), Some((_, PointerEvent::Motion(_))));
+			if grabbed && !focused && !queued_motion {
+				let Some(size) = CoreSurface::from_wl_surface(surface).and_then(|c| c.size()) else {
+					return;
+				};
+				let middle = Vector2::from([size.x as f32 / 2.0, size.y as f32 / 2.0]);
+				let motion = (event_time_us(), PointerEvent::Motion(middle));
+				surface_info.pointer_queue.push_front(motion);
+			}
+		}
+		// once it's let go of the timeout can take the pointer away again
+		self.handle_pointer_events();
+	}
+	/// Send the motion queued up since the last time, once per frame so clients get at most one
+	/// position each time they draw
+	pub fn send_pointer_motion(&self) {
+		let queued = self
+			.surfaces
+			.lock()
+			.values()
+			.any(|surface_info| !surface_info.pointer_queue.is_empty());
+		if queued {
+			self.handle_pointer_events();
+			self.input_latency.lock().sent();
+		}
 	}
 	pub fn keyboard_event(&self, surface: &WlSurface, event: KeyboardEvent) {
+		if !SEAT_CAPABILITIES.lock().keyboard {
+			return;
+		}
+		self.input_latency.lock().generated();
+		if let KeyboardEvent::Key { state: true, .. } = event {
+			focus_policy::input_happened();
+		}
 		let mut surfaces = self.surfaces.lock();
 		let Some(surface_info) = surfaces.get_mut(&surface.id()) else {return};
-		surface_info.keyboard_queue.push_back(event);
+		surface_info.keyboard_queue.push_back((event_time(), event));
 		drop(surfaces);
 		self.handle_keyboard_events();
+		self.input_latency.lock().sent();
+	}
+	/// The client committed one of its surfaces, returns when the input it's reacting to (if
+	/// any) came in
+	pub fn input_committed(&self) -> Option<Instant> {
+		self.input_latency.lock().committed()
+	}
+	/// Touches go out right away, so they're only timed from when they're sent
+	fn touch_sent(&self) {
+		let mut input_latency = self.input_latency.lock();
+		input_latency.generated();
+		input_latency.sent();
 	}
 
 	fn handle_pointer_events(&self) {
 		let mut surfaces = self.surfaces.lock();
 		let Some((pointer, pointer_focus)) = self.pointer.get() else {return};
 		let mut pointer_focus = pointer_focus.lock();
+		let mut constraints = self.pointer_constraints.lock();
+		let relative_pointers = self.relative_pointers.lock();
+		let previous_focus = pointer_focus.clone();
 
 		loop {
 			let locked = !pointer_focus.is_null();
-			// Pick a pointer to focus on if there is none
+			// Pick a pointer to focus on if there is none, a grab gets it before anything else
 			if pointer_focus.is_null() {
 				*pointer_focus = surfaces
 					.iter()
-					.filter(|(_k, v)| !v.pointer_queue.is_empty())
-					.map(|(k, _v)| k)
-					.choose(&mut thread_rng())
-					.cloned()
+					.find(|(_k, v)| v.pointer_grabbed && !v.pointer_queue.is_empty())
+					.or_else(|| {
+						surfaces
+							.iter()
+							.filter(|(_k, v)| !v.pointer_queue.is_empty())
+							.choose(&mut thread_rng())
+					})
+					.map(|(k, _v)| k.clone())
 					.unwrap_or(ObjectId::null());
 			}
 			if pointer_focus.is_null() {
@@ -367,19 +1048,52 @@ impl SeatData {
 				break;
 			}
 			let Some(surface_info) = surfaces.get_mut(&pointer_focus) else {break};
-			if surface_info.handle_pointer_events(pointer, locked) {
+			let constraint = constraints.get_mut(&pointer_focus);
+			if surface_info.handle_pointer_events(pointer, locked, constraint, &relative_pointers) {
 				// We haven't gotten to a point where we can switch the focus
 				break;
 			} else {
 				*pointer_focus = ObjectId::null();
 			}
 		}
+
+		// Keyboard focus follows the pointer onto surfaces that can take it, if the policy says so
+		if pointer_focus.is_null() {
+			return;
+		}
+		let entered = *pointer_focus != previous_focus;
+		let pressed = surfaces
+			.get_mut(&*pointer_focus)
+			.is_some_and(|s| std::mem::take(&mut s.pointer_pressed));
+		let gives_focus = (entered && focus_policy::pointer_focus(PointerFocusEvent::Enter))
+			|| (pressed && focus_policy::pointer_focus(PointerFocusEvent::Press));
+		if !gives_focus {
+			return;
+		}
+		let Some((keyboard, keyboard_focus)) = self.keyboard.get() else {return};
+		if surfaces.get(&*pointer_focus).is_some_and(|s| s.keyboard_info.is_some()) {
+			let mut keyboard_focus = keyboard_focus.lock();
+			let new_focus = pointer_focus.clone();
+			switch_keyboard_focus(&mut surfaces, keyboard, &mut keyboard_focus, new_focus);
+		}
 	}
 	fn handle_keyboard_events(&self) {
 		let mut surfaces = self.surfaces.lock();
 		let Some((keyboard, keyboard_focus)) = self.keyboard.get() else {return};
 		let mut keyboard_focus = keyboard_focus.lock();
 		loop {
+			// Don't let a focused surface with nothing held starve the others' events
+			let idle = surfaces
+				.get(&*keyboard_focus)
+				.is_some_and(|s| s.keyboard_queue.is_empty() && !s.keys_held());
+			let others_waiting = surfaces
+				.iter()
+				.any(|(k, v)| k != &*keyboard_focus && !v.keyboard_queue.is_empty());
+			if idle && others_waiting {
+				let new_focus = ObjectId::null();
+				switch_keyboard_focus(&mut surfaces, keyboard, &mut keyboard_focus, new_focus);
+			}
+
 			let locked = !keyboard_focus.is_null();
 			// Pick a keyboard to focus on if there is none
 			if keyboard_focus.is_null() {
@@ -403,11 +1117,108 @@ impl SeatData {
 		}
 	}
 
+	/// Returns false if the surface already has a constraint on it
+	pub fn add_pointer_constraint(
+		&self,
+		surface: &WlSurface,
+		mut constraint: PointerConstraint,
+	) -> bool {
+		let surfaces = self.surfaces.lock();
+		let mut constraints = self.pointer_constraints.lock();
+		if constraints.contains_key(&surface.id()) {
+			return false;
+		}
+		// The pointer may already be inside, in which case it takes effect immediately
+		let focused = self
+			.pointer
+			.get()
+			.map_or(false, |(_, focus)| *focus.lock() == surface.id());
+		if let Some(position) = surfaces
+			.get(&surface.id())
+			.and_then(|surface_info| surface_info.pointer_position)
+		{
+			if focused {
+				constraint.activate(position);
+			}
+		}
+		constraints.insert(surface.id(), constraint);
+		true
+	}
+	pub fn set_pointer_constraint_region(
+		&self,
+		surface: &WlSurface,
+		region: Option<RegionAttributes>,
+	) {
+		let mut constraints = self.pointer_constraints.lock();
+		let Some(constraint) = constraints.get_mut(&surface.id()) else {return};
+		constraint.pending_region.replace(region);
+	}
+	pub fn commit_pointer_constraint(&self, surface: &WlSurface) {
+		let mut constraints = self.pointer_constraints.lock();
+		let Some(constraint) = constraints.get_mut(&surface.id()) else {return};
+		if let Some(region) = constraint.pending_region.take() {
+			constraint.region = region;
+		}
+	}
+	pub fn remove_pointer_constraint(&self, surface_id: &ObjectId) {
+		self.pointer_constraints.lock().remove(surface_id);
+	}
+
+	pub fn set_data_device(&self, data_device: WlDataDevice) {
+		self.data_device.lock().replace(data_device);
+	}
+	pub fn data_device(&self) -> Option<WlDataDevice> {
+		self.data_device
+			.lock()
+			.clone()
+			.filter(|data_device| data_device.is_alive())
+	}
+	pub fn set_cursor(&self, surface: &WlSurface, cursor_info: Option<CursorInfo>) {
+		let surfaces = self.surfaces.lock();
+		let Some(surface_info) = surfaces.get(&surface.id()) else {return};
+		let _ = surface_info.cursor_sender.send_replace(cursor_info);
+	}
+	/// Set the cursor of the surface with pointer focus.
+	/// `enter_serial` has to match the serial of the pointer entering that surface.
+	pub fn set_focused_cursor(&self, enter_serial: u32, cursor_info: Option<CursorInfo>) {
+		let Some((_, focus)) = self.pointer.get() else {return};
+		let focus = focus.lock();
+		let surfaces = self.surfaces.lock();
+		let Some(surface_info) = surfaces.get(&focus) else {return};
+		if Some(enter_serial) != surface_info.pointer_enter_serial {
+			return;
+		}
+		let _ = surface_info.cursor_sender.send_replace(cursor_info);
+	}
+	/// Resend the cursor of every surface using `cursor` as its cursor surface, e.g. on commit.
+	pub fn update_cursor(&self, cursor: &WlSurface) {
+		let surfaces = self.surfaces.lock();
+		for surface_info in surfaces.values() {
+			let current = surface_info
+				.cursor_sender
+				.borrow()
+				.as_ref()
+				.and_then(CursorInfo::surface);
+			if current.as_ref() == Some(cursor) {
+				surface_info.cursor_sender.send_modify(|_| ());
+			}
+		}
+	}
+
+	pub fn add_relative_pointer(&self, relative_pointer: ZwpRelativePointerV1) {
+		self.relative_pointers.lock().push(relative_pointer);
+	}
+	pub fn remove_relative_pointer(&self, relative_pointer: &ZwpRelativePointerV1) {
+		self.relative_pointers
+			.lock()
+			.retain(|other| other != relative_pointer);
+	}
+
 	pub fn new_surface(&self, surface: &WlSurface) -> watch::Receiver<Option<CursorInfo>> {
 		let (tx, rx) = watch::channel(None);
 		self.surfaces
 			.lock()
-			.insert(surface.id(), SurfaceInfo::new(surface, tx));
+			.insert(surface.id(), SurfaceInfo::new(surface, tx, self.serials.clone()));
 
 		rx
 	}
@@ -425,52 +1236,269 @@ impl SeatData {
 				*keyboard_focus = ObjectId::null();
 			}
 		}
-		self.touches.lock().remove(&surface.id());
+		self.touches
+			.lock()
+			.retain(|_, touch_point| touch_point.surface != surface.id());
+		self.pointer_constraints.lock().remove(&surface.id());
+	}
+
+	/// Whether any of this client's surfaces currently has pointer or keyboard focus
+	pub fn has_input_focus(&self) -> bool {
+		let focused = |focus: &Mutex<ObjectId>| !focus.lock().is_null();
+		let pointer_focused = self.pointer.get().is_some_and(|(_, focus)| focused(focus));
+		let keyboard_focused = self.keyboard.get().is_some_and(|(_, focus)| focused(focus));
+		pointer_focused || keyboard_focused
 	}
 
-	pub fn touch_down(&self, surface: &WlSurface, id: u32, position: Vector2<f32>) {
+	/// `owner` is the panel item's toplevel, as shell touch IDs are only unique per panel item.
+	/// The client gets its own IDs that are never reused so it can't confuse different touches.
+	pub fn touch_down(
+		&self,
+		owner: &ObjectId,
+		surface: &WlSurface,
+		id: u32,
+		position: Vector2<f32>,
+	) {
+		if !SEAT_CAPABILITIES.lock().touch {
+			return;
+		}
+		focus_policy::input_happened();
 		let Some(touch) = self.touch.get() else {return};
+		let position = unbent(surface, position);
+		let mut touches = self.touches.lock();
+		// a second down for the same ID means we missed the up
+		if let Some(old) = touches.remove(&(owner.clone(), id)) {
+			let event = SerialEvent::TouchUp { id: old.wl_id };
+			let serial = self.serials.next(event, &old.surface);
+			touch.up(serial, event_time(), old.wl_id);
+		}
+		let wl_id = self.next_touch_id.fetch_add(1, Ordering::Relaxed);
 		touch.down(
-			SERIAL_COUNTER.inc(),
-			0,
+			self.serials.next(SerialEvent::TouchDown { id: wl_id }, &surface.id()),
+			event_time(),
 			surface,
-			id as i32,
+			wl_id,
 			position.x as f64,
 			position.y as f64,
 		);
-		self.touches.lock().insert(surface.id(), id);
+		touch.frame();
+		self.touch_sent();
+		let touch_point = TouchPoint {
+			wl_id,
+			surface: surface.id(),
+			wl_surface: surface.downgrade(),
+		};
+		touches.insert((owner.clone(), id), touch_point);
 	}
-	pub fn touch_move(&self, id: u32, position: Vector2<f32>) {
+	pub fn touch_move(&self, owner: &ObjectId, id: u32, position: Vector2<f32>) {
 		let Some(touch) = self.touch.get() else {return};
-		touch.motion(0, id as i32, position.x as f64, position.y as f64);
+		let touches = self.touches.lock();
+		let Some(touch_point) = touches.get(&(owner.clone(), id)) else {return};
+		let position = touch_point
+			.wl_surface
+			.upgrade()
+			.map_or(position, |surface| unbent(&surface, position));
+		touch.motion(event_time(), touch_point.wl_id, position.x as f64, position.y as f64);
+		touch.frame();
+		self.touch_sent();
 	}
-	pub fn touch_up(&self, id: u32) {
+	pub fn touch_up(&self, owner: &ObjectId, id: u32) {
 		let Some(touch) = self.touch.get() else {return};
-		touch.up(SERIAL_COUNTER.inc(), 0, id as i32);
-		let mut touches = self.touches.lock();
-		touches.retain(|_, tid| *tid != id);
+		let Some(touch_point) = self.touches.lock().remove(&(owner.clone(), id)) else {return};
+		let event = SerialEvent::TouchUp { id: touch_point.wl_id };
+		let serial = self.serials.next(event, &touch_point.surface);
+		touch.up(serial, event_time(), touch_point.wl_id);
+		touch.frame();
+		self.touch_sent();
 	}
-	pub fn reset_touches(&self) {
+	/// For when tracking got lost. wl_touch can only cancel all of the client's touches at once,
+	/// so touches on the client's other panel items end too.
+	pub fn reset_touches(&self, owner: &ObjectId) {
 		let Some(touch) = self.touch.get() else {return};
-		for (_, touch_id) in self.touches.lock().drain() {
-			touch.up(SERIAL_COUNTER.inc(), 0, touch_id as i32);
+		let mut touches = self.touches.lock();
+		if !touches.keys().any(|(touch_owner, _)| touch_owner == owner) {
+			return;
+		}
+		touch.cancel();
+		touches.clear();
+	}
+}
+
+struct TouchPoint {
+	wl_id: i32,
+	surface: ObjectId,
+	wl_surface: WlWeak<WlSurface>,
+}
+/// Shells hit-test input against the flat panel, this moves it onto the bent one if it's bent
+pub fn unbent(surface: &WlSurface, position: Vector2<f32>) -> Vector2<f32> {
+	CoreSurface::from_wl_surface(surface)
+		.map_or(position, |core_surface| core_surface.unbend(position))
+}
+/// Everything sent to clients with a time, input events as well as presentation feedback, comes
+/// from this one monotonic clock so they agree with each other and never go backwards.
+pub static CLOCK: Lazy<Clock<Monotonic>> = Lazy::new(Clock::new);
+/// Timestamp for input events in milliseconds, wrapping around every 49 days like `u32` ms do
+pub fn event_time() -> u32 {
+	event_time_ms(event_time_us())
+}
+/// Timestamp in microseconds, what queued events are stamped with so their relative motion
+/// gets the same time as their `wl_pointer.motion`
+fn event_time_us() -> u64 {
+	Duration::from(CLOCK.now()).as_micros() as u64
+}
+fn event_time_ms(utime: u64) -> u32 {
+	(utime / 1000) as u32
+}
+/// A `wl_pointer` event of a scroll, with the ones before a frame making up one scroll
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScrollEvent {
+	Source(AxisSource),
+	Axis(Axis, f64),
+	Value120(Axis, i32),
+	Discrete(Axis, i32),
+	Stop(Axis),
+}
+/// The events for one scroll to a `wl_pointer` of `version`, in the order they're sent
+fn scroll_events(
+	version: u32,
+	axis_continuous: Option<Vector2<f32>>,
+	axis_discrete: Option<Vector2<f32>>,
+) -> Vec<ScrollEvent> {
+	let mut events = Vec::new();
+	// Steps come from wheel-like input, only a distance from something like a
+	// touchpad that tells us when it stops
+	let scrolling = axis_continuous.is_some() || axis_discrete.is_some();
+	if version >= wl_pointer::EVT_AXIS_SOURCE_SINCE && scrolling {
+		let source = match axis_discrete {
+			Some(_) => AxisSource::Wheel,
+			None => AxisSource::Finger,
+		};
+		events.push(ScrollEvent::Source(source));
+	}
+	// Wayland's y axis points down
+	let axes = |scroll: Vector2<f32>| {
+		[(Axis::HorizontalScroll, scroll.x), (Axis::VerticalScroll, -scroll.y)]
+	};
+	// Clients expect an axis event alongside steps, so make up a distance if needed
+	let axis_continuous = axis_continuous.or_else(|| {
+		let steps = axis_discrete?;
+		Some(Vector2::from([
+			steps.x * SCROLL_STEP_DISTANCE,
+			steps.y * SCROLL_STEP_DISTANCE,
+		]))
+	});
+	if let Some(axis_continuous) = axis_continuous {
+		for (axis, distance) in axes(axis_continuous) {
+			if distance != 0.0 {
+				events.push(ScrollEvent::Axis(axis, distance as f64));
+			}
 		}
 	}
+	if let Some(axis_discrete) = axis_discrete {
+		for (axis, steps) in axes(axis_discrete) {
+			if steps == 0.0 {
+				continue;
+			}
+			// Fractional steps make for smooth high resolution scrolling
+			if version >= wl_pointer::EVT_AXIS_VALUE120_SINCE {
+				events.push(ScrollEvent::Value120(axis, (steps * 120.0).round() as i32));
+			} else if version >= wl_pointer::EVT_AXIS_DISCRETE_SINCE {
+				events.push(ScrollEvent::Discrete(axis, steps.round() as i32));
+			}
+		}
+	}
+	if version >= wl_pointer::EVT_AXIS_STOP_SINCE && !scrolling {
+		events.push(ScrollEvent::Stop(Axis::HorizontalScroll));
+		events.push(ScrollEvent::Stop(Axis::VerticalScroll));
+	}
+	events
 }
 
-pub struct CursorInfo {
-	pub surface: WlWeak<WlSurface>,
-	pub hotspot_x: i32,
-	pub hotspot_y: i32,
+/// The `zwp_relative_pointer_v1.relative_motion` time (split into its high and low 32 bits)
+/// and motion in surface pixels for the pointer moving from `old_pos` to `pos`
+fn relative_motion(utime: u64, old_pos: Vector2<f32>, pos: Vector2<f32>) -> (u32, u32, f64, f64) {
+	let dx = (pos.x - old_pos.x) as f64;
+	let dy = (pos.y - old_pos.y) as f64;
+	((utime >> 32) as u32, utime as u32, dx, dy)
+}
+
+pub enum CursorInfo {
+	/// A client drawn cursor from `wl_pointer.set_cursor`
+	Surface {
+		surface: WlWeak<WlSurface>,
+		hotspot_x: i32,
+		hotspot_y: i32,
+		/// The surface's `buffer_offset` when it was set, the hotspot moves back by however much
+		/// it's been moved since
+		offset: Vector2<i32>,
+	},
+	/// A named cursor from `wp_cursor_shape_device_v1.set_shape` that the shell draws itself
+	Shape(Shape),
+	/// `wl_pointer.set_cursor` with a null surface, the pointer shouldn't be drawn at all
+	Hidden,
 }
 impl CursorInfo {
+	pub fn surface(&self) -> Option<WlSurface> {
+		match self {
+			CursorInfo::Surface { surface, .. } => surface.upgrade().ok(),
+			CursorInfo::Shape(_) | CursorInfo::Hidden => None,
+		}
+	}
 	pub fn cursor_data(&self) -> Option<Geometry> {
-		let cursor_size = CoreSurface::from_wl_surface(&self.surface.upgrade().ok()?)?.size()?;
+		let CursorInfo::Surface { surface, hotspot_x, hotspot_y, offset } = self else {return None};
+		let surface = surface.upgrade().ok()?;
+		let cursor_size = CoreSurface::from_wl_surface(&surface)?.size()?;
+		let moved = buffer_offset(&surface);
 		Some(Geometry {
-			origin: [self.hotspot_x, self.hotspot_y].into(),
+			origin: [hotspot_x - (moved.x - offset.x), hotspot_y - (moved.y - offset.y)].into(),
 			size: cursor_size,
 		})
 	}
+	/// The CSS cursor name the shape corresponds to, as the protocol defines them.
+	/// A hidden cursor is "none", like in CSS.
+	pub fn shape_name(&self) -> Option<&'static str> {
+		let shape = match self {
+			CursorInfo::Surface { .. } => return None,
+			CursorInfo::Hidden => return Some("none"),
+			CursorInfo::Shape(shape) => shape,
+		};
+		Some(match shape {
+			Shape::ContextMenu => "context-menu",
+			Shape::Help => "help",
+			Shape::Pointer => "pointer",
+			Shape::Progress => "progress",
+			Shape::Wait => "wait",
+			Shape::Cell => "cell",
+			Shape::Crosshair => "crosshair",
+			Shape::Text => "text",
+			Shape::VerticalText => "vertical-text",
+			Shape::Alias => "alias",
+			Shape::Copy => "copy",
+			Shape::Move => "move",
+			Shape::NoDrop => "no-drop",
+			Shape::NotAllowed => "not-allowed",
+			Shape::Grab => "grab",
+			Shape::Grabbing => "grabbing",
+			Shape::EResize => "e-resize",
+			Shape::NResize => "n-resize",
+			Shape::NeResize => "ne-resize",
+			Shape::NwResize => "nw-resize",
+			Shape::SResize => "s-resize",
+			Shape::SeResize => "se-resize",
+			Shape::SwResize => "sw-resize",
+			Shape::WResize => "w-resize",
+			Shape::EwResize => "ew-resize",
+			Shape::NsResize => "ns-resize",
+			Shape::NeswResize => "nesw-resize",
+			Shape::NwseResize => "nwse-resize",
+			Shape::ColResize => "col-resize",
+			Shape::RowResize => "row-resize",
+			Shape::AllScroll => "all-scroll",
+			Shape::ZoomIn => "zoom-in",
+			Shape::ZoomOut => "zoom-out",
+			_ => "default",
+		})
+	}
 }
 
 impl GlobalDispatch<WlSeat, Arc<SeatData>, WaylandState> for WaylandState {
@@ -485,10 +1513,11 @@ impl GlobalDispatch<WlSeat, Arc<SeatData>, WaylandState> for WaylandState {
 		let resource = data_init.init(resource, data.clone());
 
 		if resource.version() >= EVT_NAME_SINCE {
-			resource.name(nanoid!());
+			resource.name(SEAT_NAME.to_string());
 		}
 
-		resource.capabilities(Capability::Pointer | Capability::Keyboard | Capability::Touch);
+		data.advertise_capabilities(&resource);
+		data.seats.lock().push(resource);
 	}
 
 	fn can_view(client: Client, data: &Arc<SeatData>) -> bool {
@@ -501,7 +1530,7 @@ impl Dispatch<WlSeat, Arc<SeatData>, WaylandState> for WaylandState {
 	fn request(
 		_state: &mut WaylandState,
 		_client: &Client,
-		_resource: &WlSeat,
+		resource: &WlSeat,
 		request: wl_seat::Request,
 		data: &Arc<SeatData>,
 		_dh: &DisplayHandle,
@@ -510,29 +1539,46 @@ impl Dispatch<WlSeat, Arc<SeatData>, WaylandState> for WaylandState {
 		match request {
 			wl_seat::Request::GetPointer { id } => {
 				let pointer = data_init.init(id, data.clone());
+				if data.reject_missing_capability(resource, Capability::Pointer) {
+					return;
+				}
 				let _ = data.pointer.set((pointer, Mutex::new(ObjectId::null())));
 			}
 			wl_seat::Request::GetKeyboard { id } => {
 				let keyboard = data_init.init(id, data.clone());
-				if keyboard.version() >= wl_keyboard::EVT_REPEAT_INFO_SINCE {
-					keyboard.repeat_info(0, 0);
+				if data.reject_missing_capability(resource, Capability::Keyboard) {
+					return;
 				}
+				send_repeat_info(&keyboard);
 				let _ = data.keyboard.set((keyboard, Mutex::new(ObjectId::null())));
 			}
 			wl_seat::Request::GetTouch { id } => {
-				let _ = data.touch.set(data_init.init(id, data.clone()));
+				let touch = data_init.init(id, data.clone());
+				if data.reject_missing_capability(resource, Capability::Touch) {
+					return;
+				}
+				let _ = data.touch.set(touch);
 			}
 			wl_seat::Request::Release => (),
 			_ => unreachable!(),
 		}
 	}
+
+	fn destroyed(
+		_state: &mut WaylandState,
+		_client: ClientId,
+		resource: &WlSeat,
+		data: &Arc<SeatData>,
+	) {
+		data.seats.lock().retain(|seat| seat != resource);
+	}
 }
 
 impl Dispatch<WlPointer, Arc<SeatData>, WaylandState> for WaylandState {
 	fn request(
 		_state: &mut WaylandState,
 		_client: &Client,
-		_resource: &WlPointer,
+		resource: &WlPointer,
 		request: wl_pointer::Request,
 		seat_data: &Arc<SeatData>,
 		dh: &DisplayHandle,
@@ -540,30 +1586,52 @@ impl Dispatch<WlPointer, Arc<SeatData>, WaylandState> for WaylandState {
 	) {
 		match request {
 			wl_pointer::Request::SetCursor {
-				serial: _,
+				serial,
 				surface,
 				hotspot_x,
 				hotspot_y,
 			} => {
 				if let Some(surface) = surface.as_ref() {
-					CoreSurface::add_to(dh.clone(), surface, || (), |_| ());
+					if !give_role(surface, CURSOR_ROLE) {
+						resource.post_error(
+							wl_pointer::Error::Role,
+							"The cursor surface already has another role",
+						);
+						return;
+					}
+					// the cursor's size is only known once it's mapped and can change on any commit
+					let seat = Arc::downgrade(seat_data);
+					let cursor = surface.downgrade();
+					let update_cursor = move || {
+						let Some(seat) = seat.upgrade() else {return};
+						let Ok(cursor) = cursor.upgrade() else {return};
+						seat.update_cursor(&cursor);
+					};
+					CoreSurface::add_to(
+						dh.clone(),
+						surface,
+						update_cursor.clone(),
+						|| (),
+						move |_| update_cursor(),
+					);
 					compositor::with_states(surface, |data| {
 						if let Some(core_surface) = data.data_map.get::<Arc<CoreSurface>>() {
-							core_surface.set_material_offset(1);
+							core_surface.set_material_offset(TOP_MATERIAL_OFFSET);
+							core_surface.keep_transparent();
 						}
 					})
 				}
 
-				let Some((_, focus)) = seat_data.pointer.get() else {return};
-				let focus = focus.lock();
-				let surfaces = seat_data.surfaces.lock();
-				let Some(surface_info) = surfaces.get(&focus) else {return};
-				let cursor_info = surface.map(|surface| CursorInfo {
-					surface: surface.downgrade(),
-					hotspot_x,
-					hotspot_y,
-				});
-				let _ = surface_info.cursor_sender.send_replace(cursor_info);
+				let cursor_info = match surface {
+					Some(surface) => CursorInfo::Surface {
+						surface: surface.downgrade(),
+						hotspot_x,
+						hotspot_y,
+						offset: buffer_offset(&surface),
+					},
+					None => CursorInfo::Hidden,
+				};
+				seat_data.set_focused_cursor(serial, Some(cursor_info));
 			}
 			wl_pointer::Request::Release => (),
 			_ => unreachable!(),
@@ -604,3 +1672,238 @@ impl Dispatch<WlTouch, Arc<SeatData>, WaylandState> for WaylandState {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use input_event_codes::{KEY_A, KEY_CAPSLOCK, KEY_LEFTSHIFT};
+
+	/// Just enough of a keymap for a letter, shift and caps lock, without needing xkeyboard-config
+	const TEST_KEYMAP: &str = r#"xkb_keymap {
+		xkb_keycodes "test" {
+			minimum = 8;
+			maximum = 255;
+			<AC01> = 38;
+			<LFSH> = 50;
+			<CAPS> = 66;
+		};
+		xkb_types "test" {
+			type "ONE_LEVEL" {
+				modifiers = none;
+				level_name[Level1] = "Any";
+			};
+			type "ALPHABETIC" {
+				modifiers = Shift + Lock;
+				map[Shift] = Level2;
+				map[Lock] = Level2;
+				level_name[Level1] = "Base";
+				level_name[Level2] = "Caps";
+			};
+		};
+		xkb_compatibility "test" {
+			interpret Shift_L { action = SetMods(modifiers = Shift); };
+			interpret Caps_Lock { action = LockMods(modifiers = Lock); };
+		};
+		xkb_symbols "test" {
+			key <AC01> { type = "ALPHABETIC", [ a, A ] };
+			key <LFSH> { [ Shift_L ] };
+			key <CAPS> { [ Caps_Lock ] };
+			modifier_map Shift { <LFSH> };
+			modifier_map Lock { <CAPS> };
+		};
+	};"#;
+
+	fn keyboard_info(locks: &Arc<Mutex<KeyboardLocks>>) -> KeyboardInfo {
+		let context = xkb::Context::new(xkb::CONTEXT_NO_DEFAULT_INCLUDES);
+		let keymap = Keymap::new_from_string(
+			&context,
+			TEST_KEYMAP.to_string(),
+			XKB_KEYMAP_FORMAT_TEXT_V1,
+			0,
+		)
+		.unwrap();
+		KeyboardInfo::new("test".to_string(), TEST_KEYMAP.to_string(), &keymap, locks.clone())
+	}
+
+	#[test]
+	fn held_keys_are_reported_on_enter() {
+		let mut info = keyboard_info(&Default::default());
+		assert!(info.pressed_keys().is_empty());
+		assert_eq!(info.update_key(KEY_LEFTSHIFT!(), true), Some(true));
+		assert_eq!(info.update_key(KEY_A!(), true), Some(false));
+		let mut held = info
+			.pressed_keys()
+			.chunks(4)
+			.map(|key| u32::from_ne_bytes(key.try_into().unwrap()))
+			.collect::<Vec<_>>();
+		held.sort();
+		assert_eq!(held, [KEY_A!(), KEY_LEFTSHIFT!()]);
+		assert_ne!(info.keyboard_state().mods_depressed, 0);
+	}
+
+	#[test]
+	fn caps_lock_stays_on_across_surfaces() {
+		let locks = Arc::default();
+		let tap = |info: &mut KeyboardInfo, key| {
+			info.update_key(key, true);
+			info.update_key(key, false);
+		};
+		let mut info = keyboard_info(&locks);
+		tap(&mut info, KEY_CAPSLOCK!());
+		assert!(KeyboardLeds::of(&info.state).caps_lock);
+		assert!(locks.lock().leds.caps_lock);
+
+		// another surface's keyboard state picks it up once it gets focus
+		let mut other = keyboard_info(&locks);
+		assert_eq!(other.keyboard_state().mods_locked, 0);
+		other.sync_locks();
+		assert_ne!(other.keyboard_state().mods_locked, 0);
+		assert!(KeyboardLeds::of(&other.state).caps_lock);
+
+		// and turning it off there turns it off for the seat
+		tap(&mut other, KEY_CAPSLOCK!());
+		assert!(!locks.lock().leds.caps_lock);
+		assert_eq!(other.keyboard_state().mods_locked, 0);
+	}
+
+	#[test]
+	fn led_changes_are_reported_once() {
+		let locks = Arc::default();
+		let mut info = keyboard_info(&locks);
+		assert_eq!(locks.lock().leds_change(), None);
+		info.update_key(KEY_CAPSLOCK!(), true);
+		let caps_lock = KeyboardLeds {
+			caps_lock: true,
+			..Default::default()
+		};
+		assert_eq!(locks.lock().leds_change(), Some(caps_lock));
+		// releasing the key doesn't change the lock
+		info.update_key(KEY_CAPSLOCK!(), false);
+		assert_eq!(locks.lock().leds_change(), None);
+	}
+
+	#[test]
+	fn scroll_steps_are_proportional() {
+		let steps = |x: f32, y: f32| scroll_events(8, None, Some([x, y].into()));
+		// half a wheel click down, then one and a half to the right
+		assert_eq!(
+			steps(0.0, -0.5),
+			[
+				ScrollEvent::Source(AxisSource::Wheel),
+				ScrollEvent::Axis(Axis::VerticalScroll, 5.0),
+				ScrollEvent::Value120(Axis::VerticalScroll, 60),
+			]
+		);
+		assert_eq!(
+			steps(1.5, 0.0),
+			[
+				ScrollEvent::Source(AxisSource::Wheel),
+				ScrollEvent::Axis(Axis::HorizontalScroll, 15.0),
+				ScrollEvent::Value120(Axis::HorizontalScroll, 180),
+			]
+		);
+		// before value120 only whole steps can be sent
+		assert_eq!(
+			scroll_events(5, None, Some([0.0, 2.0].into())),
+			[
+				ScrollEvent::Source(AxisSource::Wheel),
+				ScrollEvent::Axis(Axis::VerticalScroll, -20.0),
+				ScrollEvent::Discrete(Axis::VerticalScroll, -2),
+			]
+		);
+	}
+
+	#[test]
+	fn smooth_scrolls_end_with_a_stop() {
+		assert_eq!(
+			scroll_events(8, Some([3.0, 4.0].into()), None),
+			[
+				ScrollEvent::Source(AxisSource::Finger),
+				ScrollEvent::Axis(Axis::HorizontalScroll, 3.0),
+				ScrollEvent::Axis(Axis::VerticalScroll, -4.0),
+			]
+		);
+		assert_eq!(
+			scroll_events(8, None, None),
+			[ScrollEvent::Stop(Axis::HorizontalScroll), ScrollEvent::Stop(Axis::VerticalScroll)]
+		);
+		// version 1 pointers only get the distance
+		assert_eq!(
+			scroll_events(1, Some([0.0, 1.0].into()), None),
+			[ScrollEvent::Axis(Axis::VerticalScroll, -1.0)]
+		);
+		assert!(scroll_events(1, None, None).is_empty());
+	}
+
+	#[test]
+	fn held_keys_are_cleared_on_leave() {
+		let mut info = keyboard_info(&Default::default());
+		info.update_key(KEY_LEFTSHIFT!(), true);
+		info.update_key(KEY_A!(), true);
+		info.release_all();
+		assert!(info.pressed_keys().is_empty());
+		assert_eq!(info.keyboard_state().mods_depressed, 0);
+		// the client already thinks they're up, so letting go of them isn't sent
+		assert_eq!(info.update_key(KEY_A!(), false), None);
+		assert_eq!(info.update_key(KEY_LEFTSHIFT!(), false), None);
+	}
+
+	#[test]
+	fn relative_motion_is_the_pointer_move() {
+		let utime = (3 << 32) + 1_500_000;
+		let old_pos = Vector2 { x: 10.0, y: 20.0 };
+		let pos = Vector2 { x: 13.5, y: 18.0 };
+		assert_eq!(relative_motion(utime, old_pos, pos), (3, 1_500_000, 3.5, -2.0));
+	}
+
+	#[test]
+	fn event_times_never_go_backwards() {
+		let mut last = event_time_us();
+		for _ in 0..10_000 {
+			let time = event_time_us();
+			assert!(time >= last);
+			last = time;
+		}
+		// u32 milliseconds wrap like they do for every other compositor
+		assert_eq!(event_time_ms((u32::MAX as u64 + 5) * 1000), 4);
+	}
+
+	#[test]
+	fn rapid_clicks_keep_their_spacing() {
+		// A double click, each press and release seen by the seat 30ms apart, queued up before
+		// the surface gets to send any of them
+		let mut queue = VecDeque::new();
+		for state in [1, 0, 1, 0] {
+			queue.push_back((event_time_us(), PointerEvent::Button { button: 0x110, state }));
+			std::thread::sleep(Duration::from_millis(30));
+		}
+		let times = queue
+			.into_iter()
+			.map(|(time, _)| event_time_ms(time))
+			.collect::<Vec<_>>();
+		for pair in times.windows(2) {
+			assert!(pair[1] - pair[0] >= 29, "{times:?}");
+		}
+		// Well within the 400ms or so that toolkits take as a double click
+		assert!(times[3] - times[0] < 400, "{times:?}");
+	}
+
+	#[test]
+	fn devices_need_a_capability_the_seat_had() {
+		let mut advertised = AdvertisedCapabilities(Capability::empty());
+		let no_touch: SeatCapabilities = "pointer,keyboard".parse().unwrap();
+		assert_eq!(
+			advertised.advertise(no_touch),
+			Capability::Pointer | Capability::Keyboard
+		);
+		assert!(advertised.had(Capability::Pointer));
+		// get_touch on this seat is the missing_capability error
+		assert!(!advertised.had(Capability::Touch));
+
+		// a client that bound its pointer before it was taken away isn't in the wrong
+		let keyboard_only = "keyboard".parse().unwrap();
+		assert_eq!(advertised.advertise(keyboard_only), Capability::Keyboard);
+		assert!(advertised.had(Capability::Pointer));
+		assert!(!advertised.had(Capability::Touch));
+	}
+}
@@ -0,0 +1,371 @@
+use super::{seat::SeatData, SERIAL_COUNTER};
+use parking_lot::Mutex;
+use smithay::reexports::wayland_server::{
+	backend::ObjectId,
+	protocol::{wl_seat::WlSeat, wl_surface::WlSurface},
+	Resource,
+};
+use std::{collections::VecDeque, sync::Arc};
+use tracing::debug;
+
+/// How many serials each seat remembers, older ones can't be used for anything anymore
+const SERIAL_HISTORY: usize = 64;
+
+/// What a serial was sent with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialEvent {
+	PointerEnter,
+	PointerLeave,
+	PointerButton {
+		button: u32,
+		pressed: bool,
+	},
+	KeyboardEnter,
+	KeyboardLeave,
+	Key {
+		key: u32,
+		pressed: bool,
+	},
+	TouchDown {
+		id: i32,
+	},
+	TouchUp {
+		id: i32,
+	},
+	TabletToolProximityIn,
+	TabletToolProximityOut,
+	TabletToolDown,
+	/// `zwp_tablet_tool_v2.up` doesn't have a serial, it's only logged so the down isn't held
+	TabletToolUp,
+	TabletToolButton {
+		button: u32,
+		pressed: bool,
+	},
+}
+impl SerialEvent {
+	/// Something the user pressed, which grabs, moves, resizes and drags have to come from
+	pub fn is_press(self) -> bool {
+		matches!(
+			self,
+			SerialEvent::PointerButton { pressed: true, .. }
+				| SerialEvent::Key { pressed: true, .. }
+				| SerialEvent::TouchDown { .. }
+				| SerialEvent::TabletToolDown
+				| SerialEvent::TabletToolButton { pressed: true, .. }
+		)
+	}
+	/// Whether a later `leave` means this event doesn't apply anymore, touches just end
+	fn left_by(self, leave: SerialEvent) -> bool {
+		match leave {
+			SerialEvent::PointerLeave => matches!(
+				self,
+				SerialEvent::PointerEnter | SerialEvent::PointerButton { .. }
+			),
+			SerialEvent::KeyboardLeave => {
+				matches!(self, SerialEvent::KeyboardEnter | SerialEvent::Key { .. })
+			}
+			SerialEvent::TabletToolProximityOut => matches!(
+				self,
+				SerialEvent::TabletToolProximityIn
+					| SerialEvent::TabletToolDown
+					| SerialEvent::TabletToolButton { .. }
+			),
+			_ => false,
+		}
+	}
+	/// Whether `later` lets go of what this press is holding down
+	fn released_by(self, later: SerialEvent) -> bool {
+		match (self, later) {
+			(
+				SerialEvent::PointerButton {
+					button,
+					pressed: true,
+				},
+				SerialEvent::PointerButton {
+					button: released,
+					pressed: false,
+				},
+			)
+			| (
+				SerialEvent::TabletToolButton {
+					button,
+					pressed: true,
+				},
+				SerialEvent::TabletToolButton {
+					button: released,
+					pressed: false,
+				},
+			) => button == released,
+			(
+				SerialEvent::Key { key, pressed: true },
+				SerialEvent::Key {
+					key: released,
+					pressed: false,
+				},
+			) => key == released,
+			(SerialEvent::TouchDown { id }, SerialEvent::TouchUp { id: up }) => id == up,
+			(SerialEvent::TabletToolDown, SerialEvent::TabletToolUp) => true,
+			_ => false,
+		}
+	}
+}
+
+#[derive(Debug)]
+struct SerialRecord<S> {
+	serial: u32,
+	event: SerialEvent,
+	surface: S,
+}
+
+/// The serials a seat sent and what with, so requests carrying a serial can be checked against
+/// what the client actually got instead of just being any number we could have sent.
+/// `SERIAL_COUNTER` is still shared so serials are unique, but each seat (and so client)
+/// can only use the ones it was sent. Surfaces are told apart by their `ObjectId` outside tests.
+#[derive(Debug)]
+pub struct SerialLog<S = ObjectId>(Mutex<VecDeque<SerialRecord<S>>>);
+impl<S> Default for SerialLog<S> {
+	fn default() -> Self {
+		SerialLog(Mutex::new(VecDeque::new()))
+	}
+}
+impl<S: Clone + PartialEq> SerialLog<S> {
+	/// A new serial for `event` on `surface`
+	pub fn next(&self, event: SerialEvent, surface: &S) -> u32 {
+		let serial = SERIAL_COUNTER.inc();
+		let mut records = self.0.lock();
+		if records.len() >= SERIAL_HISTORY {
+			records.pop_front();
+		}
+		records.push_back(SerialRecord {
+			serial,
+			event,
+			surface: surface.clone(),
+		});
+		serial
+	}
+
+	/// Where `serial` is in `records`, if it was sent on `surface` (or any surface if `None`)
+	/// and the surface hasn't lost the pointer or keyboard focus it came with since
+	fn find(
+		records: &VecDeque<SerialRecord<S>>,
+		serial: u32,
+		surface: Option<&S>,
+	) -> Option<usize> {
+		let index = records.iter().rposition(|record| record.serial == serial)?;
+		let record = &records[index];
+		if surface.is_some_and(|surface| *surface != record.surface) {
+			return None;
+		}
+		let left = records
+			.range(index + 1..)
+			.any(|later| later.surface == record.surface && record.event.left_by(later.event));
+		(!left).then_some(index)
+	}
+	/// The event `serial` was sent with, see `find`
+	pub fn event(&self, serial: u32, surface: Option<&S>) -> Option<SerialEvent> {
+		let records = self.0.lock();
+		Some(records[Self::find(&records, serial, surface)?].event)
+	}
+	/// The serial of a press on `surface`, even if it's been let go of since (see `find`)
+	pub fn is_press(&self, serial: u32, surface: Option<&S>) -> bool {
+		self.event(serial, surface)
+			.is_some_and(SerialEvent::is_press)
+	}
+	/// The serial of a press that's still held down on `surface`, see `find`
+	pub fn is_held_press(&self, serial: u32, surface: Option<&S>) -> bool {
+		let records = self.0.lock();
+		let Some(index) = Self::find(&records, serial, surface) else {
+			return false;
+		};
+		let record = &records[index];
+		record.event.is_press()
+			&& !records.range(index + 1..).any(|later| {
+				later.surface == record.surface && record.event.released_by(later.event)
+			})
+	}
+	/// The serial of a keyboard enter, a key or a press sent to a surface while it had keyboard
+	/// focus, and still has it. Keyboard events only go to the focused surface, for the rest the
+	/// surface's last keyboard event before it has to be an enter or a key. If that's too long
+	/// ago to still be remembered it doesn't count.
+	pub fn is_focused_input(&self, serial: u32) -> bool {
+		let records = self.0.lock();
+		let Some(index) = Self::find(&records, serial, None) else {
+			return false;
+		};
+		let record = &records[index];
+		let focused = |event: SerialEvent| {
+			matches!(event, SerialEvent::KeyboardEnter | SerialEvent::Key { .. })
+		};
+		if !record.event.is_press() && !focused(record.event) {
+			return false;
+		}
+		let keyboard_events = records.range(..=index).rev().filter(|earlier| {
+			earlier.surface == record.surface
+				&& (focused(earlier.event) || earlier.event == SerialEvent::KeyboardLeave)
+		});
+		let had_focus = keyboard_events
+			.map(|earlier| focused(earlier.event))
+			.next()
+			.unwrap_or(false);
+		let lost_focus = records.range(index + 1..).any(|later| {
+			later.surface == record.surface && later.event == SerialEvent::KeyboardLeave
+		});
+		had_focus && !lost_focus
+	}
+}
+
+/// Moves, resizes and drags follow the pointer until the press they started from is let go of,
+/// so it has to be one the client got on `surface` (or any of its surfaces if `None`) that's
+/// still held. Otherwise a stale serial could start one that follows the pointer around until
+/// the next release.
+pub fn valid_press(seat_data: &SeatData, serial: u32, surface: Option<&WlSurface>) -> bool {
+	let valid = seat_data
+		.serials
+		.is_held_press(serial, surface.map(Resource::id).as_ref());
+	if !valid {
+		debug!(
+			serial,
+			?surface,
+			"Ignoring a request with an invalid serial"
+		);
+	}
+	valid
+}
+/// `valid_press` for requests that come with a `wl_seat`
+pub fn valid_seat_press(seat: &WlSeat, serial: u32, surface: Option<&WlSurface>) -> bool {
+	seat.data::<Arc<SeatData>>()
+		.is_some_and(|seat_data| valid_press(seat_data, serial, surface))
+}
+/// Popup grabs only need the client to have gotten a press, as toolkits often open menus when
+/// the button is released
+pub fn valid_popup_press(seat: &WlSeat, serial: u32) -> bool {
+	let valid = seat
+		.data::<Arc<SeatData>>()
+		.is_some_and(|seat_data| seat_data.serials.is_press(serial, None));
+	if !valid {
+		debug!(serial, "Ignoring a popup grab with an invalid serial");
+	}
+	valid
+}
+/// Setting the clipboard or primary selection has to come from input the client got while it
+/// had keyboard focus, so a client in the background can't replace what the user copied
+pub fn valid_selection(seat_data: &SeatData, serial: u32) -> bool {
+	let valid = seat_data.serials.is_focused_input(serial);
+	if !valid {
+		debug!(serial, "Ignoring a selection with an invalid serial");
+	}
+	valid
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use input_event_codes::{BTN_LEFT, BTN_RIGHT, KEY_C};
+
+	const SURFACE: u32 = 1;
+	const OTHER_SURFACE: u32 = 2;
+
+	fn button(button: u32, pressed: bool) -> SerialEvent {
+		SerialEvent::PointerButton { button, pressed }
+	}
+
+	#[test]
+	fn serials_stop_counting_after_a_leave() {
+		let log = SerialLog::default();
+		log.next(SerialEvent::PointerEnter, &SURFACE);
+		let serial = log.next(button(BTN_LEFT!(), true), &SURFACE);
+		// leaving another surface doesn't matter
+		log.next(SerialEvent::PointerLeave, &OTHER_SURFACE);
+		assert!(log.is_held_press(serial, Some(&SURFACE)));
+		log.next(SerialEvent::PointerLeave, &SURFACE);
+		assert_eq!(log.event(serial, Some(&SURFACE)), None);
+		assert!(!log.is_press(serial, None));
+	}
+
+	#[test]
+	fn serials_from_another_surface_are_rejected() {
+		let log = SerialLog::default();
+		let serial = log.next(button(BTN_LEFT!(), true), &OTHER_SURFACE);
+		assert!(!log.is_held_press(serial, Some(&SURFACE)));
+		assert!(log.is_held_press(serial, Some(&OTHER_SURFACE)));
+		// any of the client's surfaces
+		assert!(log.is_held_press(serial, None));
+	}
+
+	#[test]
+	fn old_serials_are_forgotten() {
+		let log = SerialLog::default();
+		let serial = log.next(button(BTN_LEFT!(), true), &SURFACE);
+		for _ in 1..SERIAL_HISTORY {
+			log.next(SerialEvent::PointerEnter, &OTHER_SURFACE);
+		}
+		assert!(log.is_press(serial, None));
+		log.next(SerialEvent::PointerEnter, &OTHER_SURFACE);
+		assert_eq!(log.event(serial, None), None);
+		assert!(!log.is_press(serial, None));
+	}
+
+	#[test]
+	fn releases_are_not_presses() {
+		let log = SerialLog::default();
+		let release = log.next(button(BTN_LEFT!(), false), &SURFACE);
+		assert!(!log.is_press(release, Some(&SURFACE)));
+		assert!(!log.is_held_press(release, Some(&SURFACE)));
+		let key_release = log.next(
+			SerialEvent::Key {
+				key: KEY_C!(),
+				pressed: false,
+			},
+			&SURFACE,
+		);
+		assert!(!log.is_press(key_release, Some(&SURFACE)));
+	}
+
+	#[test]
+	fn presses_stop_being_held_once_released() {
+		let log = SerialLog::default();
+		let serial = log.next(button(BTN_LEFT!(), true), &SURFACE);
+		log.next(button(BTN_RIGHT!(), false), &SURFACE);
+		assert!(log.is_held_press(serial, Some(&SURFACE)));
+		log.next(button(BTN_LEFT!(), false), &SURFACE);
+		assert!(!log.is_held_press(serial, Some(&SURFACE)));
+		// still enough for a popup
+		assert!(log.is_press(serial, Some(&SURFACE)));
+
+		let down = log.next(SerialEvent::TouchDown { id: 3 }, &SURFACE);
+		log.next(SerialEvent::TouchUp { id: 4 }, &SURFACE);
+		assert!(log.is_held_press(down, None));
+		log.next(SerialEvent::TouchUp { id: 3 }, &SURFACE);
+		assert!(!log.is_held_press(down, None));
+	}
+
+	#[test]
+	fn selections_need_keyboard_focus() {
+		let log = SerialLog::default();
+		let unfocused_click = log.next(button(BTN_LEFT!(), true), &SURFACE);
+		assert!(!log.is_focused_input(unfocused_click));
+		let enter = log.next(SerialEvent::KeyboardEnter, &SURFACE);
+		let click = log.next(button(BTN_LEFT!(), true), &SURFACE);
+		let key = log.next(
+			SerialEvent::Key {
+				key: KEY_C!(),
+				pressed: false,
+			},
+			&SURFACE,
+		);
+		let pointer_enter = log.next(SerialEvent::PointerEnter, &SURFACE);
+		assert!(log.is_focused_input(enter));
+		assert!(log.is_focused_input(click));
+		assert!(log.is_focused_input(key));
+		assert!(!log.is_focused_input(pointer_enter));
+
+		// focus on another surface doesn't make it focused
+		let other_click = log.next(button(BTN_LEFT!(), true), &OTHER_SURFACE);
+		assert!(!log.is_focused_input(other_click));
+
+		log.next(SerialEvent::KeyboardLeave, &SURFACE);
+		assert!(!log.is_focused_input(enter));
+		assert!(!log.is_focused_input(click));
+		assert!(!log.is_focused_input(key));
+	}
+}
@@ -0,0 +1,110 @@
+use color_eyre::eyre::{ensure, Result, WrapErr};
+use smithay::reexports::{
+	rustix::{
+		io::{fcntl_setfd, FdFlags},
+		net::{self, sockopt, SocketAddrAny, SocketType},
+	},
+	wayland_server::ListeningSocket,
+};
+use std::{
+	ffi::OsStr,
+	os::{
+		fd::{AsRawFd, OwnedFd, RawFd},
+		unix::{ffi::OsStrExt, net::UnixListener},
+	},
+	path::Path,
+};
+
+/// What clients connect to, dropped on shutdown so no new ones can
+pub enum WaylandSocket {
+	/// Bound by us in `$XDG_RUNTIME_DIR`, dropping it removes the socket file and its lock
+	Bound(ListeningSocket),
+	/// Handed to us already listening, e.g. by a sandboxed launcher or systemd socket activation.
+	/// Whoever bound it owns its path.
+	Inherited(OwnedFd),
+}
+impl WaylandSocket {
+	/// Bind `socket_name`, or the first free `wayland-N` if `None`
+	pub fn bind(socket_name: Option<&str>) -> Result<Self> {
+		let socket = match socket_name {
+			Some(socket_name) => ListeningSocket::bind(socket_name)
+				.wrap_err_with(|| format!("Could not listen on Wayland socket {socket_name}"))?,
+			None => ListeningSocket::bind_auto("wayland", 0..33)?,
+		};
+		Ok(WaylandSocket::Bound(socket))
+	}
+	/// Take over a socket someone else bound, which has to be a listening unix stream socket
+	pub fn inherit(fd: OwnedFd) -> Result<Self> {
+		let address = net::getsockname(&fd).wrap_err("Inherited Wayland socket isn't a socket")?;
+		ensure!(
+			matches!(address, SocketAddrAny::Unix(_)),
+			"Inherited Wayland socket isn't a unix socket"
+		);
+		ensure!(
+			sockopt::get_socket_type(&fd)? == SocketType::STREAM,
+			"Inherited Wayland socket isn't a stream socket"
+		);
+		ensure!(
+			sockopt::get_socket_acceptconn(&fd)?,
+			"Inherited Wayland socket isn't listening"
+		);
+		// inherited fds usually aren't close on exec, clients we launch shouldn't get it too
+		fcntl_setfd(&fd, FdFlags::CLOEXEC)?;
+		let listener = UnixListener::from(fd);
+		listener.set_nonblocking(true)?;
+		Ok(WaylandSocket::Inherited(listener.into()))
+	}
+
+	/// What clients put in `WAYLAND_DISPLAY` to connect. An inherited socket's is its path, just
+	/// the file name if that's in `$XDG_RUNTIME_DIR` like a bound one. `None` for abstract sockets
+	/// and names that aren't UTF-8.
+	pub fn name(&self) -> Option<String> {
+		let name = match self {
+			WaylandSocket::Bound(socket) => socket.socket_name()?.to_str()?.to_string(),
+			WaylandSocket::Inherited(fd) => {
+				let SocketAddrAny::Unix(address) = net::getsockname(fd).ok()? else {
+					return None;
+				};
+				let path = Path::new(OsStr::from_bytes(address.path()?.to_bytes()));
+				let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR");
+				let name = match runtime_dir {
+					Some(runtime_dir) if path.parent() == Some(Path::new(&runtime_dir)) => {
+						path.file_name()?
+					}
+					_ => path.as_os_str(),
+				};
+				name.to_str()?.to_string()
+			}
+		};
+		Some(name)
+	}
+}
+impl AsRawFd for WaylandSocket {
+	fn as_raw_fd(&self) -> RawFd {
+		match self {
+			WaylandSocket::Bound(socket) => socket.as_raw_fd(),
+			WaylandSocket::Inherited(fd) => fd.as_raw_fd(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn shutting_down_removes_the_socket() {
+		// the only test that touches the environment, so it can't race with another one
+		let runtime_dir = std::env::temp_dir().join(format!("stardust-{}", std::process::id()));
+		std::fs::create_dir_all(&runtime_dir).unwrap();
+		std::env::set_var("XDG_RUNTIME_DIR", &runtime_dir);
+
+		let socket = WaylandSocket::bind(Some("wayland-test")).unwrap();
+		assert_eq!(socket.name().as_deref(), Some("wayland-test"));
+		let path = runtime_dir.join("wayland-test");
+		assert!(path.exists());
+		drop(socket);
+		assert!(!path.exists());
+		let _ = std::fs::remove_dir_all(&runtime_dir);
+	}
+}
@@ -1,26 +1,54 @@
-use super::DisplayWrapper;
-use crate::wayland::{drm::wl_drm::WlDrm, seat::SeatData};
+use super::{
+	client_limits::{CommitRate, ResourceUsage},
+	data_device::DataDeviceState,
+	destroy_global,
+	primary_selection::PrimarySelectionState,
+	surface::CORE_SURFACES,
+	DisplayWrapper,
+};
+use crate::core::registry::Registry;
+use crate::wayland::{
+	color_management::wp_color_manager_v1::WpColorManagerV1, drm::wl_drm::WlDrm, seat::SeatData,
+};
+use mint::Vector2;
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
 use smithay::{
 	backend::{
-		allocator::{dmabuf::Dmabuf, Fourcc},
+		allocator::{dmabuf::Dmabuf, Format, Fourcc, Modifier},
 		egl::EGLDevice,
-		renderer::gles::GlesRenderer,
+		renderer::{gles::GlesRenderer, ImportMemWl},
 	},
-	delegate_dmabuf, delegate_output, delegate_shm,
+	delegate_dmabuf, delegate_output, delegate_presentation, delegate_shm,
+	delegate_single_pixel_buffer, delegate_viewporter,
 	output::{Mode, Output, Scale, Subpixel},
 	reexports::{
-		wayland_protocols::xdg::{
-			decoration::zv1::server::zxdg_decoration_manager_v1::ZxdgDecorationManagerV1,
-			shell::server::xdg_wm_base::XdgWmBase,
+		wayland_protocols::{
+			wp::{
+				content_type::v1::server::wp_content_type_manager_v1::WpContentTypeManagerV1,
+				cursor_shape::v1::server::wp_cursor_shape_manager_v1::WpCursorShapeManagerV1,
+				idle_inhibit::zv1::server::zwp_idle_inhibit_manager_v1::ZwpIdleInhibitManagerV1,
+				pointer_constraints::zv1::server::zwp_pointer_constraints_v1::ZwpPointerConstraintsV1,
+				primary_selection::zv1::server::zwp_primary_selection_device_manager_v1::ZwpPrimarySelectionDeviceManagerV1,
+				relative_pointer::zv1::server::zwp_relative_pointer_manager_v1::ZwpRelativePointerManagerV1,
+				tablet::zv2::server::zwp_tablet_manager_v2::ZwpTabletManagerV2,
+				text_input::zv3::server::zwp_text_input_manager_v3::ZwpTextInputManagerV3,
+			},
+			xdg::{
+				decoration::zv1::server::zxdg_decoration_manager_v1::ZxdgDecorationManagerV1,
+				foreign::zv2::server::{
+					zxdg_exporter_v2::ZxdgExporterV2, zxdg_importer_v2::ZxdgImporterV2,
+				},
+				shell::server::xdg_wm_base::XdgWmBase,
+			},
 		},
 		wayland_protocols_misc::server_decoration::server::org_kde_kwin_server_decoration_manager::Mode as DecorationMode,
 		wayland_server::{
-			backend::{ClientData, ClientId, DisconnectReason},
+			backend::{ClientData, ClientId, DisconnectReason, GlobalId},
 			protocol::{
 				wl_buffer::WlBuffer, wl_data_device_manager::WlDataDeviceManager,
-				wl_output::WlOutput,
+				wl_output::WlOutput, wl_shm,
 			},
 			DisplayHandle,
 		},
@@ -32,20 +60,101 @@ use smithay::{
 		dmabuf::{
 			self, DmabufFeedback, DmabufFeedbackBuilder, DmabufGlobal, DmabufHandler, DmabufState,
 		},
+		fractional_scale::FractionalScaleManagerState,
 		output::OutputHandler,
+		presentation::PresentationState,
 		shell::kde::decoration::KdeDecorationState,
 		shm::{ShmHandler, ShmState},
+		single_pixel_buffer::SinglePixelBufferState,
+		viewporter::ViewporterState,
+		xdg_activation::XdgActivationState,
 	},
 };
-use std::sync::{Arc, Weak};
+use std::sync::{
+	atomic::{AtomicBool, Ordering},
+	Arc, Weak,
+};
 use tokio::sync::mpsc::UnboundedSender;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
+
+pub const DEFAULT_OUTPUT: &str = "1x";
+/// In mHz, until the actual frame rate is known
+const DEFAULT_REFRESH: i32 = 60000;
+
+pub static CLIENTS: Registry<ClientState> = Registry::new();
+
+/// Outputs start active, see `WaylandState::update_output_activity`
+struct OutputActive(AtomicBool);
+pub fn output_active(output: &Output) -> bool {
+	output
+		.user_data()
+		.get::<OutputActive>()
+		.map_or(true, |active| active.0.load(Ordering::Relaxed))
+}
+
+/// Who's on the other end of a client's socket, from `SO_PEERCRED` when it connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientCredentials {
+	/// Not every platform reports it
+	pub pid: Option<i32>,
+	pub uid: u32,
+	pub gid: u32,
+}
+
+/// What buffers clients can attach, as `wl_shm` and `zwp_linux_dmabuf_v1` advertise it.
+/// Anything else fails to be created instead of showing up black. They're logged at debug level
+/// on startup, e.g. to find out why a client's video doesn't show up.
+#[derive(Debug)]
+struct BufferFormats {
+	shm: Vec<wl_shm::Format>,
+	/// Every format with the modifiers it can be imported with, straight from the renderer
+	dmabuf: Vec<(Fourcc, Vec<Modifier>)>,
+	/// `zwp_linux_dmabuf_v1` is version 4 with feedback, without a render node it's version 3
+	dmabuf_feedback: bool,
+}
+/// Every `wl_shm` global has these, whatever else it was created with
+const MANDATORY_SHM_FORMATS: [wl_shm::Format; 2] =
+	[wl_shm::Format::Argb8888, wl_shm::Format::Xrgb8888];
+/// What `ShmState` gets created with, so the mandatory formats aren't advertised twice
+fn extra_shm_formats(supported: impl Iterator<Item = wl_shm::Format>) -> Vec<wl_shm::Format> {
+	let mut formats = Vec::new();
+	for format in supported {
+		if !MANDATORY_SHM_FORMATS.contains(&format) && !formats.contains(&format) {
+			formats.push(format);
+		}
+	}
+	formats
+}
+impl BufferFormats {
+	fn new(extra_shm: &[wl_shm::Format], dmabuf: &[Format], dmabuf_feedback: bool) -> Self {
+		let shm = MANDATORY_SHM_FORMATS
+			.into_iter()
+			.chain(extra_shm.iter().copied())
+			.collect();
+		let mut grouped = Vec::<(Fourcc, Vec<Modifier>)>::new();
+		for format in dmabuf {
+			match grouped.iter_mut().find(|(code, _)| *code == format.code) {
+				Some((_, modifiers)) => modifiers.push(format.modifier),
+				None => grouped.push((format.code, vec![format.modifier])),
+			}
+		}
+		BufferFormats {
+			shm,
+			dmabuf: grouped,
+			dmabuf_feedback,
+		}
+	}
+}
 
 pub struct ClientState {
 	pub id: OnceCell<ClientId>,
+	/// `None` if the socket couldn't tell us
+	pub credentials: Option<ClientCredentials>,
 	pub compositor_state: CompositorClientState,
 	pub display: Weak<DisplayWrapper>,
 	pub seat: Arc<SeatData>,
+	pub resources: Arc<ResourceUsage>,
+	pub commit_rate: CommitRate,
 }
 impl ClientState {
 	pub fn flush(&self) {
@@ -55,6 +164,11 @@ impl ClientState {
 		let _ = display.flush_clients(self.id.get().cloned());
 	}
 }
+impl Drop for ClientState {
+	fn drop(&mut self) {
+		CLIENTS.remove(self);
+	}
+}
 impl ClientData for ClientState {
 	fn initialized(&self, client_id: ClientId) {
 		info!("Wayland client {:?} connected", client_id);
@@ -66,6 +180,8 @@ impl ClientData for ClientState {
 			"Wayland client {:?} disconnected because {:#?}",
 			client_id, reason
 		);
+		// the seat global is only for this client, nothing else can bind it
+		self.seat.destroy_global();
 	}
 }
 
@@ -74,13 +190,23 @@ pub struct WaylandState {
 	pub display_handle: DisplayHandle,
 
 	pub compositor_state: CompositorState,
-	// pub xdg_activation_state: XdgActivationState,
+	pub xdg_activation_state: XdgActivationState,
 	pub kde_decoration_state: KdeDecorationState,
+	pub fractional_scale_manager_state: FractionalScaleManagerState,
 	pub shm_state: ShmState,
+	pub viewporter_state: ViewporterState,
+	pub single_pixel_buffer_state: SinglePixelBufferState,
+	pub presentation_state: PresentationState,
+	pub primary_selection_state: PrimarySelectionState,
+	pub data_device_state: DataDeviceState,
 	dmabuf_state: (DmabufState, DmabufGlobal, Option<DmabufFeedback>),
 	pub drm_formats: Vec<Fourcc>,
 	pub dmabuf_tx: UnboundedSender<(Dmabuf, Option<dmabuf::ImportNotifier>)>,
+	/// The default output, surfaces get put here until assigned to another
 	pub output: Output,
+	pub outputs: FxHashMap<String, (Output, GlobalId)>,
+	/// Refresh rate of every output in mHz, follows StereoKit's frame rate
+	refresh: i32,
 }
 
 impl WaylandState {
@@ -88,17 +214,27 @@ impl WaylandState {
 		display_handle: DisplayHandle,
 		renderer: &GlesRenderer,
 		dmabuf_tx: UnboundedSender<(Dmabuf, Option<dmabuf::ImportNotifier>)>,
+		clock_id: u32,
 	) -> Arc<Mutex<Self>> {
 		let compositor_state = CompositorState::new::<Self>(&display_handle);
-		// let xdg_activation_state = XdgActivationState::new::<Self, _>(&display_handle);
+		let xdg_activation_state = XdgActivationState::new::<Self>(&display_handle);
 		let kde_decoration_state =
 			KdeDecorationState::new::<Self>(&display_handle, DecorationMode::Server);
-		let shm_state = ShmState::new::<Self>(&display_handle, vec![]);
+		let fractional_scale_manager_state =
+			FractionalScaleManagerState::new::<Self>(&display_handle);
+		// Everything the renderer can upload, smithay picks the matching GL format and swizzle.
+		// Buffers in formats that aren't listed are an invalid_format error when created.
+		let shm_formats = extra_shm_formats(renderer.shm_formats());
+		let shm_state = ShmState::new::<Self>(&display_handle, shm_formats.clone());
+		let viewporter_state = ViewporterState::new::<Self>(&display_handle);
+		let single_pixel_buffer_state = SinglePixelBufferState::new::<Self>(&display_handle);
+		let presentation_state = PresentationState::new::<Self>(&display_handle, clock_id);
 		let render_node = EGLDevice::device_for_display(renderer.egl_context().display())
 			.and_then(|device| device.try_get_render_node());
+		// Only advertise what we can actually sample from, clients pick their modifiers from this
 		let dmabuf_formats = renderer
 			.egl_context()
-			.dmabuf_render_formats()
+			.dmabuf_texture_formats()
 			.iter()
 			.cloned()
 			.collect::<Vec<_>>();
@@ -132,32 +268,41 @@ impl WaylandState {
 				dmabuf_state.create_global::<WaylandState>(&display_handle, dmabuf_formats.clone());
 			(dmabuf_state, dmabuf_global, None)
 		};
-
-		let output = Output::new(
-			"1x".to_owned(),
-			smithay::output::PhysicalProperties {
-				size: Size::default(),
-				subpixel: Subpixel::None,
-				make: "Virtual XR Display".to_owned(),
-				model: "Your Headset Name Here".to_owned(),
-			},
+		let buffer_formats =
+			BufferFormats::new(&shm_formats, &dmabuf_formats, dmabuf_state.2.is_some());
+		info!(
+			shm = buffer_formats.shm.len(),
+			dmabuf = buffer_formats.dmabuf.len(),
+			dmabuf_feedback = buffer_formats.dmabuf_feedback,
+			"Supported buffer formats"
 		);
-		let _output_global = output.create_global::<Self>(&display_handle);
-		let mode = Mode {
-			size: (2048, 2048).into(),
-			refresh: 60000,
-		};
-		output.change_current_state(
-			Some(mode),
-			Some(Transform::Normal),
-			Some(Scale::Integer(2)),
-			None,
+		debug!(?buffer_formats);
+
+		let mut outputs = FxHashMap::default();
+		let default_output = Self::new_output(
+			&display_handle,
+			DEFAULT_OUTPUT,
+			[2048, 2048].into(),
+			2,
+			DEFAULT_REFRESH,
 		);
-		output.set_preferred(mode);
+		let output = default_output.0.clone();
+		outputs.insert(DEFAULT_OUTPUT.to_string(), default_output);
 		display_handle.create_global::<Self, WlDataDeviceManager, _>(3, ());
 		display_handle.create_global::<Self, XdgWmBase, _>(5, ());
 		display_handle.create_global::<Self, ZxdgDecorationManagerV1, _>(1, ());
 		display_handle.create_global::<Self, WlDrm, _>(2, ());
+		display_handle.create_global::<Self, ZwpPointerConstraintsV1, _>(1, ());
+		display_handle.create_global::<Self, ZwpRelativePointerManagerV1, _>(1, ());
+		display_handle.create_global::<Self, WpCursorShapeManagerV1, _>(1, ());
+		display_handle.create_global::<Self, ZwpIdleInhibitManagerV1, _>(1, ());
+		display_handle.create_global::<Self, ZwpPrimarySelectionDeviceManagerV1, _>(1, ());
+		display_handle.create_global::<Self, ZwpTextInputManagerV3, _>(1, ());
+		display_handle.create_global::<Self, ZwpTabletManagerV2, _>(1, ());
+		display_handle.create_global::<Self, WpColorManagerV1, _>(1, ());
+		display_handle.create_global::<Self, WpContentTypeManagerV1, _>(1, ());
+		display_handle.create_global::<Self, ZxdgExporterV2, _>(1, ());
+		display_handle.create_global::<Self, ZxdgImporterV2, _>(1, ());
 
 		info!("Init Wayland compositor");
 
@@ -167,16 +312,129 @@ impl WaylandState {
 				display_handle,
 
 				compositor_state,
-				// xdg_activation_state,
+				xdg_activation_state,
 				kde_decoration_state,
+				fractional_scale_manager_state,
 				shm_state,
+				viewporter_state,
+				single_pixel_buffer_state,
+				presentation_state,
+				primary_selection_state: PrimarySelectionState::default(),
+				data_device_state: DataDeviceState::default(),
 				drm_formats,
 				dmabuf_state,
 				dmabuf_tx,
 				output,
+				outputs,
+				refresh: DEFAULT_REFRESH,
 			})
 		})
 	}
+
+	fn new_output(
+		display_handle: &DisplayHandle,
+		name: &str,
+		size: Vector2<u32>,
+		scale: i32,
+		refresh: i32,
+	) -> (Output, GlobalId) {
+		// Smithay sends geometry, mode, scale, name, description and done to each bound
+		// wl_output as far as its version supports, the description is made from these
+		let output = Output::new(
+			name.to_owned(),
+			smithay::output::PhysicalProperties {
+				size: Size::default(),
+				subpixel: Subpixel::None,
+				make: "Stardust XR".to_owned(),
+				model: "Virtual Display".to_owned(),
+			},
+		);
+		// the state has to be complete before anyone can bind it
+		let mode = Mode {
+			size: (size.x as i32, size.y as i32).into(),
+			refresh,
+		};
+		output.set_preferred(mode);
+		output.change_current_state(
+			Some(mode),
+			Some(Transform::Normal),
+			Some(Scale::Integer(scale)),
+			None,
+		);
+		let global = output.create_global::<Self>(display_handle);
+		(output, global)
+	}
+
+	/// Create a new logical output, or return the existing one with that name.
+	pub fn create_output(&mut self, name: &str, size: Vector2<u32>, scale: i32) -> Output {
+		if let Some((output, _)) = self.outputs.get(name) {
+			return output.clone();
+		}
+		let (output, global) =
+			Self::new_output(&self.display_handle, name, size, scale, self.refresh);
+		info!(name, ?size, scale, "Created Wayland output");
+		self.outputs
+			.insert(name.to_string(), (output.clone(), global));
+		output
+	}
+	pub fn get_output(&self, name: &str) -> Option<Output> {
+		self.outputs.get(name).map(|(output, _)| output.clone())
+	}
+	/// Destroy an output, moving all surfaces on it back to the default output.
+	/// The default output can't be destroyed.
+	pub fn destroy_output(&mut self, name: &str) {
+		if name == DEFAULT_OUTPUT {
+			return;
+		}
+		let Some((output, global)) = self.outputs.remove(name) else {
+			return;
+		};
+		for core_surface in CORE_SURFACES.get_valid_contents() {
+			if core_surface.output().as_ref() == Some(&output) {
+				core_surface.set_output(Some(self.output.clone()));
+			}
+		}
+		destroy_global(global);
+		info!(name, "Destroyed Wayland output");
+	}
+	/// Change the refresh rate (in mHz) of every output, clients get the new mode and a done.
+	pub fn set_refresh(&mut self, refresh: i32) {
+		if refresh == self.refresh {
+			return;
+		}
+		self.refresh = refresh;
+		for (output, _) in self.outputs.values() {
+			let Some(old_mode) = output.current_mode() else {
+				continue;
+			};
+			let mode = Mode {
+				refresh,
+				..old_mode
+			};
+			output.delete_mode(old_mode);
+			output.set_preferred(mode);
+			output.change_current_state(Some(mode), None, None, None);
+		}
+		debug!(refresh, "Output refresh rate changed");
+	}
+	/// Only outputs with a surface in view stay active, nobody's looking at the others. Surfaces
+	/// on an inactive output get no frame callbacks at all, ones that asked for a frame meanwhile
+	/// get it on the first frame after one of them comes into view again.
+	pub fn update_output_activity(&mut self, visible: &[Output]) {
+		for (name, (output, _)) in &self.outputs {
+			let active = visible.contains(output);
+			let user_data = output.user_data();
+			user_data.insert_if_missing_threadsafe(|| OutputActive(AtomicBool::new(true)));
+			let was_active = user_data
+				.get::<OutputActive>()
+				.unwrap()
+				.0
+				.swap(active, Ordering::Relaxed);
+			if was_active != active {
+				debug!(name, active, "Output activity changed");
+			}
+		}
+	}
 }
 impl Drop for WaylandState {
 	fn drop(&mut self) {
@@ -211,3 +469,41 @@ impl OutputHandler for WaylandState {
 delegate_dmabuf!(WaylandState);
 delegate_shm!(WaylandState);
 delegate_output!(WaylandState);
+delegate_presentation!(WaylandState);
+delegate_viewporter!(WaylandState);
+delegate_single_pixel_buffer!(WaylandState);
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn shm_formats_are_advertised_once() {
+		use wl_shm::Format::*;
+
+		// roughly what GlesRenderer reports with the 10 bit extensions
+		let supported = [
+			Abgr8888,
+			Xbgr8888,
+			Argb8888,
+			Xrgb8888,
+			Abgr2101010,
+			Xbgr2101010,
+			Abgr8888,
+		];
+		let extra = extra_shm_formats(supported.into_iter());
+		assert_eq!(extra, [Abgr8888, Xbgr8888, Abgr2101010, Xbgr2101010]);
+		let formats = BufferFormats::new(&extra, &[], false);
+		assert_eq!(
+			formats.shm,
+			[
+				Argb8888,
+				Xrgb8888,
+				Abgr8888,
+				Xbgr8888,
+				Abgr2101010,
+				Xbgr2101010
+			]
+		);
+	}
+}
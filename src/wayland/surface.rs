@@ -1,34 +1,203 @@
-use super::{state::WaylandState, utils::get_data};
+use super::{
+	client_limits::{BufferResource, ResourceUsage},
+	color_management::{
+		surface_image_description, ImageDescription, TransferFunction, HDR_REFERENCE_WHITE,
+	},
+	compositor::APPLYING_COMMITS,
+	content_type::surface_content_type,
+	fractional_scale::{
+		apparent_scale, send_preferred_scale, ScaleDebounce, DEFAULT_FRACTIONAL_SCALE,
+	},
+	metrics::{self, LatencyStage},
+	state::{output_active, ClientState, WaylandState},
+	utils::get_data,
+	xdg_shell::XdgCachedState,
+};
 use crate::{
 	core::{delta::Delta, destroy_queue, registry::Registry},
-	nodes::drawable::{model::ModelPart, shaders::PANEL_SHADER_BYTES},
+	nodes::{
+		drawable::{
+			model::ModelPart,
+			shaders::{PANEL_SHADER_BYTES, UNLIT_SHADER_BYTES},
+		},
+		items::panel::{ColorSpace, ContentType, PanelGeometry, SurfaceFiltering, SurfaceSnapshot},
+	},
 };
 use mint::Vector2;
-use once_cell::sync::OnceCell;
+use once_cell::sync::{Lazy, OnceCell};
 use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
 use send_wrapper::SendWrapper;
 use smithay::{
-	backend::renderer::{
-		gles::{GlesRenderer, GlesTexture},
-		utils::{import_surface_tree, on_commit_buffer_handler, RendererSurfaceStateUserData},
-		Renderer, Texture,
+	backend::{
+		allocator::{format::has_alpha, Fourcc},
+		renderer::{
+			buffer_type,
+			element::{
+				surface::{render_elements_from_surface_tree, WaylandSurfaceRenderElement},
+				Kind,
+			},
+			gles::{
+				ffi, GlesError, GlesRenderer, GlesTexProgram, GlesTexture, Uniform, UniformName,
+				UniformType,
+			},
+			utils::{
+				draw_render_elements, import_surface_tree, on_commit_buffer_handler, CommitCounter,
+				RendererSurfaceStateUserData,
+			},
+			Bind, BufferType, ExportMem, Frame, ImportMem, Offscreen, Renderer, Texture,
+			TextureMapping, Unbind,
+		},
 	},
 	desktop::utils::send_frames_surface_tree,
 	output::Output,
-	reexports::wayland_server::{self, protocol::wl_surface::WlSurface, DisplayHandle, Resource},
-	wayland::compositor::{self, SurfaceData},
+	reexports::{
+		wayland_protocols::wp::presentation_time::server::wp_presentation_feedback,
+		wayland_server::{self, protocol::wl_surface::WlSurface, DisplayHandle, Resource},
+	},
+	utils::{Buffer, Logical, Monotonic, Physical, Rectangle, Size, Time, Transform},
+	wayland::{
+		compositor::{
+			self, RectangleKind, RegionAttributes, SurfaceAttributes, SurfaceData, TraversalAction,
+		},
+		presentation::PresentationFeedbackCachedState,
+		shm::BufferAccessError,
+		single_pixel_buffer::get_single_pixel_buffer,
+	},
+};
+use std::{
+	cell::RefCell,
+	collections::VecDeque,
+	ffi::c_void,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+		Arc, Weak,
+	},
+	time::{Duration, Instant},
 };
-use std::{cell::RefCell, ffi::c_void, sync::Arc, time::Duration};
 use stereokit::{
-	Material, Shader, StereoKitDraw, Tex, TextureAddress, TextureFormat, TextureSample,
-	TextureType, Transparency,
+	Color128, Material, Shader, StereoKitDraw, StereoKitMultiThread, Tex, TextureAddress,
+	TextureFormat, TextureSample, TextureType, Transparency,
 };
+use tracing::{debug, warn};
+
+/// The commit whose shm buffer was already given back to the client
+struct ShmReleased(Mutex<Option<CommitCounter>>);
 
 pub static CORE_SURFACES: Registry<CoreSurface> = Registry::new();
+/// How often surfaces that aren't in view still get frame callbacks, so they mostly stop rendering
+const HIDDEN_FRAME_INTERVAL: Duration = Duration::from_secs(1);
+/// Updates smaller than this fraction of the surface (e.g. a blinking text cursor) only regenerate
+/// the mipmaps every `MIPMAP_INTERVAL`, the rest regenerate them right away
+const MIPMAP_SMALL_DAMAGE: f64 = 0.05;
+const MIPMAP_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_ANISOTROPY: i32 = 8;
+/// How far back commits count towards `CoreSurface::commit_rate`
+const COMMIT_RATE_WINDOW: Duration = Duration::from_secs(1);
+/// A commit that doesn't fit the frame's upload budget waits at most this many frames, then it
+/// gets uploaded anyway so even a buffer too big for any budget shows up
+const MAX_DEFERRED_FRAMES: u32 = 4;
+pub const MAX_TEXTURE_BUFFERS: usize = 3;
+/// Material queue offset of cursors and drag icons, above every stacked panel item
+pub const TOP_MATERIAL_OFFSET: u32 = u16::MAX as u32;
+/// How many textures each composited surface cycles through, so compositing the next commit
+/// doesn't have to wait for StereoKit to be done sampling the last one. 1 reuses the same one.
+/// Can be set with `--wayland-texture-buffers` or `STARDUST_TEXTURE_BUFFERS`.
+pub static TEXTURE_BUFFERS: Lazy<AtomicUsize> = Lazy::new(|| {
+	let buffers = std::env::var("STARDUST_TEXTURE_BUFFERS")
+		.ok()
+		.and_then(|buffers| buffers.parse().ok())
+		.unwrap_or(2);
+	AtomicUsize::new(usize::clamp(buffers, 1, MAX_TEXTURE_BUFFERS))
+});
+
+/// Composited into its panel item's texture along with its subsurfaces
+pub const XDG_TOPLEVEL_ROLE: &str = "xdg_toplevel";
+/// Composited on its own and drawn as a child of its parent's panel item
+pub const XDG_POPUP_ROLE: &str = "xdg_popup";
+/// Composited on its own and drawn by the shell at the pointer, at `TOP_MATERIAL_OFFSET`
+pub const CURSOR_ROLE: &str = "cursor_image";
+/// Composited on its own and drawn by the server at the dragging pointer, see `draw_drag_icon`
+pub const DND_ICON_ROLE: &str = "dnd_icon";
+// Subsurfaces get smithay's "subsurface" role, they're composited into their root surface
+
+/// A `wl_surface` can only ever have one role, so this fails if it already has a different one
+/// (including a subsurface) and the caller has to post its protocol's role error. Getting the
+/// same role again is fine, e.g. a cursor surface set again.
+pub fn give_role(surface: &WlSurface, role: &'static str) -> bool {
+	match compositor::get_role(surface) {
+		Some(current) if current == role => true,
+		Some(current) => {
+			warn!(?surface, current, role, "Surface already has another role");
+			false
+		}
+		None => compositor::give_role(surface, role).is_ok(),
+	}
+}
+
+/// `on_commit_buffer_handler` takes the buffer and damage out of the current state, so if
+/// they're back a newer commit landed since the renderer state was updated
+fn commit_unprocessed(attributes: &SurfaceAttributes) -> bool {
+	attributes.buffer.is_some() || !attributes.damage.is_empty()
+}
+
+/// Where `x` on a flat panel `width` pixels wide is on one bent `arc` radians around
+fn unbend_x(x: f32, width: f32, arc: f32) -> f32 {
+	// a point `u` of the way from the middle along the curve is `sin(u * arc) / arc` of
+	// the width from the middle of the flat panel
+	let flat = x / width - 0.5;
+	let u = (flat * arc).clamp(-1.0, 1.0).asin() / arc;
+	(u + 0.5) * width
+}
+
+/// Timing of the StereoKit frame, used for presentation feedback
+pub struct FrameTiming {
+	pub time: Time<Monotonic>,
+	/// How long a frame takes on the headset's display, the refresh rate the outputs advertise
+	pub refresh: Duration,
+	pub sequence: u64,
+}
 
 pub struct CoreSurfaceData {
 	wl_tex: Option<SendWrapper<GlesTexture>>,
+	/// The commit the texture was last imported from, damage is tracked relative to this
+	commit: CommitCounter,
+	/// Commits of every subsurface when they were last composited into `wl_tex`
+	subsurface_commits: Vec<CommitCounter>,
+	/// `wl_tex` is our own render target rather than the client's buffer
+	composited: bool,
+	/// What `wl_tex` was composited onto, if it was
+	background: SurfaceBackground,
+	/// Video or game content, which changes every frame
+	full_motion: bool,
+	/// What the texture is sampled with, never `Auto`
+	filtering: SurfaceFiltering,
 	pub size: Vector2<u32>,
+	/// From `wl_surface.set_opaque_region`, used to draw without blending where possible
+	pub opaque_region: Option<RegionAttributes>,
+	/// The texture's format has an alpha channel. Without one (like XRGB8888) the padding byte
+	/// is whatever the client left there, so the surface has to be drawn opaque.
+	has_alpha: bool,
+	/// Everything outside the window geometry got cleared to transparent when compositing
+	clipped: bool,
+	/// Counts the texture against the client's limits while it's alive
+	_resource: Option<BufferResource>,
+}
+impl CoreSurfaceData {
+	fn opaque(&self) -> bool {
+		if self.clipped {
+			return false;
+		}
+		if !self.has_alpha {
+			return true;
+		}
+		let size = (self.size.x as i32, self.size.y as i32);
+		let area = Rectangle::from_loc_and_size((0, 0), size);
+		self.opaque_region
+			.as_ref()
+			.map_or(false, |region| region_covers(region, area))
+	}
 }
 impl Drop for CoreSurfaceData {
 	fn drop(&mut self) {
@@ -36,6 +205,496 @@ impl Drop for CoreSurfaceData {
 	}
 }
 
+struct MipmapState {
+	/// The texture changed since the mipmaps were last generated
+	stale: bool,
+	last_generated: Instant,
+}
+
+/// A copy of the surface's texture kept up to date in a render target, so other things
+/// (mirrors, streams) can show it at their own size without touching the client's buffers.
+pub struct SurfaceExport {
+	model_part: Weak<ModelPart>,
+	/// Downscale to fit within this while keeping the aspect ratio
+	max_size: Option<Vector2<u32>>,
+	on_resize: Box<dyn Fn(Vector2<u32>) + Send + Sync>,
+	target: Option<(Tex, Arc<Material>, Vector2<u32>)>,
+	/// The content that was last copied into the target
+	blitted: Option<(CommitCounter, Vec<CommitCounter>)>,
+}
+impl SurfaceExport {
+	pub fn new(
+		model_part: &Arc<ModelPart>,
+		max_size: Option<Vector2<u32>>,
+		on_resize: impl Fn(Vector2<u32>) + Send + Sync + 'static,
+	) -> Self {
+		SurfaceExport {
+			model_part: Arc::downgrade(model_part),
+			max_size,
+			on_resize: Box::new(on_resize),
+			target: None,
+			blitted: None,
+		}
+	}
+
+	fn size(&self, source: Vector2<u32>) -> Vector2<u32> {
+		let Some(max_size) = self.max_size else {
+			return source;
+		};
+		let scale = (max_size.x as f64 / source.x.max(1) as f64)
+			.min(max_size.y as f64 / source.y.max(1) as f64)
+			.min(1.0);
+		Vector2::from([
+			((source.x as f64 * scale).round() as u32).max(1),
+			((source.y as f64 * scale).round() as u32).max(1),
+		])
+	}
+}
+impl Drop for SurfaceExport {
+	fn drop(&mut self) {
+		if let Some((tex, mat, _)) = self.target.take() {
+			destroy_queue::add(tex);
+			destroy_queue::add(mat);
+		}
+	}
+}
+
+/// The shaders compute `(uv + uv_offset) * uv_scale`, so get those to only sample `src` of a buffer.
+fn viewport_uv(
+	src: Rectangle<f64, Logical>,
+	buffer_size: Size<i32, Logical>,
+) -> (Vector2<f32>, Vector2<f32>) {
+	let uv_offset = [
+		(src.loc.x / src.size.w.max(f64::EPSILON)) as f32,
+		(src.loc.y / src.size.h.max(f64::EPSILON)) as f32,
+	];
+	let uv_scale = [
+		(src.size.w / buffer_size.w.max(1) as f64) as f32,
+		(src.size.h / buffer_size.h.max(1) as f64) as f32,
+	];
+	(uv_offset.into(), uv_scale.into())
+}
+
+/// smithay's texture shader, but encoding the (unpremultiplied) color to sRGB on the way out.
+/// The panel shader decodes everything it samples from sRGB, so linear content has to be
+/// encoded first to come out the same as it went in.
+const DECODE_TO_SRGB_SHADER: &str = r#"
+#version 100
+
+//_DEFINES_
+
+#if defined(EXTERNAL)
+#extension GL_OES_EGL_image_external : require
+#endif
+
+precision mediump float;
+#if defined(EXTERNAL)
+uniform samplerExternalOES tex;
+#else
+uniform sampler2D tex;
+#endif
+
+uniform float alpha;
+#if defined(PQ)
+uniform float reference_white;
+#endif
+varying vec2 v_coords;
+
+vec3 to_srgb(vec3 linear) {
+	vec3 low = linear * 12.92;
+	vec3 high = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+	return mix(high, low, vec3(lessThanEqual(linear, vec3(0.0031308))));
+}
+
+// To linear light with reference white at 1.0
+vec3 decode(vec3 color) {
+#if defined(PQ)
+	vec3 p = pow(color, vec3(1.0 / 78.84375));
+	vec3 nits = 10000.0 * pow(
+		max(p - 0.8359375, 0.0) / (18.8515625 - 18.6875 * p),
+		vec3(1.0 / 0.1593017578125)
+	);
+	return nits / reference_white;
+#elif defined(HLG)
+	vec3 low = color * color / 3.0;
+	vec3 high = (exp((color - 0.55991073) / 0.17883277) + 0.28466892) / 12.0;
+	// 75% signal is reference white
+	return mix(high, low, vec3(lessThanEqual(color, vec3(0.5)))) / 0.26496256;
+#else
+	return color;
+#endif
+}
+
+void main() {
+	vec4 color = texture2D(tex, v_coords);
+#if defined(NO_ALPHA)
+	color.a = 1.0;
+#endif
+	if (color.a > 0.0) {
+		color.rgb = to_srgb(clamp(decode(color.rgb / color.a), 0.0, 1.0)) * color.a;
+	}
+	gl_FragColor = color * alpha;
+}
+"#;
+
+/// How the colors of a surface are encoded, anything but sRGB gets
+/// decoded while compositing so StereoKit can sample it like the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Encoding {
+	Srgb,
+	Linear,
+	Pq,
+	Hlg,
+}
+impl Encoding {
+	/// The client's image description wins over what the shell set
+	fn of(color_space: ColorSpace, description: Option<&ImageDescription>) -> Self {
+		let Some(description) = description else {
+			return match color_space {
+				ColorSpace::Srgb => Encoding::Srgb,
+				ColorSpace::Linear => Encoding::Linear,
+			};
+		};
+		match description.transfer_function {
+			TransferFunction::Srgb | TransferFunction::Gamma22 | TransferFunction::Bt1886 => {
+				Encoding::Srgb
+			}
+			TransferFunction::ExtLinear => Encoding::Linear,
+			TransferFunction::St2084Pq => Encoding::Pq,
+			TransferFunction::Hlg => Encoding::Hlg,
+		}
+	}
+	fn define(self) -> &'static str {
+		match self {
+			Encoding::Srgb | Encoding::Linear => "",
+			Encoding::Pq => "#define PQ\n",
+			Encoding::Hlg => "#define HLG\n",
+		}
+	}
+}
+
+/// Compiled once per renderer and encoding and kept with its EGL context
+fn decode_program(renderer: &mut GlesRenderer, encoding: Encoding) -> Option<GlesTexProgram> {
+	type Programs = RefCell<FxHashMap<Encoding, GlesTexProgram>>;
+	let user_data = renderer.egl_context().user_data();
+	user_data.insert_if_missing(Programs::default);
+	if let Some(program) = user_data.get::<Programs>().unwrap().borrow().get(&encoding) {
+		return Some(program.clone());
+	}
+
+	let source =
+		DECODE_TO_SRGB_SHADER.replace("//_DEFINES_", &format!("{}//_DEFINES_", encoding.define()));
+	let uniforms = match encoding {
+		Encoding::Pq => vec![UniformName::new("reference_white", UniformType::_1f)],
+		_ => Vec::new(),
+	};
+	match renderer.compile_custom_texture_shader(source, &uniforms) {
+		Ok(program) => {
+			let user_data = renderer.egl_context().user_data();
+			let mut programs = user_data.get::<Programs>().unwrap().borrow_mut();
+			programs.insert(encoding, program.clone());
+			Some(program)
+		}
+		Err(err) => {
+			warn!(?err, ?encoding, "Could not compile sRGB encoding shader");
+			None
+		}
+	}
+}
+
+/// What surfaces are composited onto, it shows through wherever they're (partly) transparent.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SurfaceBackground {
+	/// Nothing, so panels float over passthrough in AR
+	#[default]
+	Transparent,
+	/// An sRGB color, e.g. to match a skybox in VR. Surfaces on it are opaque, so they're also
+	/// drawn without blending.
+	Solid([f32; 3]),
+}
+impl SurfaceBackground {
+	/// Smithay composites premultiplied, which an opaque color already is
+	fn clear_color(self) -> [f32; 4] {
+		match self {
+			SurfaceBackground::Transparent => [0.0, 0.0, 0.0, 0.0],
+			SurfaceBackground::Solid([r, g, b]) => [r, g, b, 1.0],
+		}
+	}
+}
+/// `transparent`, or a solid color as `#rrggbb`
+impl FromStr for SurfaceBackground {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s == "transparent" {
+			return Ok(SurfaceBackground::Transparent);
+		}
+		let invalid = || format!("{s:?} isn't transparent or #rrggbb");
+		let hex = s
+			.strip_prefix('#')
+			.filter(|hex| hex.len() == 6 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+			.ok_or_else(invalid)?;
+		let color = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+		let channel = |shift: u32| ((color >> shift) & 0xff) as f32 / 255.0;
+		Ok(SurfaceBackground::Solid([
+			channel(16),
+			channel(8),
+			channel(0),
+		]))
+	}
+}
+
+/// Fully opaque surfaces don't need blending, which also avoids alpha fringes
+fn set_opaque(sk: &impl StereoKitDraw, sk_mat: &Material, opaque: bool) {
+	let transparency = if opaque {
+		Transparency::None
+	} else {
+		Transparency::Blend
+	};
+	sk.material_set_transparency(sk_mat, transparency);
+}
+
+/// If the region covers all of `area`, applying the rectangles in the order they were added.
+fn region_covers(region: &RegionAttributes, area: Rectangle<i32, Logical>) -> bool {
+	region
+		.rects
+		.iter()
+		.fold(false, |covered, (kind, rect)| match kind {
+			RectangleKind::Add => covered || rect.contains_rect(area),
+			RectangleKind::Subtract => covered && !rect.overlaps(area),
+		})
+}
+
+/// If the pixel `position` is in is part of `input_region`, with no region being the whole surface
+fn region_accepts(input_region: Option<&RegionAttributes>, position: Vector2<f32>) -> bool {
+	input_region.map_or(true, |region| {
+		region.contains((position.x.floor() as i32, position.y.floor() as i32))
+	})
+}
+
+/// How many bytes of an RGBA texture of `size` get uploaded for the damage. Damage past the
+/// buffer's edges is allowed and just gets clipped.
+fn damage_bytes<'a>(
+	damage: impl Iterator<Item = &'a Rectangle<i32, Buffer>>,
+	size: Size<i32, Buffer>,
+) -> i64 {
+	let bounds = Rectangle::from_loc_and_size((0, 0), size);
+	damage
+		.filter_map(|rect| rect.intersection(bounds))
+		.map(|rect| rect.size.w as i64 * rect.size.h as i64 * 4)
+		.sum()
+}
+
+/// The (premultiplied) color of the surface's buffer if it's a `wp_single_pixel_buffer_v1`
+fn single_pixel_color(wl_surface: &WlSurface) -> Option<[u8; 4]> {
+	compositor::with_states(wl_surface, |data| {
+		let surface_state = data
+			.data_map
+			.get::<RendererSurfaceStateUserData>()?
+			.borrow();
+		let buffer = surface_state.buffer()?;
+		Some(get_single_pixel_buffer(buffer).ok()?.rgba8888())
+	})
+}
+
+/// Commits of all the subsurfaces in the tree, in z-order. Empty if there are none.
+fn subsurface_commits(wl_surface: &WlSurface) -> Vec<CommitCounter> {
+	let mut commits = Vec::new();
+	compositor::with_surface_tree_upward(
+		wl_surface,
+		(),
+		|_, _, _| TraversalAction::DoChildren(()),
+		|surface, states, _| {
+			if surface == wl_surface {
+				return;
+			}
+			if let Some(surface_state) = states.data_map.get::<RendererSurfaceStateUserData>() {
+				commits.push(surface_state.borrow().current_commit());
+			}
+		},
+		|_, _, _| true,
+	);
+	commits
+}
+
+/// The textures of every surface in the tree, which get sampled when it's composited
+fn imported_textures(renderer: &GlesRenderer, wl_surface: &WlSurface) -> Vec<u32> {
+	let mut textures = Vec::new();
+	compositor::with_surface_tree_upward(
+		wl_surface,
+		(),
+		|_, _, _| TraversalAction::DoChildren(()),
+		|_, states, _| {
+			if let Some(surface_state) = states.data_map.get::<RendererSurfaceStateUserData>() {
+				let surface_state = surface_state.borrow();
+				if let Some(texture) = surface_state.texture::<GlesRenderer>(renderer.id()) {
+					textures.push(texture.tex_id());
+				}
+			}
+		},
+		|_, _, _| true,
+	);
+	textures
+}
+
+/// Once a shm buffer is imported its contents are in the texture, so give it back to the client
+/// right away instead of when the next one gets attached, which lets double buffered clients
+/// draw their next frame while this one's still on screen. Smithay releases it again when it's
+/// replaced, a `release` for a buffer that's already free doesn't mean anything to the client.
+/// Dmabufs are sampled (or composited) straight from the client's memory whenever the texture is
+/// drawn, so those are held until they're replaced.
+fn release_uploaded_shm_buffers(wl_surface: &WlSurface) {
+	compositor::with_surface_tree_upward(
+		wl_surface,
+		(),
+		|_, _, _| TraversalAction::DoChildren(()),
+		|_, states, _| {
+			let Some(surface_state) = states.data_map.get::<RendererSurfaceStateUserData>() else {
+				return;
+			};
+			let surface_state = surface_state.borrow();
+			let Some(buffer) = surface_state.buffer() else {
+				return;
+			};
+			if buffer_type(buffer) != Some(BufferType::Shm) {
+				return;
+			}
+			states
+				.data_map
+				.insert_if_missing_threadsafe(|| ShmReleased(Mutex::new(None)));
+			let Some(released) = states.data_map.get::<ShmReleased>() else {
+				return;
+			};
+			let commit = surface_state.current_commit();
+			let mut released = released.0.lock();
+			if *released != Some(commit) {
+				*released = Some(commit);
+				buffer.release();
+			}
+		},
+		|_, _, _| true,
+	);
+}
+
+/// The output a surface is on, `current` once it's been put on one. A surface is only on an output
+/// once it has content to show there, until then the client gets no `wl_surface.enter` or frame
+/// callbacks it would render into nowhere for. Its first buffer doesn't wait on a callback, so it
+/// maps and gets them from the next frame on.
+fn surface_output<O: Clone>(
+	mapped: bool,
+	current: &mut Option<O>,
+	default_output: &O,
+	enter: impl FnOnce(&O),
+) -> Option<O> {
+	if !mapped {
+		return None;
+	}
+	let output = current.get_or_insert_with(|| {
+		enter(default_output);
+		default_output.clone()
+	});
+	Some(output.clone())
+}
+
+/// How a surface's texture gets sampled with `filtering` (never `Auto`), only our own composited
+/// copy of it has mipmaps
+fn texture_sampling(
+	filtering: SurfaceFiltering,
+	full_motion: bool,
+	composited: bool,
+) -> (TextureType, TextureSample) {
+	match filtering {
+		SurfaceFiltering::Trilinear if full_motion => {
+			(TextureType::IMAGE_NO_MIPS, TextureSample::Linear)
+		}
+		SurfaceFiltering::Trilinear if composited => (TextureType::IMAGE, TextureSample::Linear),
+		SurfaceFiltering::Linear => (TextureType::IMAGE_NO_MIPS, TextureSample::Linear),
+		_ => (TextureType::IMAGE_NO_MIPS, TextureSample::Point),
+	}
+}
+
+/// Where frame callbacks go this frame, if anywhere. Surfaces out of view or hidden only get the
+/// occasional one so they mostly stop rendering, and minimized ones or ones on inactive outputs
+/// none. `since_last` is long past once they're back, so a client waiting on its callback gets it
+/// right away.
+fn frame_callback_output<O>(
+	output: Option<&O>,
+	output_active: impl Fn(&O) -> bool,
+	minimized: bool,
+	visible: bool,
+	since_last: Duration,
+) -> Option<&O> {
+	output
+		.filter(|output| output_active(output) && !minimized)
+		.filter(|_| visible || since_last >= HIDDEN_FRAME_INTERVAL)
+}
+
+/// The part of the surface that's the window according to `xdg_surface.set_window_geometry`,
+/// `None` if that's all of it. What's past it isn't drawn: shadows, the rest of a buffer
+/// that's still the old size mid-resize, and subsurfaces hanging off the window's edge.
+fn window_clip(
+	data: &SurfaceData,
+	surface_size: Size<i32, Logical>,
+) -> Option<Rectangle<i32, Logical>> {
+	let geometry = data.cached_state.current::<XdgCachedState>().geometry?;
+	let bounds = Rectangle::from_loc_and_size((0, 0), surface_size);
+	let window = Rectangle::from_loc_and_size(
+		(geometry.origin.x, geometry.origin.y),
+		(geometry.size.x as i32, geometry.size.y as i32),
+	);
+	let clip = window.intersection(bounds)?;
+	(clip != bounds).then_some(clip)
+}
+
+/// Draw the surface and its subsurfaces at their offsets and in z-order into one texture
+/// (reusing `target` if it's still the right size and not one of the tree's own textures), as
+/// StereoKit can only sample one.
+/// Only what's inside `clip` gets drawn, the rest stays transparent.
+/// `program` replaces the texture shader everything gets drawn with, along with its uniforms.
+#[allow(clippy::too_many_arguments)]
+fn composite_surface_tree(
+	renderer: &mut GlesRenderer,
+	wl_surface: &WlSurface,
+	size: Size<i32, Physical>,
+	scale: f64,
+	clip: Option<Rectangle<i32, Logical>>,
+	target: Option<GlesTexture>,
+	program: Option<(GlesTexProgram, Vec<Uniform<'static>>)>,
+	background: SurfaceBackground,
+) -> Result<GlesTexture, GlesError> {
+	let buffer_size: Size<i32, Buffer> = (size.w, size.h).into();
+	let imported = imported_textures(renderer, wl_surface);
+	let target = match target.filter(|target| !imported.contains(&target.tex_id())) {
+		Some(target) if target.size() == buffer_size => target,
+		_ => renderer.create_buffer(Fourcc::Abgr8888, buffer_size)?,
+	};
+	let elements: Vec<WaylandSurfaceRenderElement<GlesRenderer>> =
+		render_elements_from_surface_tree(
+			renderer,
+			wl_surface,
+			(0, 0),
+			scale,
+			1.0,
+			Kind::Unspecified,
+		);
+	let full = Rectangle::from_loc_and_size((0, 0), size);
+	let damage = [clip.map_or(full, |clip| clip.to_physical_precise_round(scale))];
+
+	renderer.bind(target.clone())?;
+	let mut frame = renderer.render(size, Transform::Normal)?;
+	if let Some((program, uniforms)) = program {
+		frame.override_default_tex_program(program, uniforms);
+	}
+	if clip.is_some() {
+		frame.clear(SurfaceBackground::Transparent.clear_color(), &[full])?;
+	}
+	frame.clear(background.clear_color(), &damage)?;
+	draw_render_elements(&mut frame, scale, &elements, &damage)?;
+	frame.finish()?;
+	renderer.unbind()?;
+	Ok(target)
+}
+
 pub struct CoreSurface {
 	pub dh: DisplayHandle,
 	pub weak_surface: wayland_server::Weak<WlSurface>,
@@ -43,9 +702,49 @@ pub struct CoreSurface {
 	sk_tex: OnceCell<Tex>,
 	sk_mat: OnceCell<Arc<Material>>,
 	material_offset: Mutex<Delta<u32>>,
+	color_space: Mutex<Delta<ColorSpace>>,
+	filtering: Mutex<Delta<SurfaceFiltering>>,
+	mipmaps: Mutex<MipmapState>,
+	/// Multiplied onto the surface's own alpha, set by the shell to fade it
+	alpha: Mutex<Delta<f32>>,
+	/// Composited onto a transparent background whatever the `Wayland` one is, like cursors
+	keep_transparent: AtomicBool,
+	/// Skipped processing while hidden, so the texture is out of date
+	was_hidden: AtomicBool,
+	/// Not drawn and not getting frame callbacks, see `set_minimized`
+	minimized: AtomicBool,
+	/// What the model parts showing this surface get bent into
+	geometry: Mutex<PanelGeometry>,
+	output: Mutex<Option<Output>>,
+	fractional_scale: Mutex<f64>,
+	/// The scale the surface has appeared at, sent once it held for a bit
+	pending_scale: Mutex<ScaleDebounce>,
 	on_mapped: Box<dyn Fn() + Send + Sync>,
+	/// Called before the texture is dropped, so the surface's size is still there
+	on_unmapped: Box<dyn Fn() + Send + Sync>,
 	on_commit: Box<dyn Fn(u32) + Send + Sync>,
 	pub pending_material_applications: Registry<ModelPart>,
+	/// Everything showing this surface, to tell if it's visible
+	model_parts: Registry<ModelPart>,
+	last_frame_callback: Mutex<Instant>,
+	/// When the commits in the last `COMMIT_RATE_WINDOW` happened, oldest first
+	commit_times: Mutex<VecDeque<Instant>>,
+	/// Committed since it was last processed
+	committed: AtomicBool,
+	/// How long processing the last commit took, what the next one is guessed to take
+	upload_time: Mutex<Duration>,
+	/// Frames in a row the latest commit waited behind the upload budget, see `fits_upload_budget`
+	deferred_frames: AtomicU32,
+	/// When the input the latest commit reacted to came in and when it was committed, until
+	/// it's uploaded. Only while input latency is tracked.
+	input_commit: Mutex<Option<(Instant, Instant)>>,
+	/// The resources of the client that owns this surface, `None` for xwayland
+	resources: Option<Arc<ResourceUsage>>,
+	exports: Mutex<Vec<SurfaceExport>>,
+	/// Composited textures StereoKit isn't sampling anymore, oldest first, to composite into next
+	spare_textures: Mutex<VecDeque<SendWrapper<GlesTexture>>>,
+	/// Snapshots to take on the next frame, with the largest width or height they can have
+	snapshots: Mutex<Vec<(u32, Box<dyn FnOnce(Option<SurfaceSnapshot>) + Send>)>>,
 }
 
 impl CoreSurface {
@@ -53,6 +752,7 @@ impl CoreSurface {
 		dh: DisplayHandle,
 		surface: &WlSurface,
 		on_mapped: impl Fn() + Send + Sync + 'static,
+		on_unmapped: impl Fn() + Send + Sync + 'static,
 		on_commit: impl Fn(u32) + Send + Sync + 'static,
 	) {
 		compositor::with_states(surface, |data| {
@@ -64,31 +764,119 @@ impl CoreSurface {
 					sk_tex: OnceCell::new(),
 					sk_mat: OnceCell::new(),
 					material_offset: Mutex::new(Delta::new(0)),
+					color_space: Mutex::new(Delta::new(ColorSpace::Srgb)),
+					filtering: Mutex::new(Delta::new(SurfaceFiltering::default())),
+					mipmaps: Mutex::new(MipmapState {
+						stale: false,
+						last_generated: Instant::now(),
+					}),
+					alpha: Mutex::new(Delta::new(1.0)),
+					keep_transparent: AtomicBool::new(false),
+					was_hidden: AtomicBool::new(false),
+					minimized: AtomicBool::new(false),
+					geometry: Mutex::new(PanelGeometry::Flat),
+					output: Mutex::new(None),
+					fractional_scale: Mutex::new(DEFAULT_FRACTIONAL_SCALE),
+					pending_scale: Mutex::new(ScaleDebounce::default()),
 					on_mapped: Box::new(on_mapped) as Box<dyn Fn() + Send + Sync>,
+					on_unmapped: Box::new(on_unmapped) as Box<dyn Fn() + Send + Sync>,
 					on_commit: Box::new(on_commit) as Box<dyn Fn(u32) + Send + Sync>,
 					pending_material_applications: Registry::new(),
+					model_parts: Registry::new(),
+					last_frame_callback: Mutex::new(Instant::now()),
+					commit_times: Mutex::new(VecDeque::new()),
+					committed: AtomicBool::new(false),
+					upload_time: Mutex::new(Duration::ZERO),
+					deferred_frames: AtomicU32::new(0),
+					input_commit: Mutex::new(None),
+					resources: surface.client().and_then(|client| {
+						Some(client.get_data::<ClientState>()?.resources.clone())
+					}),
+					exports: Mutex::new(Vec::new()),
+					spare_textures: Mutex::new(VecDeque::new()),
+					snapshots: Mutex::new(Vec::new()),
 				})
 			});
 		});
 	}
 
 	pub fn commit(&self, count: u32) {
+		let now = Instant::now();
+		let mut commit_times = self.commit_times.lock();
+		commit_times.push_back(now);
+		while commit_times
+			.front()
+			.is_some_and(|time| now.duration_since(*time) > COMMIT_RATE_WINDOW)
+		{
+			commit_times.pop_front();
+		}
+		drop(commit_times);
+		self.committed.store(true, Ordering::Relaxed);
 		(self.on_commit)(count);
 	}
+	/// Whether processing the latest commit should take less than what's `left` of the frame's
+	/// upload budget, going by how long the last one took. One that already waited
+	/// `MAX_DEFERRED_FRAMES` goes anyway, so a buffer bigger than the whole budget still stalls
+	/// a frame now and then.
+	pub fn fits_upload_budget(&self, left: Duration) -> bool {
+		!self.committed.load(Ordering::Relaxed)
+			|| *self.upload_time.lock() < left
+			|| self.deferred_frames.load(Ordering::Relaxed) >= MAX_DEFERRED_FRAMES
+	}
+	/// Leave the latest commit for a later frame. The model parts still get the material, and
+	/// the frame callbacks go out as usual while the presentation feedback waits for the upload.
+	pub fn defer_upload(&self) {
+		self.deferred_frames.fetch_add(1, Ordering::Relaxed);
+		self.apply_surface_materials();
+	}
+	/// `process` took `time`, for guessing how long the next commit takes
+	pub fn processed(&self, time: Duration) {
+		if self.committed.swap(false, Ordering::Relaxed) {
+			*self.upload_time.lock() = time;
+		}
+		self.deferred_frames.store(0, Ordering::Relaxed);
+	}
+	/// The client committed in reaction to input that came in at `generated`
+	pub fn input_committed(&self, generated: Instant) {
+		self.input_commit
+			.lock()
+			.get_or_insert((generated, Instant::now()));
+	}
+	/// How many times a second the client has committed lately, which is its frame rate for
+	/// anything that draws a frame per commit
+	pub fn commit_rate(&self) -> f32 {
+		let now = Instant::now();
+		let commit_times = self.commit_times.lock();
+		let recent = commit_times
+			.iter()
+			.filter(|time| now.duration_since(**time) <= COMMIT_RATE_WINDOW)
+			.count();
+		recent as f32 / COMMIT_RATE_WINDOW.as_secs_f32()
+	}
 
 	pub fn from_wl_surface(surf: &WlSurface) -> Option<Arc<CoreSurface>> {
 		get_data(surf)
 	}
 
-	pub fn process(&self, sk: &impl StereoKitDraw, renderer: &mut GlesRenderer) {
+	pub fn process(
+		&self,
+		sk: &impl StereoKitDraw,
+		renderer: &mut GlesRenderer,
+		background: SurfaceBackground,
+	) {
 		let Some(wl_surface) = self.wl_surface() else {
 			return;
 		};
+		let background = if self.keep_transparent.load(Ordering::Relaxed) {
+			SurfaceBackground::Transparent
+		} else {
+			background
+		};
 
 		let sk_tex = self
 			.sk_tex
 			.get_or_init(|| sk.tex_create(TextureType::IMAGE_NO_MIPS, TextureFormat::RGBA32));
-		self.sk_mat.get_or_init(|| {
+		let sk_mat = self.sk_mat.get_or_init(|| {
 			let shader = sk.shader_create_mem(&PANEL_SHADER_BYTES);
 			// let _ = renderer.with_context(|c| unsafe {
 			// 	shader_inject(c, &mut shader, SIMULA_VERT_STR, SIMULA_FRAG_STR)
@@ -99,12 +887,27 @@ impl CoreSurface {
 			sk.material_set_transparency(&mat, Transparency::Blend);
 			Arc::new(mat)
 		});
+		if let Some(material_offset) = self.material_offset.lock().delta() {
+			sk.material_set_queue_offset(sk_mat.as_ref().as_ref(), *material_offset as i32);
+		}
+		if let Some(alpha) = self.alpha.lock().delta() {
+			// minimized surfaces stay in the scene, just faded out entirely
+			let alpha = if self.minimized() { 0.0 } else { *alpha };
+			// The panel shader maps the sampled alpha onto `alpha_min..alpha_max`, and blending
+			// multiplies the color by that alpha, so this fades the color along with it.
+			sk.material_set_float(sk_mat.as_ref().as_ref(), "alpha_max", alpha);
+			let opaque = self
+				.mapped_data
+				.lock()
+				.as_ref()
+				.map_or(false, CoreSurfaceData::opaque);
+			set_opaque(sk, sk_mat.as_ref().as_ref(), opaque && alpha >= 1.0);
+		}
 
 		// Let smithay handle buffer management (has to be done here as RendererSurfaceStates is not thread safe)
-		on_commit_buffer_handler::<WaylandState>(&wl_surface);
-		// Import all surface buffers into textures
-		if import_surface_tree(renderer, &wl_surface).is_err() {
-			return;
+		{
+			let _applying = APPLYING_COMMITS.lock();
+			on_commit_buffer_handler::<WaylandState>(&wl_surface);
 		}
 
 		let mapped = compositor::with_states(&wl_surface, |data| {
@@ -115,11 +918,186 @@ impl CoreSurface {
 		});
 
 		if !mapped {
+			// attaching a null buffer unmaps the surface, it gets mapped again on its next buffer
+			if self.mapped_data.lock().is_some() {
+				(self.on_unmapped)();
+				self.mapped_data.lock().take();
+				self.free_spare_textures();
+			}
+			return;
+		}
+
+		// Hidden surfaces still take their buffers above so the old ones get released and clients
+		// don't run out, but nothing's uploaded or composited. Once shown it's all composited
+		// again from the last buffer, as the damage since doesn't add up to the texture anymore.
+		if self.mapped_data.lock().is_some() && self.hidden() {
+			self.was_hidden.store(true, Ordering::Relaxed);
+			// whenever it's shown again has nothing to do with how fast the input was handled
+			self.input_commit.lock().take();
 			return;
 		}
+		if self.was_hidden.swap(false, Ordering::Relaxed) {
+			self.reset_texture();
+		}
 
+		// Import all surface buffers into textures. If a dmabuf can't be imported (e.g. an unsupported modifier)
+		// we keep whatever texture was imported last instead of dropping the surface.
+		match import_surface_tree(renderer, &wl_surface) {
+			Ok(()) => {
+				release_uploaded_shm_buffers(&wl_surface);
+				if let Some((generated, committed)) = self.input_commit.lock().take() {
+					metrics::record_latency(LatencyStage::Render, committed.elapsed());
+					metrics::record_latency(LatencyStage::Total, generated.elapsed());
+				}
+			}
+			// the client truncated its shm pool under the buffer, reading it raised SIGBUS.
+			// smithay's handler maps zeroes over the pool so the upload finishes and posts the
+			// protocol error to the client, which gets disconnected instead of taking us down.
+			Err(GlesError::BufferAccessError(BufferAccessError::BadMap)) => {
+				warn!(
+					client = ?wl_surface.client().map(|c| c.id()),
+					"Client shrank a shm pool while it was being read"
+				);
+			}
+			Err(err) => warn!(?err, "Could not import surface buffers"),
+		}
+
+		let subsurface_commits = subsurface_commits(&wl_surface);
 		let mut mapped_data = self.mapped_data.lock();
 		let just_mapped = mapped_data.is_none();
+		let surface_info = self.with_states(|data| {
+			let surface_state = data
+				.data_map
+				.get::<RendererSurfaceStateUserData>()?
+				.borrow();
+			let attributes = data.cached_state.current::<SurfaceAttributes>();
+			// the opaque region is from that newer commit then, it gets picked up next frame
+			// instead of pairing it with the older buffer
+			if commit_unprocessed(&attributes) {
+				return None;
+			}
+			let opaque_region = attributes.opaque_region.clone();
+			drop(attributes);
+			let surface_size = surface_state.surface_size()?;
+			Some((
+				surface_state.current_commit(),
+				surface_size,
+				surface_state.buffer_scale(),
+				surface_state.buffer_transform(),
+				opaque_region,
+				window_clip(data, surface_size),
+			))
+		});
+		let Some((commit, surface_size, buffer_scale, buffer_transform, opaque_region, clip)) =
+			surface_info.flatten()
+		else {
+			return;
+		};
+		let (color_space, color_space_changed) = {
+			let mut color_space = self.color_space.lock();
+			let changed = color_space.delta().is_some();
+			(**color_space, changed)
+		};
+		let (filtering, filtering_changed) = {
+			let mut filtering = self.filtering.lock();
+			let changed = filtering.delta().is_some();
+			(**filtering, changed)
+		};
+		let description = self.image_description();
+		let encoding = Encoding::of(color_space, description.as_ref());
+		let content_type = surface_content_type(&wl_surface);
+		let full_motion = matches!(content_type, ContentType::Video | ContentType::Game);
+		// the content type can change what `Auto` is without the filtering having been set
+		let filtering = filtering.for_content(content_type);
+		let filtering_changed = filtering_changed
+			|| mapped_data
+				.as_ref()
+				.is_some_and(|d| d.filtering != filtering);
+		let unchanged = !color_space_changed
+			&& !filtering_changed
+			&& mapped_data.as_ref().map_or(false, |d| {
+				d.commit == commit
+					&& d.subsurface_commits == subsurface_commits
+					&& d.background == background
+			});
+		if unchanged {
+			// Nothing new was committed, the texture StereoKit has is still current
+			drop(mapped_data);
+			self.update_mipmaps(renderer, None);
+			self.apply_surface_materials();
+			return;
+		}
+		// Subsurfaces have to be flattened onto the root surface, this has to be done out here
+		// as rendering the tree locks every surface's states.
+		// `surface_size` is already logical (scaled down and rotated by the buffer transform) but
+		// the texture is still in buffer space, so transformed buffers get rendered upright too.
+		// Linear and HDR content gets encoded to sRGB on the way, like everything else is. The
+		// image description of the root surface is used for the whole tree.
+		// Mipmaps are only generated for our own copy, as the client's buffer might be a dmabuf.
+		// Single pixel buffers get a 1x1 texture of their color stretched over the surface.
+		// A solid background always needs compositing to get under the surface.
+		// Video and games change every frame, so instead of copying them to regenerate mipmaps
+		// each time they're sampled directly with plain linear filtering.
+		let single_pixel = single_pixel_color(&wl_surface).and_then(|color| {
+			renderer
+				.import_memory(&color, Fourcc::Abgr8888, (1, 1).into(), false)
+				.map_err(|err| warn!(?err, "Could not create single pixel buffer texture"))
+				.ok()
+		});
+		// a texture that never had anything drawn into it, so it doesn't have any mipmaps either
+		let mut new_target = false;
+		let composited = if background == SurfaceBackground::Transparent
+			&& clip.is_none()
+			&& ((single_pixel.is_some() && subsurface_commits.is_empty())
+				|| (subsurface_commits.is_empty()
+					&& buffer_transform == Transform::Normal
+					&& encoding == Encoding::Srgb
+					&& (filtering != SurfaceFiltering::Trilinear || full_motion)))
+		{
+			None
+		} else {
+			let program = match encoding {
+				Encoding::Srgb => None,
+				_ => decode_program(renderer, encoding),
+			};
+			if let Some(description) = description.filter(|_| encoding != Encoding::Srgb) {
+				// HDR gets clamped to reference white, there's nothing brighter to show it on
+				let dynamic_range = description.dynamic_range();
+				debug!(?encoding, ?dynamic_range, "Decoding surface colors to sRGB");
+			}
+			let uniforms = match encoding {
+				Encoding::Pq => {
+					let reference =
+						description.map_or(HDR_REFERENCE_WHITE, |d| d.luminances().reference);
+					vec![Uniform::new("reference_white", reference as f32)]
+				}
+				_ => Vec::new(),
+			};
+			let target = self.next_target(&mapped_data);
+			let target_id = target.as_ref().map(GlesTexture::tex_id);
+			match composite_surface_tree(
+				renderer,
+				&wl_surface,
+				surface_size.to_physical(buffer_scale),
+				buffer_scale as f64,
+				clip,
+				target,
+				program.map(|program| (program, uniforms)),
+				background,
+			) {
+				Ok(texture) => {
+					metrics::frame_composited();
+					new_target = target_id != Some(texture.tex_id());
+					Some(texture)
+				}
+				Err(err) => {
+					warn!(?err, "Could not composite surface tree");
+					None
+				}
+			}
+		};
+
+		let mut damage_fraction = None;
 		self.with_states(|data| {
 			let Some(renderer_surface_state) = data
 				.data_map
@@ -128,9 +1106,15 @@ impl CoreSurface {
 			else {
 				return;
 			};
-			let Some(smithay_tex) = renderer_surface_state
-				.texture::<GlesRenderer>(renderer.id())
-				.cloned()
+			let Some(smithay_tex) =
+				composited
+					.clone()
+					.or_else(|| single_pixel.clone())
+					.or_else(|| {
+						renderer_surface_state
+							.texture::<GlesRenderer>(renderer.id())
+							.cloned()
+					})
 			else {
 				return;
 			};
@@ -138,66 +1122,622 @@ impl CoreSurface {
 			let Some(sk_tex) = self.sk_tex.get() else {
 				return;
 			};
-			let Some(sk_mat) = self.sk_mat.get() else {
-				return;
-			};
-			unsafe {
-				sk.tex_set_surface(
-					sk_tex.as_ref(),
-					smithay_tex.tex_id() as usize as *mut c_void,
-					TextureType::IMAGE_NO_MIPS,
-					smithay::backend::renderer::gles::ffi::RGBA8.into(),
-					smithay_tex.width() as i32,
-					smithay_tex.height() as i32,
-					1,
-					false,
-				);
-				sk.tex_set_sample(sk_tex.as_ref(), TextureSample::Point);
-				sk.tex_set_address(sk_tex.as_ref(), TextureAddress::Clamp);
+			// smithay only uploads the damaged regions of shm buffers into the existing texture,
+			// so we only need to rebind when the texture itself got reallocated (first commit or resize)
+			let tex_changed = mapped_data
+				.as_ref()
+				.and_then(|d| d.wl_tex.as_ref())
+				.map(|t| t.tex_id() != smithay_tex.tex_id() || t.size() != smithay_tex.size())
+				.unwrap_or(true);
+			let (tex_type, sample) = texture_sampling(filtering, full_motion, composited.is_some());
+			let full_motion_changed = mapped_data
+				.as_ref()
+				.map_or(true, |d| d.full_motion != full_motion);
+			if tex_changed || filtering_changed || full_motion_changed {
+				unsafe {
+					sk.tex_set_surface(
+						sk_tex.as_ref(),
+						smithay_tex.tex_id() as usize as *mut c_void,
+						tex_type,
+						smithay::backend::renderer::gles::ffi::RGBA8.into(),
+						smithay_tex.width() as i32,
+						smithay_tex.height() as i32,
+						1,
+						false,
+					);
+					sk.tex_set_sample(sk_tex.as_ref(), sample);
+					sk.tex_set_address(sk_tex.as_ref(), TextureAddress::Clamp);
+					if tex_type == TextureType::IMAGE {
+						sk.tex_set_anisotropy(sk_tex.as_ref(), MAX_ANISOTROPY);
+					}
+				}
 			}
-			if let Some(material_offset) = self.material_offset.lock().delta() {
-				sk.material_set_queue_offset(sk_mat.as_ref().as_ref(), *material_offset as i32);
+			let damage =
+				renderer_surface_state.damage_since(mapped_data.as_ref().map(|d| d.commit));
+			let upload_bytes = damage_bytes(damage.iter(), smithay_tex.size());
+			debug!(tex_changed, upload_bytes, "Surface texture updated");
+			let tex_bytes = smithay_tex.width() as i64 * smithay_tex.height() as i64 * 4;
+			// the spare composited into only has stale mipmaps, but those are allowed to lag anyway
+			let resized = mapped_data
+				.as_ref()
+				.and_then(|d| d.wl_tex.as_ref())
+				.map_or(true, |t| t.size() != smithay_tex.size());
+			let reallocated = if composited.is_some() {
+				new_target || resized
+			} else {
+				tex_changed
+			};
+			damage_fraction = Some(if reallocated || filtering_changed {
+				1.0
+			} else {
+				(upload_bytes as f64 / tex_bytes.max(1) as f64).min(1.0)
+			});
+
+			// wp_viewport can crop the buffer, so only sample the source rectangle of it.
+			// Composited trees already had the viewports applied while rendering.
+			if let (Some(view), Some(buffer_size)) = (
+				renderer_surface_state.view(),
+				renderer_surface_state.buffer_size(),
+			) {
+				let (uv_offset, uv_scale) = if composited.is_some() {
+					([0.0, 0.0].into(), [1.0, 1.0].into())
+				} else {
+					viewport_uv(view.src, buffer_size)
+				};
+				let sk_mat = sk_mat.as_ref().as_ref();
+				sk.material_set_vector2(sk_mat, "uv_offset", uv_offset);
+				sk.material_set_vector2(sk_mat, "uv_scale", uv_scale);
 			}
 
-			let Some(surface_size) = renderer_surface_state.surface_size() else {
-				return;
-			};
+			let faded = *self.alpha.lock().value() < 1.0;
+			let was_opaque = mapped_data.as_ref().map_or(false, CoreSurfaceData::opaque) && !faded;
+			let textures = 1 + self.recycle_texture(&mut mapped_data, composited.is_some());
+			let texture_bytes =
+				smithay_tex.width() as u64 * smithay_tex.height() as u64 * 4 * textures as u64;
+			// release the old texture's resources first so a resize doesn't count both
+			mapped_data.take();
 			let new_mapped_data = CoreSurfaceData {
 				size: Vector2::from([surface_size.w as u32, surface_size.h as u32]),
+				commit,
+				subsurface_commits: subsurface_commits.clone(),
+				composited: composited.is_some(),
+				background,
+				full_motion,
+				filtering,
+				has_alpha: smithay_tex.format().map_or(true, has_alpha)
+					&& !(composited.is_some() && background != SurfaceBackground::Transparent),
 				wl_tex: Some(SendWrapper::new(smithay_tex)),
+				opaque_region,
+				clipped: composited.is_some() && clip.is_some(),
+				_resource: self
+					.resources
+					.as_ref()
+					.map(|resources| resources.add_buffer(texture_bytes)),
 			};
+			let opaque = new_mapped_data.opaque() && !faded;
+			if opaque != was_opaque {
+				set_opaque(sk, sk_mat.as_ref().as_ref(), opaque);
+			}
 			*mapped_data = Some(new_mapped_data);
 		});
 		drop(mapped_data);
+		if let Some(damage_fraction) = damage_fraction {
+			self.update_mipmaps(renderer, Some(damage_fraction));
+		}
+		if let (Some(resources), Some(client)) = (&self.resources, wl_surface.client()) {
+			if resources.enforce(&self.dh, &client) {
+				return;
+			}
+		}
 		if just_mapped {
 			(self.on_mapped)();
 		}
 		self.apply_surface_materials();
 	}
 
-	pub fn frame(&self, sk: &impl StereoKitDraw, output: Output) {
+	/// Send the frame callbacks and presentation feedback, returns if the surface is on an output
+	/// and in view
+	pub fn frame(
+		&self,
+		sk: &impl StereoKitDraw,
+		default_output: &Output,
+		timing: &FrameTiming,
+	) -> bool {
 		let Some(wl_surface) = self.wl_surface() else {
-			return;
+			return false;
 		};
+		let output = surface_output(
+			self.mapped_data.lock().is_some(),
+			&mut self.output.lock(),
+			default_output,
+			|output| output.enter(&wl_surface),
+		);
+
+		let visible = !self.hidden() && self.in_view(sk);
+		if visible {
+			self.update_scale(sk);
+		}
+		let mut last_frame_callback = self.last_frame_callback.lock();
+		if let Some(output) = frame_callback_output(
+			output.as_ref(),
+			output_active,
+			self.minimized(),
+			visible,
+			last_frame_callback.elapsed(),
+		) {
+			*last_frame_callback = Instant::now();
+			send_frames_surface_tree(
+				&wl_surface,
+				output,
+				Duration::from_secs_f64(sk.time_get()),
+				None,
+				|_, _| Some(output.clone()),
+			);
+		}
+		drop(last_frame_callback);
 
-		send_frames_surface_tree(
+		// If we never got a texture this frame (or it's out of view) the content was never shown
+		let presented_on = output.as_ref().filter(|_| visible);
+		// a deferred commit isn't shown or replaced yet, its feedback goes out once it's uploaded
+		if self.deferred_frames.load(Ordering::Relaxed) > 0 {
+			return presented_on.is_some();
+		}
+		compositor::with_surface_tree_downward(
 			&wl_surface,
-			&output,
-			Duration::from_secs_f64(sk.time_get()),
-			None,
-			|_, _| Some(output.clone()),
+			(),
+			|_, _, _| TraversalAction::DoChildren(()),
+			|_, states, _| {
+				let callbacks = states
+					.cached_state
+					.current::<PresentationFeedbackCachedState>()
+					.callbacks
+					.drain(..)
+					.collect::<Vec<_>>();
+				for callback in callbacks {
+					if let Some(output) = presented_on {
+						callback.presented(
+							output,
+							timing.time,
+							timing.refresh,
+							timing.sequence,
+							wp_presentation_feedback::Kind::Vsync,
+						);
+					} else {
+						callback.discarded();
+					}
+				}
+			},
+			|_, _, _| true,
 		);
+		presented_on.is_some()
+	}
+
+	pub fn output(&self) -> Option<Output> {
+		self.output.lock().clone()
+	}
+	/// Move this surface to another output, sending `wl_surface.leave`/`enter` as needed.
+	pub fn set_output(&self, output: Option<Output>) {
+		let Some(wl_surface) = self.wl_surface() else {
+			return;
+		};
+		let mut current = self.output.lock();
+		if *current == output {
+			return;
+		}
+		if let Some(old) = current.take() {
+			old.leave(&wl_surface);
+		}
+		if let Some(new) = &output {
+			new.enter(&wl_surface);
+		}
+		*current = output;
 	}
 
+	pub fn fractional_scale(&self) -> f64 {
+		*self.fractional_scale.lock()
+	}
+	/// Tell the client which scale it should render at, e.g. based on how big its panel appears.
+	pub fn set_fractional_scale(&self, scale: f64) {
+		let mut fractional_scale = self.fractional_scale.lock();
+		if *fractional_scale == scale {
+			return;
+		}
+		*fractional_scale = scale;
+		if let Some(wl_surface) = self.wl_surface() {
+			send_preferred_scale(&wl_surface, scale);
+		}
+	}
+	/// Follow how big the surface appears, so the client renders closer panels at a higher scale
+	fn update_scale(&self, sk: &impl StereoKitMultiThread) {
+		let Some(size) = self.size() else {
+			return;
+		};
+		let Some(angle) = self
+			.model_parts
+			.get_valid_contents()
+			.iter()
+			.filter_map(|model_part| model_part.apparent_width(sk))
+			.reduce(f32::max)
+		else {
+			return;
+		};
+		let apparent = apparent_scale(angle, size.x, self.fractional_scale());
+		let Some(scale) = self.pending_scale.lock().update(apparent, Instant::now()) else {
+			return;
+		};
+		debug!(scale, "Surface apparent scale changed");
+		self.set_fractional_scale(scale);
+	}
+	/// The buffer size we expect the client to allocate for its logical size at the negotiated scale
+	pub fn expected_buffer_size(&self) -> Option<Vector2<u32>> {
+		let size = self.size()?;
+		let scale = self.fractional_scale();
+		Some(Vector2::from([
+			(size.x as f64 * scale).round() as u32,
+			(size.y as f64 * scale).round() as u32,
+		]))
+	}
+
+	/// The size of the surface's texture, if it's mapped
+	pub fn texture_bytes(&self) -> Option<u64> {
+		let mapped_data = self.mapped_data.lock();
+		let wl_tex = mapped_data.as_ref()?.wl_tex.as_ref()?;
+		Some(wl_tex.width() as u64 * wl_tex.height() as u64 * 4)
+	}
+
+	/// Put the surface in the panel item at `stack_position` in the draw order,
+	/// with its popups just above it
+	pub fn set_stack_position(&self, stack_position: u32, popup: bool) {
+		self.set_material_offset(stack_position * 2 + popup as u32);
+	}
 	pub fn set_material_offset(&self, material_offset: u32) {
 		*self.material_offset.lock().value_mut() = material_offset;
 	}
+	/// For cursors and drag icons, which shouldn't get a solid background behind them
+	pub fn keep_transparent(&self) {
+		self.keep_transparent.store(true, Ordering::Relaxed);
+	}
+
+	/// Regenerate the mipmaps of our own copy of the surface after its content changed.
+	/// `damage` is how much of it changed this time, `None` if nothing new was committed.
+	fn update_mipmaps(&self, renderer: &mut GlesRenderer, damage: Option<f64>) {
+		let mut mipmaps = self.mipmaps.lock();
+		let interval_elapsed = mipmaps.last_generated.elapsed() >= MIPMAP_INTERVAL;
+		match damage {
+			Some(damage) => {
+				mipmaps.stale = true;
+				if damage < MIPMAP_SMALL_DAMAGE && !interval_elapsed {
+					return;
+				}
+			}
+			None if !mipmaps.stale || !interval_elapsed => return,
+			None => (),
+		}
+
+		let mapped_data = self.mapped_data.lock();
+		let Some(tex_id) = mapped_data
+			.as_ref()
+			.filter(|d| {
+				d.composited && !d.full_motion && d.filtering == SurfaceFiltering::Trilinear
+			})
+			.and_then(|d| d.wl_tex.as_ref())
+			.map(|t| t.tex_id())
+		else {
+			return;
+		};
+		let result = renderer.with_context(|gl| unsafe {
+			gl.BindTexture(ffi::TEXTURE_2D, tex_id);
+			gl.GenerateMipmap(ffi::TEXTURE_2D);
+			gl.BindTexture(ffi::TEXTURE_2D, 0);
+		});
+		if let Err(err) = result {
+			warn!(?err, "Could not generate surface mipmaps");
+		}
+		mipmaps.stale = false;
+		mipmaps.last_generated = Instant::now();
+	}
+
+	/// Sample the surface other than with linear filtering, e.g. trilinear for text on distant
+	/// panels or nearest for pixel art
+	pub fn set_filtering(&self, filtering: SurfaceFiltering) {
+		let mut current = self.filtering.lock();
+		if **current != filtering {
+			*current.value_mut() = filtering;
+		}
+	}
+
+	pub fn set_color_space(&self, color_space: ColorSpace) {
+		let mut current = self.color_space.lock();
+		if **current != color_space {
+			*current.value_mut() = color_space;
+		}
+	}
+
+	/// Fade the surface, 1.0 shows it with just its own alpha and 0.0 hides it
+	pub fn set_alpha(&self, alpha: f32) {
+		let alpha = alpha.clamp(0.0, 1.0);
+		let mut current = self.alpha.lock();
+		if **current != alpha {
+			*current.value_mut() = alpha;
+		}
+	}
+
+	/// Stop drawing the surface and sending it frame callbacks so its client can pause, without
+	/// unmapping it: the texture is kept for snapshots and exports, and shown again as it was
+	/// once it's restored.
+	pub fn set_minimized(&self, minimized: bool) {
+		if self.minimized.swap(minimized, Ordering::Relaxed) != minimized {
+			// applies it to the material on the next frame
+			self.alpha.lock().mark_changed();
+		}
+	}
+	pub fn minimized(&self) -> bool {
+		self.minimized.load(Ordering::Relaxed)
+	}
+
+	/// Minimized, faded out completely, or the shell disabled every model showing it
+	fn hidden(&self) -> bool {
+		if self.minimized() || **self.alpha.lock() <= 0.0 {
+			return true;
+		}
+		let model_parts = self.model_parts.get_valid_contents();
+		!model_parts.is_empty() && model_parts.iter().all(|part| !part.model_enabled())
+	}
+
+	pub fn set_geometry(&self, geometry: PanelGeometry) {
+		let mut current = self.geometry.lock();
+		if *current == geometry {
+			return;
+		}
+		*current = geometry;
+		for model_part in self.model_parts.get_valid_contents() {
+			model_part.set_cylinder_radius(geometry.cylinder_radius());
+		}
+	}
+	/// Move a position from where the flat panel got hit onto the bent one, so it lands on the
+	/// pixel that's drawn there. The curve only goes sideways, so only x changes.
+	pub fn unbend(&self, position: Vector2<f32>) -> Vector2<f32> {
+		if *self.geometry.lock() == PanelGeometry::Flat {
+			return position;
+		}
+		let Some(arc) = self
+			.model_parts
+			.get_valid_contents()
+			.iter()
+			.map(|part| part.arc())
+			.find(|arc| *arc > 0.0)
+		else {
+			return position;
+		};
+		let Some(width) = self.size().map(|size| size.x as f32).filter(|w| *w > 0.0) else {
+			return position;
+		};
+		Vector2::from([unbend_x(position.x, width, arc), position.y])
+	}
 
 	pub fn apply_material(&self, model_part: &Arc<ModelPart>) {
-		self.pending_material_applications.add_raw(model_part)
+		self.pending_material_applications.add_raw(model_part);
+		self.model_parts.add_raw(model_part);
+		let geometry = *self.geometry.lock();
+		if geometry != PanelGeometry::Flat {
+			model_part.set_cylinder_radius(geometry.cylinder_radius());
+		}
+	}
+	/// How wide the widest model part showing it is in world space, `None` if nothing shows it
+	pub fn shown_width(&self) -> Option<f32> {
+		self.model_parts
+			.get_valid_contents()
+			.iter()
+			.filter_map(|part| part.width())
+			.reduce(f32::max)
+	}
+	/// Surfaces nothing was told to show yet count as visible, we can't know where they'll end up
+	fn in_view(&self, sk: &impl StereoKitMultiThread) -> bool {
+		let model_parts = self.model_parts.get_valid_contents();
+		model_parts.is_empty() || model_parts.iter().any(|part| part.in_view(sk))
+	}
+
+	/// Keep a copy of this surface in a render target applied to `model_part`, replacing any
+	/// export to the same model part.
+	pub fn add_export(&self, export: SurfaceExport) {
+		let mut exports = self.exports.lock();
+		exports.retain(|e| !Weak::ptr_eq(&e.model_part, &export.model_part));
+		exports.push(export);
+	}
+	pub fn remove_export(&self, model_part: &Arc<ModelPart>) {
+		let model_part = Arc::downgrade(model_part);
+		self.exports
+			.lock()
+			.retain(|e| !Weak::ptr_eq(&e.model_part, &model_part));
+	}
+	/// Copy the surface into the export targets if it changed since the last copy,
+	/// reallocating any whose size changed. Exports nobody can see anymore are dropped.
+	pub fn update_exports(&self, sk: &impl StereoKitDraw) {
+		let mut exports = self.exports.lock();
+		exports.retain(|e| e.model_part.strong_count() > 0);
+		if exports.is_empty() {
+			return;
+		}
+		let Some(sk_mat) = self.sk_mat.get() else {
+			return;
+		};
+		let mapped_data = self.mapped_data.lock();
+		let Some((tex_size, content)) = mapped_data.as_ref().and_then(|d| {
+			let tex = d.wl_tex.as_ref()?;
+			Some((
+				Vector2::from([tex.width(), tex.height()]),
+				(d.commit, d.subsurface_commits.clone()),
+			))
+		}) else {
+			return;
+		};
+		drop(mapped_data);
+
+		for export in exports.iter_mut() {
+			let size = export.size(tex_size);
+			if export.target.as_ref().map(|(_, _, s)| *s) != Some(size) {
+				if let Some((tex, mat, _)) = export.target.take() {
+					destroy_queue::add(tex);
+					destroy_queue::add(mat);
+				}
+				let tex = sk.tex_gen_color(
+					Color128::default(),
+					size.x as i32,
+					size.y as i32,
+					TextureType::RENDER_TARGET,
+					TextureFormat::RGBA32Linear,
+				);
+				let shader = sk.shader_create_mem(&UNLIT_SHADER_BYTES).unwrap();
+				let mat = sk.material_create(&shader);
+				sk.material_set_texture(&mat, "diffuse", &tex);
+				sk.material_set_transparency(&mat, Transparency::Blend);
+				let mat = Arc::new(mat);
+				if let Some(model_part) = export.model_part.upgrade() {
+					model_part.replace_material(mat.clone());
+				}
+				export.target = Some((tex, mat, size));
+				export.blitted = None;
+				debug!(?size, "Surface export resized");
+				(export.on_resize)(size);
+			}
+			if export.blitted.as_ref() == Some(&content) {
+				continue;
+			}
+			let Some((tex, _, _)) = &export.target else {
+				continue;
+			};
+			sk.render_blit(tex, sk_mat.as_ref().as_ref());
+			export.blitted = Some(content.clone());
+		}
 	}
 
-	fn apply_surface_materials(&self) {
+	/// The texture to composite into next, the spare StereoKit sampled longest ago
+	fn next_target(&self, mapped_data: &Option<CoreSurfaceData>) -> Option<GlesTexture> {
+		if TEXTURE_BUFFERS.load(Ordering::Relaxed) <= 1 {
+			// never draw into the client's buffer if it was shown directly before
+			return mapped_data
+				.as_ref()
+				.filter(|d| d.composited)
+				.and_then(|d| d.wl_tex.as_ref())
+				.map(|t| (**t).clone());
+		}
+		self.spare_textures
+			.lock()
+			.pop_front()
+			.map(SendWrapper::take)
+	}
+	/// Keep the composited texture that's about to be replaced as a spare, dropping the ones
+	/// that don't fit in the ring anymore. Returns how many spares there are.
+	fn recycle_texture(
+		&self,
+		mapped_data: &mut Option<CoreSurfaceData>,
+		composited: bool,
+	) -> usize {
+		let buffers = TEXTURE_BUFFERS.load(Ordering::Relaxed);
+		if !composited || buffers <= 1 {
+			self.free_spare_textures();
+			return 0;
+		}
+		let mut spare_textures = self.spare_textures.lock();
+		if let Some(texture) = mapped_data
+			.as_mut()
+			.filter(|d| d.composited)
+			.and_then(|d| d.wl_tex.take())
+		{
+			spare_textures.push_back(texture);
+		}
+		while spare_textures.len() > buffers - 1 {
+			destroy_queue::add(spare_textures.pop_front());
+		}
+		spare_textures.len()
+	}
+	/// Drop the texture and everything composited from it on shutdown, while there's still a
+	/// context to delete them with
+	pub fn free_textures(&self) {
+		self.mapped_data.lock().take();
+		self.free_spare_textures();
+		self.exports.lock().clear();
+	}
+	fn free_spare_textures(&self) {
+		destroy_queue::add(std::mem::take(&mut *self.spare_textures.lock()));
+	}
+
+	pub fn add_snapshot(
+		&self,
+		max_size: u32,
+		on_snapshot: Box<dyn FnOnce(Option<SurfaceSnapshot>) + Send>,
+	) {
+		self.snapshots.lock().push((max_size, on_snapshot));
+	}
+	/// Take the snapshots asked for since the last frame. They're composited again at the
+	/// smaller size instead of read back from the texture, which might be the client's own
+	/// buffer (in a format that can't be read back) and is usually much bigger than needed.
+	pub fn take_snapshots(&self, renderer: &mut GlesRenderer) {
+		let snapshots = std::mem::take(&mut *self.snapshots.lock());
+		for (max_size, on_snapshot) in snapshots {
+			on_snapshot(self.snapshot(renderer, max_size));
+		}
+	}
+	fn snapshot(&self, renderer: &mut GlesRenderer, max_size: u32) -> Option<SurfaceSnapshot> {
+		let wl_surface = self.wl_surface()?;
+		self.mapped_data.lock().as_ref()?;
+		let (surface_size, buffer_scale, clip) = self
+			.with_states(|data| {
+				let surface_state = data
+					.data_map
+					.get::<RendererSurfaceStateUserData>()?
+					.borrow();
+				let surface_size = surface_state.surface_size()?;
+				Some((
+					surface_size,
+					surface_state.buffer_scale(),
+					window_clip(data, surface_size),
+				))
+			})
+			.flatten()?;
+		let longest = surface_size.w.max(surface_size.h).max(1) as f64;
+		let scale = (max_size.max(1) as f64 / longest).min(buffer_scale as f64);
+		let size: Size<i32, Physical> = (
+			((surface_size.w as f64 * scale).round() as i32).max(1),
+			((surface_size.h as f64 * scale).round() as i32).max(1),
+		)
+			.into();
+
+		let result = composite_surface_tree(
+			renderer,
+			&wl_surface,
+			size,
+			scale,
+			clip,
+			None,
+			None,
+			SurfaceBackground::Transparent,
+		)
+		.and_then(|texture| {
+			let region = Rectangle::from_loc_and_size((0, 0), (size.w, size.h));
+			let mapping = renderer.copy_texture(&texture, region, Fourcc::Abgr8888)?;
+			let pixels = renderer.map_texture(&mapping)?;
+			let row = size.w as usize * 4;
+			let pixels = if mapping.flipped() {
+				pixels.chunks_exact(row).rev().flatten().copied().collect()
+			} else {
+				pixels.to_vec()
+			};
+			Ok(pixels)
+		});
+		match result {
+			Ok(pixels) => Some(SurfaceSnapshot {
+				size: [size.w as u32, size.h as u32].into(),
+				pixels,
+			}),
+			Err(err) => {
+				warn!(?err, "Could not snapshot surface");
+				None
+			}
+		}
+	}
+
+	pub fn apply_surface_materials(&self) {
 		if let Some(sk_mat) = self.sk_mat.get() {
 			for model_node in self.pending_material_applications.get_valid_contents() {
 				model_node.replace_material(sk_mat.clone());
@@ -206,6 +1746,20 @@ impl CoreSurface {
 		}
 	}
 
+	/// Drop the texture after a GPU reset so it gets imported again from the last committed
+	/// buffer, without the surface counting as newly mapped.
+	pub fn reset_texture(&self) {
+		let mut mapped_data = self.mapped_data.lock();
+		let Some(mapped_data) = mapped_data.as_mut() else {
+			return;
+		};
+		destroy_queue::add(mapped_data.wl_tex.take());
+		self.free_spare_textures();
+		mapped_data.commit = CommitCounter::default();
+		mapped_data.subsurface_commits.clear();
+		mapped_data.composited = false;
+	}
+
 	pub fn wl_surface(&self) -> Option<WlSurface> {
 		self.weak_surface.upgrade().ok()
 	}
@@ -218,6 +1772,26 @@ impl CoreSurface {
 	pub fn size(&self) -> Option<Vector2<u32>> {
 		self.mapped_data.lock().as_ref().map(|d| d.size)
 	}
+	/// What the surface is drawn with, made the first time it gets processed
+	pub fn material(&self) -> Option<Arc<Material>> {
+		self.sk_mat.get().cloned()
+	}
+	/// How the client said the surface's colors are encoded with `wp_color_management_v1`
+	pub fn image_description(&self) -> Option<ImageDescription> {
+		surface_image_description(&self.wl_surface()?)
+	}
+	/// Whether the point in surface coordinates is in the input region from
+	/// `wl_surface.set_input_region`, without one the whole surface takes input.
+	pub fn accepts_input(&self, position: Vector2<f32>) -> bool {
+		self.with_states(|data| {
+			let input_region = &data
+				.cached_state
+				.current::<SurfaceAttributes>()
+				.input_region;
+			region_accepts(input_region.as_ref(), position)
+		})
+		.unwrap_or(true)
+	}
 }
 impl Drop for CoreSurface {
 	fn drop(&mut self) {
@@ -225,5 +1799,548 @@ impl Drop for CoreSurface {
 
 		destroy_queue::add(self.sk_tex.take());
 		destroy_queue::add(self.sk_mat.take());
+		self.free_spare_textures();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::wayland::compositor::surface_tree;
+	use smithay::{
+		delegate_compositor, delegate_shm,
+		reexports::wayland_server::{
+			backend::{ClientData, ClientId, DisconnectReason},
+			protocol::wl_buffer::WlBuffer,
+			Client, Display,
+		},
+		wayland::{
+			buffer::BufferHandler,
+			compositor::{
+				BufferAssignment, CompositorClientState, CompositorHandler, CompositorState, Damage,
+			},
+			shm::{with_buffer_contents, ShmHandler, ShmState},
+		},
+	};
+	use std::{
+		io::{Read, Write},
+		os::{
+			fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+			unix::net::UnixStream,
+		},
+	};
+
+	struct ShmOnly {
+		shm: ShmState,
+	}
+	impl BufferHandler for ShmOnly {
+		fn buffer_destroyed(&mut self, _buffer: &WlBuffer) {}
+	}
+	impl ShmHandler for ShmOnly {
+		fn shm_state(&self) -> &ShmState {
+			&self.shm
+		}
+	}
+	delegate_shm!(ShmOnly);
+	/// Surfaces with shm buffers and nothing else, what `WaylandState` does with them aside
+	struct Surfaces {
+		compositor: CompositorState,
+		shm: ShmState,
+	}
+	impl Surfaces {
+		fn new(dh: &DisplayHandle) -> Self {
+			Surfaces {
+				compositor: CompositorState::new::<Self>(dh),
+				shm: ShmState::new::<Self>(dh, vec![]),
+			}
+		}
+	}
+	impl CompositorHandler for Surfaces {
+		fn compositor_state(&mut self) -> &mut CompositorState {
+			&mut self.compositor
+		}
+		fn client_compositor_state<'a>(&self, client: &'a Client) -> &'a CompositorClientState {
+			&client.get_data::<TestClient>().unwrap().compositor
+		}
+		fn commit(&mut self, surface: &WlSurface) {
+			on_commit_buffer_handler::<Self>(surface);
+		}
+	}
+	impl BufferHandler for Surfaces {
+		fn buffer_destroyed(&mut self, _buffer: &WlBuffer) {}
+	}
+	impl ShmHandler for Surfaces {
+		fn shm_state(&self) -> &ShmState {
+			&self.shm
+		}
+	}
+	delegate_compositor!(Surfaces);
+	delegate_shm!(Surfaces);
+	#[derive(Default)]
+	struct TestClient {
+		compositor: CompositorClientState,
+	}
+	impl ClientData for TestClient {
+		fn initialized(&self, _client_id: ClientId) {}
+		fn disconnected(&self, _client_id: ClientId, _reason: DisconnectReason) {}
+	}
+
+	/// A request in the Wayland wire format, there's no client library to test with
+	fn request(object: u32, opcode: u32, args: &[u32]) -> Vec<u8> {
+		let size = 8 + args.len() as u32 * 4;
+		[object, size << 16 | opcode]
+			.iter()
+			.chain(args)
+			.flat_map(|word| word.to_ne_bytes())
+			.collect()
+	}
+	fn string(string: &str) -> Vec<u32> {
+		let mut bytes = string.as_bytes().to_vec();
+		bytes.push(0);
+		bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+		let words = bytes
+			.chunks(4)
+			.map(|word| u32::from_ne_bytes(word.try_into().unwrap()));
+		std::iter::once(string.len() as u32 + 1)
+			.chain(words)
+			.collect()
+	}
+	/// `(object, opcode, args)` of each event in `data`
+	fn events(data: &[u8]) -> Vec<(u32, u32, &[u8])> {
+		let mut events = Vec::new();
+		let mut rest = data;
+		while rest.len() >= 8 {
+			let word = |i: usize| u32::from_ne_bytes(rest[i..i + 4].try_into().unwrap());
+			let (object, size, opcode) = (word(0), (word(4) >> 16) as usize, word(4) & 0xffff);
+			events.push((object, opcode, &rest[8..size]));
+			rest = &rest[size..];
+		}
+		events
+	}
+	fn send_with_fd(socket: &UnixStream, data: &[u8], fd: RawFd) {
+		let mut iov = libc::iovec {
+			iov_base: data.as_ptr() as *mut c_void,
+			iov_len: data.len(),
+		};
+		let mut control = [0u64; 8];
+		unsafe {
+			let mut msg: libc::msghdr = std::mem::zeroed();
+			msg.msg_iov = &mut iov;
+			msg.msg_iovlen = 1;
+			msg.msg_control = control.as_mut_ptr() as *mut c_void;
+			msg.msg_controllen = libc::CMSG_SPACE(4) as _;
+			let cmsg = libc::CMSG_FIRSTHDR(&msg);
+			(*cmsg).cmsg_level = libc::SOL_SOCKET;
+			(*cmsg).cmsg_type = libc::SCM_RIGHTS;
+			(*cmsg).cmsg_len = libc::CMSG_LEN(4) as _;
+			std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+			assert_eq!(
+				libc::sendmsg(socket.as_raw_fd(), &msg, 0),
+				data.len() as isize
+			);
+		}
+	}
+
+	/// Dispatch what the client sent and read what it got back
+	fn roundtrip<D: 'static>(
+		display: &mut Display<D>,
+		state: &mut D,
+		socket: &mut UnixStream,
+	) -> Vec<u8> {
+		display.dispatch_clients(state).unwrap();
+		display.flush_clients().unwrap();
+		let mut data = vec![0; 4096];
+		socket.set_nonblocking(true).unwrap();
+		let read = match socket.read(&mut data) {
+			Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => 0,
+			read => read.unwrap(),
+		};
+		data.truncate(read);
+		data
+	}
+	/// Bind `interface` from the `globals` wl_registry 2 listed as `id`
+	fn bind(socket: &mut UnixStream, globals: &[u8], interface: &str, version: u32, id: u32) {
+		let interface_arg = [interface.as_bytes(), b"\0"].concat();
+		let name = events(globals)
+			.into_iter()
+			.find(|(object, opcode, args)| {
+				*object == 2 && *opcode == 0 && args[8..].starts_with(&interface_arg)
+			})
+			.map(|(_, _, args)| u32::from_ne_bytes(args[..4].try_into().unwrap()))
+			.unwrap();
+		let args = [vec![name], string(interface), vec![version, id]].concat();
+		socket.write_all(&request(2, 0, &args)).unwrap();
+	}
+
+	#[test]
+	fn truncated_shm_pools_fail_the_read_instead_of_crashing() {
+		const POOL_SIZE: u32 = 64 * 256;
+		let mut display = Display::<ShmOnly>::new().unwrap();
+		let dh = display.handle();
+		let mut state = ShmOnly {
+			shm: ShmState::new::<ShmOnly>(&dh, vec![]),
+		};
+		let (mut socket, server_end) = UnixStream::pair().unwrap();
+		let client = dh
+			.insert_client(server_end, Arc::new(TestClient::default()))
+			.unwrap();
+
+		// wl_display.get_registry, then bind wl_shm from the globals it lists
+		socket.write_all(&request(1, 1, &[2])).unwrap();
+		let globals = roundtrip(&mut display, &mut state, &mut socket);
+		bind(&mut socket, &globals, "wl_shm", 1, 3);
+
+		// a pool with one 64x64 ARGB8888 buffer in it
+		let pool = unsafe {
+			let fd = libc::memfd_create(b"pool\0".as_ptr() as *const _, libc::MFD_CLOEXEC);
+			assert!(fd >= 0);
+			OwnedFd::from_raw_fd(fd)
+		};
+		assert_eq!(
+			unsafe { libc::ftruncate(pool.as_raw_fd(), POOL_SIZE as _) },
+			0
+		);
+		send_with_fd(&socket, &request(3, 0, &[4, POOL_SIZE]), pool.as_raw_fd());
+		socket
+			.write_all(&request(4, 0, &[5, 0, 64, 64, 256, 0]))
+			.unwrap();
+		display.dispatch_clients(&mut state).unwrap();
+		let buffer = client.object_from_protocol_id::<WlBuffer>(&dh, 5).unwrap();
+		let read_all = |ptr: *const u8, len: usize| unsafe {
+			std::slice::from_raw_parts(ptr, len)
+				.iter()
+				.map(|byte| *byte as u64)
+				.sum::<u64>()
+		};
+		let contents = with_buffer_contents(&buffer, |ptr, len, _| read_all(ptr, len));
+		assert_eq!(contents.unwrap(), 0);
+
+		// the client cuts the pool short under the buffer, reading it raises SIGBUS
+		assert_eq!(unsafe { libc::ftruncate(pool.as_raw_fd(), 0) }, 0);
+		let contents = with_buffer_contents(&buffer, |ptr, len, _| read_all(ptr, len));
+		assert!(matches!(contents, Err(BufferAccessError::BadMap)));
+		// and the client gets a protocol error for it
+		let error = roundtrip(&mut display, &mut state, &mut socket);
+		assert!(events(&error)
+			.iter()
+			.any(|(object, opcode, _)| *object == 1 && *opcode == 0));
+	}
+
+	#[test]
+	fn synced_subsurfaces_update_with_their_parent() {
+		let mut display = Display::<Surfaces>::new().unwrap();
+		let dh = display.handle();
+		let mut state = Surfaces::new(&dh);
+		let (mut socket, server_end) = UnixStream::pair().unwrap();
+		let client = dh
+			.insert_client(server_end, Arc::new(TestClient::default()))
+			.unwrap();
+		socket.write_all(&request(1, 1, &[2])).unwrap();
+		let globals = roundtrip(&mut display, &mut state, &mut socket);
+		bind(&mut socket, &globals, "wl_compositor", 4, 3);
+		bind(&mut socket, &globals, "wl_subcompositor", 1, 4);
+		bind(&mut socket, &globals, "wl_shm", 1, 5);
+
+		// two 16x16 buffers, 6 for the parent and 7 for the subsurface
+		let pool = unsafe {
+			let fd = libc::memfd_create(b"pool\0".as_ptr() as *const _, libc::MFD_CLOEXEC);
+			assert!(fd >= 0);
+			OwnedFd::from_raw_fd(fd)
+		};
+		assert_eq!(unsafe { libc::ftruncate(pool.as_raw_fd(), 2 * 16 * 64) }, 0);
+		send_with_fd(&socket, &request(5, 0, &[8, 2 * 16 * 64]), pool.as_raw_fd());
+		socket
+			.write_all(&request(8, 0, &[6, 0, 16, 16, 64, 0]))
+			.unwrap();
+		socket
+			.write_all(&request(8, 0, &[7, 16 * 64, 16, 16, 64, 0]))
+			.unwrap();
+		// surface 9 is the parent, 10 its subsurface 11, which is synchronized by default
+		socket.write_all(&request(3, 0, &[9])).unwrap();
+		socket.write_all(&request(3, 0, &[10])).unwrap();
+		socket.write_all(&request(4, 1, &[11, 10, 9])).unwrap();
+		display.dispatch_clients(&mut state).unwrap();
+		let surface = |id| {
+			client
+				.object_from_protocol_id::<WlSurface>(&dh, id)
+				.unwrap()
+		};
+		let (parent, child) = (surface(9), surface(10));
+		let has_buffer = |surface: &WlSurface| {
+			compositor::with_states(surface, |states| {
+				states
+					.data_map
+					.get::<RendererSurfaceStateUserData>()
+					.is_some_and(|state| state.borrow().buffer().is_some())
+			})
+		};
+
+		// the subsurface's own commit only gets cached
+		socket.write_all(&request(10, 1, &[7, 0, 0])).unwrap();
+		socket.write_all(&request(10, 6, &[])).unwrap();
+		display.dispatch_clients(&mut state).unwrap();
+		assert!(!has_buffer(&child));
+
+		// and the parent's applies both at once
+		socket.write_all(&request(9, 1, &[6, 0, 0])).unwrap();
+		socket.write_all(&request(9, 6, &[])).unwrap();
+		display.dispatch_clients(&mut state).unwrap();
+		assert!(has_buffer(&parent) && has_buffer(&child));
+		// which is why a parent's commit is checked along with its whole tree
+		let tree = surface_tree(&parent);
+		assert!(tree.contains(&parent) && tree.contains(&child));
+	}
+
+	#[test]
+	fn surfaces_only_ever_get_one_role() {
+		let mut display = Display::<Surfaces>::new().unwrap();
+		let dh = display.handle();
+		let mut state = Surfaces::new(&dh);
+		let (mut socket, server_end) = UnixStream::pair().unwrap();
+		let client = dh
+			.insert_client(server_end, Arc::new(TestClient::default()))
+			.unwrap();
+		socket.write_all(&request(1, 1, &[2])).unwrap();
+		let globals = roundtrip(&mut display, &mut state, &mut socket);
+		bind(&mut socket, &globals, "wl_compositor", 4, 3);
+		// wl_compositor.create_surface twice
+		socket.write_all(&request(3, 0, &[4])).unwrap();
+		socket.write_all(&request(3, 0, &[5])).unwrap();
+		display.dispatch_clients(&mut state).unwrap();
+		let surface = |id| {
+			client
+				.object_from_protocol_id::<WlSurface>(&dh, id)
+				.unwrap()
+		};
+
+		// a cursor set again is fine, making it a toplevel after isn't
+		let cursor = surface(4);
+		assert!(give_role(&cursor, CURSOR_ROLE));
+		assert!(give_role(&cursor, CURSOR_ROLE));
+		assert!(!give_role(&cursor, XDG_TOPLEVEL_ROLE));
+		assert_eq!(compositor::get_role(&cursor), Some(CURSOR_ROLE));
+		// and smithay's subsurface role counts too
+		let subsurface = surface(5);
+		compositor::give_role(&subsurface, "subsurface").unwrap();
+		assert!(!give_role(&subsurface, DND_ICON_ROLE));
+	}
+
+	#[test]
+	fn shm_buffers_are_released_once_uploaded() {
+		let mut display = Display::<Surfaces>::new().unwrap();
+		let dh = display.handle();
+		let mut state = Surfaces::new(&dh);
+		let (mut socket, server_end) = UnixStream::pair().unwrap();
+		let client = dh
+			.insert_client(server_end, Arc::new(TestClient::default()))
+			.unwrap();
+		socket.write_all(&request(1, 1, &[2])).unwrap();
+		let globals = roundtrip(&mut display, &mut state, &mut socket);
+		bind(&mut socket, &globals, "wl_compositor", 4, 3);
+		bind(&mut socket, &globals, "wl_shm", 1, 4);
+
+		// a 16x16 ARGB8888 buffer attached to a new surface and committed
+		let pool = unsafe {
+			let fd = libc::memfd_create(b"pool\0".as_ptr() as *const _, libc::MFD_CLOEXEC);
+			assert!(fd >= 0);
+			OwnedFd::from_raw_fd(fd)
+		};
+		assert_eq!(unsafe { libc::ftruncate(pool.as_raw_fd(), 16 * 64) }, 0);
+		send_with_fd(&socket, &request(4, 0, &[5, 16 * 64]), pool.as_raw_fd());
+		socket
+			.write_all(&request(5, 0, &[6, 0, 16, 16, 64, 0]))
+			.unwrap();
+		socket.write_all(&request(3, 0, &[7])).unwrap();
+		socket.write_all(&request(7, 1, &[6, 0, 0])).unwrap();
+		socket.write_all(&request(7, 6, &[])).unwrap();
+		let released = |data: &[u8]| {
+			events(data)
+				.iter()
+				.filter(|(object, opcode, _)| *object == 6 && *opcode == 0)
+				.count()
+		};
+		// the client can't have it back before it's been uploaded
+		assert_eq!(
+			released(&roundtrip(&mut display, &mut state, &mut socket)),
+			0
+		);
+
+		// what `CoreSurface::process` does after importing it into a texture
+		let surface = client.object_from_protocol_id::<WlSurface>(&dh, 7).unwrap();
+		release_uploaded_shm_buffers(&surface);
+		assert_eq!(
+			released(&roundtrip(&mut display, &mut state, &mut socket)),
+			1
+		);
+		// processing the same commit again doesn't release it again
+		release_uploaded_shm_buffers(&surface);
+		assert_eq!(
+			released(&roundtrip(&mut display, &mut state, &mut socket)),
+			0
+		);
+	}
+
+	#[test]
+	fn nearest_filtering_keeps_pixels_sharp() {
+		let point = |filtering| {
+			[(false, false), (false, true), (true, false), (true, true)]
+				.into_iter()
+				.all(|(full_motion, composited)| {
+					let (tex_type, sample) = texture_sampling(filtering, full_motion, composited);
+					// a checkerboard's edges stay one texel wide with no mipmap to blur them
+					tex_type == TextureType::IMAGE_NO_MIPS && matches!(sample, TextureSample::Point)
+				})
+		};
+		assert!(point(SurfaceFiltering::Nearest));
+		assert!(point(SurfaceFiltering::Auto.for_content(ContentType::Game)));
+		assert!(!point(SurfaceFiltering::Linear));
+		assert!(!point(
+			SurfaceFiltering::Auto.for_content(ContentType::Photo)
+		));
+		let (tex_type, sample) = texture_sampling(SurfaceFiltering::Trilinear, false, true);
+		assert!(tex_type == TextureType::IMAGE && matches!(sample, TextureSample::Linear));
+	}
+
+	#[test]
+	fn surfaces_get_an_output_once_mapped() {
+		let mut current = None;
+		let mut entered = 0;
+		let callbacks = |output: Option<&&str>| {
+			frame_callback_output(output, |_| true, false, true, HIDDEN_FRAME_INTERVAL).is_some()
+		};
+		// created before the output exists or anything was committed: nothing to render for yet
+		let output = surface_output(false, &mut current, &"output", |_| entered += 1);
+		assert_eq!(output, None);
+		assert!(!callbacks(output.as_ref()));
+		assert_eq!(entered, 0);
+		// its first buffer maps it, and it only enters the output the once
+		for _ in 0..3 {
+			let output = surface_output(true, &mut current, &"output", |_| entered += 1);
+			assert_eq!(output, Some("output"));
+			assert!(callbacks(output.as_ref()));
+		}
+		assert_eq!(entered, 1);
+	}
+
+	#[test]
+	fn minimized_surfaces_get_no_frame_callbacks() {
+		let output = "output";
+		let callbacks = |minimized, visible, since_last| {
+			frame_callback_output(Some(&output), |_| true, minimized, visible, since_last).is_some()
+		};
+		assert!(callbacks(false, true, Duration::ZERO));
+		assert!(!callbacks(true, true, Duration::ZERO));
+		// not even the occasional one hidden surfaces get
+		assert!(callbacks(false, false, HIDDEN_FRAME_INTERVAL));
+		assert!(!callbacks(true, false, Duration::from_secs(60)));
+		// once it's restored it gets one right away, however long it was minimized for
+		assert!(callbacks(false, true, Duration::from_secs(60)));
+		// and like minimized ones, ones on an output that's off get none
+		let off = frame_callback_output(Some(&output), |_| false, false, true, Duration::ZERO);
+		assert!(off.is_none());
+	}
+
+	#[test]
+	fn newer_commits_wait_for_the_next_frame() {
+		let mut attributes = SurfaceAttributes::default();
+		assert!(!commit_unprocessed(&attributes));
+		let damage = Rectangle::from_loc_and_size((0, 0), (64, 64));
+		attributes.damage.push(Damage::Buffer(damage));
+		assert!(commit_unprocessed(&attributes));
+		attributes.damage.clear();
+		attributes.buffer = Some(BufferAssignment::Removed);
+		assert!(commit_unprocessed(&attributes));
+	}
+
+	#[test]
+	fn input_lands_on_the_pixel_drawn_on_the_curve() {
+		let width = 1000.0;
+		let arc = std::f32::consts::FRAC_PI_2;
+		for column in 0..=10 {
+			// where the curve is hit when it's `u` of the way across, like `cylinder_mesh` puts it
+			let u = column as f32 / 10.0;
+			let hit = (0.5 + ((u - 0.5) * arc).sin() / arc) * width;
+			assert!((unbend_x(hit, width, arc) - u * width).abs() < 0.5);
+		}
+		// the middle doesn't move and the sides get stretched out onto the curve
+		assert_eq!(unbend_x(500.0, width, arc), 500.0);
+		assert!(unbend_x(800.0, width, arc) > 800.0);
+		assert!(unbend_x(200.0, width, arc) < 200.0);
+	}
+
+	#[test]
+	fn only_damaged_regions_are_uploaded() {
+		let size = Size::from((100, 100));
+		let full = [Rectangle::from_loc_and_size((0, 0), size)];
+		assert_eq!(damage_bytes(full.iter(), size), 100 * 100 * 4);
+		// The second commit only damaged a 10x10 square
+		let square = [Rectangle::from_loc_and_size((40, 40), (10, 10))];
+		assert_eq!(damage_bytes(square.iter(), size), 10 * 10 * 4);
+		assert_eq!(damage_bytes([].iter(), size), 0);
+	}
+
+	#[test]
+	fn damage_is_clipped_to_the_buffer() {
+		let size = Size::from((100, 100));
+		let damage = [
+			Rectangle::from_loc_and_size((95, 95), (10, 10)),
+			Rectangle::from_loc_and_size((200, 0), (10, 10)),
+			Rectangle::from_loc_and_size((-10, -10), (i32::MAX, i32::MAX)),
+		];
+		assert_eq!(damage_bytes(damage[..2].iter(), size), 5 * 5 * 4);
+		assert_eq!(damage_bytes(damage[2..].iter(), size), 100 * 100 * 4);
+	}
+
+	#[test]
+	fn input_goes_through_outside_the_input_region() {
+		// an L, the top right quarter of a 100x100 surface is cut out
+		let mut region = RegionAttributes::default();
+		region.rects.push((
+			RectangleKind::Add,
+			Rectangle::from_loc_and_size((0, 0), (100, 100)),
+		));
+		region.rects.push((
+			RectangleKind::Subtract,
+			Rectangle::from_loc_and_size((50, 0), (50, 50)),
+		));
+		let accepts = |x: f32, y: f32| region_accepts(Some(&region), [x, y].into());
+		assert!(accepts(10.0, 10.0));
+		assert!(accepts(75.0, 75.0));
+		assert!(accepts(49.9, 49.9));
+		assert!(!accepts(50.0, 10.0));
+		assert!(!accepts(99.0, 49.5));
+		assert!(!accepts(-0.5, 10.0));
+		assert!(!accepts(10.0, 100.0));
+		// without a region the whole surface takes input
+		assert!(region_accepts(None, [75.0, 10.0].into()));
+	}
+
+	#[test]
+	fn shells_set_the_encoding_unless_the_client_describes_it() {
+		assert_eq!(Encoding::of(ColorSpace::Srgb, None), Encoding::Srgb);
+		assert_eq!(Encoding::of(ColorSpace::Linear, None), Encoding::Linear);
+		// a client saying its content is sRGB overrides a shell marking it linear
+		let srgb = ImageDescription::SRGB;
+		assert_eq!(
+			Encoding::of(ColorSpace::Linear, Some(&srgb)),
+			Encoding::Srgb
+		);
+		let described = |transfer_function| ImageDescription {
+			transfer_function,
+			..ImageDescription::SRGB
+		};
+		for (transfer_function, encoding) in [
+			(TransferFunction::Gamma22, Encoding::Srgb),
+			(TransferFunction::Bt1886, Encoding::Srgb),
+			(TransferFunction::ExtLinear, Encoding::Linear),
+			(TransferFunction::St2084Pq, Encoding::Pq),
+			(TransferFunction::Hlg, Encoding::Hlg),
+		] {
+			let description = described(transfer_function);
+			assert_eq!(Encoding::of(ColorSpace::Srgb, Some(&description)), encoding);
+		}
+		// sRGB and linear share the shader, only the HDR ones need their own decoding
+		assert_eq!(Encoding::Linear.define(), "");
+		assert_eq!(Encoding::Pq.define(), "#define PQ\n");
 	}
 }
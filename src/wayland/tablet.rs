@@ -0,0 +1,427 @@
+use super::{
+	focus_policy,
+	seat::{event_time, unbent, SeatData},
+	serial::{SerialEvent, SerialLog},
+	state::WaylandState,
+};
+use crate::nodes::items::panel::{PenKind, PenState, PenTool};
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use smithay::reexports::{
+	wayland_protocols::wp::tablet::zv2::server::{
+		zwp_tablet_manager_v2::{self, ZwpTabletManagerV2},
+		zwp_tablet_seat_v2::{self, ZwpTabletSeatV2},
+		zwp_tablet_tool_v2::{self, ButtonState, Capability, Type, ZwpTabletToolV2},
+		zwp_tablet_v2::{self, ZwpTabletV2},
+	},
+	wayland_server::{
+		backend::{ClientId, ObjectId},
+		protocol::wl_surface::WlSurface,
+		Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+	},
+};
+use std::sync::Arc;
+use tracing::debug;
+
+/// `pressure` in tablet-v2 goes from 0 to this
+const MAX_PRESSURE: f32 = 65535.0;
+
+pub struct TabletSeatData {
+	seat: Arc<SeatData>,
+	/// All pens are on this one tablet, the shell doesn't tell us which device they came from
+	tablet: ZwpTabletV2,
+	/// Advertised the first time each tool gets used
+	tools: Mutex<FxHashMap<PenTool, ZwpTabletToolV2>>,
+}
+
+/// A pen over one of the client's surfaces
+struct Proximity {
+	surface: ObjectId,
+	down: bool,
+	buttons: Vec<u32>,
+}
+impl Proximity {
+	/// Whether the tip went down or up with the pen now pressing with `pressure`
+	fn tip(&mut self, pressure: f32) -> Option<Tip> {
+		let down = pressure > 0.0;
+		let changed = down != self.down;
+		self.down = down;
+		match (changed, down) {
+			(false, _) => None,
+			(true, true) => Some(Tip::Down),
+			(true, false) => Some(Tip::Up),
+		}
+	}
+	/// Whether pressing or releasing `button` changed anything
+	fn button(&mut self, button: u32, pressed: bool) -> bool {
+		if self.buttons.contains(&button) == pressed {
+			return false;
+		}
+		if pressed {
+			self.buttons.push(button);
+		} else {
+			self.buttons.retain(|held| *held != button);
+		}
+		true
+	}
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tip {
+	Down,
+	Up,
+}
+/// From the shell's 0 to 1
+fn tablet_pressure(pressure: f32) -> u32 {
+	(pressure.clamp(0.0, 1.0) * MAX_PRESSURE) as u32
+}
+
+/// All the tablet seats of a seat, every pen shows up on each of them
+pub struct Tablets {
+	dh: DisplayHandle,
+	/// The seat's
+	serials: Arc<SerialLog>,
+	tablet_seats: Mutex<Vec<ZwpTabletSeatV2>>,
+	proximity: Mutex<FxHashMap<PenTool, Proximity>>,
+}
+impl Tablets {
+	pub fn new(dh: &DisplayHandle, serials: Arc<SerialLog>) -> Self {
+		Tablets {
+			dh: dh.clone(),
+			serials,
+			tablet_seats: Mutex::new(Vec::new()),
+			proximity: Mutex::new(FxHashMap::default()),
+		}
+	}
+	fn add(&self, tablet_seat: ZwpTabletSeatV2) {
+		self.tablet_seats.lock().push(tablet_seat);
+	}
+	fn remove(&self, tablet_seat: &ZwpTabletSeatV2) {
+		self.tablet_seats
+			.lock()
+			.retain(|other| other != tablet_seat);
+	}
+
+	/// The tool on every tablet seat along with the tablet it's on
+	fn tools(&self, tool: PenTool) -> Vec<(ZwpTabletToolV2, ZwpTabletV2)> {
+		self.tablet_seats
+			.lock()
+			.iter()
+			.filter_map(|tablet_seat| {
+				let data = tablet_seat.data::<TabletSeatData>()?;
+				let mut tools = data.tools.lock();
+				if let Some(wl_tool) = tools.get(&tool) {
+					return Some((wl_tool.clone(), data.tablet.clone()));
+				}
+				let wl_tool = tablet_seat
+					.client()?
+					.create_resource::<ZwpTabletToolV2, (), WaylandState>(
+						&self.dh,
+						tablet_seat.version(),
+						(),
+					)
+					.ok()?;
+				tablet_seat.tool_added(&wl_tool);
+				describe_tool(&wl_tool, tool);
+				tools.insert(tool, wl_tool.clone());
+				Some((wl_tool, data.tablet.clone()))
+			})
+			.collect()
+	}
+
+	/// Moves `tool` into proximity of `surface` if it isn't already, the tip is down while
+	/// `state.pressure` is above 0
+	pub fn pen_motion(&self, surface: &WlSurface, tool: PenTool, state: PenState) {
+		let mut proximity = self.proximity.lock();
+		if proximity
+			.get(&tool)
+			.is_some_and(|current| current.surface != surface.id())
+		{
+			self.leave(&mut proximity, tool);
+		}
+		let tools = self.tools(tool);
+		let proximity_in = (!proximity.contains_key(&tool)).then(|| {
+			self.serials
+				.next(SerialEvent::TabletToolProximityIn, &surface.id())
+		});
+		let current = proximity.entry(tool).or_insert_with(|| Proximity {
+			surface: surface.id(),
+			down: false,
+			buttons: Vec::new(),
+		});
+		let tip = current.tip(state.pressure);
+		let down_serial = (tip == Some(Tip::Down)).then(|| {
+			focus_policy::input_happened();
+			self.serials
+				.next(SerialEvent::TabletToolDown, &surface.id())
+		});
+		let up = tip == Some(Tip::Up);
+		if up {
+			self.serials
+				.next(SerialEvent::TabletToolUp, &current.surface);
+		}
+
+		let pressure = tablet_pressure(state.pressure);
+		let position = unbent(surface, state.position);
+		let time = event_time();
+		for (wl_tool, tablet) in &tools {
+			if let Some(serial) = proximity_in {
+				wl_tool.proximity_in(serial, tablet, surface);
+			}
+			wl_tool.motion(position.x as f64, position.y as f64);
+			wl_tool.pressure(pressure);
+			wl_tool.tilt(state.tilt.x as f64, state.tilt.y as f64);
+			if let Some(serial) = down_serial {
+				wl_tool.down(serial);
+			} else if up {
+				wl_tool.up();
+			}
+			wl_tool.frame(time);
+		}
+	}
+	/// Stylus buttons, not the tip, that's `pressure` in `pen_motion`
+	pub fn pen_button(&self, tool: PenTool, button: u32, pressed: bool) {
+		let mut proximity = self.proximity.lock();
+		let Some(current) = proximity.get_mut(&tool) else {
+			return;
+		};
+		if !current.button(button, pressed) {
+			return;
+		}
+		if pressed {
+			focus_policy::input_happened();
+		}
+		let event = SerialEvent::TabletToolButton { button, pressed };
+		let serial = self.serials.next(event, &current.surface);
+		let state = if pressed {
+			ButtonState::Pressed
+		} else {
+			ButtonState::Released
+		};
+		let time = event_time();
+		for (wl_tool, _) in self.tools(tool) {
+			wl_tool.button(serial, button, state);
+			wl_tool.frame(time);
+		}
+	}
+	pub fn pen_leave(&self, tool: PenTool) {
+		let mut proximity = self.proximity.lock();
+		self.leave(&mut proximity, tool);
+	}
+	/// Lift the tip, release the buttons and move out of proximity, like a pen taken off a tablet
+	fn leave(&self, proximity: &mut FxHashMap<PenTool, Proximity>, tool: PenTool) {
+		let Some(current) = proximity.remove(&tool) else {
+			return;
+		};
+		let button_serials = current
+			.buttons
+			.iter()
+			.map(|button| {
+				let event = SerialEvent::TabletToolButton {
+					button: *button,
+					pressed: false,
+				};
+				(*button, self.serials.next(event, &current.surface))
+			})
+			.collect::<Vec<_>>();
+		self.serials
+			.next(SerialEvent::TabletToolProximityOut, &current.surface);
+		let time = event_time();
+		for (wl_tool, _) in self.tools(tool) {
+			for (button, serial) in &button_serials {
+				wl_tool.button(*serial, *button, ButtonState::Released);
+			}
+			if current.down {
+				wl_tool.up();
+			}
+			wl_tool.proximity_out();
+			wl_tool.frame(time);
+		}
+	}
+}
+
+/// Apps like Krita keep settings per tool, so they need the serial to tell them apart
+fn describe_tool(wl_tool: &ZwpTabletToolV2, tool: PenTool) {
+	wl_tool.r#type(match tool.kind {
+		PenKind::Pen => Type::Pen,
+		PenKind::Eraser => Type::Eraser,
+	});
+	wl_tool.hardware_serial((tool.serial >> 32) as u32, tool.serial as u32);
+	if tool.hardware_id != 0 {
+		wl_tool.hardware_id_wacom((tool.hardware_id >> 32) as u32, tool.hardware_id as u32);
+	}
+	wl_tool.capability(Capability::Pressure);
+	wl_tool.capability(Capability::Tilt);
+	wl_tool.done();
+}
+
+impl GlobalDispatch<ZwpTabletManagerV2, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZwpTabletManagerV2>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ZwpTabletManagerV2, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		client: &Client,
+		resource: &ZwpTabletManagerV2,
+		request: zwp_tablet_manager_v2::Request,
+		_data: &(),
+		dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_tablet_manager_v2::Request::GetTabletSeat { tablet_seat, seat } => {
+				let Some(seat) = seat.data::<Arc<SeatData>>().cloned() else {
+					return;
+				};
+				let Ok(tablet) = client.create_resource::<ZwpTabletV2, (), WaylandState>(
+					dh,
+					resource.version(),
+					(),
+				) else {
+					return;
+				};
+				let tablet_seat = data_init.init(
+					tablet_seat,
+					TabletSeatData {
+						seat: seat.clone(),
+						tablet: tablet.clone(),
+						tools: Mutex::new(FxHashMap::default()),
+					},
+				);
+				tablet_seat.tablet_added(&tablet);
+				tablet.name("Stardust XR pen".to_string());
+				tablet.done();
+				seat.tablets.add(tablet_seat);
+			}
+			zwp_tablet_manager_v2::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ZwpTabletSeatV2, TabletSeatData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwpTabletSeatV2,
+		request: zwp_tablet_seat_v2::Request,
+		_data: &TabletSeatData,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_tablet_seat_v2::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+
+	fn destroyed(
+		_state: &mut WaylandState,
+		_client: ClientId,
+		resource: &ZwpTabletSeatV2,
+		data: &TabletSeatData,
+	) {
+		data.seat.tablets.remove(resource);
+	}
+}
+
+impl Dispatch<ZwpTabletV2, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwpTabletV2,
+		request: zwp_tablet_v2::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_tablet_v2::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ZwpTabletToolV2, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwpTabletToolV2,
+		request: zwp_tablet_tool_v2::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			// the shell draws pens itself, like it does for touches
+			zwp_tablet_tool_v2::Request::SetCursor { .. } => {
+				debug!("Ignoring a tablet tool cursor");
+			}
+			zwp_tablet_tool_v2::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use input_event_codes::{BTN_STYLUS, BTN_STYLUS2};
+
+	fn proximity() -> Proximity {
+		Proximity {
+			surface: ObjectId::null(),
+			down: false,
+			buttons: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn pen_strokes_send_their_pressure() {
+		let mut pen = proximity();
+		// hovering in, pressing harder, easing off and lifting
+		let stroke = [0.0, 0.25, 0.5, 1.0, 0.5, 0.0, 0.0];
+		let sent = stroke
+			.into_iter()
+			.map(|pressure| (tablet_pressure(pressure), pen.tip(pressure)))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			sent,
+			[
+				(0, None),
+				(16383, Some(Tip::Down)),
+				(32767, None),
+				(65535, None),
+				(32767, None),
+				(0, Some(Tip::Up)),
+				(0, None),
+			]
+		);
+	}
+
+	#[test]
+	fn pressure_past_the_ends_is_clamped() {
+		assert_eq!(tablet_pressure(-0.5), 0);
+		assert_eq!(tablet_pressure(2.0), 65535);
+	}
+
+	#[test]
+	fn stylus_buttons_only_change_once() {
+		let mut pen = proximity();
+		assert!(pen.button(BTN_STYLUS!(), true));
+		assert!(!pen.button(BTN_STYLUS!(), true));
+		assert!(pen.button(BTN_STYLUS2!(), true));
+		assert!(pen.button(BTN_STYLUS!(), false));
+		assert!(!pen.button(BTN_STYLUS!(), false));
+		assert_eq!(pen.buttons, [BTN_STYLUS2!()]);
+	}
+}
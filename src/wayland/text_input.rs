@@ -0,0 +1,302 @@
+use super::{
+	seat::SeatData,
+	state::{ClientState, WaylandState},
+	utils,
+	xdg_shell::XdgBackend,
+};
+use crate::nodes::items::panel::{Geometry, PanelItem, SurfaceID, TextInputInfo, TextInputUpdate};
+use parking_lot::Mutex;
+use smithay::reexports::{
+	wayland_protocols::wp::text_input::zv3::server::{
+		zwp_text_input_manager_v3::{self, ZwpTextInputManagerV3},
+		zwp_text_input_v3::{self, ZwpTextInputV3},
+	},
+	wayland_server::{
+		backend::ClientId, protocol::wl_surface::WlSurface, Client, DataInit, Dispatch,
+		DisplayHandle, GlobalDispatch, New, Resource, WEnum, Weak as WlWeak,
+	},
+};
+use std::sync::{Arc, Weak};
+
+/// Double buffered state of a text input, applied on commit
+#[derive(Debug, Clone, Default)]
+struct TextInputState {
+	enabled: bool,
+	surrounding_text: Option<(String, i32, i32)>,
+	content_hint: u32,
+	content_purpose: u32,
+	cursor_rectangle: Option<Geometry>,
+}
+impl TextInputState {
+	fn info(&self) -> Option<TextInputInfo> {
+		if !self.enabled {
+			return None;
+		}
+		let (surrounding_text, cursor, anchor) = self
+			.surrounding_text
+			.clone()
+			.map(|(text, cursor, anchor)| (Some(text), cursor, anchor))
+			.unwrap_or_default();
+		Some(TextInputInfo {
+			surrounding_text,
+			cursor,
+			anchor,
+			content_hint: self.content_hint,
+			content_purpose: self.content_purpose,
+			cursor_rectangle: self.cursor_rectangle,
+		})
+	}
+}
+
+pub struct TextInputData {
+	seat: Arc<SeatData>,
+	pending: Mutex<TextInputState>,
+	current: Mutex<TextInputState>,
+	/// How many times the client committed, sent back with `done`
+	commits: Mutex<u32>,
+}
+
+/// All the text inputs of a seat, they all follow the focused toplevel
+#[derive(Default)]
+pub struct TextInputs {
+	text_inputs: Mutex<Vec<ZwpTextInputV3>>,
+	focus: Mutex<Option<WlWeak<WlSurface>>>,
+}
+impl TextInputs {
+	fn focus(&self) -> Option<WlSurface> {
+		self.focus.lock().as_ref()?.upgrade().ok()
+	}
+	fn add(&self, text_input: ZwpTextInputV3) {
+		if let Some(focus) = self.focus() {
+			text_input.enter(&focus);
+		}
+		self.text_inputs.lock().push(text_input);
+	}
+	fn remove(&self, text_input: &ZwpTextInputV3) {
+		self.text_inputs.lock().retain(|other| other != text_input);
+	}
+
+	pub fn set_focus(&self, surface: &WlSurface, focused: bool) {
+		let old_focus = self.focus();
+		if focused == (old_focus.as_ref() == Some(surface)) {
+			return;
+		}
+		let text_inputs = self.text_inputs.lock();
+		if let Some(old_focus) = old_focus {
+			for text_input in text_inputs.iter() {
+				text_input.leave(&old_focus);
+			}
+			send_info(&old_focus, None);
+		}
+		*self.focus.lock() = focused.then(|| surface.downgrade());
+		if focused {
+			for text_input in text_inputs.iter() {
+				text_input.enter(surface);
+			}
+		}
+		flush(surface);
+	}
+
+	pub fn update(&self, surface: &WlSurface, update: &TextInputUpdate) {
+		if self.focus().as_ref() != Some(surface) {
+			return;
+		}
+		for text_input in self.text_inputs.lock().iter() {
+			let Some(data) = text_input.data::<TextInputData>() else {
+				continue;
+			};
+			if !data.current.lock().enabled {
+				continue;
+			}
+			text_input.preedit_string(
+				update.preedit_string.clone(),
+				update.preedit_cursor_begin,
+				update.preedit_cursor_end,
+			);
+			if let Some(commit_string) = &update.commit_string {
+				text_input.commit_string(Some(commit_string.clone()));
+			}
+			if update.delete_before != 0 || update.delete_after != 0 {
+				text_input.delete_surrounding_text(update.delete_before, update.delete_after);
+			}
+			text_input.done(*data.commits.lock());
+		}
+		flush(surface);
+	}
+}
+
+/// Let the shell (and so the keyboard component) know what kind of text is wanted
+fn send_info(surface: &WlSurface, info: Option<TextInputInfo>) {
+	let Some(panel_item) =
+		utils::get_data::<Weak<PanelItem<XdgBackend>>>(surface).and_then(|p| p.upgrade())
+	else {
+		return;
+	};
+	panel_item.text_input_state(&SurfaceID::Toplevel, info);
+}
+fn flush(surface: &WlSurface) {
+	let Some(client) = surface.client() else {
+		return;
+	};
+	if let Some(client_state) = client.get_data::<ClientState>() {
+		client_state.flush();
+	}
+}
+
+impl GlobalDispatch<ZwpTextInputManagerV3, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZwpTextInputManagerV3>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ZwpTextInputManagerV3, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZwpTextInputManagerV3,
+		request: zwp_text_input_manager_v3::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_text_input_manager_v3::Request::GetTextInput { id, seat } => {
+				let Some(seat) = seat.data::<Arc<SeatData>>().cloned() else {
+					return;
+				};
+				let text_input = data_init.init(
+					id,
+					TextInputData {
+						seat: seat.clone(),
+						pending: Mutex::new(TextInputState::default()),
+						current: Mutex::new(TextInputState::default()),
+						commits: Mutex::new(0),
+					},
+				);
+				seat.text_inputs.add(text_input);
+			}
+			zwp_text_input_manager_v3::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ZwpTextInputV3, TextInputData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		resource: &ZwpTextInputV3,
+		request: zwp_text_input_v3::Request,
+		data: &TextInputData,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zwp_text_input_v3::Request::Enable => {
+				// Enabling resets all the state
+				*data.pending.lock() = TextInputState {
+					enabled: true,
+					..Default::default()
+				};
+			}
+			zwp_text_input_v3::Request::Disable => {
+				data.pending.lock().enabled = false;
+			}
+			zwp_text_input_v3::Request::SetSurroundingText {
+				text,
+				cursor,
+				anchor,
+			} => {
+				data.pending.lock().surrounding_text = Some((text, cursor, anchor));
+			}
+			zwp_text_input_v3::Request::SetTextChangeCause { .. } => (),
+			zwp_text_input_v3::Request::SetContentType { hint, purpose } => {
+				let mut pending = data.pending.lock();
+				if let WEnum::Value(hint) = hint {
+					pending.content_hint = hint.bits();
+				}
+				if let WEnum::Value(purpose) = purpose {
+					pending.content_purpose = purpose.into();
+				}
+			}
+			zwp_text_input_v3::Request::SetCursorRectangle {
+				x,
+				y,
+				width,
+				height,
+			} => {
+				data.pending.lock().cursor_rectangle = Some(Geometry {
+					origin: [x, y].into(),
+					size: [width.max(0) as u32, height.max(0) as u32].into(),
+				});
+			}
+			zwp_text_input_v3::Request::Commit => {
+				let current = data.pending.lock().clone();
+				*data.current.lock() = current.clone();
+				*data.commits.lock() += 1;
+				if let Some(focus) = data.seat.text_inputs.focus() {
+					if resource.client().map(|c| c.id()) == focus.client().map(|c| c.id()) {
+						send_info(&focus, current.info());
+					}
+				}
+			}
+			zwp_text_input_v3::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+
+	fn destroyed(
+		_state: &mut WaylandState,
+		_client: ClientId,
+		resource: &ZwpTextInputV3,
+		data: &TextInputData,
+	) {
+		data.seat.text_inputs.remove(resource);
+		if let Some(focus) = data.seat.text_inputs.focus() {
+			send_info(&focus, None);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn only_enabled_text_inputs_want_anything() {
+		let mut state = TextInputState {
+			content_purpose: zwp_text_input_v3::ContentPurpose::Digits.into(),
+			..Default::default()
+		};
+		assert!(state.info().is_none());
+		state.enabled = true;
+		let info = state.info().unwrap();
+		assert_eq!(info.content_purpose, 2);
+		assert_eq!(info.surrounding_text, None);
+		assert_eq!((info.cursor, info.anchor), (0, 0));
+	}
+
+	#[test]
+	fn surrounding_text_is_surfaced() {
+		let state = TextInputState {
+			enabled: true,
+			surrounding_text: Some(("4".to_string(), 1, 0)),
+			content_hint: zwp_text_input_v3::ContentHint::Latin.bits(),
+			..Default::default()
+		};
+		let info = state.info().unwrap();
+		assert_eq!(info.surrounding_text.as_deref(), Some("4"));
+		assert_eq!((info.cursor, info.anchor), (1, 0));
+		assert_eq!(
+			info.content_hint,
+			zwp_text_input_v3::ContentHint::Latin.bits()
+		);
+	}
+}
@@ -0,0 +1,575 @@
+use crate::core::task;
+use rustc_hash::FxHashMap;
+use std::{
+	fs::File,
+	io::{self, Read, Write},
+	os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+	sync::Arc,
+};
+use tokio::io::unix::AsyncFd;
+use tracing::{debug, warn};
+
+/// What targets that only take plain text get offered, when the source doesn't have it
+const PLAIN_TEXT_TYPES: [&str; 2] = ["text/plain;charset=utf-8", "text/plain"];
+/// Types with the same UTF-8 text in them as `PLAIN_TEXT_TYPES`, by preference
+const UTF8_TEXT_TYPES: [&str; 3] = ["text/plain;charset=utf-8", "UTF8_STRING", "text/plain"];
+const HTML_TYPE: &str = "text/html";
+/// X11's names for text, what Xwayland and older toolkits offer besides `text/*`
+const X11_TEXT_TYPES: [&str; 4] = ["UTF8_STRING", "STRING", "TEXT", "COMPOUND_TEXT"];
+/// Converted data has to be read whole, this is more than anyone would paste so a source that
+/// never stops writing can't run us out of memory
+const MAX_CONVERT_BYTES: usize = 64 << 20;
+/// Copies only ever hold this much at once, the source waits until the target has read it
+const COPY_CHUNK_BYTES: usize = 64 << 10;
+
+enum Conversion {
+	None,
+	HtmlToText,
+}
+/// Which of the source's types `mime_type` can be made from and how, if it doesn't have it.
+/// Only conversions that can't lose anything the target would want are done.
+fn find_conversion(source_types: &[String], mime_type: &str) -> Option<(String, Conversion)> {
+	if !PLAIN_TEXT_TYPES.contains(&mime_type) {
+		return None;
+	}
+	let has = |mime_type: &str| source_types.iter().any(|t| t == mime_type);
+	if let Some(text_type) = UTF8_TEXT_TYPES.into_iter().find(|t| has(t)) {
+		return Some((text_type.to_string(), Conversion::None));
+	}
+	has(HTML_TYPE).then(|| (HTML_TYPE.to_string(), Conversion::HtmlToText))
+}
+
+/// If data of `mime_type` is text, which is small enough to keep around
+pub fn is_text(mime_type: &str) -> bool {
+	mime_type.starts_with("text/") || X11_TEXT_TYPES.contains(&mime_type)
+}
+
+/// The source's type to get for `mime_type` and what to turn it into that with, if anything.
+fn source_type(
+	source_types: &[String],
+	mime_type: String,
+) -> Option<(String, Option<fn(&str) -> String>)> {
+	let (source_type, conversion) = if source_types.contains(&mime_type) {
+		(mime_type, Conversion::None)
+	} else {
+		find_conversion(source_types, &mime_type)?
+	};
+	let convert = match conversion {
+		Conversion::None => None,
+		Conversion::HtmlToText => Some(html_to_text as fn(&str) -> String),
+	};
+	Some((source_type, convert))
+}
+
+/// The source's types plus the ones that can be derived from them, so targets that don't
+/// share a type with the source can still take its data.
+pub fn offered_mime_types(source_types: &[String]) -> Vec<String> {
+	let derived = PLAIN_TEXT_TYPES
+		.into_iter()
+		.filter(|mime_type| !source_types.iter().any(|t| t == mime_type))
+		.filter(|mime_type| find_conversion(source_types, mime_type).is_some())
+		.map(ToString::to_string);
+	source_types.iter().cloned().chain(derived).collect()
+}
+
+/// Get `mime_type` from the source into `fd`, converting from another of its types if needed.
+/// `send` asks the source to write one of its own types into a file descriptor.
+pub fn receive(
+	source_types: &[String],
+	mime_type: String,
+	fd: OwnedFd,
+	send: impl FnOnce(String, BorrowedFd),
+) {
+	let Some((source_type, convert)) = source_type(source_types, mime_type) else {
+		return;
+	};
+	match Transfer::new(&fd) {
+		Ok(transfer) => {
+			send(source_type.clone(), transfer.source_end.as_fd());
+			transfer.start(source_type, convert);
+		}
+		// the target isn't something we can poll, so all we can do is hand it to the source
+		Err(err) if convert.is_none() => {
+			debug!(?err, "Selection transfer not mediated");
+			send(source_type, fd.as_fd());
+		}
+		Err(err) => warn!(?err, source_type, "Could not convert selection"),
+	}
+}
+
+/// Write `mime_type` into `fd` from data read earlier by `read_whole`, converting like `receive`.
+pub fn send_cached(cache: &FxHashMap<String, Arc<[u8]>>, mime_type: String, fd: OwnedFd) {
+	let cached_types = cache.keys().cloned().collect::<Vec<_>>();
+	let Some((source_type, convert)) = source_type(&cached_types, mime_type) else {
+		return;
+	};
+	let data = cache[&source_type].clone();
+	let to = match set_nonblocking(&fd).and_then(|_| AsyncFd::new(File::from(fd))) {
+		Ok(to) => to,
+		Err(err) => {
+			warn!(?err, source_type, "Could not send cached selection");
+			return;
+		}
+	};
+	let _ = task::new(|| "cached selection transfer", async move {
+		let result = match convert {
+			None => write_all(&to, &data).await,
+			Some(convert) => {
+				let converted = convert(&String::from_utf8_lossy(&data));
+				write_all(&to, converted.as_bytes()).await
+			}
+		};
+		if let Err(err) = result {
+			warn!(?err, source_type, "Cached selection transfer failed");
+		}
+	});
+}
+
+/// Read all of `mime_type` from the source into memory, `done` gets it once the source is done
+/// writing, or `None` if it couldn't be read or was more than `max_bytes`.
+pub fn read_whole(
+	mime_type: String,
+	max_bytes: usize,
+	send: impl FnOnce(String, BorrowedFd),
+	done: impl FnOnce(Option<Vec<u8>>) + Send + 'static,
+) {
+	let pipe = pipe().and_then(|(from, source_end)| {
+		set_nonblocking(&from)?;
+		Ok((AsyncFd::new(File::from(from))?, source_end))
+	});
+	let (from, source_end) = match pipe {
+		Ok(pipe) => pipe,
+		Err(err) => {
+			warn!(?err, mime_type, "Could not read selection");
+			done(None);
+			return;
+		}
+	};
+	send(mime_type.clone(), source_end.as_fd());
+	drop(source_end);
+	let _ = task::new(|| "selection read", async move {
+		match read_to_end(&from, max_bytes).await {
+			Ok(data) => done(Some(data)),
+			Err(err) => {
+				debug!(?err, mime_type, "Selection not read");
+				done(None);
+			}
+		}
+	});
+}
+
+/// The data goes through a pipe of ours and gets copied on a task, so a slow or huge transfer
+/// (like pasting a big image) never blocks dispatching.
+struct Transfer {
+	/// Given to the source to write into, has to be closed once sent for the copy to end
+	source_end: OwnedFd,
+	from: AsyncFd<File>,
+	to: AsyncFd<File>,
+}
+impl Transfer {
+	fn new(target: &OwnedFd) -> io::Result<Self> {
+		let (from, source_end) = pipe()?;
+		// only our ends, the source might not expect its writes to fail with EAGAIN
+		set_nonblocking(&from)?;
+		let to = target.try_clone()?;
+		set_nonblocking(&to)?;
+		Ok(Transfer {
+			source_end,
+			from: AsyncFd::new(File::from(from))?,
+			to: AsyncFd::new(File::from(to))?,
+		})
+	}
+	fn start(self, source_type: String, convert: Option<fn(&str) -> String>) {
+		// the message has its own copy of the fd by now
+		drop(self.source_end);
+		let (from, to) = (self.from, self.to);
+		let _ = task::new(|| "selection transfer", async move {
+			let result = match convert {
+				None => copy(&from, &to).await,
+				Some(convert) => match read_to_end(&from, MAX_CONVERT_BYTES).await {
+					Ok(data) => {
+						let converted = convert(&String::from_utf8_lossy(&data));
+						write_all(&to, converted.as_bytes()).await
+					}
+					Err(err) => Err(err),
+				},
+			};
+			if let Err(err) = result {
+				warn!(?err, source_type, "Selection transfer failed");
+			}
+		});
+	}
+}
+/// The read end and the write end
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+	let mut fds = [0; 2];
+	if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+fn set_nonblocking(fd: &OwnedFd) -> io::Result<()> {
+	let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+	if flags < 0
+		|| unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+	{
+		return Err(io::Error::last_os_error());
+	}
+	Ok(())
+}
+
+async fn copy(from: &AsyncFd<File>, to: &AsyncFd<File>) -> io::Result<()> {
+	let mut buf = vec![0; COPY_CHUNK_BYTES];
+	loop {
+		let read = read(from, &mut buf).await?;
+		if read == 0 {
+			return Ok(());
+		}
+		write_all(to, &buf[..read]).await?;
+	}
+}
+async fn read_to_end(from: &AsyncFd<File>, max_bytes: usize) -> io::Result<Vec<u8>> {
+	let mut data = Vec::new();
+	let mut buf = vec![0; COPY_CHUNK_BYTES];
+	loop {
+		let read = read(from, &mut buf).await?;
+		if read == 0 {
+			return Ok(data);
+		}
+		if data.len() + read > max_bytes {
+			return Err(io::Error::new(io::ErrorKind::Other, "Too much data"));
+		}
+		data.extend_from_slice(&buf[..read]);
+	}
+}
+async fn read(from: &AsyncFd<File>, buf: &mut [u8]) -> io::Result<usize> {
+	loop {
+		let mut guard = from.readable().await?;
+		if let Ok(result) = guard.try_io(|file| (&mut file.get_ref()).read(buf)) {
+			return result;
+		}
+	}
+}
+async fn write_all(to: &AsyncFd<File>, mut data: &[u8]) -> io::Result<()> {
+	while !data.is_empty() {
+		let mut guard = to.writable().await?;
+		if let Ok(result) = guard.try_io(|file| (&mut file.get_ref()).write(data)) {
+			data = &data[result?..];
+		}
+	}
+	Ok(())
+}
+
+/// Just the text of an HTML fragment, like the clipboard snippets browsers and GTK put there.
+/// Block elements end their line, whitespace collapses like it renders and entities get decoded.
+fn html_to_text(html: &str) -> String {
+	const BLOCK_TAGS: [&str; 12] = [
+		"br", "p", "div", "li", "tr", "h1", "h2", "h3", "h4", "h5", "h6", "pre",
+	];
+	/// Nothing in these is shown
+	const SKIPPED_TAGS: [&str; 4] = ["script", "style", "head", "title"];
+	let mut text = String::new();
+	let mut pending_space = false;
+	// once there's no `>` left, no `<` after it starts a tag
+	let mut tags_left = true;
+	let mut rest = html;
+	while let Some(c) = rest.chars().next() {
+		if c == '<' && tags_left {
+			if let Some(end) = rest.find('>') {
+				let tag = rest[1..end].trim();
+				rest = &rest[end + 1..];
+				let closing = tag.starts_with('/');
+				let name = tag
+					.trim_start_matches('/')
+					.split(|c: char| c.is_whitespace() || c == '/')
+					.next()
+					.unwrap_or_default()
+					.to_ascii_lowercase();
+				if !closing && SKIPPED_TAGS.contains(&name.as_str()) {
+					// what's in a script isn't markup, so only its end tag can end it
+					let end_tag = find_ignore_case(rest, &format!("</{name}"))
+						.and_then(|start| Some(start + rest[start..].find('>')? + 1));
+					rest = &rest[end_tag.unwrap_or(rest.len())..];
+				} else if BLOCK_TAGS.contains(&name.as_str()) && !text.is_empty() {
+					if !text.ends_with('\n') {
+						text.push('\n');
+					}
+					pending_space = false;
+				}
+				continue;
+			}
+			tags_left = false;
+		}
+		rest = &rest[c.len_utf8()..];
+		let c = if c == '&' {
+			let (decoded, len) = decode_entity(rest);
+			rest = &rest[len..];
+			decoded
+		} else {
+			c
+		};
+		if c.is_whitespace() && c != '\u{a0}' {
+			pending_space = true;
+			continue;
+		}
+		if pending_space && !text.is_empty() && !text.ends_with('\n') {
+			text.push(' ');
+		}
+		pending_space = false;
+		text.push(if c == '\u{a0}' { ' ' } else { c });
+	}
+	text.trim_end().to_string()
+}
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+	haystack
+		.as_bytes()
+		.windows(needle.len())
+		.position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+/// The character an entity after a `&` stands for and how long it is, a lone `&` stays one
+fn decode_entity(entity: &str) -> (char, usize) {
+	let Some(end) = entity.find(';').filter(|end| *end <= 10) else {
+		return ('&', 0);
+	};
+	let name = &entity[..end];
+	let decoded = match name {
+		"amp" => Some('&'),
+		"lt" => Some('<'),
+		"gt" => Some('>'),
+		"quot" => Some('"'),
+		"apos" => Some('\''),
+		"nbsp" => Some('\u{a0}'),
+		_ => name
+			.strip_prefix("#x")
+			.or_else(|| name.strip_prefix("#X"))
+			.and_then(|hex| u32::from_str_radix(hex, 16).ok())
+			.or_else(|| name.strip_prefix('#')?.parse().ok())
+			.and_then(char::from_u32),
+	};
+	match decoded {
+		Some(c) => (c, end + 1),
+		None => ('&', 0),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn html_fragments_become_their_text() {
+		// what GTK puts on the clipboard when copying rich text
+		let gtk = "<meta http-equiv=\"content-type\" content=\"text/html; charset=utf-8\">\
+			<b>Bold</b>   text<br>next\n line";
+		assert_eq!(html_to_text(gtk), "Bold text\nnext line");
+		// and what browsers do
+		let browser = "<html><body>\n<!--StartFragment--><p>One</p><P>Two</P>\
+			<!--EndFragment-->\n</body></html>";
+		assert_eq!(html_to_text(browser), "One\nTwo");
+	}
+
+	#[test]
+	fn entities_are_decoded() {
+		assert_eq!(
+			html_to_text("a &lt;b&gt; &amp; &#39;c&#x27; &quot;d&quot;&nbsp;e"),
+			"a <b> & 'c' \"d\" e"
+		);
+		// ones that aren't entities stay as they are
+		assert_eq!(html_to_text("&bogus; & &amp"), "&bogus; & &amp");
+		assert_eq!(decode_entity("amp;rest"), ('&', 4));
+		assert_eq!(decode_entity("#x1F600;"), ('\u{1F600}', 8));
+		assert_eq!(decode_entity("#128512;"), ('\u{1F600}', 8));
+		assert_eq!(decode_entity("#xD800;"), ('&', 0));
+		assert_eq!(decode_entity("waytoolongforanentity;"), ('&', 0));
+	}
+
+	#[test]
+	fn scripts_and_styles_are_skipped() {
+		let html = "<head><title>Title</title></head><style>p { color: red }</style>\
+			<p>Shown</p><SCRIPT>if (a < b) x()</SCRIPT>After";
+		assert_eq!(html_to_text(html), "Shown\nAfter");
+		// an unclosed one hides everything after it
+		assert_eq!(html_to_text("Before<script>never closed"), "Before");
+	}
+
+	#[test]
+	fn unterminated_tags_are_text() {
+		assert_eq!(html_to_text("1 < 2"), "1 < 2");
+		assert_eq!(html_to_text("<i>x</i> <"), "x <");
+	}
+
+	#[test]
+	fn plain_text_is_offered_for_html() {
+		let source_types = vec![HTML_TYPE.to_string()];
+		assert_eq!(
+			offered_mime_types(&source_types),
+			["text/html", "text/plain;charset=utf-8", "text/plain"]
+		);
+		// text the source has anyway isn't converted
+		let source_types = vec![HTML_TYPE.to_string(), "UTF8_STRING".to_string()];
+		let (from, convert) = source_type(&source_types, "text/plain".to_string()).unwrap();
+		assert_eq!(from, "UTF8_STRING");
+		assert!(convert.is_none());
+		// and nothing is made up for types that aren't plain text
+		assert!(source_type(&source_types, "image/png".to_string()).is_none());
+	}
+
+	/// Paste `mime_type` from a source with `source_types` that writes `data` for whatever it's
+	/// asked for, returning what it was asked for and what the target got
+	fn paste(source_types: &[&str], mime_type: &str, data: Vec<u8>) -> (Option<String>, Vec<u8>) {
+		let runtime = tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap();
+		runtime.block_on(async {
+			let (target_read, target_write) = pipe().unwrap();
+			let source_types = source_types
+				.iter()
+				.map(ToString::to_string)
+				.collect::<Vec<_>>();
+			let mut asked_for = None;
+			receive(
+				&source_types,
+				mime_type.to_string(),
+				target_write,
+				|mime_type, fd| {
+					asked_for = Some(mime_type);
+					let mut source = File::from(fd.try_clone_to_owned().unwrap());
+					// like a real client, which writes whenever it gets around to it
+					std::thread::spawn(move || source.write_all(&data).unwrap());
+				},
+			);
+			set_nonblocking(&target_read).unwrap();
+			let target = AsyncFd::new(File::from(target_read)).unwrap();
+			(asked_for, read_to_end(&target, usize::MAX).await.unwrap())
+		})
+	}
+
+	#[test]
+	fn html_is_pasted_as_plain_text() {
+		let (asked_for, pasted) = paste(
+			&[HTML_TYPE],
+			"text/plain;charset=utf-8",
+			b"<p>Hello&nbsp;<b>world</b></p>".to_vec(),
+		);
+		assert_eq!(asked_for.as_deref(), Some(HTML_TYPE));
+		assert_eq!(pasted, b"Hello world");
+	}
+
+	#[test]
+	fn text_round_trips_unchanged() {
+		// what a middle click paste from a terminal looks like
+		let text = "selected  text\twith ✓ and\r\n".as_bytes();
+		let (asked_for, pasted) =
+			paste(&["UTF8_STRING", "text/plain"], "UTF8_STRING", text.to_vec());
+		assert_eq!(asked_for.as_deref(), Some("UTF8_STRING"));
+		assert_eq!(pasted, text);
+	}
+
+	#[test]
+	fn big_pastes_do_not_hold_up_everything_else() {
+		use std::sync::atomic::{AtomicUsize, Ordering};
+
+		// way more than a pipe holds, so the source can only finish once we're copying
+		let image = (0..8 << 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+		let runtime = tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap();
+		runtime.block_on(async {
+			let (target_read, target_write) = pipe().unwrap();
+			let mut source = None;
+			let data = image.clone();
+			receive(
+				&["image/png".to_string()],
+				"image/png".to_string(),
+				target_write,
+				|_, fd| {
+					let mut fd = File::from(fd.try_clone_to_owned().unwrap());
+					source = Some(std::thread::spawn(move || fd.write_all(&data).unwrap()));
+				},
+			);
+			// receive came back right away, before the source got anywhere
+			let source = source.unwrap();
+			assert!(!source.is_finished());
+
+			// stands in for the rest of the loop, like another client getting dispatched
+			let ticks = Arc::new(AtomicUsize::new(0));
+			let other_client = tokio::task::spawn({
+				let ticks = ticks.clone();
+				async move {
+					loop {
+						ticks.fetch_add(1, Ordering::Relaxed);
+						tokio::task::yield_now().await;
+					}
+				}
+			});
+			set_nonblocking(&target_read).unwrap();
+			let target = AsyncFd::new(File::from(target_read)).unwrap();
+			let pasted = read_to_end(&target, usize::MAX).await.unwrap();
+			other_client.abort();
+
+			source.join().unwrap();
+			assert!(pasted == image);
+			assert!(ticks.load(Ordering::Relaxed) > 1);
+		});
+	}
+
+	/// Cache `mime_type` from a source that writes `data` and then exits, like `Selection` does
+	async fn cached(mime_type: &str, data: &[u8], max_bytes: usize) -> Option<Vec<u8>> {
+		let (done, cached) = tokio::sync::oneshot::channel();
+		let data = data.to_vec();
+		read_whole(
+			mime_type.to_string(),
+			max_bytes,
+			|_, fd| {
+				let mut source = File::from(fd.try_clone_to_owned().unwrap());
+				// the write end is closed once the thread is done, like when the client exits
+				std::thread::spawn(move || {
+					let _ = source.write_all(&data);
+				});
+			},
+			|data| done.send(data).unwrap(),
+		);
+		cached.await.unwrap()
+	}
+
+	#[test]
+	fn cached_text_is_pasted_after_the_source_exits() {
+		let runtime = tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap();
+		runtime.block_on(async {
+			let data = cached("text/html", b"<b>copied</b>", 1 << 20)
+				.await
+				.unwrap();
+			let mut cache = FxHashMap::default();
+			cache.insert("text/html".to_string(), Arc::from(data));
+
+			for (mime_type, expected) in [
+				("text/html", &b"<b>copied</b>"[..]),
+				("text/plain", b"copied"),
+			] {
+				let (target_read, target_write) = pipe().unwrap();
+				send_cached(&cache, mime_type.to_string(), target_write);
+				set_nonblocking(&target_read).unwrap();
+				let target = AsyncFd::new(File::from(target_read)).unwrap();
+				assert_eq!(read_to_end(&target, usize::MAX).await.unwrap(), expected);
+			}
+		});
+	}
+
+	#[test]
+	fn selections_over_the_cap_are_not_cached() {
+		let runtime = tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap();
+		runtime.block_on(async {
+			let small = cached("text/plain", b"small", 5).await;
+			assert_eq!(small.as_deref(), Some(&b"small"[..]));
+			assert_eq!(cached("text/plain", b"too big", 5).await, None);
+		});
+	}
+}
@@ -0,0 +1,116 @@
+use super::xdg_shell::reject_unconfigured_buffer;
+use parking_lot::Mutex;
+use smithay::{
+	backend::renderer::buffer_dimensions,
+	reexports::wayland_server::{
+		protocol::wl_surface::{self, WlSurface},
+		Resource,
+	},
+	utils::{Buffer, Size},
+	wayland::{
+		compositor::{self, BufferAssignment, SurfaceAttributes},
+		viewporter,
+	},
+};
+use tracing::warn;
+
+/// `wl_surface` only made this an error in version 6, older clients get it wrong all the time
+const BUFFER_SCALE_SIZE_SINCE: u32 = 6;
+
+/// Post the protocol error for a commit that can't be shown as it is, which disconnects just
+/// the client that sent it. Returns if it did, the commit shouldn't be processed then.
+pub fn reject_invalid_commit(surface: &WlSurface) -> bool {
+	reject_unscalable_buffer(surface)
+		|| reject_out_of_buffer_viewport(surface)
+		|| reject_unconfigured_buffer(surface)
+}
+
+/// The buffer has to be a whole number of surface pixels at its scale
+fn reject_unscalable_buffer(surface: &WlSurface) -> bool {
+	let (size, scale) = compositor::with_states(surface, |data| {
+		let attributes = data.cached_state.current::<SurfaceAttributes>();
+		let size = match &attributes.buffer {
+			Some(BufferAssignment::NewBuffer(buffer)) => buffer_dimensions(buffer),
+			_ => None,
+		};
+		(size, attributes.buffer_scale)
+	});
+	let Some(message) = size.and_then(|size| unscalable(surface.version(), size, scale)) else {
+		return false;
+	};
+	warn!(
+		?surface,
+		?size,
+		scale,
+		"Client committed a buffer that doesn't fit its scale"
+	);
+	surface.post_error(wl_surface::Error::InvalidSize, message);
+	true
+}
+/// The `invalid_size` error for a buffer of `size` at `scale` on a `wl_surface` of `version`
+fn unscalable(version: u32, size: Size<i32, Buffer>, scale: i32) -> Option<String> {
+	if version < BUFFER_SCALE_SIZE_SINCE
+		|| scale <= 1
+		|| (size.w % scale == 0 && size.h % scale == 0)
+	{
+		return None;
+	}
+	Some(format!(
+		"Buffer is {}x{}, which isn't a multiple of its scale {scale}",
+		size.w, size.h
+	))
+}
+
+/// Size of the surface's buffer, kept for commits that change the viewport without attaching one
+#[derive(Default)]
+struct BufferSize(Mutex<Option<Size<i32, Buffer>>>);
+
+/// `wp_viewport`'s source rectangle has to be inside the buffer the commit applied
+fn reject_out_of_buffer_viewport(surface: &WlSurface) -> bool {
+	let valid = compositor::with_states(surface, |data| {
+		data.data_map
+			.insert_if_missing_threadsafe(BufferSize::default);
+		let mut buffer_size = data.data_map.get::<BufferSize>().unwrap().0.lock();
+		let attributes = data.cached_state.current::<SurfaceAttributes>();
+		match &attributes.buffer {
+			Some(BufferAssignment::NewBuffer(buffer)) => *buffer_size = buffer_dimensions(buffer),
+			Some(BufferAssignment::Removed) => *buffer_size = None,
+			None => (),
+		}
+		let Some(buffer_size) = *buffer_size else {
+			return true;
+		};
+		let buffer_size =
+			buffer_size.to_logical(attributes.buffer_scale, attributes.buffer_transform.into());
+		drop(attributes);
+		// posts `out_of_buffer` itself
+		viewporter::ensure_viewport_valid(data, buffer_size)
+	});
+	if !valid {
+		warn!(
+			?surface,
+			"Client committed a viewport outside of its buffer"
+		);
+	}
+	!valid
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn buffers_have_to_fit_their_scale() {
+		// an odd sized buffer at scale 2, the classic toolkit mistake
+		let odd = Size::from((1001, 600));
+		assert_eq!(
+			unscalable(6, odd, 2).as_deref(),
+			Some("Buffer is 1001x600, which isn't a multiple of its scale 2")
+		);
+		assert!(unscalable(6, odd, 1).is_none());
+		assert!(unscalable(6, Size::from((1000, 600)), 2).is_none());
+		assert!(unscalable(6, Size::from((1000, 601)), 2).is_some());
+		// older clients get away with it
+		assert!(unscalable(5, odd, 2).is_none());
+	}
+}
@@ -1,34 +1,125 @@
+use super::{
+	focus_policy::{self, FocusRequest},
+	seat::SeatData,
+	state::WaylandState,
+	utils,
+	xdg_shell::XdgBackend,
+};
+use crate::nodes::items::panel::{Backend, PanelItem, PanelItemTrait};
 use smithay::{
 	delegate_xdg_activation,
-	reexports::wayland_server::protocol::wl_surface::WlSurface,
-	wayland::xdg_activation::{XdgActivationHandler, XdgActivationToken, XdgActivationTokenData},
+	reexports::wayland_server::{protocol::wl_surface::WlSurface, Resource},
+	wayland::xdg_activation::{
+		XdgActivationHandler, XdgActivationState, XdgActivationToken, XdgActivationTokenData,
+	},
+};
+use std::{
+	sync::{Arc, Weak},
+	time::{Duration, Instant},
 };
+use tracing::{debug, warn};
 
-use super::state::WaylandState;
+/// How long a token can be used to activate a surface after it was created
+const TOKEN_LIFETIME: Duration = Duration::from_secs(10);
+
+/// Tokens are single use, so this removes it. Returns if it was still there and not expired.
+fn consume_token(state: &mut XdgActivationState, token: &XdgActivationToken, now: Instant) -> bool {
+	let Some(data) = state.data_for_token(token) else {
+		return false;
+	};
+	let expired = now.saturating_duration_since(data.timestamp) >= TOKEN_LIFETIME;
+	state.remove_token(token);
+	!expired
+}
 
 impl XdgActivationHandler for WaylandState {
-	fn activation_state(&mut self) -> &mut smithay::wayland::xdg_activation::XdgActivationState {
+	fn activation_state(&mut self) -> &mut XdgActivationState {
 		&mut self.xdg_activation_state
 	}
 
-	fn request_activation(
-		&mut self,
-		token: XdgActivationToken,
-		token_data: XdgActivationTokenData,
-		_surface: WlSurface,
-	) {
-		dbg!(token);
-		dbg!(token_data);
+	fn token_created(&mut self, token: XdgActivationToken, data: XdgActivationTokenData) -> bool {
+		self.xdg_activation_state
+			.retain_tokens(|_, data| data.timestamp.elapsed() < TOKEN_LIFETIME);
+
+		// tokens have to come from a surface of the requesting client that just got input
+		let Some(client_id) = data.client_id.clone() else {
+			return false;
+		};
+		let Some(surface) = data.surface.as_ref() else {
+			warn!(?token, "Activation token requested without a surface");
+			return false;
+		};
+		if surface.client().map(|c| c.id()) != Some(client_id.clone()) {
+			return false;
+		}
+		let Some((serial, seat)) = data.serial.as_ref() else {
+			warn!(?token, "Activation token requested without a serial");
+			return false;
+		};
+		let Some(seat_data) = seat.data::<Arc<SeatData>>() else {
+			return false;
+		};
+		// any event the client got while it still has that focus, not just presses,
+		// as toolkits use the serial of whatever input event they last handled
+		let valid = seat_data.client.get() == Some(&client_id)
+			&& seat_data.serials.event(u32::from(*serial), None).is_some()
+			&& seat_data.has_input_focus();
+		debug!(?token, valid, "Create activation token");
+		valid
 	}
 
-	fn destroy_activation(
+	fn request_activation(
 		&mut self,
 		token: XdgActivationToken,
-		token_data: XdgActivationTokenData,
-		_surface: WlSurface,
+		_token_data: XdgActivationTokenData,
+		surface: WlSurface,
 	) {
-		dbg!(token);
-		dbg!(token_data);
+		if !consume_token(&mut self.xdg_activation_state, &token, Instant::now()) {
+			warn!(?token, "Activation token expired or already used");
+			return;
+		}
+
+		let Some(panel_item) = utils::get_data::<Weak<PanelItem<XdgBackend>>>(&surface)
+			.as_deref()
+			.and_then(Weak::upgrade)
+		else {
+			return;
+		};
+		if !focus_policy::allow_request(FocusRequest::Activation) {
+			panel_item.set_toplevel_attention(true);
+			return;
+		}
+		debug!(?token, ?surface, "Activate surface");
+		panel_item.backend.set_toplevel_focused_visuals(true);
+		panel_item.raise_toplevel();
+		panel_item.toplevel_activation_request();
 	}
 }
 delegate_xdg_activation!(WaylandState);
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use smithay::reexports::wayland_server::Display;
+
+	#[test]
+	fn tokens_activate_once() {
+		let display = Display::<WaylandState>::new().unwrap();
+		let mut state = XdgActivationState::new::<WaylandState>(&display.handle());
+		let token = state.create_external_token(None).0.clone();
+		let now = Instant::now();
+		assert!(consume_token(&mut state, &token, now));
+		assert!(!consume_token(&mut state, &token, now));
+		assert!(state.data_for_token(&token).is_none());
+	}
+
+	#[test]
+	fn expired_tokens_are_rejected_and_removed() {
+		let display = Display::<WaylandState>::new().unwrap();
+		let mut state = XdgActivationState::new::<WaylandState>(&display.handle());
+		let token = state.create_external_token(None).0.clone();
+		let later = Instant::now() + TOKEN_LIFETIME;
+		assert!(!consume_token(&mut state, &token, later));
+		assert!(state.data_for_token(&token).is_none());
+	}
+}
@@ -0,0 +1,278 @@
+use super::{state::WaylandState, utils::get_data, xdg_shell::ToplevelData};
+use nanoid::nanoid;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use smithay::reexports::{
+	wayland_protocols::xdg::foreign::zv2::server::{
+		zxdg_exported_v2::{self, ZxdgExportedV2},
+		zxdg_exporter_v2::{self, ZxdgExporterV2},
+		zxdg_imported_v2::{self, ZxdgImportedV2},
+		zxdg_importer_v2::{self, ZxdgImporterV2},
+	},
+	wayland_server::{
+		backend::ClientId, protocol::wl_surface::WlSurface, Client, DataInit, Dispatch,
+		DisplayHandle, GlobalDispatch, New, Resource, Weak as WlWeak,
+	},
+};
+use tracing::debug;
+
+/// An exported toplevel and everything that imported it
+struct Export {
+	surface: WlWeak<WlSurface>,
+	imports: Vec<ZxdgImportedV2>,
+}
+
+/// Handles are only valid while their `zxdg_exported_v2` and toplevel both exist, so a client
+/// can't keep parenting to a window that's long gone or belongs to whoever reused the handle.
+static EXPORTS: Lazy<Mutex<FxHashMap<String, Export>>> =
+	Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+pub struct ExportedData {
+	handle: String,
+}
+pub struct ImportedData {
+	handle: String,
+	/// `None` if the handle wasn't valid when it got imported
+	surface: Option<WlWeak<WlSurface>>,
+	/// Toplevels parented to the imported one through this, unparented when it goes away
+	children: Mutex<Vec<WlWeak<WlSurface>>>,
+}
+impl ImportedData {
+	fn unparent_children(&self) {
+		let Some(surface) = &self.surface else {
+			return;
+		};
+		for child in self.children.lock().drain(..) {
+			let Ok(child) = child.upgrade() else {
+				continue;
+			};
+			let Some(toplevel_data) = get_data::<ToplevelData>(&child) else {
+				continue;
+			};
+			if toplevel_data
+				.parent()
+				.is_some_and(|parent| parent.id() == surface.id())
+			{
+				toplevel_data.set_parent(None);
+			}
+		}
+	}
+}
+
+/// Tell every importer of the handle it's gone and drop the parent relationships made with it
+fn revoke_handle(handle: &str) {
+	let Some(export) = EXPORTS.lock().remove(handle) else {
+		return;
+	};
+	debug!(
+		handle,
+		imports = export.imports.len(),
+		"Revoke exported toplevel"
+	);
+	for imported in export.imports {
+		if let Some(data) = imported.data::<ImportedData>() {
+			data.unparent_children();
+		}
+		imported.destroyed();
+	}
+}
+/// The toplevel role of `surface` got destroyed, so any handle for it is too
+pub fn revoke(surface: &WlSurface) {
+	let handles = EXPORTS
+		.lock()
+		.iter()
+		.filter(|(_, export)| export.surface.id() == surface.id())
+		.map(|(handle, _)| handle.clone())
+		.collect::<Vec<_>>();
+	for handle in handles {
+		revoke_handle(&handle);
+	}
+}
+
+impl GlobalDispatch<ZxdgExporterV2, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZxdgExporterV2>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ZxdgExporterV2, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		exporter: &ZxdgExporterV2,
+		request: zxdg_exporter_v2::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zxdg_exporter_v2::Request::ExportToplevel { id, surface } => {
+				if get_data::<ToplevelData>(&surface).is_none() {
+					exporter.post_error(
+						zxdg_exporter_v2::Error::InvalidSurface,
+						"Only xdg toplevels can be exported",
+					);
+					return;
+				}
+				let handle = nanoid!();
+				debug!(?surface, handle, "Export toplevel");
+				EXPORTS.lock().insert(
+					handle.clone(),
+					Export {
+						surface: surface.downgrade(),
+						imports: Vec::new(),
+					},
+				);
+				let exported = data_init.init(
+					id,
+					ExportedData {
+						handle: handle.clone(),
+					},
+				);
+				exported.handle(handle);
+			}
+			zxdg_exporter_v2::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ZxdgExportedV2, ExportedData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZxdgExportedV2,
+		request: zxdg_exported_v2::Request,
+		_data: &ExportedData,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zxdg_exported_v2::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+
+	/// Also happens when the exporting client disconnects
+	fn destroyed(
+		_state: &mut WaylandState,
+		_client: ClientId,
+		_resource: &ZxdgExportedV2,
+		data: &ExportedData,
+	) {
+		revoke_handle(&data.handle);
+	}
+}
+
+impl GlobalDispatch<ZxdgImporterV2, (), WaylandState> for WaylandState {
+	fn bind(
+		_state: &mut WaylandState,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZxdgImporterV2>,
+		_global_data: &(),
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		data_init.init(resource, ());
+	}
+}
+
+impl Dispatch<ZxdgImporterV2, (), WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		_resource: &ZxdgImporterV2,
+		request: zxdg_importer_v2::Request,
+		_data: &(),
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zxdg_importer_v2::Request::ImportToplevel { id, handle } => {
+				let mut exports = EXPORTS.lock();
+				let export = exports
+					.get_mut(&handle)
+					.filter(|export| export.surface.upgrade().is_ok());
+				let imported = data_init.init(
+					id,
+					ImportedData {
+						handle: handle.clone(),
+						surface: export.as_ref().map(|export| export.surface.clone()),
+						children: Mutex::new(Vec::new()),
+					},
+				);
+				debug!(handle, valid = export.is_some(), "Import toplevel");
+				match export {
+					Some(export) => export.imports.push(imported),
+					// an invalid handle still gets an object, it's just destroyed right away
+					None => imported.destroyed(),
+				}
+			}
+			zxdg_importer_v2::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+}
+
+impl Dispatch<ZxdgImportedV2, ImportedData, WaylandState> for WaylandState {
+	fn request(
+		_state: &mut WaylandState,
+		_client: &Client,
+		imported: &ZxdgImportedV2,
+		request: zxdg_imported_v2::Request,
+		data: &ImportedData,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, WaylandState>,
+	) {
+		match request {
+			zxdg_imported_v2::Request::SetParentOf { surface } => {
+				let Some(toplevel_data) = get_data::<ToplevelData>(&surface) else {
+					imported.post_error(
+						zxdg_imported_v2::Error::InvalidSurface,
+						"Only xdg toplevels can have a parent",
+					);
+					return;
+				};
+				// revoked, the client just hasn't seen `destroyed` yet
+				if !EXPORTS.lock().contains_key(&data.handle) {
+					return;
+				}
+				let Some(Ok(parent)) = data.surface.as_ref().map(WlWeak::upgrade) else {
+					return;
+				};
+				debug!(
+					handle = data.handle,
+					?surface,
+					"Set imported toplevel as parent"
+				);
+				toplevel_data.set_parent(Some(&parent));
+				let mut children = data.children.lock();
+				children.retain(|child| child.upgrade().is_ok() && child.id() != surface.id());
+				children.push(surface.downgrade());
+			}
+			zxdg_imported_v2::Request::Destroy => (),
+			_ => unreachable!(),
+		}
+	}
+
+	/// Also happens when the importing client disconnects
+	fn destroyed(
+		_state: &mut WaylandState,
+		_client: ClientId,
+		resource: &ZxdgImportedV2,
+		data: &ImportedData,
+	) {
+		data.unparent_children();
+		if let Some(export) = EXPORTS.lock().get_mut(&data.handle) {
+			export.imports.retain(|imported| imported != resource);
+		}
+	}
+}
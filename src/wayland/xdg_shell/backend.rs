@@ -3,41 +3,191 @@ use crate::{
 	nodes::{
 		data::KEYMAPS,
 		drawable::model::ModelPart,
-		items::panel::{Backend, ChildInfo, PanelItem, PanelItemInitData, SurfaceID},
+		items::panel::{
+			Backend, ChildInfo, ClientInfo, ColorSpace, DecorationTheme, Geometry, KeyboardState,
+			PanelGeometry, PanelItem, PanelItemInitData, PenState, PenTool, SurfaceFiltering,
+			SurfaceID, SurfaceSnapshot, TextInputUpdate, ToplevelInfo, ToplevelStates,
+		},
+		spatial::Spatial,
+		Node,
 	},
 	wayland::{
+		self,
+		decoration::DECORATION_THEME,
 		seat::{CursorInfo, KeyboardEvent, PointerEvent, SeatData},
 		state::ClientState,
-		surface::CoreSurface,
-		utils, SERIAL_COUNTER,
+		surface::{CoreSurface, SurfaceExport},
+		utils,
 	},
+	SK_MULTITHREAD,
 };
 use color_eyre::eyre::{eyre, Result};
+use glam::{vec3, Mat4, Quat, Vec3};
 use mint::Vector2;
 use parking_lot::Mutex;
 use rustc_hash::FxHashMap;
-use smithay::reexports::{
-	wayland_protocols::xdg::shell::server::xdg_toplevel::XdgToplevel,
-	wayland_server::{protocol::wl_surface::WlSurface, Resource, Weak},
+use smithay::{
+	output::Output,
+	reexports::{
+		wayland_protocols::xdg::shell::server::xdg_toplevel::XdgToplevel,
+		wayland_server::{protocol::wl_surface::WlSurface, Resource, Weak},
+	},
 };
-use std::sync::Arc;
+use std::sync::{Arc, Weak as ArcWeak};
+use stereokit::StereoKitMultiThread;
 use tokio::sync::watch;
 use tracing::debug;
 
-pub struct XdgToplevelState {
-	pub fullscreen: bool,
-	pub activated: bool,
+/// Surface pixels per meter until the toplevel is shown on a model part it can be measured from
+const DEFAULT_PIXELS_PER_METER: f32 = 3000.0;
+/// Meters in front of the user fullscreen toplevels get put
+const FULLSCREEN_DISTANCE: f32 = 1.5;
+/// Degrees of the user's view fullscreen toplevels fill horizontally
+const FULLSCREEN_FOV: f32 = 70.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ResizeEdges {
+	pub up: bool,
+	pub down: bool,
+	pub left: bool,
+	pub right: bool,
+}
+
+/// A move or resize started by the client through `xdg_toplevel.move`/`xdg_toplevel.resize`
+#[derive(Debug)]
+enum InteractiveGrab {
+	Move {
+		anchor: Option<Vector2<f32>>,
+	},
+	Resize {
+		edges: ResizeEdges,
+		start: Option<Vector2<f32>>,
+		start_size: Vector2<u32>,
+		size: Vector2<u32>,
+	},
+}
+
+/// Where a fullscreen toplevel was before, to put it back when it leaves fullscreen
+#[derive(Debug, Clone, Copy)]
+struct FullscreenRestore {
+	transform: Option<Mat4>,
+	size: Vector2<u32>,
+}
+
+/// The output's size in surface coordinates
+fn output_size(output: &Output) -> Option<Vector2<u32>> {
+	let mode = output.current_mode()?;
+	let scale = output.current_scale().integer_scale().max(1);
+	Some([(mode.size.w / scale) as u32, (mode.size.h / scale) as u32].into())
+}
+/// Upright in front of the user and facing them, scaled up from `pixels_per_meter` so `size`
+/// fills `FULLSCREEN_FOV` of their view
+fn fullscreen_transform(size: Vector2<u32>, pixels_per_meter: f32) -> Option<Mat4> {
+	let head = SK_MULTITHREAD.get()?.input_head();
+	let head_position: Vec3 = head.position.into();
+	let head_orientation: Quat = head.orientation.into();
+	let forward = head_orientation * Vec3::NEG_Z;
+	let forward = vec3(forward.x, 0.0, forward.z).try_normalize()?;
+	let width = 2.0 * FULLSCREEN_DISTANCE * (FULLSCREEN_FOV.to_radians() * 0.5).tan();
+	let scale = width / (size.x.max(1) as f32 / pixels_per_meter);
+	Some(Mat4::from_scale_rotation_translation(
+		Vec3::splat(scale),
+		Quat::from_rotation_y((-forward.x).atan2(-forward.z)),
+		head_position + forward * FULLSCREEN_DISTANCE,
+	))
+}
+
+/// Surface pixels per meter of the panel item's space, measured from the widest model part the
+/// shell shows the toplevel on so whatever's drawn on the panel matches the size it made it.
+pub fn pixels_per_meter(toplevel: &WlSurface, spatial: &Spatial) -> f32 {
+	measured_pixels_per_meter(toplevel, spatial)
+		.filter(|pixels_per_meter| pixels_per_meter.is_finite() && *pixels_per_meter > 0.0)
+		.unwrap_or(DEFAULT_PIXELS_PER_METER)
+}
+fn measured_pixels_per_meter(toplevel: &WlSurface, spatial: &Spatial) -> Option<f32> {
+	let core_surface = CoreSurface::from_wl_surface(toplevel)?;
+	let pixels = core_surface.size()?.x as f32;
+	// the model parts are measured in world space, the panel item itself may be scaled
+	let scale = spatial
+		.global_transform()
+		.transform_vector3(Vec3::X)
+		.length();
+	Some(pixels * scale / core_surface.shown_width()?)
+}
+
+/// Surface pixels (from the top left, y down) to world space, for a toplevel with its window
+/// geometry centered on its panel item at `pixels_per_meter`, so shadows and other margins
+/// outside it don't push the window off center. It follows the panel item's whole transform, so
+/// everything the server draws on or hit tests against a panel goes through this to stay lined
+/// up with the panel however the shell rotates or scales it.
+pub fn panel_transform(toplevel: &WlSurface, spatial: &Spatial, window: Geometry) -> Mat4 {
+	spatial.global_transform() * surface_to_panel(window, pixels_per_meter(toplevel, spatial))
+}
+/// Surface pixels to the panel item's own space
+fn surface_to_panel(window: Geometry, pixels_per_meter: f32) -> Mat4 {
+	let center = vec3(
+		window.origin.x as f32 + window.size.x as f32 * 0.5,
+		window.origin.y as f32 + window.size.y as f32 * 0.5,
+		0.0,
+	);
+	Mat4::from_scale(vec3(1.0 / pixels_per_meter, -1.0 / pixels_per_meter, 1.0))
+		* Mat4::from_translation(-center)
+}
+/// Where a world space ray hits the plane of the panel with `panel_transform`, in surface
+/// pixels. It can be outside the surface, `None` if the panel's behind or side on to the ray.
+pub fn ray_surface_position(
+	panel_transform: Mat4,
+	origin: Vec3,
+	direction: Vec3,
+) -> Option<Vector2<f32>> {
+	let inverse = panel_transform.inverse();
+	let origin = inverse.transform_point3(origin);
+	let direction = inverse.transform_vector3(direction);
+	if direction.z.abs() <= f32::EPSILON {
+		return None;
+	}
+	let distance = -origin.z / direction.z;
+	if distance < 0.0 {
+		return None;
+	}
+	let point = origin + (direction * distance);
+	Some([point.x, point.y].into())
+}
+
+/// The `xdg_toplevel.state`s a configure carries
+fn configure_states(toplevel_state: &ToplevelStates) -> Vec<u32> {
+	let mut states = vec![5, 6, 7, 8]; // tiled on all edges
+	if toplevel_state.maximized {
+		states.push(1);
+	}
+	if toplevel_state.fullscreen {
+		states.push(2);
+	}
+	if toplevel_state.resizing {
+		states.push(3);
+	}
+	// there's nothing of a minimized toplevel to interact with
+	if toplevel_state.activated && !toplevel_state.minimized {
+		states.push(4);
+	}
+	states
 }
 
 pub struct XdgBackend {
 	toplevel: Weak<XdgToplevel>,
 	toplevel_wl_surface: Weak<WlSurface>,
-	pub toplevel_state: Mutex<XdgToplevelState>,
+	toplevel_state: Mutex<ToplevelStates>,
 	popups: Mutex<FxHashMap<String, Weak<WlSurface>>>,
 	pub cursor: watch::Receiver<Option<CursorInfo>>,
 	pub seat: Arc<SeatData>,
 	pointer_grab: Mutex<Option<SurfaceID>>,
 	keyboard_grab: Mutex<Option<SurfaceID>>,
+	interactive_grab: Mutex<Option<InteractiveGrab>>,
+	alpha: Mutex<f32>,
+	stack_position: Mutex<u32>,
+	fullscreen_restore: Mutex<Option<FullscreenRestore>>,
+	/// Overrides `DECORATION_THEME` for just this toplevel
+	decoration_theme: Mutex<Option<Arc<DecorationTheme>>>,
 }
 impl XdgBackend {
 	pub fn create(
@@ -49,20 +199,33 @@ impl XdgBackend {
 		XdgBackend {
 			toplevel: toplevel.downgrade(),
 			toplevel_wl_surface: toplevel_wl_surface.downgrade(),
-			toplevel_state: Mutex::new(XdgToplevelState {
-				fullscreen: false,
-				activated: false,
+			toplevel_state: Mutex::new(ToplevelStates {
+				// panels have no surrounding desktop, so toplevels start maximized
+				maximized: true,
+				..Default::default()
 			}),
 			popups: Mutex::new(FxHashMap::default()),
 			cursor,
 			seat,
 			pointer_grab: Mutex::new(None),
 			keyboard_grab: Mutex::new(None),
+			interactive_grab: Mutex::new(None),
+			alpha: Mutex::new(1.0),
+			stack_position: Mutex::new(0),
+			fullscreen_restore: Mutex::new(None),
+			decoration_theme: Mutex::new(None),
 		}
 	}
+	/// What the toplevel's server-side decorations look like
+	pub fn decoration_theme(&self) -> Arc<DecorationTheme> {
+		self.decoration_theme
+			.lock()
+			.clone()
+			.unwrap_or_else(|| DECORATION_THEME.lock().clone())
+	}
 	fn wl_surface_from_id(&self, id: &SurfaceID) -> Option<WlSurface> {
 		match id {
-			SurfaceID::Cursor => self.cursor.borrow().as_ref()?.surface.upgrade().ok(),
+			SurfaceID::Cursor => self.cursor.borrow().as_ref()?.surface(),
 			SurfaceID::Toplevel => self.toplevel_wl_surface(),
 			SurfaceID::Child(popup) => {
 				let popups = self.popups.lock();
@@ -84,34 +247,238 @@ impl XdgBackend {
 		let Some(xdg_surface_data) = wl_surface.data::<XdgSurfaceData>() else {
 			return;
 		};
-		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
-			return;
-		};
-		let Some(surface_size) = core_surface.size() else {
+		// configure sizes are of the window geometry, not the surface with its shadows
+		let Some(window_size) = xdg_surface_data.window_geometry().map(|g| g.size) else {
 			return;
 		};
 
+		let size = size.unwrap_or(window_size);
 		xdg_toplevel.configure(
-			size.unwrap_or(surface_size).x as i32,
-			size.unwrap_or(surface_size).y as i32,
-			self.states()
+			size.x as i32,
+			size.y as i32,
+			configure_states(&self.toplevel_state.lock())
 				.into_iter()
 				.flat_map(|state| state.to_ne_bytes())
 				.collect(),
 		);
-		xdg_surface_data.xdg_surface.configure(SERIAL_COUNTER.inc());
+		xdg_surface_data.configure(Some(size));
 		self.flush_client();
 	}
-	fn states(&self) -> Vec<u32> {
-		let mut states = vec![1, 5, 6, 7, 8]; // maximized always and tiled
-		let toplevel_state = self.toplevel_state.lock();
-		if toplevel_state.fullscreen {
-			states.push(2);
+	pub fn toplevel_states(&self) -> ToplevelStates {
+		*self.toplevel_state.lock()
+	}
+	/// Change the toplevel's states, echoing them to the client and panel item if anything changed
+	pub fn update_toplevel_states(&self, update: impl FnOnce(&mut ToplevelStates)) {
+		let states = {
+			let mut toplevel_state = self.toplevel_state.lock();
+			let old_states = *toplevel_state;
+			update(&mut toplevel_state);
+			if *toplevel_state == old_states {
+				return;
+			}
+			*toplevel_state
+		};
+		self.configure(None);
+		if let Some(panel_item) = self.panel_item() {
+			panel_item.toplevel_states_changed(states);
+		}
+	}
+	/// Configure the toplevel to `output`'s size as fullscreen and activated, and put its panel
+	/// item big in front of the user until `unset_fullscreen`.
+	pub fn set_fullscreen(&self, output: &Output) {
+		let Some(size) = output_size(output) else {
+			return;
+		};
+		let spatial = self.spatial();
+		let mut fullscreen_restore = self.fullscreen_restore.lock();
+		if fullscreen_restore.is_none() {
+			let Some(restore_size) = self.toplevel_size() else {
+				return;
+			};
+			*fullscreen_restore = Some(FullscreenRestore {
+				transform: spatial.as_ref().map(|spatial| spatial.local_transform()),
+				size: restore_size,
+			});
+		}
+		drop(fullscreen_restore);
+		debug!(output = output.name(), ?size, "Fullscreen toplevel");
+
+		let pixels_per_meter = spatial
+			.as_ref()
+			.zip(self.toplevel_wl_surface())
+			.map_or(DEFAULT_PIXELS_PER_METER, |(spatial, wl_surface)| {
+				pixels_per_meter(&wl_surface, spatial)
+			});
+		let transform = fullscreen_transform(size, pixels_per_meter);
+		if let (Some(spatial), Some(transform)) = (spatial, transform) {
+			let parent_transform = spatial.global_transform() * spatial.local_transform().inverse();
+			spatial.set_local_transform(parent_transform.inverse() * transform);
+		}
+		let states = {
+			let mut toplevel_state = self.toplevel_state.lock();
+			toplevel_state.fullscreen = true;
+			toplevel_state.activated = true;
+			*toplevel_state
+		};
+		self.configure(Some(size));
+		if let Some(panel_item) = self.panel_item() {
+			panel_item.toplevel_states_changed(states);
+			panel_item.toplevel_fullscreen_active(true);
+		}
+	}
+	/// Put the toplevel back how it was before `set_fullscreen`
+	pub fn unset_fullscreen(&self) {
+		let Some(restore) = self.fullscreen_restore.lock().take() else {
+			return;
+		};
+		if let (Some(spatial), Some(transform)) = (self.spatial(), restore.transform) {
+			spatial.set_local_transform(transform);
+		}
+		let states = {
+			let mut toplevel_state = self.toplevel_state.lock();
+			toplevel_state.fullscreen = false;
+			*toplevel_state
+		};
+		self.configure(Some(restore.size));
+		if let Some(panel_item) = self.panel_item() {
+			panel_item.toplevel_states_changed(states);
+			panel_item.toplevel_fullscreen_active(false);
+		}
+	}
+	fn panel_item(&self) -> Option<Arc<PanelItem<XdgBackend>>> {
+		let wl_surface = self.toplevel_wl_surface()?;
+		utils::get_data::<ArcWeak<PanelItem<XdgBackend>>>(&wl_surface)?.upgrade()
+	}
+	fn spatial(&self) -> Option<Arc<Spatial>> {
+		let wl_surface = self.toplevel_wl_surface()?;
+		utils::get_data::<Node>(&wl_surface)?
+			.get_aspect::<Spatial>()
+			.ok()
+	}
+	/// The size of the window geometry, what configures and size hints are in
+	fn toplevel_size(&self) -> Option<Vector2<u32>> {
+		let wl_surface = self.toplevel_wl_surface()?;
+		Some(wl_surface.data::<XdgSurfaceData>()?.window_geometry()?.size)
+	}
+	fn clamp_size(&self, size: Vector2<u32>) -> Vector2<u32> {
+		let toplevel_data = self
+			.toplevel_wl_surface()
+			.as_ref()
+			.and_then(utils::get_data::<ToplevelData>);
+		let Some(toplevel_data) = toplevel_data else {
+			return size;
+		};
+		// a 0 in either dimension of a size hint means that dimension is unconstrained
+		let mut size = size;
+		if let Some(min_size) = toplevel_data.min_size() {
+			size.x = size.x.max(min_size.x);
+			size.y = size.y.max(min_size.y);
+		}
+		if let Some(max_size) = toplevel_data.max_size() {
+			if max_size.x > 0 {
+				size.x = size.x.min(max_size.x);
+			}
+			if max_size.y > 0 {
+				size.y = size.y.min(max_size.y);
+			}
+		}
+		size
+	}
+
+	pub fn start_move(&self) {
+		self.interactive_grab
+			.lock()
+			.replace(InteractiveGrab::Move { anchor: None });
+	}
+	pub fn start_resize(&self, edges: ResizeEdges) {
+		let Some(size) = self.toplevel_size() else {
+			return;
+		};
+		self.interactive_grab
+			.lock()
+			.replace(InteractiveGrab::Resize {
+				edges,
+				start: None,
+				start_size: size,
+				size,
+			});
+		self.update_toplevel_states(|states| states.resizing = true);
+	}
+	/// Drop the grab without any final configure, e.g. when the toplevel is destroyed mid-grab
+	pub fn cancel_interactive_grab(&self) {
+		self.interactive_grab.lock().take();
+		self.toplevel_state.lock().resizing = false;
+	}
+	fn end_interactive_grab(&self) {
+		let Some(grab) = self.interactive_grab.lock().take() else {
+			return;
+		};
+		if let InteractiveGrab::Resize { size, .. } = grab {
+			self.toplevel_state.lock().resizing = false;
+			self.configure(Some(size));
+			if let Some(panel_item) = self.panel_item() {
+				panel_item.toplevel_states_changed(self.toplevel_states());
+			}
+		}
+	}
+	/// Returns true if the motion was consumed by an interactive grab
+	fn interactive_grab_motion(&self, surface: &SurfaceID, position: Vector2<f32>) -> bool {
+		let mut interactive_grab = self.interactive_grab.lock();
+		let Some(grab) = interactive_grab.as_mut() else {
+			return false;
+		};
+		// positions on popups are in a different coordinate space, so just swallow them
+		if !matches!(surface, SurfaceID::Toplevel) {
+			return true;
 		}
-		if toplevel_state.activated {
-			states.push(4);
+		match grab {
+			InteractiveGrab::Move { anchor } => {
+				// the surface moves along with the pointer, so keep the grabbed point under it
+				let anchor = *anchor.get_or_insert(position);
+				let (Some(spatial), Some(wl_surface)) =
+					(self.spatial(), self.toplevel_wl_surface())
+				else {
+					return true;
+				};
+				let pixels_per_meter = pixels_per_meter(&wl_surface, &spatial);
+				let offset = vec3(
+					(position.x - anchor.x) / pixels_per_meter,
+					-(position.y - anchor.y) / pixels_per_meter,
+					0.0,
+				);
+				spatial.set_local_transform(
+					spatial.local_transform() * Mat4::from_translation(offset),
+				);
+			}
+			InteractiveGrab::Resize {
+				edges,
+				start,
+				start_size,
+				size,
+			} => {
+				let start = *start.get_or_insert(position);
+				let delta = [position.x - start.x, position.y - start.y];
+				let mut width = start_size.x as f32;
+				let mut height = start_size.y as f32;
+				if edges.left {
+					width -= delta[0];
+				} else if edges.right {
+					width += delta[0];
+				}
+				if edges.up {
+					height -= delta[1];
+				} else if edges.down {
+					height += delta[1];
+				}
+				let new_size =
+					self.clamp_size([width.max(1.0) as u32, height.max(1.0) as u32].into());
+				if new_size != *size {
+					*size = new_size;
+					self.configure(Some(new_size));
+				}
+			}
 		}
-		states
+		true
 	}
 
 	pub fn new_popup(
@@ -123,6 +490,12 @@ impl XdgBackend {
 		self.popups
 			.lock()
 			.insert(data.uid.clone(), popup_wl_surface.downgrade());
+		// popups fade along with the rest of the panel item, and stay right above it
+		if let Some(core_surface) = CoreSurface::from_wl_surface(popup_wl_surface) {
+			core_surface.set_alpha(*self.alpha.lock());
+			core_surface.set_minimized(self.toplevel_states().minimized);
+			core_surface.set_stack_position(*self.stack_position.lock(), true);
+		}
 
 		let Some(geometry) = data.geometry() else {
 			return;
@@ -144,6 +517,24 @@ impl XdgBackend {
 		};
 		panel_item.reposition_child(&popup_state.uid, geometry)
 	}
+	/// Reposition reactive popups whose constrained geometry changed with their parent
+	pub fn reconfigure_reactive_popups(&self, panel_item: &PanelItem<XdgBackend>) {
+		let popups: Vec<WlSurface> = self
+			.popups
+			.lock()
+			.values()
+			.filter_map(|popup| popup.upgrade().ok())
+			.collect();
+		for wl_surface in popups {
+			let Some(popup_data) = utils::get_data::<PopupData>(&wl_surface) else {
+				continue;
+			};
+			if popup_data.reconfigure_reactive(&wl_surface) {
+				self.reposition_popup(panel_item, &popup_data);
+			}
+		}
+		self.flush_client();
+	}
 	pub fn drop_popup(&self, panel_item: &PanelItem<XdgBackend>, uid: &str) {
 		panel_item.drop_child(uid);
 		let Some(popup) = self.popups.lock().remove(uid) else {
@@ -162,10 +553,7 @@ impl XdgBackend {
 			let parent = utils::get_data::<SurfaceID>(&popup_data.parent())?
 				.as_ref()
 				.clone();
-			let geometry = utils::get_data::<XdgSurfaceData>(&wl_surface)?
-				.geometry
-				.lock()
-				.clone()?;
+			let geometry = popup_data.geometry()?;
 			Some((uid.clone(), ChildInfo { parent, geometry }))
 		}))
 	}
@@ -198,10 +586,20 @@ impl Backend for XdgBackend {
 
 		Ok(PanelItemInitData {
 			cursor: self.cursor.borrow().as_ref().and_then(|c| c.cursor_data()),
-			toplevel: toplevel_data.into(),
+			cursor_shape: self
+				.cursor
+				.borrow()
+				.as_ref()
+				.and_then(|c| c.shape_name())
+				.map(str::to_string),
+			toplevel: ToplevelInfo {
+				states: self.toplevel_states(),
+				..toplevel_data.into()
+			},
 			children: self.child_data(),
 			pointer_grab,
 			keyboard_grab,
+			stack_position: *self.stack_position.lock(),
 		})
 	}
 
@@ -215,6 +613,128 @@ impl Backend for XdgBackend {
 
 		core_surface.apply_material(model_part);
 	}
+	fn set_surface_color_space(&self, surface: SurfaceID, color_space: ColorSpace) {
+		let Some(wl_surface) = self.wl_surface_from_id(&surface) else {
+			return;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
+			return;
+		};
+
+		core_surface.set_color_space(color_space);
+	}
+	fn set_surface_filtering(&self, surface: SurfaceID, filtering: SurfaceFiltering) {
+		let Some(wl_surface) = self.wl_surface_from_id(&surface) else {
+			return;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
+			return;
+		};
+
+		core_surface.set_filtering(filtering);
+	}
+	fn export_surface(
+		&self,
+		surface: SurfaceID,
+		model_part: &Arc<ModelPart>,
+		max_size: Option<Vector2<u32>>,
+		on_resize: Box<dyn Fn(Vector2<u32>) + Send + Sync>,
+	) {
+		let Some(wl_surface) = self.wl_surface_from_id(&surface) else {
+			return;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
+			return;
+		};
+
+		core_surface.add_export(SurfaceExport::new(model_part, max_size, on_resize));
+	}
+	fn stop_surface_export(&self, surface: SurfaceID, model_part: &Arc<ModelPart>) {
+		let Some(wl_surface) = self.wl_surface_from_id(&surface) else {
+			return;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
+			return;
+		};
+
+		core_surface.remove_export(model_part);
+	}
+	fn snapshot_surface(
+		&self,
+		surface: SurfaceID,
+		max_size: u32,
+		on_snapshot: Box<dyn FnOnce(Option<SurfaceSnapshot>) + Send>,
+	) {
+		let Some(core_surface) = self
+			.wl_surface_from_id(&surface)
+			.and_then(|wl_surface| CoreSurface::from_wl_surface(&wl_surface))
+		else {
+			on_snapshot(None);
+			return;
+		};
+		core_surface.add_snapshot(max_size, on_snapshot);
+	}
+
+	fn set_alpha(&self, alpha: f32) {
+		*self.alpha.lock() = alpha;
+		let popups = self.popups.lock();
+		let surfaces = self
+			.toplevel_wl_surface()
+			.into_iter()
+			.chain(popups.values().filter_map(|popup| popup.upgrade().ok()));
+		for wl_surface in surfaces {
+			if let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) {
+				core_surface.set_alpha(alpha);
+			}
+		}
+	}
+
+	fn set_toplevel_minimized(&self, minimized: bool) {
+		let popups = self.popups.lock();
+		let surfaces = self
+			.toplevel_wl_surface()
+			.into_iter()
+			.chain(popups.values().filter_map(|popup| popup.upgrade().ok()));
+		for wl_surface in surfaces {
+			if let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) {
+				core_surface.set_minimized(minimized);
+			}
+		}
+		drop(popups);
+		self.update_toplevel_states(|states| states.minimized = minimized);
+	}
+
+	fn set_stack_position(&self, position: u32) {
+		*self.stack_position.lock() = position;
+		if let Some(core_surface) = self
+			.toplevel_wl_surface()
+			.and_then(|wl_surface| CoreSurface::from_wl_surface(&wl_surface))
+		{
+			core_surface.set_stack_position(position, false);
+		}
+		for popup in self.popups.lock().values() {
+			if let Some(core_surface) = popup
+				.upgrade()
+				.ok()
+				.and_then(|wl_surface| CoreSurface::from_wl_surface(&wl_surface))
+			{
+				core_surface.set_stack_position(position, true);
+			}
+		}
+	}
+
+	fn set_geometry(&self, geometry: PanelGeometry) {
+		let Some(core_surface) = self
+			.toplevel_wl_surface()
+			.and_then(|wl_surface| CoreSurface::from_wl_surface(&wl_surface))
+		else {
+			return;
+		};
+		core_surface.set_geometry(geometry);
+	}
+	fn set_decoration_theme(&self, theme: Option<DecorationTheme>) {
+		*self.decoration_theme.lock() = theme.map(Arc::new);
+	}
 
 	fn close_toplevel(&self) {
 		let Ok(xdg_toplevel) = self.toplevel.upgrade() else {
@@ -222,6 +742,19 @@ impl Backend for XdgBackend {
 		};
 		xdg_toplevel.close();
 	}
+	fn kill_client(&self) {
+		let Some(client) = self
+			.toplevel_wl_surface()
+			.and_then(|surface| surface.client())
+		else {
+			return;
+		};
+		debug!(client = ?client.id(), "Kill toplevel's client");
+		wayland::kill_client(client.id());
+	}
+	fn client_info(&self) -> Option<ClientInfo> {
+		wayland::client_info(&self.toplevel_wl_surface()?.client()?)
+	}
 	fn auto_size_toplevel(&self) {
 		self.configure(Some([0, 0].into()));
 	}
@@ -229,11 +762,16 @@ impl Backend for XdgBackend {
 		self.configure(Some(size));
 	}
 	fn set_toplevel_focused_visuals(&self, focused: bool) {
-		self.toplevel_state.lock().activated = focused;
-		self.configure(None);
+		self.update_toplevel_states(|states| states.activated = focused);
+		if let Some(wl_surface) = self.toplevel_wl_surface() {
+			self.seat.text_inputs.set_focus(&wl_surface, focused);
+		}
 	}
 
 	fn pointer_motion(&self, surface: &SurfaceID, position: Vector2<f32>) {
+		if self.interactive_grab_motion(surface, position) {
+			return;
+		}
 		let Some(surface) = self.wl_surface_from_id(surface) else {
 			return;
 		};
@@ -241,6 +779,9 @@ impl Backend for XdgBackend {
 			.pointer_event(&surface, PointerEvent::Motion(position));
 	}
 	fn pointer_button(&self, surface: &SurfaceID, button: u32, pressed: bool) {
+		if !pressed {
+			self.end_interactive_grab();
+		}
 		let Some(surface) = self.wl_surface_from_id(surface) else {
 			return;
 		};
@@ -269,16 +810,25 @@ impl Backend for XdgBackend {
 			},
 		)
 	}
+	fn set_pointer_grab(&self, grabbed: bool) {
+		let Some(surface) = self.wl_surface_from_id(&SurfaceID::Toplevel) else {
+			return;
+		};
+		self.seat.grab_pointer(&surface, grabbed);
+	}
 
-	fn keyboard_keys(&self, surface: &SurfaceID, keymap_id: &str, keys: Vec<i32>) {
-		let Some(surface) = self.wl_surface_from_id(surface) else {
+	fn keyboard_keys(&self, surface_id: &SurfaceID, keymap_id: &str, keys: Vec<i32>) {
+		let Some(surface) = self.wl_surface_from_id(surface_id) else {
 			return;
 		};
-		let keymaps = KEYMAPS.lock();
-		let Some(keymap) = keymaps.get(keymap_id).cloned() else {
+		let Some(keymap) = KEYMAPS.lock().get(keymap_id).cloned() else {
 			return;
 		};
-		if self.seat.set_keymap(keymap, vec![surface.clone()]).is_err() {
+		if self
+			.seat
+			.set_keymap(keymap_id, keymap, vec![surface.clone()])
+			.is_err()
+		{
 			return;
 		}
 		for key in keys {
@@ -290,21 +840,128 @@ impl Backend for XdgBackend {
 				},
 			);
 		}
+		let Some(panel_item) = self.panel_item() else {
+			return;
+		};
+		if let Some(leds) = self.seat.keyboard_leds_change() {
+			panel_item.keyboard_leds_changed(leds);
+		}
+		if let Some(state) = self.seat.keyboard_layout_change(&surface) {
+			panel_item.keyboard_layout_changed(surface_id, state);
+		}
+	}
+	fn keyboard_state(&self, surface: &SurfaceID) -> Option<KeyboardState> {
+		let surface = self.wl_surface_from_id(surface)?;
+		self.seat.keyboard_state(&surface)
+	}
+
+	fn text_input_update(&self, surface: &SurfaceID, update: TextInputUpdate) {
+		let Some(surface) = self.wl_surface_from_id(surface) else {
+			return;
+		};
+		self.seat.text_inputs.update(&surface, &update);
 	}
 
 	fn touch_down(&self, surface: &SurfaceID, id: u32, position: Vector2<f32>) {
 		let Some(surface) = self.wl_surface_from_id(surface) else {
 			return;
 		};
-		self.seat.touch_down(&surface, id, position)
+		self.seat
+			.touch_down(&self.toplevel_wl_surface.id(), &surface, id, position)
 	}
 	fn touch_move(&self, id: u32, position: Vector2<f32>) {
-		self.seat.touch_move(id, position)
+		self.seat
+			.touch_move(&self.toplevel_wl_surface.id(), id, position)
 	}
 	fn touch_up(&self, id: u32) {
-		self.seat.touch_up(id)
+		self.seat.touch_up(&self.toplevel_wl_surface.id(), id)
 	}
 	fn reset_touches(&self) {
-		self.seat.reset_touches()
+		self.seat.reset_touches(&self.toplevel_wl_surface.id())
+	}
+
+	fn pen_motion(&self, surface: &SurfaceID, tool: PenTool, state: PenState) {
+		let Some(surface) = self.wl_surface_from_id(surface) else {
+			return;
+		};
+		self.seat.tablets.pen_motion(&surface, tool, state)
+	}
+	fn pen_button(&self, tool: PenTool, button: u32, pressed: bool) {
+		self.seat.tablets.pen_button(tool, button, pressed)
+	}
+	fn pen_leave(&self, tool: PenTool) {
+		self.seat.tablets.pen_leave(tool)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use smithay::output::{Mode, PhysicalProperties, Scale, Subpixel};
+
+	#[test]
+	fn fullscreen_fills_the_output_in_surface_coordinates() {
+		let output = Output::new(
+			"HEADSET-1".to_owned(),
+			PhysicalProperties {
+				size: (0, 0).into(),
+				subpixel: Subpixel::None,
+				make: "Stardust XR".to_owned(),
+				model: "Virtual Display".to_owned(),
+			},
+		);
+		assert_eq!(output_size(&output), None);
+		let mode = Mode {
+			size: (3840, 2160).into(),
+			refresh: 90_000,
+		};
+		output.change_current_state(Some(mode), None, Some(Scale::Integer(2)), None);
+		assert_eq!(output_size(&output), Some([1920, 1080].into()));
+	}
+
+	#[test]
+	fn rotated_panels_are_hit_on_the_right_pixel() {
+		// a window with 30 pixels of shadow around it
+		let window = Geometry {
+			origin: [30, 30].into(),
+			size: [1920, 1080].into(),
+		};
+		// on a curved wall, turned away and tilted back a bit off to the side
+		let panel_item = Mat4::from_scale_rotation_translation(
+			Vec3::splat(0.5),
+			Quat::from_rotation_y(0.6) * Quat::from_rotation_x(-0.2),
+			vec3(1.0, 1.5, -2.0),
+		);
+		let transform = panel_item * surface_to_panel(window, PIXELS_PER_METER);
+		let normal = transform.transform_vector3(Vec3::Z).normalize();
+		for pixel in [[990.0, 570.0], [0.0, 0.0], [1500.0, 200.0], [100.0, 1000.0]] {
+			let point = transform.transform_point3(vec3(pixel[0], pixel[1], 0.0));
+			// pointing at it from in front and off at an angle
+			let origin = point + normal + vec3(0.3, -0.2, 0.0);
+			let hit = ray_surface_position(transform, origin, point - origin).unwrap();
+			assert!((hit.x - pixel[0]).abs() < 0.5 && (hit.y - pixel[1]).abs() < 0.5);
+		}
+		// the window's center is the panel item's origin, not the surface's
+		let center = transform.transform_point3(vec3(990.0, 570.0, 0.0));
+		assert!(center.distance(vec3(1.0, 1.5, -2.0)) < 1e-4);
+		// pointing away from the panel
+		let in_front = transform.transform_point3(Vec3::ZERO) + normal;
+		assert!(ray_surface_position(transform, in_front, normal).is_none());
+	}
+
+	#[test]
+	fn fullscreen_configures_are_fullscreen_and_activated() {
+		let fullscreen = ToplevelStates {
+			fullscreen: true,
+			activated: true,
+			..Default::default()
+		};
+		assert_eq!(configure_states(&fullscreen), [5, 6, 7, 8, 2, 4]);
+		let minimized = ToplevelStates {
+			minimized: true,
+			..fullscreen
+		};
+		assert_eq!(configure_states(&minimized), [5, 6, 7, 8, 2]);
+		assert_eq!(configure_states(&ToplevelStates::default()), [5, 6, 7, 8]);
 	}
 }
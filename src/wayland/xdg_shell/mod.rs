@@ -1,13 +1,21 @@
-use self::{backend::XdgBackend, toplevel::ToplevelData};
+pub use self::backend::{panel_transform, pixels_per_meter, ray_surface_position, XdgBackend};
+pub use self::surface::{reject_unconfigured_buffer, XdgCachedState, XdgSurfaceData};
+pub use self::toplevel::ToplevelData;
 use super::state::WaylandState;
 use crate::wayland::{
+	surface::{XDG_POPUP_ROLE, XDG_TOPLEVEL_ROLE},
 	utils::insert_data,
 	xdg_shell::{positioner::PositionerData, surface::XdgSurfaceData},
 };
 use parking_lot::Mutex;
-use smithay::reexports::{
-	wayland_protocols::xdg::shell::server::xdg_wm_base::{self, XdgWmBase},
-	wayland_server::{Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource},
+use smithay::{
+	reexports::{
+		wayland_protocols::xdg::shell::server::xdg_wm_base::{self, XdgWmBase},
+		wayland_server::{
+			Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+		},
+	},
+	wayland::compositor::{self, BufferAssignment, SurfaceAttributes},
 };
 use tracing::debug;
 
@@ -34,7 +42,7 @@ impl Dispatch<XdgWmBase, (), WaylandState> for WaylandState {
 	fn request(
 		_state: &mut WaylandState,
 		_client: &Client,
-		_resource: &XdgWmBase,
+		resource: &XdgWmBase,
 		request: xdg_wm_base::Request,
 		_data: &(),
 		_dhandle: &DisplayHandle,
@@ -46,16 +54,38 @@ impl Dispatch<XdgWmBase, (), WaylandState> for WaylandState {
 				debug!(?positioner, "Create XDG positioner");
 			}
 			xdg_wm_base::Request::GetXdgSurface { id, surface } => {
+				// attached or committed, both count
+				let has_buffer = compositor::with_states(&surface, |data| {
+					let pending = matches!(
+						data.cached_state.pending::<SurfaceAttributes>().buffer,
+						Some(BufferAssignment::NewBuffer(_))
+					);
+					let current = matches!(
+						data.cached_state.current::<SurfaceAttributes>().buffer,
+						Some(BufferAssignment::NewBuffer(_))
+					);
+					pending || current
+				});
 				let xdg_surface = data_init.init(id, surface.downgrade());
+				// it can be made an xdg surface again after the last one got destroyed
+				let other_role = compositor::get_role(&surface)
+					.filter(|role| ![XDG_TOPLEVEL_ROLE, XDG_POPUP_ROLE].contains(role));
+				if let Some(role) = other_role {
+					resource.post_error(
+						xdg_wm_base::Error::Role,
+						format!("The surface already has the {role} role"),
+					);
+					return;
+				}
+				if has_buffer {
+					resource.post_error(
+						xdg_wm_base::Error::InvalidSurfaceState,
+						"The surface already has a buffer",
+					);
+					return;
+				}
 				debug!(?xdg_surface, "Create XDG surface");
-				insert_data(
-					&surface,
-					XdgSurfaceData {
-						wl_surface: surface.downgrade(),
-						xdg_surface,
-						geometry: Mutex::new(None),
-					},
-				);
+				insert_data(&surface, XdgSurfaceData::new(&surface, xdg_surface));
 			}
 			xdg_wm_base::Request::Pong { serial } => {
 				debug!(serial, "Client pong");
@@ -1,12 +1,15 @@
-use super::{backend::XdgBackend, positioner::PositionerData};
+use super::{backend::XdgBackend, positioner::PositionerData, surface::XdgSurfaceData};
 use crate::{
 	nodes::items::panel::{Geometry, PanelItem, SurfaceID},
-	wayland::{state::WaylandState, utils::get_data},
+	wayland::{
+		serial::valid_popup_press, state::WaylandState, surface::CoreSurface, utils::get_data,
+	},
 };
+use mint::Vector2;
 use parking_lot::Mutex;
 use smithay::reexports::{
 	wayland_protocols::xdg::shell::server::{
-		xdg_popup::{self, XdgPopup},
+		xdg_popup::{self, XdgPopup, EVT_REPOSITIONED_SINCE},
 		xdg_positioner::XdgPositioner,
 	},
 	wayland_server::{
@@ -25,6 +28,7 @@ pub struct PopupData {
 	parent: Mutex<WlWeak<WlSurface>>,
 	panel_item: Weak<PanelItem<XdgBackend>>,
 	positioner: Mutex<XdgPositioner>,
+	geometry: Mutex<Option<Geometry>>,
 }
 impl PopupData {
 	pub fn new(
@@ -39,16 +43,92 @@ impl PopupData {
 			parent: Mutex::new(parent.downgrade()),
 			panel_item: Arc::downgrade(panel_item),
 			positioner: Mutex::new(positioner),
+			geometry: Mutex::new(None),
 		}
 	}
-	pub fn geometry(&self) -> Option<Geometry> {
+	fn positioner_data(&self) -> Option<PositionerData> {
 		let positioner = self.positioner.lock().clone();
-		let positioner_data = positioner.data::<Mutex<PositionerData>>()?.lock();
-		Some(positioner_data.clone().into())
+		let positioner_data = *positioner.data::<Mutex<PositionerData>>()?.lock();
+		Some(positioner_data)
+	}
+	/// The last geometry sent to the client, or the unconstrained one before the first configure
+	pub fn geometry(&self) -> Option<Geometry> {
+		if let Some(geometry) = *self.geometry.lock() {
+			return Some(geometry);
+		}
+		Some(self.positioner_data()?.into())
 	}
 	pub fn parent(&self) -> WlSurface {
 		self.parent.lock().upgrade().unwrap()
 	}
+
+	/// The toplevel's window geometry in the coordinate space of this popup's parent,
+	/// walking through any parent popups so nested submenus are kept inside the toplevel too.
+	fn bounds(&self) -> Option<Geometry> {
+		let mut offset = Vector2::from([0, 0]);
+		let mut parent = self.parent.lock().upgrade().ok()?;
+		while let Some(parent_popup) = get_data::<PopupData>(&parent) {
+			let parent_geometry = parent_popup.geometry()?;
+			offset.x += parent_geometry.origin.x;
+			offset.y += parent_geometry.origin.y;
+			parent = parent_popup.parent.lock().upgrade().ok()?;
+		}
+		let window_geometry = get_data::<XdgSurfaceData>(&parent)?.geometry();
+		let size = match window_geometry {
+			Some(geometry) => geometry.size,
+			None => CoreSurface::from_wl_surface(&parent)?.size()?,
+		};
+		Some(Geometry {
+			origin: [-offset.x, -offset.y].into(),
+			size,
+		})
+	}
+	fn constrained_geometry(&self) -> Option<Geometry> {
+		let positioner_data = self.positioner_data()?;
+		Some(match self.bounds() {
+			Some(bounds) => positioner_data.constrained_geometry(bounds),
+			None => positioner_data.into(),
+		})
+	}
+
+	/// Send the constrained geometry to the client, with `repositioned` when answering a reposition
+	pub fn configure(&self, wl_surface: &WlSurface, reposition_token: Option<u32>) {
+		let Some(geometry) = self.constrained_geometry() else {
+			return;
+		};
+		self.geometry.lock().replace(geometry);
+
+		let Some(xdg_popup) = get_data::<XdgPopup>(wl_surface) else {
+			return;
+		};
+		let Some(xdg_surface_data) = get_data::<XdgSurfaceData>(wl_surface) else {
+			return;
+		};
+		if let Some(token) = reposition_token {
+			if xdg_popup.version() >= EVT_REPOSITIONED_SINCE {
+				xdg_popup.repositioned(token);
+			}
+		}
+		xdg_popup.configure(
+			geometry.origin.x,
+			geometry.origin.y,
+			geometry.size.x as i32,
+			geometry.size.y as i32,
+		);
+		xdg_surface_data.configure(Some(geometry.size));
+	}
+	/// Reconfigure a reactive popup if its parent changed enough to change its constrained geometry
+	pub fn reconfigure_reactive(&self, wl_surface: &WlSurface) -> bool {
+		if !self.positioner_data().is_some_and(|p| p.reactive()) {
+			return false;
+		}
+		let geometry = self.constrained_geometry();
+		if geometry.is_none() || geometry == *self.geometry.lock() {
+			return false;
+		}
+		self.configure(wl_surface, None);
+		true
+	}
 }
 
 impl Dispatch<XdgPopup, WlWeak<WlSurface>, WaylandState> for WaylandState {
@@ -75,21 +155,35 @@ impl Dispatch<XdgPopup, WlWeak<WlSurface>, WaylandState> for WaylandState {
 		};
 		match request {
 			xdg_popup::Request::Grab { seat, serial } => {
-				*popup_data.grabbed.lock() = true;
 				debug!(?xdg_popup, ?seat, serial, "XDG popup grab");
+				// the press is on the parent, not the popup that's only just being mapped
+				if !valid_popup_press(&seat, serial) {
+					xdg_popup.popup_done();
+					return;
+				}
+				*popup_data.grabbed.lock() = true;
 				panel_item.grab_keyboard(Some(SurfaceID::Child(popup_data.uid.clone())));
 			}
 			xdg_popup::Request::Reposition { positioner, token } => {
 				debug!(?xdg_popup, ?positioner, token, "XDG popup reposition");
 				*popup_data.positioner.lock() = positioner;
+				popup_data.configure(&wl_surface, Some(token));
 				panel_item
 					.backend
 					.reposition_popup(&panel_item, &popup_data);
+				panel_item.backend.reconfigure_reactive_popups(&panel_item);
 			}
 			xdg_popup::Request::Destroy => {
 				debug!(?xdg_popup, "Destroy XDG popup");
 				if *popup_data.grabbed.lock() {
-					panel_item.grab_keyboard(None);
+					// hand the grab back to the parent popup if this was a grabbing submenu
+					let parent = popup_data.parent.lock().upgrade().ok();
+					let parent_grab = parent
+						.as_ref()
+						.and_then(get_data::<PopupData>)
+						.filter(|parent_popup| *parent_popup.grabbed.lock())
+						.map(|parent_popup| SurfaceID::Child(parent_popup.uid.clone()));
+					panel_item.grab_keyboard(parent_grab);
 				}
 			}
 			_ => unreachable!(),
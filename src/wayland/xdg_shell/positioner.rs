@@ -122,6 +122,119 @@ impl PositionerData {
 
 		pos
 	}
+
+	pub fn reactive(&self) -> bool {
+		self.reactive
+	}
+
+	fn flipped_x(&self) -> Self {
+		let mut flipped = *self;
+		flipped.anchor = match self.anchor {
+			Anchor::Left => Anchor::Right,
+			Anchor::Right => Anchor::Left,
+			Anchor::TopLeft => Anchor::TopRight,
+			Anchor::TopRight => Anchor::TopLeft,
+			Anchor::BottomLeft => Anchor::BottomRight,
+			Anchor::BottomRight => Anchor::BottomLeft,
+			anchor => anchor,
+		};
+		flipped.gravity = match self.gravity {
+			Gravity::Left => Gravity::Right,
+			Gravity::Right => Gravity::Left,
+			Gravity::TopLeft => Gravity::TopRight,
+			Gravity::TopRight => Gravity::TopLeft,
+			Gravity::BottomLeft => Gravity::BottomRight,
+			Gravity::BottomRight => Gravity::BottomLeft,
+			gravity => gravity,
+		};
+		flipped
+	}
+	fn flipped_y(&self) -> Self {
+		let mut flipped = *self;
+		flipped.anchor = match self.anchor {
+			Anchor::Top => Anchor::Bottom,
+			Anchor::Bottom => Anchor::Top,
+			Anchor::TopLeft => Anchor::BottomLeft,
+			Anchor::BottomLeft => Anchor::TopLeft,
+			Anchor::TopRight => Anchor::BottomRight,
+			Anchor::BottomRight => Anchor::TopRight,
+			anchor => anchor,
+		};
+		flipped.gravity = match self.gravity {
+			Gravity::Top => Gravity::Bottom,
+			Gravity::Bottom => Gravity::Top,
+			Gravity::TopLeft => Gravity::BottomLeft,
+			Gravity::BottomLeft => Gravity::TopLeft,
+			Gravity::TopRight => Gravity::BottomRight,
+			Gravity::BottomRight => Gravity::TopRight,
+			gravity => gravity,
+		};
+		flipped
+	}
+
+	/// Apply the constraint adjustment so the popup stays inside `bounds` (in parent coordinates).
+	/// Each axis tries flipping first, then sliding, then resizing, as the protocol specifies.
+	pub fn constrained_geometry(&self, bounds: Geometry) -> Geometry {
+		let mut geometry: Geometry = (*self).into();
+		let min = bounds.origin;
+		let max = Vector2::from([
+			bounds.origin.x + bounds.size.x as i32,
+			bounds.origin.y + bounds.size.y as i32,
+		]);
+		let fits_x = |geometry: &Geometry| {
+			geometry.origin.x >= min.x && geometry.origin.x + geometry.size.x as i32 <= max.x
+		};
+		let fits_y = |geometry: &Geometry| {
+			geometry.origin.y >= min.y && geometry.origin.y + geometry.size.y as i32 <= max.y
+		};
+		let adjustment = self.constraint_adjustment;
+
+		if !fits_x(&geometry) && adjustment.contains(ConstraintAdjustment::FlipX) {
+			let flipped: Geometry = self.flipped_x().into();
+			if fits_x(&flipped) {
+				geometry.origin.x = flipped.origin.x;
+			}
+		}
+		if !fits_x(&geometry) && adjustment.contains(ConstraintAdjustment::SlideX) {
+			if geometry.origin.x < min.x {
+				geometry.origin.x = min.x;
+			} else if geometry.origin.x + geometry.size.x as i32 > max.x {
+				geometry.origin.x = (max.x - geometry.size.x as i32).max(min.x);
+			}
+		}
+		if !fits_x(&geometry) && adjustment.contains(ConstraintAdjustment::ResizeX) {
+			let left = geometry.origin.x.max(min.x);
+			let right = (geometry.origin.x + geometry.size.x as i32).min(max.x);
+			if right > left {
+				geometry.origin.x = left;
+				geometry.size.x = (right - left) as u32;
+			}
+		}
+
+		if !fits_y(&geometry) && adjustment.contains(ConstraintAdjustment::FlipY) {
+			let flipped: Geometry = self.flipped_y().into();
+			if fits_y(&flipped) {
+				geometry.origin.y = flipped.origin.y;
+			}
+		}
+		if !fits_y(&geometry) && adjustment.contains(ConstraintAdjustment::SlideY) {
+			if geometry.origin.y < min.y {
+				geometry.origin.y = min.y;
+			} else if geometry.origin.y + geometry.size.y as i32 > max.y {
+				geometry.origin.y = (max.y - geometry.size.y as i32).max(min.y);
+			}
+		}
+		if !fits_y(&geometry) && adjustment.contains(ConstraintAdjustment::ResizeY) {
+			let top = geometry.origin.y.max(min.y);
+			let bottom = (geometry.origin.y + geometry.size.y as i32).min(max.y);
+			if bottom > top {
+				geometry.origin.y = top;
+				geometry.size.y = (bottom - top) as u32;
+			}
+		}
+
+		geometry
+	}
 }
 impl From<PositionerData> for Geometry {
 	fn from(value: PositionerData) -> Self {
@@ -224,3 +337,93 @@ impl Dispatch<XdgPositioner, Mutex<PositionerData>, WaylandState> for WaylandSta
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn positioner(
+		size: [u32; 2],
+		anchor_rect: [i32; 4],
+		anchor: Anchor,
+		gravity: Gravity,
+		constraint_adjustment: ConstraintAdjustment,
+	) -> PositionerData {
+		PositionerData {
+			size: size.into(),
+			anchor_rect_pos: [anchor_rect[0], anchor_rect[1]].into(),
+			anchor_rect_size: [anchor_rect[2] as u32, anchor_rect[3] as u32].into(),
+			anchor,
+			gravity,
+			constraint_adjustment,
+			..Default::default()
+		}
+	}
+	fn geometry(origin: [i32; 2], size: [u32; 2]) -> Geometry {
+		Geometry {
+			origin: origin.into(),
+			size: size.into(),
+		}
+	}
+
+	#[test]
+	fn flips_when_the_flipped_popup_fits() {
+		let positioner = positioner(
+			[100, 50],
+			[350, 10, 20, 20],
+			Anchor::TopRight,
+			Gravity::BottomRight,
+			ConstraintAdjustment::FlipX,
+		);
+		assert_eq!(
+			positioner.constrained_geometry(geometry([0, 0], [400, 300])),
+			geometry([250, 10], [100, 50])
+		);
+	}
+
+	#[test]
+	fn slides_unflipped_when_the_flipped_popup_does_not_fit() {
+		let positioner = positioner(
+			[300, 50],
+			[150, 10, 20, 20],
+			Anchor::TopRight,
+			Gravity::BottomRight,
+			ConstraintAdjustment::FlipX | ConstraintAdjustment::SlideX,
+		);
+		assert_eq!(
+			positioner.constrained_geometry(geometry([0, 0], [400, 300])),
+			geometry([100, 10], [300, 50])
+		);
+	}
+
+	#[test]
+	fn resizes_to_the_bounds() {
+		let positioner = positioner(
+			[100, 100],
+			[10, 250, 20, 20],
+			Anchor::BottomLeft,
+			Gravity::BottomRight,
+			ConstraintAdjustment::ResizeY,
+		);
+		assert_eq!(
+			positioner.constrained_geometry(geometry([0, 0], [400, 300])),
+			geometry([10, 270], [100, 30])
+		);
+	}
+
+	#[test]
+	fn nested_popups_are_kept_inside_the_toplevel() {
+		// a submenu of a popup that sits at (250, 40) in a 400x300 toplevel
+		let positioner = positioner(
+			[120, 80],
+			[0, 20, 150, 20],
+			Anchor::TopRight,
+			Gravity::BottomRight,
+			ConstraintAdjustment::FlipX,
+		);
+		assert_eq!(
+			positioner.constrained_geometry(geometry([-250, -40], [400, 300])),
+			geometry([-120, 20], [120, 80])
+		);
+	}
+}
@@ -1,34 +1,184 @@
 use crate::{
-	nodes::items::panel::{Geometry, PanelItem, SurfaceID},
+	nodes::items::panel::{Geometry, PanelItem, SurfaceID, ToplevelInfo},
 	wayland::{
+		focus_policy::{self, FocusRequest},
 		seat::handle_cursor,
 		state::{ClientState, WaylandState},
-		surface::CoreSurface,
+		surface::{give_role, CoreSurface, XDG_POPUP_ROLE, XDG_TOPLEVEL_ROLE},
 		utils,
 		xdg_shell::{popup::PopupData, toplevel::ToplevelData, XdgBackend},
 		SERIAL_COUNTER,
 	},
 };
+use mint::Vector2;
 use nanoid::nanoid;
 use parking_lot::Mutex;
-use smithay::reexports::{
-	wayland_protocols::xdg::shell::server::{
-		xdg_surface::{self, XdgSurface},
-		xdg_toplevel::{XdgToplevel, EVT_WM_CAPABILITIES_SINCE},
+use smithay::{
+	reexports::{
+		wayland_protocols::xdg::shell::server::{
+			xdg_surface::{self, XdgSurface},
+			xdg_toplevel::{XdgToplevel, EVT_WM_CAPABILITIES_SINCE},
+		},
+		wayland_server::{
+			protocol::wl_surface::WlSurface, Client, DataInit, Dispatch, DisplayHandle, Resource,
+			Weak as WlWeak,
+		},
 	},
-	wayland_server::{
-		protocol::wl_surface::WlSurface, Client, DataInit, Dispatch, DisplayHandle, Resource,
-		Weak as WlWeak,
+	wayland::compositor::{self, BufferAssignment, Cacheable, SurfaceAttributes},
+};
+use std::{
+	collections::VecDeque,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Weak,
 	},
 };
-use std::sync::{Arc, Weak};
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
+
+/// The xdg state that's double-buffered like the rest of the surface's, so a new window geometry
+/// or size hint only shows up with the buffer it was committed with
+#[derive(Debug, Default, Clone, Copy)]
+pub struct XdgCachedState {
+	pub geometry: Option<Geometry>,
+	pub min_size: Option<Vector2<u32>>,
+	pub max_size: Option<Vector2<u32>>,
+}
+impl Cacheable for XdgCachedState {
+	fn commit(&mut self, _dh: &DisplayHandle) -> Self {
+		*self
+	}
+	fn merge_into(self, into: &mut Self, _dh: &DisplayHandle) {
+		*into = self;
+	}
+}
+pub fn set_pending_xdg_state(wl_surface: &WlSurface, update: impl FnOnce(&mut XdgCachedState)) {
+	compositor::with_states(wl_surface, |data| {
+		update(&mut data.cached_state.pending::<XdgCachedState>())
+	});
+}
+pub fn current_xdg_state(wl_surface: &WlSurface) -> XdgCachedState {
+	compositor::with_states(wl_surface, |data| {
+		*data.cached_state.current::<XdgCachedState>()
+	})
+}
+
+/// A configure sent to the client, with the window size it asked for if it wasn't up to them
+#[derive(Debug, Clone, Copy)]
+struct SentConfigure {
+	serial: u32,
+	size: Option<Vector2<u32>>,
+}
+#[derive(Debug, Default)]
+struct Configures {
+	/// Sent but not acked yet, oldest first
+	sent: VecDeque<SentConfigure>,
+	/// The configure acked since the last commit, the commit after an ack is the one that has
+	/// the buffer for it
+	acked: Option<SentConfigure>,
+}
+impl Configures {
+	fn send(&mut self, serial: u32, size: Option<Vector2<u32>>) {
+		let size = size.filter(|size| size.x > 0 && size.y > 0);
+		self.sent.push_back(SentConfigure { serial, size });
+	}
+	/// Acking a configure also drops every one sent before it. Returns false if it wasn't
+	/// waiting to be acked.
+	fn ack(&mut self, serial: u32) -> bool {
+		let Some(index) = self.sent.iter().position(|c| c.serial == serial) else {
+			return false;
+		};
+		self.acked = self.sent.drain(..=index).last();
+		true
+	}
+	/// See `XdgSurfaceData::commit_window_size`
+	fn commit_window_size(&mut self, window_size: Option<Vector2<u32>>) -> bool {
+		let resize_pending = self.sent.iter().any(|c| c.size.is_some());
+		match self.acked.take().and_then(|configure| configure.size) {
+			Some(size) if Some(size) == window_size => true,
+			_ => !resize_pending,
+		}
+	}
+}
 
 #[derive(Debug)]
 pub struct XdgSurfaceData {
 	pub wl_surface: WlWeak<WlSurface>,
 	pub xdg_surface: XdgSurface,
-	pub geometry: Mutex<Option<Geometry>>,
+	/// The client acked a configure, before that it can't attach buffers
+	pub configured: AtomicBool,
+	configures: Mutex<Configures>,
+}
+impl XdgSurfaceData {
+	pub fn new(wl_surface: &WlSurface, xdg_surface: XdgSurface) -> Self {
+		XdgSurfaceData {
+			wl_surface: wl_surface.downgrade(),
+			xdg_surface,
+			configured: AtomicBool::new(false),
+			configures: Mutex::new(Configures::default()),
+		}
+	}
+	/// Finish a configure sequence, `size` being the window size asked for in the role's
+	/// configure right before if there was one
+	pub fn configure(&self, size: Option<Vector2<u32>>) {
+		let serial = SERIAL_COUNTER.inc();
+		self.configures.lock().send(serial, size);
+		self.xdg_surface.configure(serial);
+	}
+	fn ack(&self, serial: u32) -> bool {
+		self.configures.lock().ack(serial)
+	}
+	/// Whether the window size this commit brought should be shown, to call on every commit.
+	/// While the client still has to ack a resize it's in the middle of drawing for it, so panels
+	/// keep their old size until it acks and commits a buffer of the size asked for. That size
+	/// is only a hint: if it commits another one for the latest configure, that's the size.
+	pub fn commit_window_size(&self, window_size: Option<Vector2<u32>>) -> bool {
+		self.configures.lock().commit_window_size(window_size)
+	}
+	/// The committed window geometry
+	pub fn geometry(&self) -> Option<Geometry> {
+		current_xdg_state(&self.wl_surface.upgrade().ok()?).geometry
+	}
+	/// The part of the surface that's the window itself, without shadows or other margins. It's
+	/// the whole surface if the client never set a geometry.
+	pub fn window_geometry(&self) -> Option<Geometry> {
+		let wl_surface = self.wl_surface.upgrade().ok()?;
+		if let Some(geometry) = current_xdg_state(&wl_surface).geometry {
+			return Some(geometry);
+		}
+		Some(Geometry {
+			origin: [0, 0].into(),
+			size: CoreSurface::from_wl_surface(&wl_surface)?.size()?,
+		})
+	}
+}
+
+/// Post `unconfigured_buffer` if an xdg surface got a buffer before it acked its first
+/// configure, which also covers ones that never got a role. Returns if it did.
+pub fn reject_unconfigured_buffer(wl_surface: &WlSurface) -> bool {
+	let Some(xdg_surface_data) = utils::get_data::<XdgSurfaceData>(wl_surface) else {
+		return false;
+	};
+	if xdg_surface_data.configured.load(Ordering::Relaxed) {
+		return false;
+	}
+	let has_buffer = compositor::with_states(wl_surface, |data| {
+		matches!(
+			data.cached_state.current::<SurfaceAttributes>().buffer,
+			Some(BufferAssignment::NewBuffer(_))
+		)
+	});
+	if !has_buffer {
+		return false;
+	}
+	warn!(
+		?wl_surface,
+		"Client attached a buffer to an unconfigured xdg surface"
+	);
+	xdg_surface_data.xdg_surface.post_error(
+		xdg_surface::Error::UnconfiguredBuffer,
+		"Buffer attached before the first configure was acked",
+	);
+	true
 }
 
 impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
@@ -51,6 +201,20 @@ impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
 		};
 		match request {
 			xdg_surface::Request::GetToplevel { id } => {
+				if utils::get_data::<SurfaceID>(&wl_surface).is_some() {
+					xdg_surface.post_error(
+						xdg_surface::Error::AlreadyConstructed,
+						"The xdg surface already has a role",
+					);
+					return;
+				}
+				if !give_role(&wl_surface, XDG_TOPLEVEL_ROLE) {
+					xdg_surface.post_error(
+						xdg_surface::Error::AlreadyConstructed,
+						"The surface already has another role",
+					);
+					return;
+				}
 				let toplevel = data_init.init(id, wl_surface_resource.clone());
 				utils::insert_data(&wl_surface, SurfaceID::Toplevel);
 				utils::insert_data(&wl_surface, toplevel.clone());
@@ -72,7 +236,7 @@ impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
 						vec![]
 					},
 				);
-				xdg_surface.configure(SERIAL_COUNTER.inc());
+				xdg_surface_data.configure(None);
 
 				let client_credentials = client.get_credentials(&state.display_handle).ok();
 				let Some(seat_data) = client.get_data::<ClientState>().map(|s| s.seat.clone())
@@ -80,7 +244,6 @@ impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
 					return;
 				};
 
-				let xdg_surface = xdg_surface.clone();
 				CoreSurface::add_to(
 					state.display_handle.clone(),
 					&wl_surface,
@@ -88,6 +251,15 @@ impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
 						let wl_surface_resource = wl_surface_resource.clone();
 						move || {
 							let wl_surface = wl_surface_resource.upgrade().unwrap();
+							// mapped again after a null buffer, it's still the same window
+							if let Some(panel_item) =
+								utils::get_data::<Weak<PanelItem<XdgBackend>>>(&wl_surface)
+									.as_deref()
+									.and_then(Weak::upgrade)
+							{
+								panel_item.toplevel_mapped();
+								return;
+							}
 
 							let backend = XdgBackend::create(
 								wl_surface.clone(),
@@ -101,16 +273,56 @@ impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
 							utils::insert_data(&wl_surface, Arc::downgrade(&panel_item));
 							utils::insert_data_raw(&wl_surface, node);
 							handle_cursor(&panel_item, panel_item.backend.cursor.clone());
+							panel_item.toplevel_mapped();
+							if focus_policy::allow_request(FocusRequest::NewToplevel) {
+								panel_item.toplevel_activation_request();
+							} else {
+								panel_item.set_toplevel_attention(true);
+							}
+						}
+					},
+					{
+						let wl_surface_resource = wl_surface_resource.clone();
+						move || {
+							let Ok(wl_surface) = wl_surface_resource.upgrade() else {
+								return;
+							};
+							let Some(panel_item) =
+								utils::get_data::<Weak<PanelItem<XdgBackend>>>(&wl_surface)
+									.as_deref()
+									.and_then(Weak::upgrade)
+							else {
+								return;
+							};
+							let Some(toplevel_data) = utils::get_data::<ToplevelData>(&wl_surface)
+							else {
+								return;
+							};
+							let geometry = ToplevelInfo::from(&*toplevel_data).logical_rectangle;
+							panel_item.toplevel_unmapped(geometry);
 						}
 					},
 					{
 						let wl_surface_resource = wl_surface_resource.clone();
 						move |_| {
 							let wl_surface = wl_surface_resource.upgrade().unwrap();
-
-							let Some(panel_item) =
-								utils::get_data::<PanelItem<XdgBackend>>(&wl_surface)
+							let mapped = CoreSurface::from_wl_surface(&wl_surface)
+								.map_or(false, |core_surface| core_surface.size().is_some());
+							let Some(xdg_surface_data) =
+								utils::get_data::<XdgSurfaceData>(&wl_surface)
 							else {
+								return;
+							};
+							let geometry = xdg_surface_data.window_geometry();
+							let size_settled =
+								xdg_surface_data.commit_window_size(geometry.map(|g| g.size));
+
+							let panel_item =
+								utils::get_data::<Weak<PanelItem<XdgBackend>>>(&wl_surface)
+									.as_deref()
+									.and_then(Weak::upgrade);
+							// unmapped toplevels need a configure again before they can map
+							let Some(panel_item) = panel_item.filter(|_| mapped) else {
 								let Some(toplevel) = utils::get_data::<XdgToplevel>(&wl_surface)
 								else {
 									return;
@@ -128,7 +340,7 @@ impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
 										vec![]
 									},
 								);
-								xdg_surface.configure(SERIAL_COUNTER.inc());
+								xdg_surface_data.configure(None);
 								return;
 							};
 							let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface)
@@ -138,7 +350,18 @@ impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
 							let Some(size) = core_surface.size() else {
 								return;
 							};
-							panel_item.toplevel_size_changed(size);
+							if size_settled {
+								panel_item.toplevel_size_changed(size);
+								if let Some(geometry) = geometry {
+									panel_item.toplevel_geometry_changed(geometry);
+								}
+								panel_item.backend.reconfigure_reactive_popups(&panel_item);
+							}
+							if let Some(toplevel_data) =
+								utils::get_data::<ToplevelData>(&wl_surface)
+							{
+								toplevel_data.send_metadata_changes();
+							}
 						}
 					},
 				);
@@ -148,6 +371,20 @@ impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
 				parent,
 				positioner,
 			} => {
+				if utils::get_data::<SurfaceID>(&wl_surface).is_some() {
+					xdg_surface.post_error(
+						xdg_surface::Error::AlreadyConstructed,
+						"The xdg surface already has a role",
+					);
+					return;
+				}
+				if !give_role(&wl_surface, XDG_POPUP_ROLE) {
+					xdg_surface.post_error(
+						xdg_surface::Error::AlreadyConstructed,
+						"The surface already has another role",
+					);
+					return;
+				}
 				let Some(parent) = parent else { return };
 				let Some(parent_wl_surface) = parent
 					.data::<WlWeak<WlSurface>>()
@@ -183,8 +420,8 @@ impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
 				utils::insert_data(&wl_surface, xdg_popup.clone());
 				debug!(?xdg_popup, ?xdg_surface, "Create XDG popup");
 
-				let xdg_surface = xdg_surface.downgrade();
 				let popup_wl_surface = wl_surface.downgrade();
+				let configure_wl_surface = wl_surface.downgrade();
 				CoreSurface::add_to(
 					state.display_handle.clone(),
 					&wl_surface,
@@ -199,12 +436,18 @@ impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
 							.backend
 							.new_popup(&panel_item, &wl_surface, &*popup_data);
 					},
+					|| (),
 					move |commit_count| {
-						if commit_count == 0 {
-							if let Ok(xdg_surface) = xdg_surface.upgrade() {
-								xdg_surface.configure(SERIAL_COUNTER.inc())
-							}
+						if commit_count != 0 {
+							return;
 						}
+						let Ok(wl_surface) = configure_wl_surface.upgrade() else {
+							return;
+						};
+						let Some(popup_data) = utils::get_data::<PopupData>(&wl_surface) else {
+							return;
+						};
+						popup_data.configure(&wl_surface, None);
 					},
 				);
 			}
@@ -218,14 +461,29 @@ impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
 					?xdg_surface,
 					x, y, width, height, "Set XDG surface geometry"
 				);
+				if width <= 0 || height <= 0 {
+					xdg_surface.post_error(
+						xdg_surface::Error::InvalidSize,
+						format!("Window geometry is {width}x{height}, it has to be positive"),
+					);
+					return;
+				}
 				let geometry = Geometry {
 					origin: [x, y].into(),
 					size: [width as u32, height as u32].into(),
 				};
-				xdg_surface_data.geometry.lock().replace(geometry);
+				set_pending_xdg_state(&wl_surface, |state| state.geometry = Some(geometry));
 			}
 			xdg_surface::Request::AckConfigure { serial } => {
 				debug!(?xdg_surface, serial, "Acknowledge XDG surface configure");
+				// acking one that was superseded already is harmless, it just doesn't count
+				if !xdg_surface_data.ack(serial) {
+					debug!(
+						?xdg_surface,
+						serial, "Acked an outdated or unknown configure"
+					);
+				}
+				xdg_surface_data.configured.store(true, Ordering::Relaxed);
 			}
 			xdg_surface::Request::Destroy => {
 				debug!(?xdg_surface, "Destroy XDG surface");
@@ -234,3 +492,69 @@ impl Dispatch<XdgSurface, WlWeak<WlSurface>, WaylandState> for WaylandState {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use smithay::reexports::wayland_server::Display;
+
+	#[test]
+	fn xdg_state_only_changes_on_commit() {
+		let display = Display::<WaylandState>::new().unwrap();
+		let dh = display.handle();
+		let mut pending = XdgCachedState::default();
+		let mut current = XdgCachedState::default();
+		let geometry = Geometry {
+			origin: [8, 8].into(),
+			size: [800, 600].into(),
+		};
+		pending.geometry = Some(geometry);
+		pending.min_size = Some([400, 300].into());
+		assert!(current.geometry.is_none());
+		pending.commit(&dh).merge_into(&mut current, &dh);
+		assert_eq!(current.geometry, Some(geometry));
+		assert_eq!(current.min_size, Some([400, 300].into()));
+
+		// a later commit that only changes the max size keeps the rest
+		pending.max_size = Some([1600, 1200].into());
+		assert!(current.max_size.is_none());
+		pending.commit(&dh).merge_into(&mut current, &dh);
+		assert_eq!(current.geometry, Some(geometry));
+		assert_eq!(current.max_size, Some([1600, 1200].into()));
+	}
+
+	#[test]
+	fn panels_resize_on_the_commit_after_the_ack() {
+		let mut configures = Configures::default();
+		let old = Some([800, 600].into());
+		let new = Some([1000, 700].into());
+		// nothing's pending, so commits go straight through
+		assert!(configures.commit_window_size(old));
+
+		configures.send(1, new);
+		// the client's still drawing at the old size
+		assert!(!configures.commit_window_size(old));
+		assert!(configures.ack(1));
+		// the commit with the ack is the one showing the new size
+		assert!(configures.commit_window_size(new));
+		assert!(configures.commit_window_size(new));
+		// acking it again doesn't count
+		assert!(!configures.ack(1));
+	}
+
+	#[test]
+	fn acks_skip_superseded_configures() {
+		let mut configures = Configures::default();
+		configures.send(1, Some([900, 600].into()));
+		configures.send(2, Some([1000, 700].into()));
+		// the commit answering the older one is shown, but not the ones after it while the
+		// newer resize is still on the way
+		assert!(configures.ack(1));
+		assert!(configures.commit_window_size(Some([900, 600].into())));
+		assert!(!configures.commit_window_size(Some([900, 600].into())));
+		assert!(configures.ack(2));
+		assert!(configures.sent.is_empty());
+		// the size is only a hint, what it commits for the latest is what it gets
+		assert!(configures.commit_window_size(Some([1000, 650].into())));
+	}
+}
@@ -1,34 +1,65 @@
-use super::{backend::XdgBackend, surface::XdgSurfaceData};
+use super::{
+	backend::{ResizeEdges, XdgBackend},
+	surface::{current_xdg_state, set_pending_xdg_state, XdgSurfaceData},
+};
 use crate::{
-	nodes::items::panel::{Geometry, PanelItem, ToplevelInfo},
+	nodes::items::panel::{ContentType, Geometry, PanelItem, ToplevelInfo},
 	wayland::{
+		content_type::surface_content_type,
+		serial::valid_seat_press,
 		state::WaylandState,
 		surface::CoreSurface,
 		utils::{self, get_data},
+		xdg_foreign,
 	},
 };
 use mint::Vector2;
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
-use smithay::reexports::{
-	wayland_protocols::xdg::shell::server::xdg_toplevel::{self, ResizeEdge, XdgToplevel},
-	wayland_server::{
-		protocol::wl_surface::WlSurface, Client, DataInit, Dispatch, DisplayHandle, Resource,
-		Weak as WlWeak,
+use smithay::{
+	output::Output,
+	reexports::{
+		wayland_protocols::xdg::shell::server::xdg_toplevel::{self, ResizeEdge, XdgToplevel},
+		wayland_server::{
+			protocol::wl_surface::WlSurface, Client, DataInit, Dispatch, DisplayHandle, Resource,
+			Weak as WlWeak,
+		},
 	},
 };
-use std::sync::Weak;
+use std::sync::{Arc, Weak};
 use tracing::{debug, error};
 use wayland_backend::protocol::WEnum;
 
+/// A title or app ID, empty ones being none. Changes are sent to the panel item on commit.
+#[derive(Debug, Default)]
+struct Metadata {
+	value: Option<String>,
+	changed: bool,
+}
+impl Metadata {
+	fn set(&mut self, value: String) {
+		let value = (!value.is_empty()).then_some(value);
+		if self.value != value {
+			self.value = value;
+			self.changed = true;
+		}
+	}
+	/// What to send the panel item, if it changed since it was last sent
+	fn take_change(&mut self) -> Option<&str> {
+		std::mem::take(&mut self.changed).then(|| self.value.as_deref().unwrap_or_default())
+	}
+}
+
 pub struct ToplevelData {
 	panel_item: OnceCell<Weak<PanelItem<XdgBackend>>>,
 	wl_surface: WlWeak<WlSurface>,
 	parent: Mutex<Option<WlWeak<WlSurface>>>,
-	title: Mutex<Option<String>>,
-	app_id: Mutex<Option<String>>,
-	max_size: Mutex<Option<Vector2<u32>>>,
-	min_size: Mutex<Option<Vector2<u32>>>,
+	title: Mutex<Metadata>,
+	app_id: Mutex<Metadata>,
+	/// The last min and max size sent to the panel item
+	size_hints: Mutex<(Option<Vector2<u32>>, Option<Vector2<u32>>)>,
+	/// The last content type sent to the panel item
+	content_type: Mutex<ContentType>,
 }
 impl ToplevelData {
 	pub fn new(wl_surface: &WlSurface) -> Self {
@@ -36,10 +67,10 @@ impl ToplevelData {
 			panel_item: OnceCell::new(),
 			wl_surface: wl_surface.downgrade(),
 			parent: Mutex::new(None),
-			title: Mutex::new(None),
-			app_id: Mutex::new(None),
-			max_size: Mutex::new(None),
-			min_size: Mutex::new(None),
+			title: Mutex::new(Metadata::default()),
+			app_id: Mutex::new(Metadata::default()),
+			size_hints: Mutex::new((None, None)),
+			content_type: Mutex::new(ContentType::None),
 		}
 	}
 	pub fn parent(&self) -> Option<WlSurface> {
@@ -50,18 +81,83 @@ impl ToplevelData {
 			.map(Result::ok)
 			.flatten()
 	}
+	/// From `xdg_toplevel.set_parent` or `zxdg_imported_v2.set_parent_of`, the parent can be
+	/// another client's toplevel. Unsetting it leaves the panel item wherever it is.
+	pub fn set_parent(&self, parent: Option<&WlSurface>) {
+		*self.parent.lock() = parent.map(WlSurface::downgrade);
+		let Some(parent_panel_item) = parent
+			.and_then(get_data::<ToplevelData>)
+			.and_then(|parent_toplevel_data| parent_toplevel_data.panel_item())
+		else {
+			return;
+		};
+		let Some(panel_item) = self.panel_item() else {
+			error!("Couldn't get the panel item");
+			return;
+		};
+		panel_item.toplevel_parent_changed(&parent_panel_item.uid);
+	}
+	fn panel_item(&self) -> Option<Arc<PanelItem<XdgBackend>>> {
+		if let Some(panel_item) = self.panel_item.get().and_then(Weak::upgrade) {
+			return Some(panel_item);
+		}
+		let wl_surface = self.wl_surface.upgrade().ok()?;
+		let panel_item = get_data::<Weak<PanelItem<XdgBackend>>>(&wl_surface)?;
+		let _ = self.panel_item.set((*panel_item).clone());
+		panel_item.upgrade()
+	}
+	/// Send title, app ID and content type changes since the last commit, so clients that retitle
+	/// constantly (like terminals on every keystroke) only cause one signal per commit.
+	/// Size hints are double-buffered, so they're sent here once they've been committed.
+	pub fn send_metadata_changes(&self) {
+		let Some(panel_item) = self.panel_item() else {
+			return;
+		};
+		if let Some(title) = self.title.lock().take_change() {
+			panel_item.toplevel_title_changed(title);
+		}
+		if let Some(app_id) = self.app_id.lock().take_change() {
+			panel_item.toplevel_app_id_changed(app_id);
+		}
+		let Ok(wl_surface) = self.wl_surface.upgrade() else {
+			return;
+		};
+		let size_hints = (self.min_size(), self.max_size());
+		let mut sent_size_hints = self.size_hints.lock();
+		if *sent_size_hints != size_hints {
+			*sent_size_hints = size_hints;
+			panel_item.toplevel_size_hints_changed(size_hints.0, size_hints.1);
+		}
+		drop(sent_size_hints);
+		let content_type = surface_content_type(&wl_surface);
+		let mut sent_content_type = self.content_type.lock();
+		if *sent_content_type != content_type {
+			*sent_content_type = content_type;
+			panel_item.toplevel_content_type_changed(content_type);
+		}
+	}
+	pub fn title(&self) -> Option<String> {
+		self.title.lock().value.clone()
+	}
+	pub fn min_size(&self) -> Option<Vector2<u32>> {
+		current_xdg_state(&self.wl_surface.upgrade().ok()?).min_size
+	}
+	pub fn max_size(&self) -> Option<Vector2<u32>> {
+		current_xdg_state(&self.wl_surface.upgrade().ok()?).max_size
+	}
 }
 impl From<&ToplevelData> for ToplevelInfo {
 	fn from(value: &ToplevelData) -> Self {
 		let wl_surface = value.wl_surface.upgrade().ok();
-		let size = CoreSurface::from_wl_surface(wl_surface.as_ref().unwrap())
-			.unwrap()
-			.size()
-			.unwrap();
+		let mapped_size = wl_surface
+			.as_ref()
+			.and_then(CoreSurface::from_wl_surface)
+			.and_then(|core_surface| core_surface.size());
+		let size = mapped_size.unwrap_or([0, 0].into());
 		let logical_rectangle = wl_surface
 			.as_ref()
 			.and_then(utils::get_data::<XdgSurfaceData>)
-			.and_then(|d| d.geometry.lock().clone())
+			.and_then(|d| d.window_geometry())
 			.unwrap_or_else(|| Geometry {
 				origin: [0, 0].into(),
 				size,
@@ -75,12 +171,18 @@ impl From<&ToplevelData> for ToplevelInfo {
 			.map(|i| i.uid.clone());
 		ToplevelInfo {
 			parent,
-			title: value.title.lock().clone(),
-			app_id: value.app_id.lock().clone(),
+			title: value.title.lock().value.clone(),
+			app_id: value.app_id.lock().value.clone(),
 			size,
-			min_size: value.min_size.lock().clone(),
-			max_size: value.max_size.lock().clone(),
+			min_size: value.min_size(),
+			max_size: value.max_size(),
 			logical_rectangle,
+			states: Default::default(),
+			mapped: mapped_size.is_some(),
+			content_type: wl_surface
+				.as_ref()
+				.map(surface_content_type)
+				.unwrap_or_default(),
 		}
 	}
 }
@@ -93,9 +195,22 @@ impl Drop for ToplevelData {
 	}
 }
 
+/// Negative size hints are a protocol error, 0 just means unconstrained. Returns if posted.
+fn reject_size_hint(xdg_toplevel: &XdgToplevel, width: i32, height: i32) -> bool {
+	let Some(message) = size_hint_error(width, height) else {
+		return false;
+	};
+	xdg_toplevel.post_error(xdg_toplevel::Error::InvalidSize, message);
+	true
+}
+fn size_hint_error(width: i32, height: i32) -> Option<String> {
+	(width < 0 || height < 0)
+		.then(|| format!("Size hint is {width}x{height}, it can't be negative"))
+}
+
 impl Dispatch<XdgToplevel, WlWeak<WlSurface>, WaylandState> for WaylandState {
 	fn request(
-		_state: &mut WaylandState,
+		state: &mut WaylandState,
 		_client: &Client,
 		xdg_toplevel: &XdgToplevel,
 		request: xdg_toplevel::Request,
@@ -115,55 +230,38 @@ impl Dispatch<XdgToplevel, WlWeak<WlSurface>, WaylandState> for WaylandState {
 			xdg_toplevel::Request::SetParent { parent } => {
 				debug!(?xdg_toplevel, ?parent, "Set XDG Toplevel parent");
 				let Some(parent_xdg_toplevel) = parent else {
-					*toplevel_data.parent.lock() = None;
-					return;
-				};
-				let Some(parent_toplevel_data) = parent_xdg_toplevel.data::<ToplevelData>() else {
-					error!("Couldn't get XDG toplevel parent data");
-					return;
-				};
-				let Ok(parent_wl_surface) = parent_toplevel_data.wl_surface.upgrade() else {
-					error!("Couldn't get XDG toplevel parent wl surface");
+					toplevel_data.set_parent(None);
 					return;
 				};
-				*toplevel_data.parent.lock() = Some(parent_wl_surface.downgrade());
-				let Some(parent_panel_item) = parent_toplevel_data
-					.panel_item
-					.get()
-					.and_then(Weak::upgrade)
+				// toplevels keep their wl_surface as their user data, not the `ToplevelData`
+				let Some(Ok(parent_wl_surface)) = parent_xdg_toplevel
+					.data::<WlWeak<WlSurface>>()
+					.map(WlWeak::upgrade)
 				else {
+					error!("Couldn't get XDG toplevel parent wl surface");
 					return;
 				};
-				let Some(panel_item) = get_data::<PanelItem<XdgBackend>>(&wl_surface) else {
-					error!("Couldn't get the panel item");
-					return;
-				};
-				panel_item.toplevel_parent_changed(&parent_panel_item.uid);
+				toplevel_data.set_parent(Some(&parent_wl_surface));
 			}
 			xdg_toplevel::Request::SetTitle { title } => {
 				debug!(?xdg_toplevel, ?title, "Set XDG Toplevel title");
-				*toplevel_data.title.lock() = (!title.is_empty()).then_some(title.clone());
-				let Some(panel_item) = get_data::<PanelItem<XdgBackend>>(&wl_surface) else {
-					error!("Couldn't get the panel item");
-					return;
-				};
-				panel_item.toplevel_title_changed(&title);
+				toplevel_data.title.lock().set(title);
 			}
 			xdg_toplevel::Request::SetAppId { app_id } => {
 				debug!(?xdg_toplevel, ?app_id, "Set XDG Toplevel app ID");
-				*toplevel_data.app_id.lock() = (!app_id.is_empty()).then_some(app_id.clone());
-				let Some(panel_item) = get_data::<PanelItem<XdgBackend>>(&wl_surface) else {
-					error!("Couldn't get the panel item");
-					return;
-				};
-				panel_item.toplevel_app_id_changed(&app_id);
+				toplevel_data.app_id.lock().set(app_id);
 			}
 			xdg_toplevel::Request::Move { seat, serial } => {
 				debug!(?xdg_toplevel, ?seat, serial, "XDG Toplevel move request");
-				let Some(panel_item) = get_data::<PanelItem<XdgBackend>>(&wl_surface) else {
+				if !valid_seat_press(&seat, serial, Some(&wl_surface)) {
+					return;
+				}
+				let Some(panel_item) = toplevel_data.panel_item() else {
 					error!("Couldn't get the panel item");
 					return;
 				};
+				// the backend does the grab itself, the shell is still told so it can follow
+				panel_item.backend.start_move();
 				panel_item.toplevel_move_request();
 			}
 			xdg_toplevel::Request::Resize {
@@ -190,46 +288,100 @@ impl Dispatch<XdgToplevel, WlWeak<WlSurface>, WaylandState> for WaylandState {
 					ResizeEdge::BottomRight => (false, true, false, true),
 					_ => (false, false, false, false),
 				};
-				let Some(panel_item) = get_data::<PanelItem<XdgBackend>>(&wl_surface) else {
+				if !valid_seat_press(&seat, serial, Some(&wl_surface)) {
+					return;
+				}
+				let Some(panel_item) = toplevel_data.panel_item() else {
 					error!("Couldn't get the panel item");
 					return;
 				};
-				panel_item.toplevel_resize_request(up, down, left, right)
+				panel_item.backend.start_resize(ResizeEdges {
+					up,
+					down,
+					left,
+					right,
+				});
+				panel_item.toplevel_resize_request(up, down, left, right);
 			}
 			xdg_toplevel::Request::SetMaxSize { width, height } => {
 				debug!(?xdg_toplevel, width, height, "Set XDG Toplevel max size");
-				*toplevel_data.max_size.lock() = (width > 1 || height > 1)
+				if reject_size_hint(xdg_toplevel, width, height) {
+					return;
+				}
+				let max_size = (width > 1 || height > 1)
 					.then_some(Vector2::from([width as u32, height as u32]));
+				set_pending_xdg_state(&wl_surface, |state| state.max_size = max_size);
 			}
 			xdg_toplevel::Request::SetMinSize { width, height } => {
 				debug!(?xdg_toplevel, width, height, "Set XDG Toplevel min size");
-				*toplevel_data.min_size.lock() = (width > 1 || height > 1)
+				if reject_size_hint(xdg_toplevel, width, height) {
+					return;
+				}
+				let min_size = (width > 1 || height > 1)
 					.then_some(Vector2::from([width as u32, height as u32]));
+				set_pending_xdg_state(&wl_surface, |state| state.min_size = min_size);
+			}
+			xdg_toplevel::Request::SetMaximized => {
+				debug!(?xdg_toplevel, "Set XDG Toplevel maximized");
+				let Some(panel_item) = toplevel_data.panel_item() else {
+					error!("Couldn't get the panel item");
+					return;
+				};
+				panel_item
+					.backend
+					.update_toplevel_states(|states| states.maximized = true);
+			}
+			xdg_toplevel::Request::UnsetMaximized => {
+				debug!(?xdg_toplevel, "Unset XDG Toplevel maximized");
+				let Some(panel_item) = toplevel_data.panel_item() else {
+					error!("Couldn't get the panel item");
+					return;
+				};
+				panel_item
+					.backend
+					.update_toplevel_states(|states| states.maximized = false);
+			}
+			xdg_toplevel::Request::SetMinimized => {
+				debug!(?xdg_toplevel, "Set XDG Toplevel minimized");
+				let Some(panel_item) = toplevel_data.panel_item() else {
+					error!("Couldn't get the panel item");
+					return;
+				};
+				panel_item.toplevel_minimize_request();
 			}
-			xdg_toplevel::Request::SetFullscreen { output: _ } => {
-				let Some(panel_item) = get_data::<PanelItem<XdgBackend>>(&wl_surface) else {
+			xdg_toplevel::Request::SetFullscreen { output } => {
+				debug!(?xdg_toplevel, ?output, "Set XDG Toplevel fullscreen");
+				let Some(panel_item) = toplevel_data.panel_item() else {
 					error!("Couldn't get the panel item");
 					return;
 				};
-				panel_item.backend.toplevel_state.lock().fullscreen = true;
-				panel_item.backend.configure(None);
-				panel_item.toplevel_fullscreen_active(true);
+				// the output asked for, which the toplevel moves to, otherwise the one it's on
+				let core_surface = CoreSurface::from_wl_surface(&wl_surface);
+				let requested = output.as_ref().and_then(Output::from_resource);
+				if let (Some(core_surface), Some(requested)) = (&core_surface, &requested) {
+					core_surface.set_output(Some(requested.clone()));
+				}
+				let output = requested
+					.or_else(|| core_surface?.output())
+					.unwrap_or_else(|| state.output.clone());
+				panel_item.backend.set_fullscreen(&output);
 			}
 			xdg_toplevel::Request::UnsetFullscreen => {
-				let Some(panel_item) = get_data::<PanelItem<XdgBackend>>(&wl_surface) else {
+				debug!(?xdg_toplevel, "Unset XDG Toplevel fullscreen");
+				let Some(panel_item) = toplevel_data.panel_item() else {
 					error!("Couldn't get the panel item");
 					return;
 				};
-				panel_item.backend.toplevel_state.lock().fullscreen = false;
-				panel_item.backend.configure(None);
-				panel_item.toplevel_fullscreen_active(false);
+				panel_item.backend.unset_fullscreen();
 			}
 			xdg_toplevel::Request::Destroy => {
 				debug!(?xdg_toplevel, "Destroy XDG Toplevel");
-				let Some(panel_item) = get_data::<PanelItem<XdgBackend>>(&wl_surface) else {
+				xdg_foreign::revoke(&wl_surface);
+				let Some(panel_item) = toplevel_data.panel_item() else {
 					error!("Couldn't get the panel item");
 					return;
 				};
+				panel_item.backend.cancel_interactive_grab();
 				panel_item.backend.seat.drop_surface(&wl_surface);
 				panel_item.drop_toplevel();
 			}
@@ -237,3 +389,46 @@ impl Dispatch<XdgToplevel, WlWeak<WlSurface>, WaylandState> for WaylandState {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn negative_size_hints_are_errors() {
+		assert!(size_hint_error(0, 0).is_none());
+		assert!(size_hint_error(800, 0).is_none());
+		assert_eq!(
+			size_hint_error(-1, 600).as_deref(),
+			Some("Size hint is -1x600, it can't be negative")
+		);
+		assert!(size_hint_error(800, -600).is_some());
+	}
+
+	#[test]
+	fn title_changes_are_sent_once_per_commit() {
+		let mut title = Metadata::default();
+		assert_eq!(title.take_change(), None);
+		// a terminal retitling on every keystroke between two commits
+		title.set("vim".to_string());
+		title.set("vim a".to_string());
+		title.set("vim ab".to_string());
+		assert_eq!(title.take_change(), Some("vim ab"));
+		assert_eq!(title.take_change(), None);
+	}
+
+	#[test]
+	fn unchanged_titles_are_not_sent() {
+		let mut title = Metadata::default();
+		title.set("shell".to_string());
+		title.take_change();
+		title.set("shell".to_string());
+		assert_eq!(title.take_change(), None);
+		// clearing it is a change too, sent as an empty title
+		title.set("".to_string());
+		assert_eq!(title.value, None);
+		assert_eq!(title.take_change(), Some(""));
+		title.set("".to_string());
+		assert_eq!(title.take_change(), None);
+	}
+}
@@ -6,9 +6,14 @@ use crate::{
 	nodes::{
 		data::KEYMAPS,
 		drawable::model::ModelPart,
-		items::panel::{Backend, Geometry, PanelItem, PanelItemInitData, SurfaceID, ToplevelInfo},
+		items::panel::{
+			Backend, ClientInfo, ColorSpace, ContentType, DecorationTheme, Geometry, KeyboardState,
+			PanelGeometry, PanelItem, PanelItemInitData, PenState, PenTool, SurfaceFiltering,
+			SurfaceID, SurfaceSnapshot, TextInputUpdate, ToplevelInfo, ToplevelStates,
+		},
+		Node,
 	},
-	wayland::surface::CoreSurface,
+	wayland::surface::{CoreSurface, SurfaceExport},
 };
 use color_eyre::eyre::Result;
 use mint::Vector2;
@@ -22,7 +27,6 @@ use smithay::{
 		x11rb::protocol::xproto::Window,
 	},
 	utils::{Logical, Rectangle},
-	wayland::compositor,
 	xwayland::{
 		xwm::{Reorder, ResizeEdge, XwmId},
 		X11Surface, X11Wm, XWayland, XWaylandEvent, XwmHandler,
@@ -30,7 +34,7 @@ use smithay::{
 };
 use std::{ffi::OsStr, iter::empty, sync::Arc, time::Duration};
 use tokio::sync::oneshot;
-use tracing::debug;
+use tracing::{debug, warn};
 
 pub struct XWaylandState {
 	pub display: u32,
@@ -109,11 +113,17 @@ struct XWaylandHandler {
 }
 impl XWaylandHandler {
 	fn panel_item(&self, window: &X11Surface) -> Option<Arc<PanelItem<X11Backend>>> {
-		compositor::with_states(&window.wl_surface()?, |s| {
-			s.data_map.get::<Arc<PanelItem<X11Backend>>>().cloned()
-		})
+		window_panel_item(window)
 	}
 }
+/// The panel item stays with the window while it's unmapped, as X windows get a new
+/// wl_surface every time they're mapped.
+fn window_panel_item(window: &X11Surface) -> Option<Arc<PanelItem<X11Backend>>> {
+	let (_node, panel_item) = window
+		.user_data()
+		.get::<(Arc<Node>, Arc<PanelItem<X11Backend>>)>()?;
+	Some(panel_item.clone())
+}
 
 impl XwmHandler for XWaylandHandler {
 	fn xwm_state(&mut self, _xwm: XwmId) -> &mut X11Wm {
@@ -148,9 +158,13 @@ impl XwmHandler for XWaylandHandler {
 					let Some(wl_surface) = window.wl_surface() else {
 						return;
 					};
+					if let Some(panel_item) = window_panel_item(&window) {
+						panel_item.toplevel_mapped();
+						return;
+					}
 					let seat = seat.clone();
 					window.user_data().insert_if_missing_threadsafe(|| {
-						let panel_item = PanelItem::create(
+						PanelItem::create(
 							Box::new(X11Backend {
 								toplevel_parent: None,
 								toplevel: window.clone(),
@@ -162,14 +176,16 @@ impl XwmHandler for XWaylandHandler {
 								.client()
 								.and_then(|c| c.get_credentials(&dh).ok())
 								.map(|c| c.pid),
-						);
-						panel_item
+						)
 					});
+					if let Some(panel_item) = window_panel_item(&window) {
+						panel_item.toplevel_mapped();
+					}
 				}
 			},
+			|| (),
 			move |_| {
-				let Some(panel_item) = window.user_data().get::<Arc<PanelItem<X11Backend>>>()
-				else {
+				let Some(panel_item) = window_panel_item(&window) else {
 					return;
 				};
 				panel_item.toplevel_size_changed(
@@ -188,12 +204,19 @@ impl XwmHandler for XWaylandHandler {
 
 	fn unmapped_window(&mut self, _xwm: XwmId, window: X11Surface) {
 		debug!(?window, "Unmap X window");
-		if let Some(panel_item) = window.user_data().get::<Arc<PanelItem<X11Backend>>>() {
-			panel_item.drop_toplevel();
+		if let Some(panel_item) = window_panel_item(&window) {
+			let geometry = window.geometry();
+			panel_item.toplevel_unmapped(Geometry {
+				origin: [0, 0].into(),
+				size: [geometry.size.w as u32, geometry.size.h as u32].into(),
+			});
 		}
 	}
 	fn destroyed_window(&mut self, _xwm: XwmId, window: X11Surface) {
 		debug!(?window, "Destroy X window");
+		if let Some(panel_item) = window_panel_item(&window) {
+			panel_item.drop_toplevel();
+		}
 	}
 
 	fn configure_request(
@@ -257,13 +280,29 @@ impl XwmHandler for XWaylandHandler {
 			return;
 		};
 		panel_item.toplevel_fullscreen_active(true);
+		panel_item.toplevel_states_changed(panel_item.backend.states());
 	}
 	fn unfullscreen_request(&mut self, _xwm: XwmId, window: X11Surface) {
 		let _ = window.set_fullscreen(false);
 		let Some(panel_item) = self.panel_item(&window) else {
 			return;
 		};
-		panel_item.toplevel_fullscreen_active(true);
+		panel_item.toplevel_fullscreen_active(false);
+		panel_item.toplevel_states_changed(panel_item.backend.states());
+	}
+	fn maximize_request(&mut self, _xwm: XwmId, window: X11Surface) {
+		let _ = window.set_maximized(true);
+		let Some(panel_item) = self.panel_item(&window) else {
+			return;
+		};
+		panel_item.toplevel_states_changed(panel_item.backend.states());
+	}
+	fn unmaximize_request(&mut self, _xwm: XwmId, window: X11Surface) {
+		let _ = window.set_maximized(false);
+		let Some(panel_item) = self.panel_item(&window) else {
+			return;
+		};
+		panel_item.toplevel_states_changed(panel_item.backend.states());
 	}
 }
 
@@ -283,6 +322,20 @@ impl X11Backend {
 		}
 	}
 
+	fn states(&self) -> ToplevelStates {
+		ToplevelStates {
+			maximized: self.toplevel.is_maximized(),
+			fullscreen: self.toplevel.is_fullscreen(),
+			activated: self.toplevel.is_activated(),
+			resizing: false,
+			minimized: self
+				.toplevel
+				.wl_surface()
+				.and_then(|wl_surface| CoreSurface::from_wl_surface(&wl_surface))
+				.is_some_and(|core_surface| core_surface.minimized()),
+		}
+	}
+
 	// fn flush_client(&self) {
 	// 	let Some(client) = self.toplevel.wl_surface().and_then(|s| s.client()) else {return};
 	// 	if let Some(client_state) = client.get_data::<ClientState>() {
@@ -294,6 +347,7 @@ impl Backend for X11Backend {
 	fn start_data(&self) -> Result<PanelItemInitData> {
 		Ok(PanelItemInitData {
 			cursor: None,
+			cursor_shape: None,
 			toplevel: ToplevelInfo {
 				parent: None,
 				title: Some(self.toplevel.title()),
@@ -319,15 +373,31 @@ impl Backend for X11Backend {
 					]
 					.into(),
 				},
+				states: self.states(),
+				mapped: self.toplevel.is_mapped(),
+				// Xwayland never sets one
+				content_type: ContentType::None,
 			},
 			children: FxHashMap::default(),
 			pointer_grab: self._pointer_grab.lock().clone(),
 			keyboard_grab: self._keyboard_grab.lock().clone(),
+			// filled in by the panel item
+			stack_position: 0,
 		})
 	}
 	fn close_toplevel(&self) {
 		let _ = self.toplevel.close();
 	}
+	/// Every X11 window comes from Xwayland's one Wayland client, killing that would take all of
+	/// them down. So this can only ask nicely.
+	fn kill_client(&self) {
+		warn!("X11 clients can't be killed on their own, closing the window instead");
+		let _ = self.toplevel.close();
+	}
+	/// All Xwayland's, which isn't what anyone asking wants to know about
+	fn client_info(&self) -> Option<ClientInfo> {
+		None
+	}
 
 	fn auto_size_toplevel(&self) {
 		let _ = self.toplevel.configure(None);
@@ -341,6 +411,48 @@ impl Backend for X11Backend {
 	fn set_toplevel_focused_visuals(&self, focused: bool) {
 		let _ = self.toplevel.set_activated(focused);
 	}
+	fn set_alpha(&self, alpha: f32) {
+		let Some(wl_surface) = self.toplevel.wl_surface() else {
+			return;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
+			return;
+		};
+		core_surface.set_alpha(alpha);
+	}
+	/// Only hidden on our side, the X11 client isn't told
+	fn set_toplevel_minimized(&self, minimized: bool) {
+		let Some(wl_surface) = self.toplevel.wl_surface() else {
+			return;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
+			return;
+		};
+		core_surface.set_minimized(minimized);
+		if let Some(panel_item) = window_panel_item(&self.toplevel) {
+			panel_item.toplevel_states_changed(self.states());
+		}
+	}
+	fn set_stack_position(&self, position: u32) {
+		let Some(wl_surface) = self.toplevel.wl_surface() else {
+			return;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
+			return;
+		};
+		core_surface.set_stack_position(position, false);
+	}
+	fn set_geometry(&self, geometry: PanelGeometry) {
+		let Some(wl_surface) = self.toplevel.wl_surface() else {
+			return;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
+			return;
+		};
+		core_surface.set_geometry(geometry);
+	}
+	/// X11 windows draw their own decorations
+	fn set_decoration_theme(&self, _theme: Option<DecorationTheme>) {}
 
 	fn apply_surface_material(&self, surface: SurfaceID, model_part: &Arc<ModelPart>) {
 		let Some(wl_surface) = self.wl_surface_from_id(&surface) else {
@@ -352,6 +464,67 @@ impl Backend for X11Backend {
 
 		core_surface.apply_material(model_part);
 	}
+	fn set_surface_color_space(&self, surface: SurfaceID, color_space: ColorSpace) {
+		let Some(wl_surface) = self.wl_surface_from_id(&surface) else {
+			return;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
+			return;
+		};
+
+		core_surface.set_color_space(color_space);
+	}
+	fn set_surface_filtering(&self, surface: SurfaceID, filtering: SurfaceFiltering) {
+		let Some(wl_surface) = self.wl_surface_from_id(&surface) else {
+			return;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
+			return;
+		};
+
+		core_surface.set_filtering(filtering);
+	}
+	fn export_surface(
+		&self,
+		surface: SurfaceID,
+		model_part: &Arc<ModelPart>,
+		max_size: Option<Vector2<u32>>,
+		on_resize: Box<dyn Fn(Vector2<u32>) + Send + Sync>,
+	) {
+		let Some(wl_surface) = self.wl_surface_from_id(&surface) else {
+			return;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
+			return;
+		};
+
+		core_surface.add_export(SurfaceExport::new(model_part, max_size, on_resize));
+	}
+	fn stop_surface_export(&self, surface: SurfaceID, model_part: &Arc<ModelPart>) {
+		let Some(wl_surface) = self.wl_surface_from_id(&surface) else {
+			return;
+		};
+		let Some(core_surface) = CoreSurface::from_wl_surface(&wl_surface) else {
+			return;
+		};
+
+		core_surface.remove_export(model_part);
+	}
+	fn snapshot_surface(
+		&self,
+		surface: SurfaceID,
+		max_size: u32,
+		on_snapshot: Box<dyn FnOnce(Option<SurfaceSnapshot>) + Send>,
+	) {
+		let Some(core_surface) = self
+			.wl_surface_from_id(&surface)
+			.and_then(|wl_surface| CoreSurface::from_wl_surface(&wl_surface))
+		else {
+			on_snapshot(None);
+			return;
+		};
+		core_surface.add_snapshot(max_size, on_snapshot);
+	}
 
 	fn pointer_motion(&self, surface: &SurfaceID, position: Vector2<f32>) {
 		let Some(surface) = self.wl_surface_from_id(surface) else {
@@ -389,16 +562,25 @@ impl Backend for X11Backend {
 			},
 		)
 	}
+	fn set_pointer_grab(&self, grabbed: bool) {
+		let Some(surface) = self.wl_surface_from_id(&SurfaceID::Toplevel) else {
+			return;
+		};
+		self.seat.grab_pointer(&surface, grabbed);
+	}
 
-	fn keyboard_keys(&self, surface: &SurfaceID, keymap_id: &str, keys: Vec<i32>) {
-		let Some(surface) = self.wl_surface_from_id(surface) else {
+	fn keyboard_keys(&self, surface_id: &SurfaceID, keymap_id: &str, keys: Vec<i32>) {
+		let Some(surface) = self.wl_surface_from_id(surface_id) else {
 			return;
 		};
-		let keymaps = KEYMAPS.lock();
-		let Some(keymap) = keymaps.get(keymap_id).cloned() else {
+		let Some(keymap) = KEYMAPS.lock().get(keymap_id).cloned() else {
 			return;
 		};
-		if self.seat.set_keymap(keymap, vec![surface.clone()]).is_err() {
+		if self
+			.seat
+			.set_keymap(keymap_id, keymap, vec![surface.clone()])
+			.is_err()
+		{
 			return;
 		}
 		for key in keys {
@@ -406,25 +588,67 @@ impl Backend for X11Backend {
 				&surface,
 				KeyboardEvent::Key {
 					key: key.abs() as u32,
-					state: key < 0,
+					state: key > 0,
 				},
 			);
 		}
+		let Some(panel_item) = window_panel_item(&self.toplevel) else {
+			return;
+		};
+		if let Some(leds) = self.seat.keyboard_leds_change() {
+			panel_item.keyboard_leds_changed(leds);
+		}
+		if let Some(state) = self.seat.keyboard_layout_change(&surface) {
+			panel_item.keyboard_layout_changed(surface_id, state);
+		}
 	}
+	fn keyboard_state(&self, surface: &SurfaceID) -> Option<KeyboardState> {
+		let surface = self.wl_surface_from_id(surface)?;
+		self.seat.keyboard_state(&surface)
+	}
+
+	// X11 clients don't speak text-input-v3, they only get keys
+	fn text_input_update(&self, _surface: &SurfaceID, _update: TextInputUpdate) {}
 
 	fn touch_down(&self, surface: &SurfaceID, id: u32, position: Vector2<f32>) {
 		let Some(surface) = self.wl_surface_from_id(surface) else {
 			return;
 		};
-		self.seat.touch_down(&surface, id, position)
+		let Some(toplevel) = self.toplevel.wl_surface() else {
+			return;
+		};
+		self.seat.touch_down(&toplevel.id(), &surface, id, position)
 	}
 	fn touch_move(&self, id: u32, position: Vector2<f32>) {
-		self.seat.touch_move(id, position)
+		let Some(surface) = self.toplevel.wl_surface() else {
+			return;
+		};
+		self.seat.touch_move(&surface.id(), id, position)
 	}
 	fn touch_up(&self, id: u32) {
-		self.seat.touch_up(id)
+		let Some(surface) = self.toplevel.wl_surface() else {
+			return;
+		};
+		self.seat.touch_up(&surface.id(), id)
 	}
 	fn reset_touches(&self) {
-		self.seat.reset_touches()
+		let Some(surface) = self.toplevel.wl_surface() else {
+			return;
+		};
+		self.seat.reset_touches(&surface.id())
+	}
+
+	// Xwayland binds tablet-v2 itself and passes it on to X11 clients as XInput tablets
+	fn pen_motion(&self, surface: &SurfaceID, tool: PenTool, state: PenState) {
+		let Some(surface) = self.wl_surface_from_id(surface) else {
+			return;
+		};
+		self.seat.tablets.pen_motion(&surface, tool, state)
+	}
+	fn pen_button(&self, tool: PenTool, button: u32, pressed: bool) {
+		self.seat.tablets.pen_button(tool, button, pressed)
+	}
+	fn pen_leave(&self, tool: PenTool) {
+		self.seat.tablets.pen_leave(tool)
 	}
 }